@@ -30,11 +30,18 @@ cfg_if! {
         use static_cell::StaticCell;
 
         use crate::modules::button_task;
+        use crate::modules::code_table::CodeTable;
+        use crate::modules::pulse_capture;
         use crate::modules::terminal;
+        use crate::modules::unknown_codes;
         use crate::modules::mqtt::{MQTT, WifiHw};
         use crate::modules::usb_communication::{self, UsbSender};
         use crate::modules::persistency::Persistency;
         use crate::modules::parser::Parser;
+        use crate::modules::receiver_control::ReceiverControl;
+        use crate::modules::receivers::ReceiverPins;
+        use crate::modules::transmitter::{Transmitter, TransmitterSettings};
+        use crate::modules::cc1101::{Cc1101, Cc1101Spi, Radio, RadioSettings};
     }
 }
 
@@ -49,29 +56,90 @@ async fn main(spawner: Spawner) {
 
     static PERSISTENCY: StaticCell<Persistency> = StaticCell::new();
     let persistency = PERSISTENCY.init(Persistency::new(peripherals.FLASH, peripherals.DMA_CH0));
+    if persistency.upgrade().await {
+        // The codes that used to be built in are kept for the devices that were set up with them.
+        if let Err(msg) = CodeTable::defaults().save(persistency).await {
+            defmt::error!("Error saving the default codes: {}", msg);
+        }
+    }
+
+    static RECEIVER_CONTROL: StaticCell<ReceiverControl> = StaticCell::new();
+    let receiver_control = RECEIVER_CONTROL.init(ReceiverControl::new());
 
-    let parser = Parser::new(persistency);
+    let parser = Parser::new(persistency, receiver_control);
+    let radio_settings = RadioSettings::load(persistency).await;
 
     spawner.spawn(terminal::run(usb_receiver, usb_sender, parser)).unwrap();
+    spawner.spawn(pulse_capture::run(receiver_control, usb_sender, radio_settings.tuned_frequency_hz())).unwrap();
+    spawner.spawn(unknown_codes::run(receiver_control, usb_sender)).unwrap();
 
     bind_interrupts!(struct Pio1Irqs {
         PIO1_IRQ_0 => pio::InterruptHandler<PIO1>;
     });
-    let pio = Pio::new(peripherals.PIO1, Pio1Irqs);
+    let Pio { common: mut pio_1_common, sm0: pio_1_sm0, sm1: pio_1_sm1, irq0: pio_1_irq0, .. } = Pio::new(peripherals.PIO1, Pio1Irqs);
+
+    // The transmitter shares PIO1 with Wi-Fi, so all state machines of PIO0 are left for receivers.
+    // The pin types differ, so every allowed pin of the transmitter needs its own arm.
+    let tx_settings = TransmitterSettings::load(persistency).await;
+    let transmitter = match tx_settings.pin {
+        16 => Transmitter::new(&mut pio_1_common, pio_1_sm1, peripherals.PIN_16, tx_settings.repeats),
+        17 => Transmitter::new(&mut pio_1_common, pio_1_sm1, peripherals.PIN_17, tx_settings.repeats),
+        18 => Transmitter::new(&mut pio_1_common, pio_1_sm1, peripherals.PIN_18, tx_settings.repeats),
+        19 => Transmitter::new(&mut pio_1_common, pio_1_sm1, peripherals.PIN_19, tx_settings.repeats),
+        20 => Transmitter::new(&mut pio_1_common, pio_1_sm1, peripherals.PIN_20, tx_settings.repeats),
+        21 => Transmitter::new(&mut pio_1_common, pio_1_sm1, peripherals.PIN_21, tx_settings.repeats),
+        22 => Transmitter::new(&mut pio_1_common, pio_1_sm1, peripherals.PIN_22, tx_settings.repeats),
+        26 => Transmitter::new(&mut pio_1_common, pio_1_sm1, peripherals.PIN_26, tx_settings.repeats),
+        _ => Transmitter::new(&mut pio_1_common, pio_1_sm1, peripherals.PIN_27, tx_settings.repeats),
+    };
+
     let wifi_hw = WifiHw {
         pin_23: peripherals.PIN_23,
         pin_24: peripherals.PIN_24,
         pin_25: peripherals.PIN_25,
         pin_29: peripherals.PIN_29,
-        pio_1: pio,
+        pio_1_common,
+        pio_1_sm0,
+        pio_1_irq0,
         dma_ch1: peripherals.DMA_CH1,
     };
 
-    if let Some(mqtt) = MQTT::new(persistency, wifi_hw, spawner).await {
+    if let Some(mqtt) = MQTT::new(persistency, wifi_hw, receiver_control.connection(), spawner).await {
         bind_interrupts!(struct Pio0Irqs {
             PIO0_IRQ_0 => pio::InterruptHandler<PIO0>;
         });
-        let pio = Pio::new(peripherals.PIO0, Pio0Irqs);
-        spawner.spawn(button_task::run(pio, peripherals.PIN_28, usb_sender, mqtt)).unwrap();
+        let Pio { common, sm0, sm1, sm2, sm3, .. } = Pio::new(peripherals.PIO0, Pio0Irqs);
+        let receiver_pins = ReceiverPins {
+            pin_2: Some(peripherals.PIN_2),
+            pin_3: Some(peripherals.PIN_3),
+            pin_4: Some(peripherals.PIN_4),
+            pin_5: Some(peripherals.PIN_5),
+            pin_6: Some(peripherals.PIN_6),
+            pin_7: Some(peripherals.PIN_7),
+            pin_8: Some(peripherals.PIN_8),
+            pin_9: Some(peripherals.PIN_9),
+            pin_10: Some(peripherals.PIN_10),
+            pin_11: Some(peripherals.PIN_11),
+            pin_28: Some(peripherals.PIN_28),
+        };
+
+        // GDO0 of the CC1101 is connected to the receiver pin, so it only needs to be configured.
+        let radio = match radio_settings.radio {
+            Radio::Cc1101 => {
+                let spi = Cc1101Spi::new(peripherals.SPI1, peripherals.PIN_14, peripherals.PIN_15, peripherals.PIN_12, peripherals.PIN_13, peripherals.DMA_CH2, peripherals.DMA_CH3);
+                let mut cc1101 = Cc1101::new(spi);
+                match cc1101.init(&radio_settings).await {
+                    Ok(()) => Some(cc1101),
+                    Err(msg) => {
+                        defmt::error!("CC1101 initialization failed: {}", msg);
+                        None
+                    },
+                }
+            },
+            Radio::Module => None,
+        };
+
+        mqtt.receive_commands(spawner, persistency, receiver_control);
+        spawner.spawn(button_task::run(common, (sm0, sm1, sm2, sm3), receiver_pins, transmitter, radio, usb_sender, persistency, receiver_control, mqtt)).unwrap();
     }
 }
@@ -0,0 +1,295 @@
+//! Turns the stream of confirmed button codes into press, hold and release events.
+//! Time is passed in as milliseconds, so the logic does not depend on a real clock.
+
+use core::fmt::{self, Write};
+use heapless::{String, Vec};
+
+use crate::modules::code_table::{Code, MAX_NAME_LENGTH};
+use crate::modules::ev1527::RemoteFrame;
+use crate::modules::persistency::{PersistencyTrait, ValueId};
+
+const DEFAULT_RELEASE_GAP_MS: u64 = 250;
+
+// A button counts as held once it is pressed this long, held events are repeated in the same interval.
+const HOLD_INTERVAL_MS: u64 = 500;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EventKind {
+    Pressed,
+    Held { duration_ms: u64 },
+    Released { duration_ms: u64 },
+    Clicks { count: u8 },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ButtonEvent {
+    pub code: Code,
+    pub name: String<MAX_NAME_LENGTH>,
+    pub remote: Option<RemoteFrame>,
+    pub kind: EventKind,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventKind::Pressed => write!(f, "pressed"),
+            EventKind::Held { duration_ms } => write!(f, "held {}", duration_ms),
+            EventKind::Released { duration_ms } => write!(f, "released {}", duration_ms),
+            EventKind::Clicks { count: 1 } => write!(f, "single"),
+            EventKind::Clicks { count: 2 } => write!(f, "double"),
+            EventKind::Clicks { count: 3 } => write!(f, "triple"),
+            EventKind::Clicks { count } => write!(f, "clicks {}", count),
+        }
+    }
+}
+
+impl ButtonEvent {
+    pub fn payload(&self) -> String<48> {
+        let mut payload = String::new();
+        write!(payload, "{} {}", self.name, self.kind).unwrap();
+        payload
+    }
+
+    /// Topic and payload for the topic of the remote, if the code could be split.
+    /// The key takes the place of the name, so keys work without being added to the code table.
+    pub fn remote_message(&self) -> Option<(String<48>, String<48>)> {
+        self.remote.map(|remote| {
+            let mut payload = String::new();
+            write!(payload, "{:X} {}", remote.key, self.kind).unwrap();
+            (remote.topic(), payload)
+        })
+    }
+}
+
+struct ActiveButton {
+    code: Code,
+    name: String<MAX_NAME_LENGTH>,
+    remote: Option<RemoteFrame>,
+    pressed_ms: u64,
+    last_seen_ms: u64,
+    last_held_ms: u64,
+}
+
+pub struct ButtonEventDetector {
+    release_gap_ms: u64,
+    active: Option<ActiveButton>,
+}
+
+impl ButtonEventDetector {
+    pub fn new(release_gap_ms: u64) -> Self {
+        Self {
+            release_gap_ms,
+            active: None,
+        }
+    }
+
+    pub async fn load_release_gap_ms<P>(persistency: &P) -> u64
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; 10];
+        match persistency.read(ValueId::ReleaseGapMs, &mut bytes).await {
+            Ok(length) => core::str::from_utf8(&bytes[..length]).ok()
+                .and_then(|text| text.parse().ok())
+                .unwrap_or(DEFAULT_RELEASE_GAP_MS),
+            Err(_) => DEFAULT_RELEASE_GAP_MS,
+        }
+    }
+
+    /// To be called for every confirmed code. Codes only belong to the same button if protocol and bit count match as well.
+    pub fn update(&mut self, code: Code, name: &str, remote: Option<RemoteFrame>, now_ms: u64) -> Vec<ButtonEvent, 2> {
+        let mut events = Vec::new();
+
+        if let Some(active) = self.active.as_mut() {
+            if active.code == code {
+                active.last_seen_ms = now_ms;
+                if now_ms - active.last_held_ms >= HOLD_INTERVAL_MS {
+                    active.last_held_ms = now_ms;
+                    let kind = EventKind::Held { duration_ms: now_ms - active.pressed_ms };
+                    events.push(Self::event(active, kind)).unwrap();
+                }
+                return events;
+            }
+
+            // Another button was pressed before the silence gap of the active one was over.
+            let active = self.active.take().unwrap();
+            let kind = EventKind::Released { duration_ms: active.last_seen_ms - active.pressed_ms };
+            events.push(Self::event(&active, kind)).unwrap();
+        }
+
+        let active = ActiveButton {
+            code,
+            name: String::try_from(name).unwrap_or_default(),
+            remote,
+            pressed_ms: now_ms,
+            last_seen_ms: now_ms,
+            last_held_ms: now_ms,
+        };
+        events.push(Self::event(&active, EventKind::Pressed)).unwrap();
+        self.active = Some(active);
+        events
+    }
+
+    /// To be called when the deadline is reached, returns the release event if there is one.
+    pub fn poll(&mut self, now_ms: u64) -> Option<ButtonEvent> {
+        match self.deadline_ms() {
+            Some(deadline_ms) if now_ms >= deadline_ms => {
+                let active = self.active.take().unwrap();
+                let kind = EventKind::Released { duration_ms: active.last_seen_ms - active.pressed_ms };
+                Some(Self::event(&active, kind))
+            },
+            _ => None,
+        }
+    }
+
+    /// The time at which `poll` must be called next, if any.
+    pub fn deadline_ms(&self) -> Option<u64> {
+        self.active.as_ref().map(|active| active.last_seen_ms + self.release_gap_ms)
+    }
+
+    fn event(active: &ActiveButton, kind: EventKind) -> ButtonEvent {
+        ButtonEvent {
+            code: active.code,
+            name: active.name.clone(),
+            remote: active.remote,
+            kind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::persistency::MockPersistencyTrait;
+
+    const CODE_1: Code = Code { value: 0x017E9E90, protocol: Code::FIXED_TIMING_PROTOCOL, bit_count: Code::FIXED_TIMING_BIT_COUNT };
+    const CODE_2: Code = Code { value: 0x017E9E88, protocol: Code::FIXED_TIMING_PROTOCOL, bit_count: Code::FIXED_TIMING_BIT_COUNT };
+
+    fn kinds(events: &[ButtonEvent]) -> std::vec::Vec<(Code, EventKind)> {
+        events.iter().map(|event| (event.code, event.kind)).collect()
+    }
+
+    #[test]
+    fn tap() {
+        let mut detector = ButtonEventDetector::new(250);
+
+        let events = detector.update(CODE_1, "button 1", None, 1000);
+        assert_eq!(kinds(&events), [(CODE_1, EventKind::Pressed)]);
+        assert_eq!(events[0].name, "button 1");
+
+        assert_eq!(kinds(&detector.update(CODE_1, "button 1", None, 1040)), []);
+        assert_eq!(kinds(&detector.update(CODE_1, "button 1", None, 1080)), []);
+
+        assert_eq!(detector.deadline_ms(), Some(1080 + 250));
+        assert_eq!(detector.poll(1329), None);
+
+        let event = detector.poll(1330).unwrap();
+        assert_eq!((event.code, event.kind), (CODE_1, EventKind::Released { duration_ms: 80 }));
+        assert_eq!(detector.deadline_ms(), None);
+        assert_eq!(detector.poll(5000), None);
+    }
+
+    #[test]
+    fn hold() {
+        let mut detector = ButtonEventDetector::new(250);
+        let mut events = std::vec::Vec::new();
+
+        // frames every 40ms for 1.2s
+        for n in 0..=30 {
+            events.extend(detector.update(CODE_1, "button 1", None, 1000 + n * 40));
+        }
+        events.extend(detector.poll(detector.deadline_ms().unwrap()));
+
+        assert_eq!(kinds(&events), [
+            (CODE_1, EventKind::Pressed),
+            (CODE_1, EventKind::Held { duration_ms: 520 }),
+            (CODE_1, EventKind::Held { duration_ms: 1040 }),
+            (CODE_1, EventKind::Released { duration_ms: 1200 }),
+        ]);
+    }
+
+    #[test]
+    fn other_button_releases_active_one() {
+        let mut detector = ButtonEventDetector::new(250);
+
+        let _ = detector.update(CODE_1, "button 1", None, 1000);
+        let _ = detector.update(CODE_1, "button 1", None, 1040);
+
+        let events = detector.update(CODE_2, "button 2", None, 1100);
+        assert_eq!(kinds(&events), [
+            (CODE_1, EventKind::Released { duration_ms: 40 }),
+            (CODE_2, EventKind::Pressed),
+        ]);
+        assert_eq!(detector.deadline_ms(), Some(1100 + 250));
+    }
+
+    #[test]
+    fn same_value_of_another_protocol_is_another_button() {
+        let mut detector = ButtonEventDetector::new(250);
+        let other = Code { protocol: 1, bit_count: 24, ..CODE_1 };
+
+        let _ = detector.update(CODE_1, "button 1", None, 1000);
+        let events = detector.update(other, "socket", None, 1040);
+        assert_eq!(kinds(&events), [
+            (CODE_1, EventKind::Released { duration_ms: 0 }),
+            (other, EventKind::Pressed),
+        ]);
+    }
+
+    #[test]
+    fn press_again_after_release() {
+        let mut detector = ButtonEventDetector::new(100);
+
+        let _ = detector.update(CODE_1, "button 1", None, 1000);
+        assert!(detector.poll(1100).is_some());
+
+        assert_eq!(kinds(&detector.update(CODE_1, "button 1", None, 1150)), [(CODE_1, EventKind::Pressed)]);
+    }
+
+    #[test]
+    fn payload() {
+        let name: String<MAX_NAME_LENGTH> = String::try_from("button 1").unwrap();
+        let event = |kind| ButtonEvent { code: CODE_1, name: name.clone(), remote: None, kind };
+
+        assert_eq!(event(EventKind::Pressed).payload(), "button 1 pressed");
+        assert_eq!(event(EventKind::Held { duration_ms: 520 }).payload(), "button 1 held 520");
+        assert_eq!(event(EventKind::Released { duration_ms: 1200 }).payload(), "button 1 released 1200");
+        assert_eq!(event(EventKind::Clicks { count: 1 }).payload(), "button 1 single");
+        assert_eq!(event(EventKind::Clicks { count: 2 }).payload(), "button 1 double");
+        assert_eq!(event(EventKind::Clicks { count: 3 }).payload(), "button 1 triple");
+    }
+
+    #[test]
+    fn remote_message() {
+        let remote = RemoteFrame { protocol: 1, remote_id: 0xBF4F4, key: 0xC, bit_count: 24 };
+        let event = ButtonEvent { code: CODE_1, name: String::try_from("button 3").unwrap(), remote: Some(remote), kind: EventKind::Clicks { count: 2 } };
+
+        let (topic, payload) = event.remote_message().unwrap();
+        assert_eq!(topic, "433MHz_to_MQTT_remote/BF4F4");
+        assert_eq!(payload, "C double");
+
+        let event = ButtonEvent { remote: None, ..event };
+        assert_eq!(event.remote_message(), None);
+    }
+
+    #[tokio::test]
+    async fn load_release_gap() {
+        const STORED: &[(&[u8], u64)] = &[
+            (b"400", 400),
+            (b"", DEFAULT_RELEASE_GAP_MS),
+            (b"abc", DEFAULT_RELEASE_GAP_MS),
+        ];
+
+        for (stored, release_gap_ms) in STORED {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            mock_persistency.expect_read()
+                .times(1)
+                .withf(|id, _| *id == ValueId::ReleaseGapMs)
+                .returning_st(move |_, answer| {
+                    answer[..stored.len()].copy_from_slice(stored);
+                    Ok(stored.len())
+                });
+
+            assert_eq!(ButtonEventDetector::load_release_gap_ms(&mock_persistency).await, *release_gap_ms);
+        }
+    }
+}
@@ -5,35 +5,229 @@ use cfg_if::cfg_if;
 cfg_if! {
     if #[cfg(not(test))] {
         use embassy_executor::task;
-        use embassy_rp::pio::Pio;
-        use embassy_rp::peripherals::{PIO0, PIN_28};
+        use defmt::{debug, error};
+        use embassy_rp::pio::{Common, StateMachine};
+        use embassy_rp::peripherals::{PIO0, PIO1};
+        use embassy_futures::select::{select3, Either3};
+        use embassy_time::{Instant, Timer};
+        use heapless::String;
 
-        use crate::modules::remote_receiver::RemoteReceiver;
+        use crate::modules::remote_receiver::{ConfirmationPolicy, Received, ReceiverMode};
+        use crate::modules::receivers::{self, ReceiverLabel, ReceiverPins, ReceiverTable, Receivers};
         use crate::modules::mqtt::MQTT;
         use crate::modules::usb_communication::UsbSender;
+        use crate::modules::receiver_control::ReceiverControl;
+        use crate::modules::button_events::{ButtonEvent, ButtonEventDetector, EventKind};
+        use crate::modules::unknown_codes::{self, UnknownCode};
+        use crate::modules::click_detector::ClickDetector;
+        use crate::modules::fixed_timing::FixedTimingSettings;
+        use crate::modules::flex::FlexTable;
+        use crate::modules::entities::{EntityTable, EntityTracker, StateMessage};
+        use crate::modules::devices::{DeviceFlags, DiagnosticMessage};
+        use crate::modules::transmitter::Transmitter;
+        use crate::modules::cc1101::{Cc1101, Cc1101Spi};
+        use crate::modules::signal_quality::FrameQuality;
+
+        // Note: This dependency should be removed. But as embassy::task does not support generics it cant be replaced with trait.
+        use crate::modules::persistency::Persistency;
     }
 }
 
 #[cfg(not(test))]
 #[task]
-pub async fn run(mut pio: Pio<'static, PIO0>, receiver_pin: PIN_28, _usb_sender: &'static UsbSender, mut mqtt: MQTT) {
-    // It would be nice to have generic types for pio and receiver_pin but I couldn't figure out how to do it.
+#[allow(clippy::too_many_arguments)]
+pub async fn run(mut pio: Common<'static, PIO0>, receiver_sms: (StateMachine<'static, PIO0, 0>, StateMachine<'static, PIO0, 1>, StateMachine<'static, PIO0, 2>, StateMachine<'static, PIO0, 3>), receiver_pins: ReceiverPins, mut transmitter: Transmitter<'static, PIO1, 1>, mut radio: Option<Cc1101<Cc1101Spi>>, _usb_sender: &'static UsbSender, persistency: &'static Persistency, receiver_control: &'static ReceiverControl, mut mqtt: MQTT) {
+    let mode = ReceiverMode::load(persistency).await;
+    let fixed_timing = FixedTimingSettings::load(persistency).await;
+    let policy = ConfirmationPolicy::load(persistency).await;
 
-    let mut remote_receiver = RemoteReceiver::new(
-        &mut pio.common,
-        pio.sm0,
-        receiver_pin,
+    let receiver_table = ReceiverTable::load(persistency).await.unwrap_or_else(|msg| {
+        error!("{}, the default receiver is used", msg);
+        ReceiverTable::new()
+    });
+    let flex_table = FlexTable::load(persistency).await.unwrap_or_else(|msg| {
+        error!("{}, no flex decoders are used", msg);
+        FlexTable::new()
+    });
+    let mut receivers = Receivers::new(
+        &mut pio,
+        receiver_sms,
+        receiver_pins,
+        &receiver_table.receivers(mode),
+        fixed_timing,
+        policy,
+        &flex_table,
+        persistency,
+        receiver_control,
     );
 
+    let mut button_event_detector = ButtonEventDetector::new(ButtonEventDetector::load_release_gap_ms(persistency).await);
+    let mut click_detector = ClickDetector::new(ClickDetector::load_click_window_ms(persistency).await);
+    let mut entity_generation = receiver_control.table_generation();
+    let mut entity_tracker = EntityTracker::new(EntityTable::load(persistency).await.unwrap_or_else(|_| EntityTable::new()));
+    let quality_enabled = FrameQuality::load_enabled(persistency).await;
+    // The label of the receiver that received the active button, its later events carry it as well.
+    let mut button_label = ReceiverLabel::new();
+
     loop {
-        let pressed_button = remote_receiver.read().await;
+        let deadline_ms = [button_event_detector.deadline_ms(), click_detector.deadline_ms(), entity_tracker.deadline_ms()]
+            .into_iter()
+            .flatten()
+            .min();
+        let timeout = async {
+            match deadline_ms {
+                Some(deadline_ms) => Timer::at(Instant::from_millis(deadline_ms)).await,
+                None => core::future::pending().await,
+            }
+        };
+        let event = select3(receivers.read(), receiver_control.transmission(), timeout).await;
+        let (label, received, mut quality) = match event {
+            Either3::First((label, received, quality)) => (label, Some(received), quality),
+            Either3::Second(frame) => {
+                // Nothing is read while sending, the own signal that was received meanwhile is dropped.
+                if let Err(msg) = transmitter.send(&frame).await {
+                    error!("sending failed: {}", msg);
+                }
+                receivers.discard_received();
+                continue;
+            },
+            Either3::Third(_) => (ReceiverLabel::new(), None, FrameQuality::default()),
+        };
+        let now_ms = Instant::now().as_millis();
+
+        // Only the CC1101 measures the signal strength, the plain receiver module has no RSSI output.
+        let mut rssi_dbm = None;
+        if let (Some(cc1101), Some(_)) = (&mut radio, &received) {
+            match cc1101.rssi_dbm().await {
+                Ok(rssi) => {
+                    debug!("frame received with {} dBm", rssi);
+                    rssi_dbm = Some(rssi);
+                },
+                Err(msg) => error!("reading RSSI failed: {}", msg),
+            }
+        }
+        quality.rssi_dbm = rssi_dbm;
+        if received.is_some() {
+            receiver_control.record_quality(&label, quality).await;
+        }
+        // Only added to the payloads if enabled, the statistics are always kept.
+        let shown_quality = quality_enabled.then_some(quality);
+
+        match received {
+            Some(Received::Sensor(reading)) => {
+                mqtt.send_message_to(&reading.topic(), decorated(&reading.payload(), shown_quality, &label).as_bytes()).await;
+                for message in receiver_control.update_device(&reading.device_name(), reading.flags(), now_ms).await {
+                    publish_diagnostic(&mut mqtt, &message).await;
+                }
+            },
+            Some(Received::Intertechno(command)) => {
+                mqtt.send_message_to(&command.topic(), decorated(&command.payload(), shown_quality, &label).as_bytes()).await;
+            },
+            Some(Received::Flex(frame)) => {
+                mqtt.send_message_to(&frame.topic(), decorated(&frame.payload(), shown_quality, &label).as_bytes()).await;
+            },
+            Some(Received::Button(pressed_button)) => {
+                receiver_control.offer_code(pressed_button.code);
+
+                // Changes made on the terminal take effect with the next code.
+                let generation = receiver_control.table_generation();
+                if generation != entity_generation {
+                    if let Ok(table) = EntityTable::load(persistency).await {
+                        entity_tracker.reload(table);
+                    }
+                    entity_generation = generation;
+                }
+                if let Some(messages) = entity_tracker.update(pressed_button.code.value, now_ms) {
+                    for message in messages {
+                        publish_state(&mut mqtt, &message).await;
+                    }
+                    if let Some((name, tampered)) = entity_tracker.tamper_flag(pressed_button.code.value) {
+                        let flags = DeviceFlags { battery_low: None, tampered: Some(tampered) };
+                        for message in receiver_control.update_device(name, flags, now_ms).await {
+                            publish_diagnostic(&mut mqtt, &message).await;
+                        }
+                    }
+                    continue;
+                }
 
-        mqtt.send_message(pressed_button.as_bytes()).await;
+                let name = pressed_button.name.as_deref().unwrap_or("");
+                for event in button_event_detector.update(pressed_button.code, name, pressed_button.remote, now_ms) {
+                    if event.kind == EventKind::Pressed {
+                        button_label = label.clone();
+                        if let Some(tri_state) = &pressed_button.tri_state {
+                            mqtt.send_message_to(&tri_state.topic(), decorated(&tri_state.payload(), shown_quality, &label).as_bytes()).await;
+                        }
+                    }
+                    if event.kind == EventKind::Pressed && pressed_button.name.is_none() {
+                        let unknown_code = UnknownCode {
+                            code: pressed_button.code,
+                            timestamp_ms: now_ms,
+                            tri_state: pressed_button.tri_state,
+                            rssi_dbm,
+                        };
+                        mqtt.send_message_to(unknown_codes::TOPIC, receivers::labelled(&unknown_code.payload(), &label).as_bytes()).await;
+                        receiver_control.offer_unknown_code(unknown_code);
+                    }
+                    // The quality belongs to the pressed event this frame caused, not to a release of the previous button.
+                    // The click detector passes the event on as it is and adds the clicks, which get no quality.
+                    let kind = event.kind;
+                    let quality = shown_quality.filter(|_| kind == EventKind::Pressed);
+                    for event in click_detector.update(event, now_ms) {
+                        publish(&mut mqtt, &event, &button_label, quality.filter(|_| event.kind == kind)).await;
+                    }
+                }
+            },
+            None => {
+                if let Some(event) = button_event_detector.poll(now_ms) {
+                    for event in click_detector.update(event, now_ms) {
+                        publish(&mut mqtt, &event, &button_label, None).await;
+                    }
+                }
+                if let Some(event) = click_detector.poll(now_ms) {
+                    publish(&mut mqtt, &event, &button_label, None).await;
+                }
+                for message in entity_tracker.poll(now_ms) {
+                    publish_state(&mut mqtt, &message).await;
+                }
+            },
+        }
 
         // It can be helpful to have the pressed button printed to the console for debugging.
         // But this blocks forever if no terminal is connected.
         // let mut sender = usb_sender.lock().await;
-        // let _ = sender.write_packet(pressed_button.as_bytes()).await;
+        // let _ = sender.write_packet(pressed_button.name.as_bytes()).await;
         // let _ = sender.write_packet(b"\n").await;
     }
 }
+
+// Codes of EV1527 style remotes are published on the topic of their remote, named codes by name as well.
+// Other codes without a name are only reported as unknown codes.
+// Only the pressed events carry the quality, the later ones are not tied to a frame.
+#[cfg(not(test))]
+async fn publish(mqtt: &mut MQTT, event: &ButtonEvent, label: &str, quality: Option<FrameQuality>) {
+    if let Some((topic, payload)) = event.remote_message() {
+        mqtt.send_message_to(&topic, decorated(&payload, quality, label).as_bytes()).await;
+    }
+    if !event.name.is_empty() {
+        mqtt.send_message(decorated(&event.payload(), quality, label).as_bytes()).await;
+    }
+}
+
+// The unknown codes are left out, they carry the RSSI already and are meant for learning.
+#[cfg(not(test))]
+fn decorated(payload: &str, quality: Option<FrameQuality>, label: &str) -> String<256> {
+    match quality {
+        Some(quality) => receivers::labelled(&quality.add_to(payload), label),
+        None => receivers::labelled(payload, label),
+    }
+}
+
+#[cfg(not(test))]
+async fn publish_state(mqtt: &mut MQTT, message: &StateMessage) {
+    mqtt.send_retained_message_to(&message.topic, message.payload.as_bytes()).await;
+}
+
+#[cfg(not(test))]
+async fn publish_diagnostic(mqtt: &mut MQTT, message: &DiagnosticMessage) {
+    mqtt.send_retained_message_to(&message.topic, message.payload.as_bytes()).await;
+}
@@ -0,0 +1,212 @@
+//! Calibration of the fixed timing receiver to the remote at hand.
+//! While calibrating, the pulses of a receiver in pulse width mode are measured frame by frame.
+//! The frames are taken like rc-switch protocol 1 sends them: data bits of a short and a long high pulse,
+//! each bit four base time units long, followed by a sync pulse with a long gap.
+//! The fixed timing settings are derived from the averages, so remotes drifting with temperature or battery are read again.
+
+use core::fmt::Write;
+use heapless::{String, Vec};
+
+use crate::modules::fixed_timing::FixedTimingSettings;
+
+// Low periods longer than this separate two frames, like in the rc-switch decoder.
+const SEPARATION_LIMIT_US: u32 = 4300;
+// Longer low periods are the silence between two button presses, the fixed timing receiver can't count them anyway.
+const MAX_SYNC_GAP_US: u32 = 30_000;
+const MIN_BIT_COUNT: usize = 8;
+const MAX_BIT_COUNT: usize = 32;
+
+// The program counts the sync gap in loops of two ticks and delays the sample by 5 bit immediates,
+// some ticks are left as margin for rounding.
+const MAX_SYNC_GAP_TICKS: u32 = 60;
+const MAX_SAMPLE_TICKS: u32 = 30;
+const SYSTEM_CLOCK_MHZ: u32 = 125;
+
+/// The averages of the measured frames.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Calibration {
+    pub frames: u32,
+    pub base_us: u32, // a quarter of the bit period
+    pub short_us: u32, // high pulse of a zero
+    pub long_us: u32, // high pulse of a one
+    pub sync_gap_us: u32,
+}
+
+impl Calibration {
+    /// The sample is taken between the end of a short and a long pulse, the sync gap is detected well before it ends.
+    /// The glitch filter is kept, but limited to half of a short pulse.
+    pub fn settings(&self, glitch_filter_us: u32) -> Result<FixedTimingSettings, &'static str> {
+        let sample_delay_us = (self.short_us + self.long_us) / 2;
+        let sync_gap_us = self.sync_gap_us * 2 / 3;
+        let clock_divider = (sync_gap_us * SYSTEM_CLOCK_MHZ).div_ceil(MAX_SYNC_GAP_TICKS)
+            .max((sample_delay_us * SYSTEM_CLOCK_MHZ).div_ceil(MAX_SAMPLE_TICKS));
+        let settings = FixedTimingSettings {
+            clock_divider,
+            sync_gap_us,
+            sample_delay_us,
+            glitch_filter_us: glitch_filter_us.min(self.short_us / 2),
+        };
+        settings.ticks().map_err(|_| "the measured timing can't be read by the fixed timing receiver")?;
+        Ok(settings)
+    }
+
+    pub fn text(&self) -> String<128> {
+        let mut text = String::new();
+        write!(text, "{} frames measured: base time unit {} us, short pulse {} us, long pulse {} us, sync gap {} us",
+            self.frames, self.base_us, self.short_us, self.long_us, self.sync_gap_us).unwrap();
+        text
+    }
+}
+
+pub struct Calibrator {
+    highs: Vec<u32, MAX_BIT_COUNT>,
+    periods: Vec<u32, MAX_BIT_COUNT>,
+    synchronized: bool,
+    frames: u32,
+    short_sum_us: u32,
+    short_count: u32,
+    long_sum_us: u32,
+    long_count: u32,
+    period_sum_us: u32,
+    sync_gap_sum_us: u32,
+}
+
+impl Calibrator {
+    pub fn new() -> Self {
+        Self {
+            highs: Vec::new(),
+            periods: Vec::new(),
+            synchronized: false,
+            frames: 0,
+            short_sum_us: 0,
+            short_count: 0,
+            long_sum_us: 0,
+            long_count: 0,
+            period_sum_us: 0,
+            sync_gap_sum_us: 0,
+        }
+    }
+
+    pub fn add_pulse(&mut self, high_us: u32, low_us: u32) {
+        if low_us > SEPARATION_LIMIT_US {
+            // The pulse ending with the gap is the sync pulse, the pulses before are the data bits.
+            if self.synchronized && low_us <= MAX_SYNC_GAP_US {
+                self.measure_frame(low_us);
+            }
+            self.highs.clear();
+            self.periods.clear();
+            self.synchronized = low_us <= MAX_SYNC_GAP_US;
+            return;
+        }
+        if self.highs.push(high_us).is_err() || self.periods.push(high_us + low_us).is_err() {
+            // Too long to be a frame, wait for the next gap.
+            self.highs.clear();
+            self.periods.clear();
+            self.synchronized = false;
+        }
+    }
+
+    // Frames with only zeros or only ones can't tell short from long pulses, they are skipped.
+    fn measure_frame(&mut self, sync_gap_us: u32) {
+        if !(MIN_BIT_COUNT..=MAX_BIT_COUNT).contains(&self.highs.len()) {
+            return;
+        }
+        let (Some(&min_us), Some(&max_us)) = (self.highs.iter().min(), self.highs.iter().max()) else {
+            return;
+        };
+        if max_us < 2 * min_us {
+            return;
+        }
+        let limit_us = (min_us + max_us) / 2;
+        for &high_us in self.highs.iter() {
+            if high_us < limit_us {
+                self.short_sum_us += high_us;
+                self.short_count += 1;
+            } else {
+                self.long_sum_us += high_us;
+                self.long_count += 1;
+            }
+        }
+        self.period_sum_us += self.periods.iter().sum::<u32>();
+        self.sync_gap_sum_us += sync_gap_us;
+        self.frames += 1;
+    }
+
+    pub fn result(&self) -> Result<Calibration, &'static str> {
+        if self.frames == 0 {
+            return Err("no frames measured, press a button of the remote while calibrating");
+        }
+        Ok(Calibration {
+            frames: self.frames,
+            base_us: self.period_sum_us / (self.short_count + self.long_count) / 4,
+            short_us: self.short_sum_us / self.short_count,
+            long_us: self.long_sum_us / self.long_count,
+            sync_gap_us: self.sync_gap_sum_us / self.frames,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::rc_switch::test_signals;
+
+    fn measure(pulses: &[(u32, u32)]) -> Result<Calibration, &'static str> {
+        let mut calibrator = Calibrator::new();
+        for (high_us, low_us) in pulses {
+            calibrator.add_pulse(*high_us, *low_us);
+        }
+        calibrator.result()
+    }
+
+    #[test]
+    fn measure_protocol_1() {
+        // rc-switch protocol 1 has a base time unit of 350us.
+        let pulses = test_signals::rc_switch(1, 0x7E9E90, 24, 4);
+        let calibration = measure(&pulses).unwrap();
+        // The first sync pulse synchronizes, the last frame ends in silence instead of a sync gap.
+        assert_eq!(calibration, Calibration { frames: 3, base_us: 350, short_us: 350, long_us: 1050, sync_gap_us: 10850 });
+    }
+
+    #[test]
+    fn measure_drifting_remote() {
+        // The receiver lengthens high pulses, the sum of high and low stays.
+        let mut pulses = std::vec::Vec::new();
+        for _ in 0..4 {
+            pulses.push((420, 12000));
+            for bit in [1, 0, 1, 1, 0, 1, 0, 0, 1, 1] {
+                pulses.push(if bit == 1 { (1220, 380) } else { (420, 1180) });
+            }
+        }
+        pulses.push((420, 12000));
+        let calibration = measure(&pulses).unwrap();
+        assert_eq!(calibration, Calibration { frames: 4, base_us: 400, short_us: 420, long_us: 1220, sync_gap_us: 12000 });
+        assert_eq!(calibration.text(), "4 frames measured: base time unit 400 us, short pulse 420 us, long pulse 1220 us, sync gap 12000 us");
+    }
+
+    #[test]
+    fn nothing_measured() {
+        const ERROR: Result<Calibration, &str> = Err("no frames measured, press a button of the remote while calibrating");
+        assert_eq!(measure(&[]), ERROR);
+        // too few bits
+        assert_eq!(measure(&test_signals::rc_switch(1, 0x5, 4, 4)), ERROR);
+        // only zeros
+        assert_eq!(measure(&test_signals::rc_switch(1, 0, 24, 4)), ERROR);
+    }
+
+    #[test]
+    fn derived_settings() {
+        // The values the fixed timing receiver was built with are met closely.
+        let calibration = Calibration { frames: 5, base_us: 300, short_us: 300, long_us: 900, sync_gap_us: 9300 };
+        let settings = calibration.settings(0).unwrap();
+        assert_eq!(settings, FixedTimingSettings { clock_divider: 12917, sync_gap_us: 6200, sample_delay_us: 600, glitch_filter_us: 0 });
+
+        let calibration = Calibration { frames: 5, base_us: 400, short_us: 420, long_us: 1220, sync_gap_us: 12000 };
+        let settings = calibration.settings(300).unwrap();
+        assert_eq!(settings, FixedTimingSettings { clock_divider: 16667, sync_gap_us: 8000, sample_delay_us: 820, glitch_filter_us: 210 });
+        assert!(settings.ticks().is_ok());
+
+        let calibration = Calibration { frames: 5, base_us: 10, short_us: 10, long_us: 30, sync_gap_us: 4400 };
+        assert_eq!(calibration.settings(0), Err("the measured timing can't be read by the fixed timing receiver"));
+    }
+}
@@ -0,0 +1,446 @@
+//! Driver of the CC1101 transceiver, an alternative to the plain OOK receiver module.
+//! It is configured over SPI for asynchronous serial mode, the demodulated signal is put out on GDO0.
+//! GDO0 is connected to the receiver pin, so the signal goes through the same PIO program and decoders.
+//! In addition to the module it can receive 2-FSK, is tuned to 315, 433 or 868 MHz and measures the RSSI.
+
+use cfg_if::cfg_if;
+
+use crate::modules::persistency::{PersistencyTrait, ValueId};
+
+cfg_if! {
+    if #[cfg(not(test))] {
+        use embassy_rp::{gpio, spi};
+        use embassy_rp::peripherals::{DMA_CH2, DMA_CH3, PIN_12, PIN_13, PIN_14, PIN_15, SPI1};
+        use embassy_time::Timer;
+    }
+}
+
+const CRYSTAL_HZ: u64 = 26_000_000;
+
+// The frequency bands the synthesizer supports.
+const BANDS_KHZ: [core::ops::RangeInclusive<u32>; 3] = [300_000..=348_000, 387_000..=464_000, 779_000..=928_000];
+
+// Below this frequency the VCO needs other calibration settings (see SmartRF Studio).
+const LOW_VCO_LIMIT_KHZ: u32 = 361_000;
+
+// Header byte of an SPI access.
+const READ: u8 = 0x80;
+const BURST: u8 = 0x40;
+
+// Configuration registers, written in one burst starting at IOCFG2.
+const IOCFG2: u8 = 0x00;
+const CONFIG_REGISTER_COUNT: usize = 0x2F;
+const FREQ2: usize = 0x0D;
+const MDMCFG2: usize = 0x12;
+const AGCCTRL2: usize = 0x1B;
+const AGCCTRL1: usize = 0x1C;
+const FREND1: usize = 0x21;
+const FREND0: usize = 0x22;
+const TEST0: usize = 0x2E;
+
+// Status registers, they are read with the burst bit set.
+const VERSION: u8 = 0x31;
+const RSSI: u8 = 0x34;
+
+const PATABLE: u8 = 0x3E;
+
+// Command strobes
+const SRES: u8 = 0x30;
+const SRX: u8 = 0x34;
+
+// The RSSI value is given in half dB relative to this offset.
+const RSSI_OFFSET_DB: i16 = 74;
+
+// Asynchronous serial mode with the demodulated data on GDO0, no packet handling, no sync word.
+// Based on the values of SmartRF Studio for 433.92 MHz, the values depending on the settings are replaced.
+const CONFIG_REGISTERS: [u8; CONFIG_REGISTER_COUNT] = [
+    0x2E, // IOCFG2: high impedance
+    0x2E, // IOCFG1: high impedance
+    0x0D, // IOCFG0: serial data output
+    0x47, // FIFOTHR
+    0xD3, // SYNC1
+    0x91, // SYNC0
+    0xFF, // PKTLEN
+    0x04, // PKTCTRL1
+    0x32, // PKTCTRL0: asynchronous serial mode, infinite packet length
+    0x00, // ADDR
+    0x00, // CHANNR
+    0x06, // FSCTRL1: IF frequency
+    0x00, // FSCTRL0
+    0x10, // FREQ2
+    0xB0, // FREQ1
+    0x71, // FREQ0
+    0x87, // MDMCFG4: 203kHz receiver bandwidth
+    0x32, // MDMCFG3
+    0x30, // MDMCFG2: modulation, no sync word
+    0x22, // MDMCFG1
+    0xF8, // MDMCFG0
+    0x47, // DEVIATN: 47.6kHz, only used with 2-FSK
+    0x07, // MCSM2
+    0x30, // MCSM1: stay in RX
+    0x18, // MCSM0: calibrate when going from IDLE to RX
+    0x16, // FOCCFG
+    0x6C, // BSCFG
+    0x03, // AGCCTRL2
+    0x00, // AGCCTRL1
+    0x91, // AGCCTRL0
+    0x87, // WOREVT1
+    0x6B, // WOREVT0
+    0xFB, // WORCTRL
+    0xB6, // FREND1
+    0x11, // FREND0
+    0xE9, // FSCAL3
+    0x2A, // FSCAL2
+    0x00, // FSCAL1
+    0x1F, // FSCAL0
+    0x41, // RCCTRL1
+    0x00, // RCCTRL0
+    0x59, // FSTEST
+    0x7F, // PTEST
+    0x3F, // AGCTEST
+    0x81, // TEST2
+    0x35, // TEST1
+    0x09, // TEST0
+];
+
+#[cfg_attr(test, mockall::automock)]
+pub trait SpiBusTrait {
+    /// Selects the chip, exchanges the bytes of the buffer and deselects the chip.
+    async fn transfer(&mut self, buffer: &mut [u8]) -> Result<(), &'static str>;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Radio {
+    Module, // plain OOK receiver module
+    Cc1101,
+}
+
+impl Radio {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            b"module" => Some(Self::Module),
+            b"cc1101" => Some(Self::Cc1101),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Modulation {
+    Ook,
+    Fsk2,
+}
+
+impl Modulation {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            b"ook" => Some(Self::Ook),
+            b"fsk" => Some(Self::Fsk2),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RadioSettings {
+    pub radio: Radio,
+    pub frequency_khz: u32,
+    pub modulation: Modulation, // only for the CC1101
+}
+
+impl RadioSettings {
+    pub const DEFAULT: Self = Self { radio: Radio::Module, frequency_khz: 433_920, modulation: Modulation::Ook };
+
+    pub async fn load<P>(persistency: &P) -> Self
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; 10];
+        Self {
+            radio: Self::load_value(persistency, ValueId::Radio, &mut bytes).await
+                .and_then(Radio::from_bytes)
+                .unwrap_or(Self::DEFAULT.radio),
+            frequency_khz: Self::load_value(persistency, ValueId::RadioFrequencyKhz, &mut bytes).await
+                .and_then(|bytes| core::str::from_utf8(bytes).ok()?.parse().ok())
+                .filter(|frequency_khz| frequency_word(*frequency_khz).is_ok())
+                .unwrap_or(Self::DEFAULT.frequency_khz),
+            modulation: Self::load_value(persistency, ValueId::RadioModulation, &mut bytes).await
+                .and_then(Modulation::from_bytes)
+                .unwrap_or(Self::DEFAULT.modulation),
+        }
+    }
+
+    /// The frequency the receiver is tuned to, only the CC1101 is tuned by the gateway.
+    pub fn tuned_frequency_hz(&self) -> Option<u32> {
+        match self.radio {
+            Radio::Cc1101 => Some(self.frequency_khz * 1000),
+            Radio::Module => None,
+        }
+    }
+
+    async fn load_value<'a, P>(persistency: &P, value_id: ValueId, bytes: &'a mut [u8]) -> Option<&'a [u8]>
+    where P: PersistencyTrait,
+    {
+        let length = persistency.read(value_id, bytes).await.ok()?;
+        Some(&bytes[..length])
+    }
+}
+
+/// The value of the FREQ registers, the carrier frequency in steps of the crystal frequency / 2^16.
+pub fn frequency_word(frequency_khz: u32) -> Result<u32, &'static str> {
+    if !BANDS_KHZ.iter().any(|band| band.contains(&frequency_khz)) {
+        return Err("radio_frequency_khz must be within 300000-348000, 387000-464000 or 779000-928000");
+    }
+    Ok(((frequency_khz as u64 * 1000 * (1 << 16) + CRYSTAL_HZ / 2) / CRYSTAL_HZ) as u32)
+}
+
+fn config_registers(settings: &RadioSettings) -> Result<[u8; CONFIG_REGISTER_COUNT], &'static str> {
+    let mut registers = CONFIG_REGISTERS;
+    let word = frequency_word(settings.frequency_khz)?;
+    registers[FREQ2..FREQ2 + 3].copy_from_slice(&word.to_be_bytes()[1..]);
+    // Recommended AGC and front end settings of TI for OOK (DN022) and of SmartRF Studio for 2-FSK.
+    match settings.modulation {
+        Modulation::Ook => (),
+        Modulation::Fsk2 => {
+            registers[MDMCFG2] = 0x00;
+            registers[AGCCTRL2] = 0x43;
+            registers[AGCCTRL1] = 0x40;
+            registers[FREND1] = 0x56;
+            registers[FREND0] = 0x10;
+        },
+    }
+    if settings.frequency_khz < LOW_VCO_LIMIT_KHZ {
+        registers[TEST0] = 0x0B;
+    }
+    Ok(registers)
+}
+
+// The power amplifier is switched by the level for OOK (index 0 off, index 1 full power).
+fn pa_table(modulation: Modulation) -> &'static [u8] {
+    match modulation {
+        Modulation::Ook => &[0x00, 0xC0],
+        Modulation::Fsk2 => &[0xC0],
+    }
+}
+
+/// The register value is a signed number of half dB.
+pub fn rssi_dbm(raw: u8) -> i16 {
+    raw as i8 as i16 / 2 - RSSI_OFFSET_DB
+}
+
+pub struct Cc1101<S: SpiBusTrait> {
+    spi: S,
+}
+
+impl<S: SpiBusTrait> Cc1101<S> {
+    pub fn new(spi: S) -> Self {
+        Self { spi }
+    }
+
+    /// Resets the chip, configures it and starts receiving.
+    pub async fn init(&mut self, settings: &RadioSettings) -> Result<(), &'static str> {
+        let registers = config_registers(settings)?;
+
+        self.strobe(SRES).await?;
+        let version = self.read_status(VERSION).await?;
+        if version == 0x00 || version == 0xFF {
+            return Err("no CC1101 found");
+        }
+        self.write_burst(IOCFG2, &registers).await?;
+        self.write_burst(PATABLE, pa_table(settings.modulation)).await?;
+        self.strobe(SRX).await
+    }
+
+    /// The signal strength right now. Remotes repeat their frames, so read after a frame it is the one of the sender.
+    pub async fn rssi_dbm(&mut self) -> Result<i16, &'static str> {
+        Ok(rssi_dbm(self.read_status(RSSI).await?))
+    }
+
+    async fn strobe(&mut self, command: u8) -> Result<(), &'static str> {
+        self.spi.transfer(&mut [command]).await
+    }
+
+    async fn read_status(&mut self, address: u8) -> Result<u8, &'static str> {
+        let mut buffer = [address | READ | BURST, 0];
+        self.spi.transfer(&mut buffer).await?;
+        Ok(buffer[1])
+    }
+
+    async fn write_burst(&mut self, address: u8, values: &[u8]) -> Result<(), &'static str> {
+        let mut buffer = [0u8; CONFIG_REGISTER_COUNT + 1];
+        buffer[0] = address | BURST;
+        buffer[1..=values.len()].copy_from_slice(values);
+        self.spi.transfer(&mut buffer[..=values.len()]).await
+    }
+}
+
+/// The CC1101 on SPI1: SCK on GP14, MOSI on GP15, MISO on GP12 and chip select on GP13.
+#[cfg(not(test))]
+pub struct Cc1101Spi {
+    spi: spi::Spi<'static, SPI1, spi::Async>,
+    cs: gpio::Output<'static>,
+}
+
+#[cfg(not(test))]
+impl Cc1101Spi {
+    pub fn new(spi: SPI1, sck: PIN_14, mosi: PIN_15, miso: PIN_12, cs: PIN_13, tx_dma: DMA_CH2, rx_dma: DMA_CH3) -> Self {
+        let mut config = spi::Config::default();
+        config.frequency = 1_000_000;
+        Self {
+            spi: spi::Spi::new(spi, sck, mosi, miso, tx_dma, rx_dma, config),
+            cs: gpio::Output::new(cs, gpio::Level::High),
+        }
+    }
+}
+
+#[cfg(not(test))]
+impl SpiBusTrait for Cc1101Spi {
+    async fn transfer(&mut self, buffer: &mut [u8]) -> Result<(), &'static str> {
+        self.cs.set_low();
+        // The chip needs up to 150us after a reset until it is ready. MISO can't be watched while it belongs to the SPI.
+        Timer::after_micros(150).await;
+        let result = self.spi.transfer_in_place(buffer).await;
+        self.cs.set_high();
+        result.map_err(|_| "SPI transfer failed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockall::Sequence;
+    use crate::modules::persistency::MockPersistencyTrait;
+
+    const OOK_433: RadioSettings = RadioSettings { radio: Radio::Cc1101, frequency_khz: 433_920, modulation: Modulation::Ook };
+
+    // Expects a transfer of the given bytes and answers with the given ones.
+    fn expect_transfer(spi: &mut MockSpiBusTrait, sequence: &mut Sequence, sent: std::vec::Vec<u8>, answer: std::vec::Vec<u8>) {
+        spi.expect_transfer()
+            .times(1)
+            .in_sequence(sequence)
+            .withf(move |buffer| *buffer == sent[..])
+            .returning(move |buffer| {
+                buffer.copy_from_slice(&answer);
+                Ok(())
+            });
+    }
+
+    #[test]
+    fn frequency_words() {
+        assert_eq!(frequency_word(433_920), Ok(0x10B071));
+        assert_eq!(frequency_word(868_300), Ok(0x21656A));
+        assert_eq!(frequency_word(315_000), Ok(0x0C1D8A));
+        assert_eq!(frequency_word(300_000), Ok(0x0B89D9));
+        assert_eq!(frequency_word(928_000), Ok(0x23B13B));
+
+        for frequency_khz in [0, 299_999, 350_000, 500_000, 928_001] {
+            assert!(frequency_word(frequency_khz).is_err(), "{}", frequency_khz);
+        }
+    }
+
+    #[test]
+    fn tuned_frequency() {
+        assert_eq!(RadioSettings { frequency_khz: 868_300, ..OOK_433 }.tuned_frequency_hz(), Some(868_300_000));
+        assert_eq!(RadioSettings::DEFAULT.tuned_frequency_hz(), None);
+    }
+
+    #[test]
+    fn registers_for_the_settings() {
+        // The 433.92 MHz OOK settings are the ones of the table.
+        assert_eq!(config_registers(&OOK_433), Ok(CONFIG_REGISTERS));
+
+        let registers = config_registers(&RadioSettings { frequency_khz: 868_300, modulation: Modulation::Fsk2, ..OOK_433 }).unwrap();
+        assert_eq!(registers[FREQ2..FREQ2 + 3], [0x21, 0x65, 0x6A]);
+        assert_eq!(registers[MDMCFG2], 0x00);
+        assert_eq!(registers[FREND0], 0x10);
+        assert_eq!(registers[TEST0], 0x09);
+
+        let registers = config_registers(&RadioSettings { frequency_khz: 315_000, ..OOK_433 }).unwrap();
+        assert_eq!(registers[MDMCFG2], 0x30);
+        assert_eq!(registers[TEST0], 0x0B);
+
+        assert!(config_registers(&RadioSettings { frequency_khz: 500_000, ..OOK_433 }).is_err());
+    }
+
+    #[test]
+    fn rssi_conversion() {
+        assert_eq!(rssi_dbm(0x00), -74);
+        assert_eq!(rssi_dbm(0x50), -34);
+        assert_eq!(rssi_dbm(0x80), -138);
+        assert_eq!(rssi_dbm(0xD0), -98);
+    }
+
+    #[tokio::test]
+    async fn init() {
+        let mut spi = MockSpiBusTrait::new();
+        let mut sequence = Sequence::new();
+        expect_transfer(&mut spi, &mut sequence, vec![SRES], vec![0x0F]);
+        expect_transfer(&mut spi, &mut sequence, vec![0xF1, 0x00], vec![0x0F, 0x14]);
+        let mut burst = vec![0x40];
+        burst.extend_from_slice(&CONFIG_REGISTERS);
+        expect_transfer(&mut spi, &mut sequence, burst, vec![0x0F; CONFIG_REGISTER_COUNT + 1]);
+        expect_transfer(&mut spi, &mut sequence, vec![0x7E, 0x00, 0xC0], vec![0x0F; 3]);
+        expect_transfer(&mut spi, &mut sequence, vec![SRX], vec![0x0F]);
+
+        let mut cc1101 = Cc1101::new(spi);
+        assert_eq!(cc1101.init(&OOK_433).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn init_without_chip() {
+        for version in [0x00, 0xFF] {
+            let mut spi = MockSpiBusTrait::new();
+            let mut sequence = Sequence::new();
+            expect_transfer(&mut spi, &mut sequence, vec![SRES], vec![0xFF]);
+            expect_transfer(&mut spi, &mut sequence, vec![0xF1, 0x00], vec![0xFF, version]);
+
+            let mut cc1101 = Cc1101::new(spi);
+            assert_eq!(cc1101.init(&OOK_433).await, Err("no CC1101 found"));
+        }
+    }
+
+    #[tokio::test]
+    async fn init_with_invalid_frequency() {
+        let mut spi = MockSpiBusTrait::new();
+        spi.expect_transfer().never();
+
+        let mut cc1101 = Cc1101::new(spi);
+        assert!(cc1101.init(&RadioSettings { frequency_khz: 500_000, ..OOK_433 }).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_rssi() {
+        let mut spi = MockSpiBusTrait::new();
+        let mut sequence = Sequence::new();
+        expect_transfer(&mut spi, &mut sequence, vec![0xF4, 0x00], vec![0x1F, 0xD0]);
+
+        let mut cc1101 = Cc1101::new(spi);
+        assert_eq!(cc1101.rssi_dbm().await, Ok(-98));
+    }
+
+    #[tokio::test]
+    async fn load_settings() {
+        // radio, frequency and modulation as stored, then the settings they load to
+        type StoredSettings = (&'static [u8], &'static [u8], &'static [u8], RadioSettings);
+        const STORED: &[StoredSettings] = &[
+            (b"cc1101", b"868300", b"fsk", RadioSettings { radio: Radio::Cc1101, frequency_khz: 868_300, modulation: Modulation::Fsk2 }),
+            (b"", b"", b"", RadioSettings::DEFAULT),
+            (b"cc1100", b"500000", b"ask", RadioSettings::DEFAULT),
+        ];
+
+        for (radio, frequency_khz, modulation, settings) in STORED {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            mock_persistency.expect_read()
+                .returning_st(move |id, answer| {
+                    let stored = match id {
+                        ValueId::Radio => radio,
+                        ValueId::RadioFrequencyKhz => frequency_khz,
+                        _ => modulation,
+                    };
+                    answer[..stored.len()].copy_from_slice(stored);
+                    Ok(stored.len())
+                });
+
+            assert_eq!(RadioSettings::load(&mock_persistency).await, *settings);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Combines short presses of the same button into single, double and triple clicks.
+//! The press, hold and release events are passed on as well, the click events come in addition.
+
+use heapless::{String, Vec};
+
+use crate::modules::button_events::{ButtonEvent, EventKind};
+use crate::modules::code_table::{Code, MAX_NAME_LENGTH};
+use crate::modules::ev1527::RemoteFrame;
+use crate::modules::persistency::{PersistencyTrait, ValueId};
+
+const DEFAULT_CLICK_WINDOW_MS: u64 = 400;
+const MAX_CLICKS: u8 = 3;
+
+struct ClickSequence {
+    code: Code,
+    name: String<MAX_NAME_LENGTH>,
+    remote: Option<RemoteFrame>,
+    clicks: u8,
+    held: bool,
+    window_end_ms: Option<u64>, // None while the button is pressed
+}
+
+pub struct ClickDetector {
+    click_window_ms: u64,
+    sequence: Option<ClickSequence>,
+}
+
+impl ClickDetector {
+    pub fn new(click_window_ms: u64) -> Self {
+        Self {
+            click_window_ms,
+            sequence: None,
+        }
+    }
+
+    pub async fn load_click_window_ms<P>(persistency: &P) -> u64
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; 10];
+        match persistency.read(ValueId::ClickWindowMs, &mut bytes).await {
+            Ok(length) => core::str::from_utf8(&bytes[..length]).ok()
+                .and_then(|text| text.parse().ok())
+                .unwrap_or(DEFAULT_CLICK_WINDOW_MS),
+            Err(_) => DEFAULT_CLICK_WINDOW_MS,
+        }
+    }
+
+    /// Takes the events of the button event detector and returns the events to be published.
+    pub fn update(&mut self, event: ButtonEvent, now_ms: u64) -> Vec<ButtonEvent, 3> {
+        let mut events = Vec::new();
+
+        if self.sequence.as_ref().is_some_and(|sequence| sequence.code != event.code) {
+            events.extend(self.finish());
+        }
+
+        match event.kind {
+            EventKind::Pressed => {
+                match self.sequence.as_mut() {
+                    Some(sequence) => sequence.window_end_ms = None,
+                    None => self.sequence = Some(ClickSequence {
+                        code: event.code,
+                        name: event.name.clone(),
+                        remote: event.remote,
+                        clicks: 0,
+                        held: false,
+                        window_end_ms: None,
+                    }),
+                }
+                events.push(event).unwrap();
+            },
+            EventKind::Held { .. } => {
+                // Clicks before a long press are reported on their own.
+                if let Some(sequence) = self.sequence.as_mut() {
+                    if !sequence.held {
+                        events.extend(Self::clicks_event(sequence));
+                        sequence.clicks = 0;
+                        sequence.held = true;
+                    }
+                }
+                events.push(event).unwrap();
+            },
+            EventKind::Released { .. } => {
+                events.push(event).unwrap();
+                match self.sequence.as_mut() {
+                    Some(sequence) if sequence.held => self.sequence = None,
+                    Some(sequence) => {
+                        sequence.clicks += 1;
+                        sequence.window_end_ms = Some(now_ms + self.click_window_ms);
+                        if sequence.clicks >= MAX_CLICKS {
+                            events.extend(self.finish());
+                        }
+                    },
+                    None => (),
+                }
+            },
+            EventKind::Clicks { .. } => events.push(event).unwrap(),
+        }
+        events
+    }
+
+    /// To be called when the deadline is reached, returns the click event if there is one.
+    pub fn poll(&mut self, now_ms: u64) -> Option<ButtonEvent> {
+        match self.deadline_ms() {
+            Some(deadline_ms) if now_ms >= deadline_ms => self.finish(),
+            _ => None,
+        }
+    }
+
+    /// The time at which `poll` must be called next, if any.
+    pub fn deadline_ms(&self) -> Option<u64> {
+        self.sequence.as_ref().and_then(|sequence| sequence.window_end_ms)
+    }
+
+    fn finish(&mut self) -> Option<ButtonEvent> {
+        self.sequence.take().and_then(|sequence| Self::clicks_event(&sequence))
+    }
+
+    fn clicks_event(sequence: &ClickSequence) -> Option<ButtonEvent> {
+        if sequence.clicks == 0 {
+            return None;
+        }
+        Some(ButtonEvent {
+            code: sequence.code,
+            name: sequence.name.clone(),
+            remote: sequence.remote,
+            kind: EventKind::Clicks { count: sequence.clicks },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::persistency::MockPersistencyTrait;
+
+    const CODE_1: Code = Code { value: 0x017E9E90, protocol: Code::FIXED_TIMING_PROTOCOL, bit_count: Code::FIXED_TIMING_BIT_COUNT };
+    const CODE_2: Code = Code { value: 0x017E9E88, protocol: Code::FIXED_TIMING_PROTOCOL, bit_count: Code::FIXED_TIMING_BIT_COUNT };
+
+    fn event(code: Code, kind: EventKind) -> ButtonEvent {
+        let name = if code == CODE_1 { "button 1" } else { "button 2" };
+        ButtonEvent { code, name: String::try_from(name).unwrap(), remote: None, kind }
+    }
+
+    fn payloads(events: &[ButtonEvent]) -> std::vec::Vec<std::string::String> {
+        events.iter().map(|event| event.payload().as_str().into()).collect()
+    }
+
+    fn click(detector: &mut ClickDetector, code: Code, now_ms: u64) -> std::vec::Vec<ButtonEvent> {
+        let mut events: std::vec::Vec<ButtonEvent> = detector.update(event(code, EventKind::Pressed), now_ms).into_iter().collect();
+        events.extend(detector.update(event(code, EventKind::Released { duration_ms: 80 }), now_ms + 330));
+        events
+    }
+
+    // The click events only, the press and release events are passed on unchanged.
+    fn clicks(events: &[ButtonEvent]) -> std::vec::Vec<std::string::String> {
+        payloads(events).into_iter().filter(|payload| !payload.contains("pressed") && !payload.contains("released")).collect()
+    }
+
+    #[test]
+    fn single_click() {
+        let mut detector = ClickDetector::new(400);
+
+        assert_eq!(payloads(&click(&mut detector, CODE_1, 1000)), ["button 1 pressed", "button 1 released 80"]);
+        assert_eq!(detector.deadline_ms(), Some(1330 + 400));
+        assert_eq!(detector.poll(1729), None);
+        assert_eq!(payloads(&detector.poll(1730).into_iter().collect::<std::vec::Vec<_>>()), ["button 1 single"]);
+        assert_eq!(detector.deadline_ms(), None);
+    }
+
+    #[test]
+    fn double_click() {
+        let mut detector = ClickDetector::new(400);
+
+        assert_eq!(clicks(&click(&mut detector, CODE_1, 1000)), [] as [&str; 0]);
+        assert_eq!(clicks(&click(&mut detector, CODE_1, 1500)), [] as [&str; 0]);
+
+        // the window is started again after each click
+        assert_eq!(detector.poll(1900), None);
+        assert_eq!(payloads(&detector.poll(1830 + 400).into_iter().collect::<std::vec::Vec<_>>()), ["button 1 double"]);
+    }
+
+    #[test]
+    fn triple_click_is_reported_immediately() {
+        let mut detector = ClickDetector::new(400);
+
+        let _ = click(&mut detector, CODE_1, 1000);
+        let _ = click(&mut detector, CODE_1, 1500);
+        assert_eq!(payloads(&click(&mut detector, CODE_1, 2000)), ["button 1 pressed", "button 1 released 80", "button 1 triple"]);
+        assert_eq!(detector.deadline_ms(), None);
+    }
+
+    #[test]
+    fn clicks_too_far_apart() {
+        let mut detector = ClickDetector::new(400);
+
+        let _ = click(&mut detector, CODE_1, 1000);
+        assert_eq!(payloads(&detector.poll(1730).into_iter().collect::<std::vec::Vec<_>>()), ["button 1 single"]);
+        let _ = click(&mut detector, CODE_1, 1800);
+        assert_eq!(payloads(&detector.poll(2530).into_iter().collect::<std::vec::Vec<_>>()), ["button 1 single"]);
+    }
+
+    #[test]
+    fn other_button_ends_sequence() {
+        let mut detector = ClickDetector::new(400);
+
+        let _ = click(&mut detector, CODE_1, 1000);
+        let _ = click(&mut detector, CODE_1, 1500);
+        assert_eq!(payloads(&click(&mut detector, CODE_2, 1900)), ["button 1 double", "button 2 pressed", "button 2 released 80"]);
+        assert_eq!(payloads(&detector.poll(2230 + 400).into_iter().collect::<std::vec::Vec<_>>()), ["button 2 single"]);
+    }
+
+    #[test]
+    fn long_press() {
+        let mut detector = ClickDetector::new(400);
+
+        let mut events = std::vec::Vec::new();
+        events.extend(detector.update(event(CODE_1, EventKind::Pressed), 1000));
+        events.extend(detector.update(event(CODE_1, EventKind::Held { duration_ms: 520 }), 1520));
+        events.extend(detector.update(event(CODE_1, EventKind::Held { duration_ms: 1040 }), 2040));
+        events.extend(detector.update(event(CODE_1, EventKind::Released { duration_ms: 1200 }), 2450));
+
+        assert_eq!(payloads(&events), ["button 1 pressed", "button 1 held 520", "button 1 held 1040", "button 1 released 1200"]);
+        assert_eq!(detector.deadline_ms(), None);
+    }
+
+    #[test]
+    fn click_then_long_press() {
+        let mut detector = ClickDetector::new(400);
+
+        let _ = click(&mut detector, CODE_1, 1000);
+        let mut events = std::vec::Vec::new();
+        events.extend(detector.update(event(CODE_1, EventKind::Pressed), 1500));
+        events.extend(detector.update(event(CODE_1, EventKind::Held { duration_ms: 520 }), 2020));
+
+        assert_eq!(payloads(&events), ["button 1 pressed", "button 1 single", "button 1 held 520"]);
+    }
+
+    #[tokio::test]
+    async fn load_click_window() {
+        const STORED: &[(&[u8], u64)] = &[
+            (b"600", 600),
+            (b"", DEFAULT_CLICK_WINDOW_MS),
+            (b"-1", DEFAULT_CLICK_WINDOW_MS),
+        ];
+
+        for (stored, click_window_ms) in STORED {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            mock_persistency.expect_read()
+                .times(1)
+                .withf(|id, _| *id == ValueId::ClickWindowMs)
+                .returning_st(move |_, answer| {
+                    answer[..stored.len()].copy_from_slice(stored);
+                    Ok(stored.len())
+                });
+
+            assert_eq!(ClickDetector::load_click_window_ms(&mock_persistency).await, *click_window_ms);
+        }
+    }
+}
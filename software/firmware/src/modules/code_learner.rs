@@ -0,0 +1,36 @@
+//! Hands codes confirmed by the remote receiver over to the terminal, so they can be learned.
+
+use cfg_if::cfg_if;
+
+cfg_if! {
+    if #[cfg(not(test))] {
+        use crate::modules::code_table::Code;
+        use embassy_sync::signal::Signal;
+        use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+        use embassy_time::{Duration, with_timeout};
+
+        const LEARN_TIMEOUT: Duration = Duration::from_secs(10);
+    }
+}
+
+#[cfg(not(test))]
+pub struct CodeLearner {
+    signal: Signal<CriticalSectionRawMutex, Code>,
+}
+
+#[cfg(not(test))]
+impl CodeLearner {
+    pub fn new() -> Self {
+        Self { signal: Signal::new() }
+    }
+
+    pub fn offer(&self, code: Code) {
+        self.signal.signal(code);
+    }
+
+    pub async fn next_code(&self) -> Result<Code, &'static str> {
+        // Codes confirmed before learning was started must not be taken.
+        self.signal.reset();
+        with_timeout(LEARN_TIMEOUT, self.signal.wait()).await.map_err(|_| "no code received within 10 seconds")
+    }
+}
@@ -0,0 +1,436 @@
+//! Table that maps received codes to button names.
+//! A whole EV1527 style remote can be named as well, its keys are then named after the remote.
+//! The table is stored persistently and can be edited from the terminal.
+
+use core::fmt::{self, Write};
+use heapless::{String, Vec};
+
+use crate::modules::persistency::{PersistencyTrait, ValueId};
+use crate::modules::rc_switch::Frame;
+
+pub const MAX_NAME_LENGTH: usize = 16;
+// Leaves plenty of room for new remotes next to the built-in codes restored on upgrade.
+const MAX_ENTRIES: usize = 48;
+
+// The codes of the remote that was built in before the code table existed, as read by the fixed timing receiver.
+const DEFAULT_CODES: [(u32, &str); 10] = [
+    (0x017E9E90, "button 1"),
+    (0x017E9E88, "button 2"),
+    (0x017E9E98, "button 3"),
+    (0x017E9E84, "button 4"),
+    (0x017E9E94, "button 5"),
+    (0x017E9E8C, "button 6"),
+    (0x017E9E9C, "button 7"),
+    (0x017E9E82, "button 8"),
+    (0x017E9E92, "button 9"),
+    (0x017E9E8A, "button 10"),
+];
+
+// Each entry is stored as: code (4 bytes, little endian), protocol (1 byte), bit count (1 byte), name length (1 byte), name.
+// The entries of remotes hold the remote id as code and have this flag set in the name length.
+const ENTRY_HEADER_SIZE: usize = 7;
+const REMOTE_FLAG: u8 = 0x80;
+const MAX_STORED_SIZE: usize = MAX_ENTRIES * (ENTRY_HEADER_SIZE + MAX_NAME_LENGTH);
+
+// The key is appended to the name of a remote, separated by a space.
+const MAX_REMOTE_NAME_LENGTH: usize = MAX_NAME_LENGTH - 2;
+
+/// A code as the receiver reports it. Codes are only the same if protocol and bit count match as well.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Code {
+    pub value: u32,
+    pub protocol: u8, // the rc-switch protocol or one of the tags below
+    pub bit_count: u8,
+}
+
+impl Code {
+    // The rc-switch protocols are numbered from 1, so 0 is free to tag the codes of the fixed timing receiver.
+    pub const FIXED_TIMING_PROTOCOL: u8 = 0;
+    // 24 bits of rc-switch protocol 1 and the following sync pulse, which is always read as 0.
+    pub const FIXED_TIMING_BIT_COUNT: u8 = 25;
+    // The fixed parts of rolling codes are tagged as well, they could match the codes of rc-switch remotes otherwise.
+    pub const KEELOQ_PROTOCOL: u8 = 0x80;
+    pub const SOMFY_PROTOCOL: u8 = 0x81;
+
+    pub fn fixed_timing(value: u32) -> Self {
+        Self { value, protocol: Self::FIXED_TIMING_PROTOCOL, bit_count: Self::FIXED_TIMING_BIT_COUNT }
+    }
+
+    pub fn pulse_width(frame: &Frame) -> Self {
+        Self { value: frame.value, protocol: frame.protocol, bit_count: frame.bit_count }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.protocol {
+            Self::FIXED_TIMING_PROTOCOL => write!(f, "0x{:08X} fixed timing", self.value),
+            Self::KEELOQ_PROTOCOL => write!(f, "0x{:08X} KeeLoq", self.value),
+            Self::SOMFY_PROTOCOL => write!(f, "0x{:08X} Somfy RTS", self.value),
+            protocol => write!(f, "0x{:08X} protocol {} {} bits", self.value, protocol, self.bit_count),
+        }
+    }
+}
+
+struct CodeEntry {
+    code: Code,
+    name: String<MAX_NAME_LENGTH>,
+    remote: bool,
+}
+
+pub struct CodeTable {
+    entries: Vec<CodeEntry, MAX_ENTRIES>,
+}
+
+impl CodeTable {
+    pub fn new() -> Self {
+        Self { entries: Vec::new() }
+    }
+
+    /// The codes that were built in, for devices upgraded from a firmware without the code table.
+    pub fn defaults() -> Self {
+        let mut table = Self::new();
+        for (value, name) in DEFAULT_CODES {
+            table.add(Code::fixed_timing(value), name).unwrap();
+        }
+        table
+    }
+
+    pub async fn load<P>(persistency: &P) -> Result<Self, &'static str>
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = persistency.read(ValueId::CodeTable, &mut bytes).await?;
+        Self::from_bytes(&bytes[..length])
+    }
+
+    pub async fn save<P>(&self, persistency: &P) -> Result<(), &'static str>
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = self.to_bytes(&mut bytes)?;
+        persistency.store(&bytes[..length], ValueId::CodeTable).await
+    }
+
+    pub fn lookup(&self, code: &Code) -> Option<&str> {
+        self.entries.iter()
+            .find(|entry| !entry.remote && entry.code == *code)
+            .map(|entry| entry.name.as_str())
+    }
+
+    /// The name of a key of a named remote, e.g. "garage 8" for key 0x8 of the remote named "garage".
+    pub fn lookup_key(&self, remote_id: u32, key: u8) -> Option<String<MAX_NAME_LENGTH>> {
+        self.entries.iter()
+            .find(|entry| entry.remote && entry.code.value == remote_id)
+            .map(|entry| {
+                let mut name = entry.name.clone();
+                write!(name, " {:X}", key).unwrap();
+                name
+            })
+    }
+
+    pub fn code_of(&self, name: &str) -> Option<Code> {
+        self.entries.iter()
+            .find(|entry| !entry.remote && entry.name == name)
+            .map(|entry| entry.code)
+    }
+
+    pub fn contains_name(&self, name: &str) -> bool {
+        self.entries.iter().any(|entry| entry.name == name)
+    }
+
+    pub fn add(&mut self, code: Code, name: &str) -> Result<(), &'static str> {
+        if self.lookup(&code).is_some() {
+            return Err("code is already in the table");
+        }
+        self.push(code, name, false)
+    }
+
+    /// Names all keys of the remote at once.
+    pub fn add_remote(&mut self, remote_id: u32, name: &str) -> Result<(), &'static str> {
+        if self.entries.iter().any(|entry| entry.remote && entry.code.value == remote_id) {
+            return Err("remote is already in the table");
+        }
+        if name.len() > MAX_REMOTE_NAME_LENGTH {
+            return Err("name is too long");
+        }
+        self.push(Code { value: remote_id, protocol: 0, bit_count: 0 }, name, true)
+    }
+
+    fn push(&mut self, code: Code, name: &str, remote: bool) -> Result<(), &'static str> {
+        if name.is_empty() {
+            return Err("name must not be empty");
+        }
+        if self.contains_name(name) {
+            return Err("name is already in the table");
+        }
+        let name = String::try_from(name).map_err(|_| "name is too long")?;
+        self.entries.push(CodeEntry { code, name, remote }).map_err(|_| "code table is full")
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), &'static str> {
+        match self.entries.iter().position(|entry| entry.name == name) {
+            Some(index) => {
+                self.entries.remove(index);
+                Ok(())
+            },
+            None => Err("name not found in the table"),
+        }
+    }
+
+    pub fn list(&self, answer: &mut [u8]) -> Result<usize, &'static str> {
+        if self.entries.is_empty() {
+            let text = b"code table is empty";
+            if text.len() > answer.len() {
+                return Err("answer buffer too small");
+            }
+            answer[..text.len()].copy_from_slice(text);
+            return Ok(text.len());
+        }
+
+        let mut length = 0;
+        for (n, entry) in self.entries.iter().enumerate() {
+            let mut line: String<{ 40 + MAX_NAME_LENGTH }> = String::new();
+            if n > 0 {
+                line.push('\n').unwrap();
+            }
+            match entry.remote {
+                true => write!(line, "remote 0x{:05X}: {}", entry.code.value, entry.name).unwrap(),
+                false => write!(line, "{}: {}", entry.code, entry.name).unwrap(),
+            }
+
+            if length + line.len() > answer.len() {
+                return Err("answer buffer too small");
+            }
+            answer[length..length + line.len()].copy_from_slice(line.as_bytes());
+            length += line.len();
+        }
+        Ok(length)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        let mut table = Self::new();
+        let mut index = 0;
+
+        while index < bytes.len() {
+            if index + ENTRY_HEADER_SIZE > bytes.len() {
+                return Err("stored code table is corrupt");
+            }
+            let code = Code {
+                value: u32::from_le_bytes(bytes[index..index + 4].try_into().unwrap()),
+                protocol: bytes[index + 4],
+                bit_count: bytes[index + 5],
+            };
+            let remote = bytes[index + 6] & REMOTE_FLAG != 0;
+            let name_length = (bytes[index + 6] & !REMOTE_FLAG) as usize;
+            index += ENTRY_HEADER_SIZE;
+
+            if index + name_length > bytes.len() {
+                return Err("stored code table is corrupt");
+            }
+            let name = core::str::from_utf8(&bytes[index..index + name_length]).map_err(|_| "stored code table is corrupt")?;
+            index += name_length;
+
+            match remote {
+                true => table.add_remote(code.value, name)?,
+                false => table.add(code, name)?,
+            }
+        }
+        Ok(table)
+    }
+
+    fn to_bytes(&self, bytes: &mut [u8]) -> Result<usize, &'static str> {
+        if self.stored_size() > bytes.len() {
+            return Err("buffer too small for code table");
+        }
+
+        let mut index = 0;
+        for entry in self.entries.iter() {
+            bytes[index..index + 4].copy_from_slice(&entry.code.value.to_le_bytes());
+            bytes[index + 4] = entry.code.protocol;
+            bytes[index + 5] = entry.code.bit_count;
+            bytes[index + 6] = entry.name.len() as u8 | if entry.remote { REMOTE_FLAG } else { 0 };
+            index += ENTRY_HEADER_SIZE;
+
+            bytes[index..index + entry.name.len()].copy_from_slice(entry.name.as_bytes());
+            index += entry.name.len();
+        }
+        Ok(index)
+    }
+
+    fn stored_size(&self) -> usize {
+        self.entries.iter().map(|entry| ENTRY_HEADER_SIZE + entry.name.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUTTON_1: Code = Code { value: 0x017E9E90, protocol: Code::FIXED_TIMING_PROTOCOL, bit_count: 25 };
+    const BUTTON_2: Code = Code { value: 0x017E9E88, protocol: Code::FIXED_TIMING_PROTOCOL, bit_count: 25 };
+    const SOCKET: Code = Code { value: 0x1C5103, protocol: 1, bit_count: 24 };
+
+    #[test]
+    fn add_and_lookup() {
+        let mut table = CodeTable::new();
+        table.add(BUTTON_1, "button 1").unwrap();
+        table.add(BUTTON_2, "button 2").unwrap();
+        table.add(SOCKET, "socket").unwrap();
+
+        assert_eq!(table.lookup(&BUTTON_1), Some("button 1"));
+        assert_eq!(table.lookup(&BUTTON_2), Some("button 2"));
+        assert_eq!(table.lookup(&SOCKET), Some("socket"));
+        assert_eq!(table.lookup(&Code { value: 42, ..SOCKET }), None);
+        assert_eq!(table.code_of("socket"), Some(SOCKET));
+        assert_eq!(table.code_of("button 3"), None);
+    }
+
+    #[test]
+    fn protocol_and_bit_count_are_part_of_the_code() {
+        let mut table = CodeTable::new();
+        table.add(SOCKET, "socket").unwrap();
+
+        assert_eq!(table.lookup(&Code { protocol: 2, ..SOCKET }), None);
+        assert_eq!(table.lookup(&Code { bit_count: 32, ..SOCKET }), None);
+        table.add(Code { protocol: 2, ..SOCKET }, "other socket").unwrap();
+        assert_eq!(table.code_of("other socket"), Some(Code { protocol: 2, ..SOCKET }));
+
+        // The fixed part of a rolling code is no rc-switch code, even if it has the same value and bit count.
+        assert_eq!(table.lookup(&Code { protocol: Code::SOMFY_PROTOCOL, ..SOCKET }), None);
+    }
+
+    #[test]
+    fn defaults() {
+        let table = CodeTable::defaults();
+        assert_eq!(table.lookup(&Code::fixed_timing(0x017E9E90)), Some("button 1"));
+        assert_eq!(table.lookup(&Code::fixed_timing(0x017E9E8A)), Some("button 10"));
+
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = table.to_bytes(&mut bytes).unwrap();
+        assert_eq!(CodeTable::from_bytes(&bytes[..length]).unwrap().entries.len(), 10);
+    }
+
+    #[test]
+    fn add_rejects_duplicates() {
+        let mut table = CodeTable::new();
+        table.add(BUTTON_1, "button 1").unwrap();
+
+        assert_eq!(table.add(BUTTON_1, "other"), Err("code is already in the table"));
+        assert_eq!(table.add(BUTTON_2, "button 1"), Err("name is already in the table"));
+    }
+
+    #[test]
+    fn add_rejects_invalid_names() {
+        let mut table = CodeTable::new();
+
+        assert_eq!(table.add(SOCKET, ""), Err("name must not be empty"));
+        assert_eq!(table.add(SOCKET, "this name is far too long"), Err("name is too long"));
+    }
+
+    #[test]
+    fn add_until_full() {
+        let mut table = CodeTable::new();
+
+        let mut result = Ok(());
+        let mut value = 0;
+        while result.is_ok() {
+            let mut name: String<MAX_NAME_LENGTH> = String::new();
+            write!(name, "long name {:06}", value).unwrap();
+            result = table.add(Code { value, ..SOCKET }, &name);
+            value += 1;
+        }
+        assert_eq!(result, Err("code table is full"));
+        assert!(table.stored_size() <= MAX_STORED_SIZE);
+    }
+
+    #[test]
+    fn remove() {
+        let mut table = CodeTable::new();
+        table.add(BUTTON_1, "button 1").unwrap();
+        table.add(BUTTON_2, "button 2").unwrap();
+
+        table.remove("button 1").unwrap();
+        assert_eq!(table.lookup(&BUTTON_1), None);
+        assert_eq!(table.lookup(&BUTTON_2), Some("button 2"));
+
+        assert_eq!(table.remove("button 1"), Err("name not found in the table"));
+    }
+
+    #[test]
+    fn list() {
+        let mut table = CodeTable::new();
+        let mut answer = [0u8; 100];
+
+        let length = table.list(&mut answer).unwrap();
+        assert_eq!(&answer[..length], b"code table is empty");
+
+        table.add(BUTTON_1, "button 1").unwrap();
+        table.add(SOCKET, "socket").unwrap();
+        table.add(Code { value: 0xA1B2C32, protocol: Code::KEELOQ_PROTOCOL, bit_count: 32 }, "gate").unwrap();
+        let mut answer = [0u8; 200];
+        let length = table.list(&mut answer).unwrap();
+        assert_eq!(&answer[..length], b"0x017E9E90 fixed timing: button 1\n0x001C5103 protocol 1 24 bits: socket\n0x0A1B2C32 KeeLoq: gate");
+
+        let mut answer = [0u8; 10];
+        assert_eq!(table.list(&mut answer), Err("answer buffer too small"));
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut table = CodeTable::new();
+        table.add(BUTTON_1, "button 1").unwrap();
+        table.add(SOCKET, "socket").unwrap();
+
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = table.to_bytes(&mut bytes).unwrap();
+        assert_eq!(&bytes[..length], b"\x90\x9E\x7E\x01\x00\x19\x08button 1\x03\x51\x1C\x00\x01\x18\x06socket");
+
+        let table = CodeTable::from_bytes(&bytes[..length]).unwrap();
+        assert_eq!(table.lookup(&BUTTON_1), Some("button 1"));
+        assert_eq!(table.lookup(&SOCKET), Some("socket"));
+    }
+
+    #[test]
+    fn remotes() {
+        let mut table = CodeTable::new();
+        table.add(Code { value: 0xBF4F4, ..SOCKET }, "doorbell").unwrap();
+        table.add_remote(0xBF4F4, "garage").unwrap();
+
+        assert_eq!(table.lookup(&Code { value: 0xBF4F4, ..SOCKET }), Some("doorbell"));
+        assert_eq!(table.lookup_key(0xBF4F4, 0xC).unwrap(), "garage C");
+        assert_eq!(table.lookup_key(0xBF4F5, 0xC), None);
+        assert_eq!(table.code_of("garage"), None);
+
+        assert_eq!(table.add_remote(0xBF4F4, "gate"), Err("remote is already in the table"));
+        assert_eq!(table.add_remote(0xBF4F5, "doorbell"), Err("name is already in the table"));
+        assert_eq!(table.add_remote(0xBF4F5, "fifteen chars!!"), Err("name is too long"));
+
+        table.add_remote(0xBF4F5, "fourteen chars").unwrap();
+        let mut answer = [0u8; 100];
+        let length = table.list(&mut answer).unwrap();
+        assert_eq!(&answer[..length], b"0x000BF4F4 protocol 1 24 bits: doorbell\nremote 0xBF4F4: garage\nremote 0xBF4F5: fourteen chars");
+
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = table.to_bytes(&mut bytes).unwrap();
+        assert_eq!(&bytes[ENTRY_HEADER_SIZE + 8..2 * ENTRY_HEADER_SIZE + 14], b"\xF4\xF4\x0B\x00\x00\x00\x86garage");
+        let mut table = CodeTable::from_bytes(&bytes[..length]).unwrap();
+        assert_eq!(table.lookup_key(0xBF4F4, 0x8).unwrap(), "garage 8");
+
+        table.remove("garage").unwrap();
+        assert_eq!(table.lookup_key(0xBF4F4, 0x8), None);
+    }
+
+    #[test]
+    fn from_empty_bytes() {
+        let table = CodeTable::from_bytes(&[]).unwrap();
+        assert_eq!(table.entries.len(), 0);
+    }
+
+    #[test]
+    fn from_corrupt_bytes() {
+        assert!(CodeTable::from_bytes(&[0x90, 0x9E]).is_err());
+        assert!(CodeTable::from_bytes(&[0x90, 0x9E, 0x7E, 0x01, 0, 25, 8, b'b']).is_err());
+        assert!(CodeTable::from_bytes(&[0x90, 0x9E, 0x7E, 0x01, 0, 25, 1, 0xFF]).is_err());
+    }
+}
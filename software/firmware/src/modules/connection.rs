@@ -0,0 +1,150 @@
+//! The state of the connection to the MQTT broker and the backoff between attempts to establish it.
+//! The layers are established one after the other: Wi-Fi, DHCP, TCP and MQTT.
+//! If one of them fails, the supervisor in `mqtt` starts over with the lowest one that is lost.
+
+use cfg_if::cfg_if;
+
+cfg_if! {
+    if #[cfg(not(test))] {
+        use core::cell::Cell;
+        use defmt::info;
+        use embassy_sync::blocking_mutex::Mutex;
+        use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+        use embassy_time::Timer;
+        use portable_atomic::{AtomicU32, Ordering};
+    }
+}
+
+const MIN_DELAY_MS: u64 = 1000;
+const MAX_DELAY_MS: u64 = 60_000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConnectionState {
+    Joining, // the access point is being joined
+    WaitingForDhcp,
+    ConnectingTcp,
+    ConnectingBroker,
+    Connected,
+}
+
+impl ConnectionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Joining => "joining Wi-Fi",
+            Self::WaitingForDhcp => "waiting for DHCP",
+            Self::ConnectingTcp => "connecting TCP",
+            Self::ConnectingBroker => "connecting to broker",
+            Self::Connected => "connected",
+        }
+    }
+}
+
+/// Exponential backoff with jitter, so gateways that lost the broker at the same time don't retry at the same time.
+/// Half of the delay is fixed, the other half is random.
+pub struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self { failures: 0 }
+    }
+
+    /// Called once a connection is established, the next loss is retried quickly again.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// The delay after the next failure, random is any random number.
+    pub fn next_delay_ms(&mut self, random: u32) -> u64 {
+        let delay_ms = MIN_DELAY_MS.saturating_mul(1 << self.failures.min(16)).min(MAX_DELAY_MS);
+        self.failures = self.failures.saturating_add(1);
+        delay_ms / 2 + random as u64 % (delay_ms / 2 + 1)
+    }
+
+    #[cfg(not(test))]
+    pub async fn wait(&mut self, random: u32) {
+        Timer::after_millis(self.next_delay_ms(random)).await;
+    }
+}
+
+/// Shared with the modules that want to know whether messages get through.
+#[cfg(not(test))]
+pub struct ConnectionStatus {
+    state: Mutex<CriticalSectionRawMutex, Cell<ConnectionState>>,
+    connections: AtomicU32,
+}
+
+#[cfg(not(test))]
+impl ConnectionStatus {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(Cell::new(ConnectionState::Joining)),
+            connections: AtomicU32::new(0),
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state.lock(|state| state.get())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state() == ConnectionState::Connected
+    }
+
+    /// The number of times the connection was established, more than one means it was lost in between.
+    pub fn connections(&self) -> u32 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, new_state: ConnectionState) {
+        let old_state = self.state.lock(|state| state.replace(new_state));
+        if old_state == new_state {
+            return;
+        }
+        info!("connection: {}", new_state.as_str());
+        if new_state == ConnectionState::Connected {
+            self.connections.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_delays() {
+        let mut backoff = Backoff::new();
+        // without jitter the fixed half is left
+        let delays: std::vec::Vec<u64> = (0..8).map(|_| backoff.next_delay_ms(0)).collect();
+        assert_eq!(delays, [500, 1000, 2000, 4000, 8000, 16_000, 30_000, 30_000]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay_ms(0), 500);
+    }
+
+    #[test]
+    fn jitter() {
+        let mut backoff = Backoff::new();
+        assert_eq!(backoff.next_delay_ms(500), 1000);
+        assert_eq!(backoff.next_delay_ms(501), 1501);
+        for _ in 0..6 {
+            backoff.next_delay_ms(0);
+        }
+        // the random half never exceeds the maximum
+        for random in [0, 1, 29_999, 30_000, 30_001, u32::MAX] {
+            let delay_ms = backoff.next_delay_ms(random);
+            assert!((30_000..=60_000).contains(&delay_ms));
+        }
+    }
+
+    #[test]
+    fn state_names() {
+        assert_eq!(ConnectionState::Joining.as_str(), "joining Wi-Fi");
+        assert_eq!(ConnectionState::WaitingForDhcp.as_str(), "waiting for DHCP");
+        assert_eq!(ConnectionState::ConnectingTcp.as_str(), "connecting TCP");
+        assert_eq!(ConnectionState::ConnectingBroker.as_str(), "connecting to broker");
+        assert_eq!(ConnectionState::Connected.as_str(), "connected");
+    }
+}
@@ -0,0 +1,131 @@
+//! Common interface of everything that decodes the pulse stream of the receiver.
+//! A new protocol is added by implementing `Decoder` and adding it to the set of decoders in use.
+
+use core::marker::PhantomData;
+
+pub trait Decoder {
+    type Frame;
+
+    /// Takes the duration of a high pulse and the following low pulse.
+    /// Returns a frame as soon as one is complete.
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<Self::Frame>;
+}
+
+// Several decoders run on the same pulse stream by combining them in a tuple.
+// All of them see every pulse, if more than one completes a frame the first one wins.
+macro_rules! impl_decoder_for_tuple {
+    ($first_type:ident $first:ident, $($other_type:ident $other:ident),+) => {
+        impl<$first_type: Decoder, $($other_type: Decoder<Frame = $first_type::Frame>),+> Decoder for ($first_type, $($other_type),+) {
+            type Frame = $first_type::Frame;
+
+            fn run(&mut self, high_us: u32, low_us: u32) -> Option<Self::Frame> {
+                let ($first, $($other),+) = self;
+                let frame = $first.run(high_us, low_us);
+                $(let frame = frame.or($other.run(high_us, low_us));)+
+                frame
+            }
+        }
+    };
+}
+
+impl_decoder_for_tuple!(A a, B b);
+impl_decoder_for_tuple!(A a, B b, C c);
+impl_decoder_for_tuple!(A a, B b, C c, D d);
+impl_decoder_for_tuple!(A a, B b, C c, D d, E e);
+
+/// Converts the frames of a decoder, so decoders of different protocols can be combined in one tuple.
+pub struct Converted<D, F> {
+    decoder: D,
+    frame: PhantomData<F>,
+}
+
+impl<D, F> Converted<D, F> {
+    pub fn new(decoder: D) -> Self {
+        Self { decoder, frame: PhantomData }
+    }
+
+    pub fn decoder(&mut self) -> &mut D {
+        &mut self.decoder
+    }
+}
+
+impl<D: Decoder, F: From<D::Frame>> Decoder for Converted<D, F> {
+    type Frame = F;
+
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<F> {
+        self.decoder.run(high_us, low_us).map(F::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns its number after the given count of pulses.
+    struct CountingDecoder {
+        number: u8,
+        after: u32,
+        pulses: u32,
+    }
+
+    impl Decoder for CountingDecoder {
+        type Frame = u8;
+
+        fn run(&mut self, _high_us: u32, _low_us: u32) -> Option<u8> {
+            self.pulses += 1;
+            (self.pulses % self.after == 0).then_some(self.number)
+        }
+    }
+
+    fn counting(number: u8, after: u32) -> CountingDecoder {
+        CountingDecoder { number, after, pulses: 0 }
+    }
+
+    #[test]
+    fn all_decoders_see_every_pulse() {
+        let mut decoders = (counting(1, 3), counting(2, 2), counting(3, 5));
+
+        let frames: std::vec::Vec<Option<u8>> = (0..6).map(|_| decoders.run(500, 500)).collect();
+        assert_eq!(frames, [None, Some(2), Some(1), Some(2), Some(3), Some(1)]);
+        assert_eq!((decoders.0.pulses, decoders.1.pulses, decoders.2.pulses), (6, 6, 6));
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Number {
+        Small(u8),
+        Large(u32),
+    }
+
+    impl From<u8> for Number {
+        fn from(number: u8) -> Self {
+            Number::Small(number)
+        }
+    }
+
+    impl From<u32> for Number {
+        fn from(number: u32) -> Self {
+            Number::Large(number)
+        }
+    }
+
+    // Returns 1000 times its number after the given count of pulses.
+    struct LargeDecoder(CountingDecoder);
+
+    impl Decoder for LargeDecoder {
+        type Frame = u32;
+
+        fn run(&mut self, high_us: u32, low_us: u32) -> Option<u32> {
+            self.0.run(high_us, low_us).map(|number| number as u32 * 1000)
+        }
+    }
+
+    #[test]
+    fn converted_decoders_of_different_frames() {
+        let mut decoders: (Converted<CountingDecoder, Number>, Converted<LargeDecoder, Number>) =
+            (Converted::new(counting(1, 3)), Converted::new(LargeDecoder(counting(2, 2))));
+
+        let frames: std::vec::Vec<Option<Number>> = (0..4).map(|_| decoders.run(500, 500)).collect();
+        assert_eq!(frames, [None, Some(Number::Large(2000)), Some(Number::Small(1)), Some(Number::Large(2000))]);
+        assert_eq!(decoders.1.decoder().0.pulses, 4);
+    }
+}
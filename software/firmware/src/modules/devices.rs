@@ -0,0 +1,217 @@
+//! Keeps the battery and tamper flags of the devices that report them.
+//! Weather sensors send a battery-low bit, contact entities with a tamper code report tampering.
+//! Changes of the flags are published as retained diagnostic messages, the list is shown on the terminal.
+
+use core::fmt::Write;
+use heapless::{String, Vec};
+
+const MAX_DEVICES: usize = 16;
+const MAX_DEVICE_NAME_LENGTH: usize = 24;
+
+pub type DeviceName = String<MAX_DEVICE_NAME_LENGTH>;
+
+/// The flags carried by one message of a device, None if the message does not contain the flag.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct DeviceFlags {
+    pub battery_low: Option<bool>,
+    pub tampered: Option<bool>,
+}
+
+/// A retained message with a changed flag of a device.
+#[derive(Debug, PartialEq)]
+pub struct DiagnosticMessage {
+    pub topic: String<64>,
+    pub payload: &'static str,
+}
+
+impl DiagnosticMessage {
+    fn new(name: &str, subtopic: &str, payload: &'static str) -> Self {
+        let mut topic = String::new();
+        write!(topic, "433MHz_to_MQTT_diagnostic/{}/{}", name, subtopic).unwrap();
+        Self { topic, payload }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct Device {
+    name: DeviceName,
+    flags: DeviceFlags,
+    last_seen_ms: u64,
+}
+
+pub struct DeviceList {
+    devices: Vec<Device, MAX_DEVICES>,
+}
+
+impl DeviceList {
+    pub const fn new() -> Self {
+        Self { devices: Vec::new() }
+    }
+
+    /// Returns a message for every flag that is reported for the first time or has changed.
+    /// Devices are only added once they report a flag, so sensors without flags don't push others out.
+    /// If the list is full, the device not seen for the longest time is replaced.
+    pub fn update(&mut self, name: &str, flags: DeviceFlags, now_ms: u64) -> Vec<DiagnosticMessage, 2> {
+        let name = truncated(name);
+        let n = match self.devices.iter().position(|device| device.name == name) {
+            Some(n) => n,
+            None if flags == DeviceFlags::default() => return Vec::new(),
+            None => {
+                let device = Device { name, flags: DeviceFlags::default(), last_seen_ms: now_ms };
+                if let Err(device) = self.devices.push(device) {
+                    let oldest = self.devices.iter()
+                        .enumerate()
+                        .min_by_key(|(_, device)| device.last_seen_ms)
+                        .map(|(n, _)| n)
+                        .unwrap();
+                    self.devices[oldest] = device;
+                    oldest
+                } else {
+                    self.devices.len() - 1
+                }
+            },
+        };
+        let device = &mut self.devices[n];
+        device.last_seen_ms = now_ms;
+
+        let mut messages = Vec::new();
+        if let Some(battery_low) = flags.battery_low.filter(|battery_low| device.flags.battery_low != Some(*battery_low)) {
+            device.flags.battery_low = Some(battery_low);
+            messages.push(DiagnosticMessage::new(&device.name, "battery", if battery_low { "low" } else { "ok" })).unwrap();
+        }
+        if let Some(tampered) = flags.tampered.filter(|tampered| device.flags.tampered != Some(*tampered)) {
+            device.flags.tampered = Some(tampered);
+            messages.push(DiagnosticMessage::new(&device.name, "tamper", if tampered { "tampered" } else { "ok" })).unwrap();
+        }
+        messages
+    }
+
+    pub fn list(&self, now_ms: u64, answer: &mut [u8]) -> Result<usize, &'static str> {
+        if self.devices.is_empty() {
+            let text = b"no device has reported battery or tamper flags yet";
+            if text.len() > answer.len() {
+                return Err("answer buffer too small");
+            }
+            answer[..text.len()].copy_from_slice(text);
+            return Ok(text.len());
+        }
+
+        let mut length = 0;
+        for (n, device) in self.devices.iter().enumerate() {
+            let mut line: String<{ 64 + MAX_DEVICE_NAME_LENGTH }> = String::new();
+            if n > 0 {
+                line.push('\n').unwrap();
+            }
+            write!(line, "{}:", device.name).unwrap();
+            if let Some(battery_low) = device.flags.battery_low {
+                write!(line, " battery {},", if battery_low { "low" } else { "ok" }).unwrap();
+            }
+            if let Some(tampered) = device.flags.tampered {
+                write!(line, " {},", if tampered { "tampered" } else { "not tampered" }).unwrap();
+            }
+            write!(line, " seen {} s ago", (now_ms - device.last_seen_ms) / 1000).unwrap();
+
+            if length + line.len() > answer.len() {
+                return Err("answer buffer too small");
+            }
+            answer[length..length + line.len()].copy_from_slice(line.as_bytes());
+            length += line.len();
+        }
+        Ok(length)
+    }
+}
+
+// Cuts the name at a character boundary, if it is too long.
+fn truncated(name: &str) -> DeviceName {
+    let mut truncated = String::new();
+    for c in name.chars() {
+        if truncated.push(c).is_err() {
+            break;
+        }
+    }
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BATTERY_OK: DeviceFlags = DeviceFlags { battery_low: Some(false), tampered: None };
+    const BATTERY_LOW: DeviceFlags = DeviceFlags { battery_low: Some(true), tampered: None };
+
+    fn messages(messages: &[DiagnosticMessage]) -> std::vec::Vec<(&str, &str)> {
+        messages.iter().map(|message| (message.topic.as_str(), message.payload)).collect()
+    }
+
+    #[test]
+    fn only_changes_are_published() {
+        let mut list = DeviceList::new();
+
+        assert_eq!(messages(&list.update("Nexus-TH/5A", BATTERY_OK, 1000)), [("433MHz_to_MQTT_diagnostic/Nexus-TH/5A/battery", "ok")]);
+        assert_eq!(messages(&list.update("Nexus-TH/5A", BATTERY_OK, 2000)), []);
+        assert_eq!(messages(&list.update("Nexus-TH/5A", BATTERY_LOW, 3000)), [("433MHz_to_MQTT_diagnostic/Nexus-TH/5A/battery", "low")]);
+        assert_eq!(messages(&list.update("Nexus-TH/5A", DeviceFlags::default(), 4000)), []);
+    }
+
+    #[test]
+    fn devices_without_flags_are_not_recorded() {
+        let mut list = DeviceList::new();
+
+        assert_eq!(messages(&list.update("Acurite-609TXC/21", DeviceFlags::default(), 1000)), []);
+        assert!(list.devices.is_empty());
+
+        list.update("Nexus-TH/5A", BATTERY_OK, 1000);
+        list.update("Nexus-TH/5A", DeviceFlags::default(), 5000);
+        assert_eq!(list.devices[0].last_seen_ms, 5000);
+    }
+
+    #[test]
+    fn tamper_flag() {
+        let mut list = DeviceList::new();
+        let tampered = |tampered| DeviceFlags { battery_low: None, tampered: Some(tampered) };
+
+        assert_eq!(messages(&list.update("front door", tampered(true), 1000)), [("433MHz_to_MQTT_diagnostic/front door/tamper", "tampered")]);
+        assert_eq!(messages(&list.update("front door", tampered(true), 2000)), []);
+        assert_eq!(messages(&list.update("front door", tampered(false), 3000)), [("433MHz_to_MQTT_diagnostic/front door/tamper", "ok")]);
+    }
+
+    #[test]
+    fn list() {
+        let mut list = DeviceList::new();
+        let mut answer = [0; 200];
+
+        assert_eq!(list.list(0, &mut answer), Ok(50));
+        assert_eq!(&answer[..50], b"no device has reported battery or tamper flags yet");
+
+        list.update("Nexus-TH/5A", BATTERY_LOW, 1000);
+        list.update("front door", DeviceFlags { battery_low: None, tampered: Some(false) }, 5000);
+        let length = list.list(65_000, &mut answer).unwrap();
+        assert_eq!(core::str::from_utf8(&answer[..length]).unwrap(), "Nexus-TH/5A: battery low, seen 64 s ago\nfront door: not tampered, seen 60 s ago");
+
+        assert_eq!(list.list(65_000, &mut answer[..20]), Err("answer buffer too small"));
+    }
+
+    #[test]
+    fn oldest_device_is_replaced_when_full() {
+        let mut list = DeviceList::new();
+        for n in 0..MAX_DEVICES as u64 {
+            let mut name: String<8> = String::new();
+            write!(name, "dev{}", n).unwrap();
+            list.update(&name, BATTERY_OK, 1000 * (n + 1));
+        }
+        list.update("dev0", BATTERY_OK, 100_000);
+
+        assert_eq!(messages(&list.update("new", BATTERY_OK, 200_000)), [("433MHz_to_MQTT_diagnostic/new/battery", "ok")]);
+        assert_eq!(list.devices.len(), MAX_DEVICES);
+        assert!(list.devices.iter().any(|device| device.name == "dev0"));
+        assert!(!list.devices.iter().any(|device| device.name == "dev1"));
+    }
+
+    #[test]
+    fn long_names_are_truncated() {
+        let mut list = DeviceList::new();
+
+        list.update("Oregon-THGR122N/AB and more", BATTERY_OK, 0);
+        assert_eq!(list.devices[0].name, "Oregon-THGR122N/AB and m");
+    }
+}
@@ -0,0 +1,484 @@
+//! Sensor entities like door contacts and motion detectors, declared in a persisted table.
+//! Their state is derived from the received codes and published as retained MQTT messages.
+//! A contact has codes for open, closed and optionally tamper, a motion detector has one code and
+//! returns to clear after a configured time without motion.
+
+use core::fmt::Write;
+use heapless::{String, Vec};
+
+use crate::modules::code_table::MAX_NAME_LENGTH;
+use crate::modules::persistency::{PersistencyTrait, ValueId};
+
+const MAX_ENTITIES: usize = 8;
+
+// Each entity is stored as: kind (1 byte), three parameters (4 bytes each, little endian), name length (1 byte), name.
+// Contact: open code, closed code, tamper code (0 if there is none). Motion: code, clear time in seconds, 0.
+const ENTRY_HEADER_SIZE: usize = 14;
+const MAX_STORED_SIZE: usize = MAX_ENTITIES * (ENTRY_HEADER_SIZE + MAX_NAME_LENGTH);
+const KIND_CONTACT: u8 = 0;
+const KIND_MOTION: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EntityKind {
+    Contact { open_code: u32, closed_code: u32, tamper_code: Option<u32> },
+    Motion { code: u32, clear_s: u32 },
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Entity {
+    pub name: String<MAX_NAME_LENGTH>,
+    pub kind: EntityKind,
+}
+
+impl Entity {
+    fn uses_code(&self, code: u32) -> bool {
+        match self.kind {
+            EntityKind::Contact { open_code, closed_code, tamper_code } => {
+                code == open_code || code == closed_code || tamper_code == Some(code)
+            },
+            EntityKind::Motion { code: motion_code, .. } => code == motion_code,
+        }
+    }
+
+    fn codes(&self) -> [Option<u32>; 3] {
+        match self.kind {
+            EntityKind::Contact { open_code, closed_code, tamper_code } => [Some(open_code), Some(closed_code), tamper_code],
+            EntityKind::Motion { code, .. } => [Some(code), None, None],
+        }
+    }
+}
+
+pub struct EntityTable {
+    entities: Vec<Entity, MAX_ENTITIES>,
+}
+
+impl EntityTable {
+    pub fn new() -> Self {
+        Self { entities: Vec::new() }
+    }
+
+    pub async fn load<P>(persistency: &P) -> Result<Self, &'static str>
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = persistency.read(ValueId::Entities, &mut bytes).await?;
+        Self::from_bytes(&bytes[..length])
+    }
+
+    pub async fn save<P>(&self, persistency: &P) -> Result<(), &'static str>
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = self.to_bytes(&mut bytes)?;
+        persistency.store(&bytes[..length], ValueId::Entities).await
+    }
+
+    pub fn add(&mut self, name: &str, kind: EntityKind) -> Result<(), &'static str> {
+        if name.is_empty() {
+            return Err("name must not be empty");
+        }
+        if self.entities.iter().any(|entity| entity.name == name) {
+            return Err("name is already in the entity table");
+        }
+        let entity = Entity {
+            name: String::try_from(name).map_err(|_| "name is too long")?,
+            kind,
+        };
+        let codes = entity.codes();
+        if codes.contains(&Some(0)) {
+            return Err("code 0 can not be used");
+        }
+        if codes.iter().enumerate().any(|(n, code)| code.is_some() && codes[..n].contains(code)) {
+            return Err("codes of an entity must differ");
+        }
+        if codes.iter().flatten().any(|code| self.entities.iter().any(|other| other.uses_code(*code))) {
+            return Err("code is already used by another entity");
+        }
+        if let EntityKind::Motion { clear_s: 0, .. } = kind {
+            return Err("clear time must be at least 1 second");
+        }
+        self.entities.push(entity).map_err(|_| "entity table is full")
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), &'static str> {
+        match self.entities.iter().position(|entity| entity.name == name) {
+            Some(index) => {
+                self.entities.remove(index);
+                Ok(())
+            },
+            None => Err("name not found in the entity table"),
+        }
+    }
+
+    pub fn list(&self, answer: &mut [u8]) -> Result<usize, &'static str> {
+        if self.entities.is_empty() {
+            let text = b"entity table is empty";
+            if text.len() > answer.len() {
+                return Err("answer buffer too small");
+            }
+            answer[..text.len()].copy_from_slice(text);
+            return Ok(text.len());
+        }
+
+        let mut length = 0;
+        for (n, entity) in self.entities.iter().enumerate() {
+            let mut line: String<{ 64 + MAX_NAME_LENGTH }> = String::new();
+            if n > 0 {
+                line.push('\n').unwrap();
+            }
+            match entity.kind {
+                EntityKind::Contact { open_code, closed_code, tamper_code } => {
+                    write!(line, "{}: contact, open 0x{:08X}, closed 0x{:08X}", entity.name, open_code, closed_code).unwrap();
+                    if let Some(tamper_code) = tamper_code {
+                        write!(line, ", tamper 0x{:08X}", tamper_code).unwrap();
+                    }
+                },
+                EntityKind::Motion { code, clear_s } => {
+                    write!(line, "{}: motion 0x{:08X}, clear after {} s", entity.name, code, clear_s).unwrap();
+                },
+            }
+
+            if length + line.len() > answer.len() {
+                return Err("answer buffer too small");
+            }
+            answer[length..length + line.len()].copy_from_slice(line.as_bytes());
+            length += line.len();
+        }
+        Ok(length)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        let mut table = Self::new();
+        let mut index = 0;
+
+        while index < bytes.len() {
+            if index + ENTRY_HEADER_SIZE > bytes.len() {
+                return Err("stored entity table is corrupt");
+            }
+            let parameter = |n: usize| u32::from_le_bytes(bytes[index + 1 + 4 * n..index + 5 + 4 * n].try_into().unwrap());
+            let kind = match bytes[index] {
+                KIND_CONTACT => EntityKind::Contact {
+                    open_code: parameter(0),
+                    closed_code: parameter(1),
+                    tamper_code: Some(parameter(2)).filter(|code| *code != 0),
+                },
+                KIND_MOTION => EntityKind::Motion { code: parameter(0), clear_s: parameter(1) },
+                _ => return Err("stored entity table is corrupt"),
+            };
+            let name_length = bytes[index + ENTRY_HEADER_SIZE - 1] as usize;
+            index += ENTRY_HEADER_SIZE;
+
+            if index + name_length > bytes.len() {
+                return Err("stored entity table is corrupt");
+            }
+            let name = core::str::from_utf8(&bytes[index..index + name_length]).map_err(|_| "stored entity table is corrupt")?;
+            index += name_length;
+
+            table.add(name, kind)?;
+        }
+        Ok(table)
+    }
+
+    fn to_bytes(&self, bytes: &mut [u8]) -> Result<usize, &'static str> {
+        if self.stored_size() > bytes.len() {
+            return Err("buffer too small for entity table");
+        }
+
+        let mut index = 0;
+        for entity in self.entities.iter() {
+            let (kind, parameters) = match entity.kind {
+                EntityKind::Contact { open_code, closed_code, tamper_code } => (KIND_CONTACT, [open_code, closed_code, tamper_code.unwrap_or(0)]),
+                EntityKind::Motion { code, clear_s } => (KIND_MOTION, [code, clear_s, 0]),
+            };
+            bytes[index] = kind;
+            for (n, parameter) in parameters.iter().enumerate() {
+                bytes[index + 1 + 4 * n..index + 5 + 4 * n].copy_from_slice(&parameter.to_le_bytes());
+            }
+            bytes[index + ENTRY_HEADER_SIZE - 1] = entity.name.len() as u8;
+            index += ENTRY_HEADER_SIZE;
+
+            bytes[index..index + entity.name.len()].copy_from_slice(entity.name.as_bytes());
+            index += entity.name.len();
+        }
+        Ok(index)
+    }
+
+    fn stored_size(&self) -> usize {
+        self.entities.iter().map(|entity| ENTRY_HEADER_SIZE + entity.name.len()).sum()
+    }
+}
+
+/// A retained message with the new state of an entity.
+#[derive(Debug, PartialEq)]
+pub struct StateMessage {
+    pub topic: String<48>,
+    pub payload: &'static str,
+}
+
+impl StateMessage {
+    fn new(entity: &Entity, subtopic: &str, payload: &'static str) -> Self {
+        let mut topic = String::new();
+        write!(topic, "433MHz_to_MQTT_entity/{}{}", entity.name, subtopic).unwrap();
+        Self { topic, payload }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+struct EntityState {
+    open: Option<bool>, // None until the first code of the contact is received
+    tampered: bool,
+    motion_until_ms: Option<u64>,
+}
+
+/// Keeps the state of the entities, only changes of the state are published.
+pub struct EntityTracker {
+    table: EntityTable,
+    states: Vec<EntityState, MAX_ENTITIES>,
+}
+
+impl EntityTracker {
+    pub fn new(table: EntityTable) -> Self {
+        let states = table.entities.iter().map(|_| EntityState::default()).collect();
+        Self { table, states }
+    }
+
+    /// Takes a reloaded table, the states of unchanged entities are kept.
+    pub fn reload(&mut self, table: EntityTable) {
+        let states = table.entities.iter()
+            .map(|entity| {
+                self.table.entities.iter()
+                    .position(|old| old == entity)
+                    .map(|n| self.states[n])
+                    .unwrap_or_default()
+            })
+            .collect();
+        self.table = table;
+        self.states = states;
+    }
+
+    /// Returns None if the code does not belong to an entity.
+    pub fn update(&mut self, code: u32, now_ms: u64) -> Option<Vec<StateMessage, 2>> {
+        let n = self.table.entities.iter().position(|entity| entity.uses_code(code))?;
+        let entity = &self.table.entities[n];
+        let state = &mut self.states[n];
+        let mut messages = Vec::new();
+
+        match entity.kind {
+            EntityKind::Contact { open_code, tamper_code, .. } => {
+                if tamper_code == Some(code) {
+                    if !state.tampered {
+                        state.tampered = true;
+                        messages.push(StateMessage::new(entity, "/tamper", "tampered")).unwrap();
+                    }
+                    return Some(messages);
+                }
+                // A regular code after a tamper code means the sensor is closed again.
+                if state.tampered {
+                    state.tampered = false;
+                    messages.push(StateMessage::new(entity, "/tamper", "ok")).unwrap();
+                }
+                let open = code == open_code;
+                if state.open != Some(open) {
+                    state.open = Some(open);
+                    messages.push(StateMessage::new(entity, "", if open { "open" } else { "closed" })).unwrap();
+                }
+            },
+            EntityKind::Motion { clear_s, .. } => {
+                if state.motion_until_ms.is_none() {
+                    messages.push(StateMessage::new(entity, "", "motion")).unwrap();
+                }
+                state.motion_until_ms = Some(now_ms + clear_s as u64 * 1000);
+            },
+        }
+        Some(messages)
+    }
+
+    /// The name and tamper flag of the contact the code belongs to, None if it has no tamper code.
+    pub fn tamper_flag(&self, code: u32) -> Option<(&str, bool)> {
+        self.table.entities.iter()
+            .zip(self.states.iter())
+            .find(|(entity, _)| entity.uses_code(code))
+            .filter(|(entity, _)| matches!(entity.kind, EntityKind::Contact { tamper_code: Some(_), .. }))
+            .map(|(entity, state)| (entity.name.as_str(), state.tampered))
+    }
+
+    /// To be called when the deadline is reached, returns the motion detectors that are clear again.
+    pub fn poll(&mut self, now_ms: u64) -> Vec<StateMessage, MAX_ENTITIES> {
+        let mut messages = Vec::new();
+        for (entity, state) in self.table.entities.iter().zip(self.states.iter_mut()) {
+            if state.motion_until_ms.is_some_and(|until_ms| now_ms >= until_ms) {
+                state.motion_until_ms = None;
+                messages.push(StateMessage::new(entity, "", "clear")).unwrap();
+            }
+        }
+        messages
+    }
+
+    /// The time at which `poll` must be called next, if any.
+    pub fn deadline_ms(&self) -> Option<u64> {
+        self.states.iter().filter_map(|state| state.motion_until_ms).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOOR: EntityKind = EntityKind::Contact { open_code: 0x00A1B2C1, closed_code: 0x00A1B2C2, tamper_code: Some(0x00A1B2C4) };
+    const HALL: EntityKind = EntityKind::Motion { code: 0x00D4E5F6, clear_s: 30 };
+
+    fn table() -> EntityTable {
+        let mut table = EntityTable::new();
+        table.add("front door", DOOR).unwrap();
+        table.add("hall", HALL).unwrap();
+        table
+    }
+
+    fn messages<const N: usize>(messages: &Vec<StateMessage, N>) -> std::vec::Vec<(&str, &str)> {
+        messages.iter().map(|message| (message.topic.as_str(), message.payload)).collect()
+    }
+
+    #[test]
+    fn add_rejects_invalid_entities() {
+        let mut table = table();
+        const ENTITIES: &[(&str, EntityKind, &str)] = &[
+            ("", HALL, "name must not be empty"),
+            ("front door", HALL, "name is already in the entity table"),
+            ("this name is far too long", HALL, "name is too long"),
+            ("window", EntityKind::Contact { open_code: 1, closed_code: 1, tamper_code: None }, "codes of an entity must differ"),
+            ("window", EntityKind::Contact { open_code: 1, closed_code: 0x00A1B2C4, tamper_code: None }, "code is already used by another entity"),
+            ("window", EntityKind::Contact { open_code: 1, closed_code: 0, tamper_code: None }, "code 0 can not be used"),
+            ("garage", EntityKind::Motion { code: 0x00D4E5F6, clear_s: 30 }, "code is already used by another entity"),
+            ("garage", EntityKind::Motion { code: 1, clear_s: 0 }, "clear time must be at least 1 second"),
+        ];
+
+        for (name, kind, error) in ENTITIES {
+            assert_eq!(table.add(name, *kind), Err(*error), "{}", name);
+        }
+        assert_eq!(table.entities.len(), 2);
+    }
+
+    #[test]
+    fn remove() {
+        let mut table = table();
+
+        table.remove("front door").unwrap();
+        assert_eq!(table.entities.len(), 1);
+        assert_eq!(table.remove("front door"), Err("name not found in the entity table"));
+    }
+
+    #[test]
+    fn list() {
+        let mut answer = [0u8; 200];
+
+        let length = EntityTable::new().list(&mut answer).unwrap();
+        assert_eq!(&answer[..length], b"entity table is empty");
+
+        let length = table().list(&mut answer).unwrap();
+        assert_eq!(core::str::from_utf8(&answer[..length]).unwrap(), concat!(
+            "front door: contact, open 0x00A1B2C1, closed 0x00A1B2C2, tamper 0x00A1B2C4\n",
+            "hall: motion 0x00D4E5F6, clear after 30 s"
+        ));
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let mut table = table();
+        table.add("window", EntityKind::Contact { open_code: 0x11, closed_code: 0x12, tamper_code: None }).unwrap();
+
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = table.to_bytes(&mut bytes).unwrap();
+        assert_eq!(length, 3 * ENTRY_HEADER_SIZE + "front door".len() + "hall".len() + "window".len());
+
+        let loaded = EntityTable::from_bytes(&bytes[..length]).unwrap();
+        assert_eq!(loaded.entities, table.entities);
+    }
+
+    #[test]
+    fn from_corrupt_bytes() {
+        assert!(EntityTable::from_bytes(&[KIND_CONTACT, 1, 0, 0]).is_err());
+        assert!(EntityTable::from_bytes(&[7, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, b'x']).is_err());
+        assert!(EntityTable::from_bytes(&[KIND_MOTION, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 5, b'x']).is_err());
+    }
+
+    #[test]
+    fn add_until_full() {
+        let mut table = EntityTable::new();
+
+        let mut result = Ok(());
+        let mut code = 1;
+        while result.is_ok() {
+            let mut name: String<MAX_NAME_LENGTH> = String::new();
+            write!(name, "long name {:06}", code).unwrap();
+            result = table.add(&name, EntityKind::Motion { code, clear_s: 10 });
+            code += 1;
+        }
+        assert_eq!(result, Err("entity table is full"));
+        assert!(table.stored_size() <= MAX_STORED_SIZE);
+    }
+
+    #[test]
+    fn contact() {
+        let mut tracker = EntityTracker::new(table());
+
+        assert_eq!(messages(&tracker.update(0x00A1B2C2, 1000).unwrap()), [("433MHz_to_MQTT_entity/front door", "closed")]);
+        assert_eq!(messages(&tracker.update(0x00A1B2C2, 1040).unwrap()), []);
+        assert_eq!(messages(&tracker.update(0x00A1B2C1, 5000).unwrap()), [("433MHz_to_MQTT_entity/front door", "open")]);
+        assert_eq!(messages(&tracker.update(0x00A1B2C4, 6000).unwrap()), [("433MHz_to_MQTT_entity/front door/tamper", "tampered")]);
+        assert_eq!(messages(&tracker.update(0x00A1B2C4, 6040).unwrap()), []);
+        assert_eq!(messages(&tracker.update(0x00A1B2C2, 9000).unwrap()), [
+            ("433MHz_to_MQTT_entity/front door/tamper", "ok"),
+            ("433MHz_to_MQTT_entity/front door", "closed"),
+        ]);
+        assert_eq!(tracker.deadline_ms(), None);
+    }
+
+    #[test]
+    fn tamper_flag() {
+        let mut tracker = EntityTracker::new(table());
+
+        assert_eq!(tracker.tamper_flag(0x00A1B2C1), Some(("front door", false)));
+        let _ = tracker.update(0x00A1B2C4, 1000);
+        assert_eq!(tracker.tamper_flag(0x00A1B2C4), Some(("front door", true)));
+        assert_eq!(tracker.tamper_flag(0x00D4E5F6), None);
+        assert_eq!(tracker.tamper_flag(0x017E9E90), None);
+    }
+
+    #[test]
+    fn motion() {
+        let mut tracker = EntityTracker::new(table());
+
+        assert_eq!(messages(&tracker.update(0x00D4E5F6, 1000).unwrap()), [("433MHz_to_MQTT_entity/hall", "motion")]);
+        assert_eq!(tracker.deadline_ms(), Some(31_000));
+
+        // Further motion extends the time until clear.
+        assert_eq!(messages(&tracker.update(0x00D4E5F6, 20_000).unwrap()), []);
+        assert_eq!(tracker.deadline_ms(), Some(50_000));
+        assert_eq!(messages(&tracker.poll(49_999)), []);
+
+        assert_eq!(messages(&tracker.poll(50_000)), [("433MHz_to_MQTT_entity/hall", "clear")]);
+        assert_eq!(tracker.deadline_ms(), None);
+    }
+
+    #[test]
+    fn other_codes_are_no_entities() {
+        let mut tracker = EntityTracker::new(table());
+
+        assert_eq!(tracker.update(0x017E9E90, 1000), None);
+    }
+
+    #[test]
+    fn reload_keeps_state_of_unchanged_entities() {
+        let mut tracker = EntityTracker::new(table());
+        let _ = tracker.update(0x00A1B2C1, 1000);
+        let _ = tracker.update(0x00D4E5F6, 1000);
+
+        let mut table = table();
+        table.remove("hall").unwrap();
+        table.add("hall", EntityKind::Motion { code: 0x00D4E5F6, clear_s: 60 }).unwrap();
+        tracker.reload(table);
+
+        assert_eq!(messages(&tracker.update(0x00A1B2C1, 2000).unwrap()), []);
+        assert_eq!(messages(&tracker.update(0x00D4E5F6, 2000).unwrap()), [("433MHz_to_MQTT_entity/hall", "motion")]);
+    }
+}
@@ -0,0 +1,113 @@
+//! Splits frames of EV1527 style remotes into the id of the remote and the pressed key.
+//! These remotes send a 20 bit address, which is unique per remote, followed by 4 key bits.
+
+use core::fmt::Write;
+use heapless::String;
+
+use crate::modules::rc_switch::Frame;
+
+const BIT_COUNT: u8 = 24;
+const KEY_BITS: u8 = 4;
+
+// The protocol the fixed timing receiver is made for.
+const FIXED_TIMING_PROTOCOL: u8 = 1;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RemoteFrame {
+    pub protocol: u8,
+    pub remote_id: u32,
+    pub key: u8,
+    pub bit_count: u8,
+}
+
+impl RemoteFrame {
+    /// Returns None if the frame does not have the length of an EV1527 frame.
+    pub fn split(frame: &Frame) -> Option<Self> {
+        if frame.bit_count != BIT_COUNT {
+            return None;
+        }
+        Some(Self {
+            protocol: frame.protocol,
+            remote_id: frame.value >> KEY_BITS,
+            key: (frame.value & ((1 << KEY_BITS) - 1)) as u8,
+            bit_count: frame.bit_count,
+        })
+    }
+
+    /// The fixed timing receiver samples the 24 data bits followed by the sync pulse, which is always read as 0.
+    pub fn from_fixed_timing(value: u32) -> Option<Self> {
+        if value & 1 != 0 || value >> (BIT_COUNT + 1) != 0 {
+            return None;
+        }
+        Self::split(&Frame {
+            protocol: FIXED_TIMING_PROTOCOL,
+            value: value >> 1,
+            bit_count: BIT_COUNT,
+        })
+    }
+
+    /// The MQTT topic of all keys of this remote.
+    pub fn topic(&self) -> String<48> {
+        let mut topic = String::new();
+        write!(topic, "433MHz_to_MQTT_remote/{:05X}", self.remote_id).unwrap();
+        topic
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        let frame = Frame { protocol: 1, value: 0xBF4F48, bit_count: 24 };
+
+        assert_eq!(RemoteFrame::split(&frame), Some(RemoteFrame { protocol: 1, remote_id: 0xBF4F4, key: 0x8, bit_count: 24 }));
+    }
+
+    #[test]
+    fn other_lengths_are_not_split() {
+        assert_eq!(RemoteFrame::split(&Frame { protocol: 1, value: 0x5555, bit_count: 16 }), None);
+        assert_eq!(RemoteFrame::split(&Frame { protocol: 2, value: 0x0BF4F48, bit_count: 28 }), None);
+    }
+
+    #[test]
+    fn buttons_of_one_remote() {
+        // The codes of the remote the gateway was developed with, as read by the fixed timing receiver.
+        const CODES: &[(u32, u8)] = &[
+            (0x017E9E90, 0x8),
+            (0x017E9E88, 0x4),
+            (0x017E9E98, 0xC),
+            (0x017E9E84, 0x2),
+            (0x017E9E94, 0xA),
+            (0x017E9E8C, 0x6),
+            (0x017E9E9C, 0xE),
+            (0x017E9E82, 0x1),
+            (0x017E9E92, 0x9),
+            (0x017E9E8A, 0x5),
+        ];
+
+        for (code, key) in CODES {
+            let frame = RemoteFrame::from_fixed_timing(*code).unwrap();
+            assert_eq!(frame, RemoteFrame { protocol: 1, remote_id: 0xBF4F4, key: *key, bit_count: 24 }, "code 0x{:08X}", code);
+        }
+    }
+
+    #[test]
+    fn invalid_fixed_timing_values() {
+        // sync pulse read as 1
+        assert_eq!(RemoteFrame::from_fixed_timing(0x017E9E91), None);
+        // more than 25 bits
+        assert_eq!(RemoteFrame::from_fixed_timing(0x037E9E90), None);
+    }
+
+    #[test]
+    fn two_remotes_of_the_same_model() {
+        let remote_1 = RemoteFrame::split(&Frame { protocol: 1, value: 0xBF4F48, bit_count: 24 }).unwrap();
+        let remote_2 = RemoteFrame::split(&Frame { protocol: 1, value: 0x12AB08, bit_count: 24 }).unwrap();
+
+        assert_eq!(remote_1.key, remote_2.key);
+        assert_eq!(remote_1.topic(), "433MHz_to_MQTT_remote/BF4F4");
+        assert_eq!(remote_2.topic(), "433MHz_to_MQTT_remote/12AB0");
+    }
+}
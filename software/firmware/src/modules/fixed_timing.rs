@@ -0,0 +1,347 @@
+//! Settings and PIO program of the fixed timing receiver.
+//! The program waits for the sync gap and then samples 25 bits at a fixed delay after each rising edge.
+//! High pulses shorter than the glitch filter abort the frame, short spikes within the sync gap are ignored.
+//! The program is built from an own instruction list, which the tests run on recorded signals.
+
+use cfg_if::cfg_if;
+use heapless::Vec;
+
+use crate::modules::persistency::{PersistencyTrait, ValueId};
+
+cfg_if! {
+    if #[cfg(not(test))] {
+        use embassy_rp::pio::program::{
+            Assembler, InSource, JmpCondition, Label, MovDestination, MovOperation, MovSource, Program, SetDestination,
+            WaitSource, RP2040_MAX_PROGRAM_SIZE,
+        };
+    }
+}
+
+const SYSTEM_CLOCK_HZ: u64 = 125_000_000;
+
+// Limits given by the instruction set: immediate values and delays have 5 bits.
+const MAX_SYNC_LOOPS: u32 = 32;
+const MAX_DELAY: u32 = 31;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FixedTimingSettings {
+    pub clock_divider: u32,
+    pub sync_gap_us: u32,
+    pub sample_delay_us: u32, // after the rising edge of a bit
+    pub glitch_filter_us: u32, // minimum length of a high pulse, 0 disables the filter
+}
+
+/// The settings converted to PIO clock ticks.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ProgramTicks {
+    pub sync_loops: u8, // one loop takes two ticks
+    pub sample_ticks: u8,
+    pub glitch_ticks: u8,
+}
+
+impl FixedTimingSettings {
+    // The values the receiver was built with: 10kHz, a 6.4ms sync gap and sampling 600us after the rising edge.
+    pub const DEFAULT: Self = Self {
+        clock_divider: 12500,
+        sync_gap_us: 6400,
+        sample_delay_us: 600,
+        glitch_filter_us: 0,
+    };
+
+    pub async fn load<P>(persistency: &P) -> Self
+    where P: PersistencyTrait,
+    {
+        Self {
+            clock_divider: Self::load_number(persistency, ValueId::ClockDivider).await.unwrap_or(Self::DEFAULT.clock_divider),
+            sync_gap_us: Self::load_number(persistency, ValueId::SyncGapUs).await.unwrap_or(Self::DEFAULT.sync_gap_us),
+            sample_delay_us: Self::load_number(persistency, ValueId::SampleDelayUs).await.unwrap_or(Self::DEFAULT.sample_delay_us),
+            glitch_filter_us: Self::load_number(persistency, ValueId::GlitchFilterUs).await.unwrap_or(Self::DEFAULT.glitch_filter_us),
+        }
+    }
+
+    async fn load_number<P>(persistency: &P, value_id: ValueId) -> Option<u32>
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; 10];
+        let length = persistency.read(value_id, &mut bytes).await.ok()?;
+        core::str::from_utf8(&bytes[..length]).ok()?.parse().ok()
+    }
+
+    pub fn ticks(&self) -> Result<ProgramTicks, &'static str> {
+        if !(1..=65535).contains(&self.clock_divider) {
+            return Err("clock_divider must be between 1 and 65535");
+        }
+        let to_ticks = |us: u32| ((us as u64 * SYSTEM_CLOCK_HZ / 1_000_000 + self.clock_divider as u64 / 2) / self.clock_divider as u64) as u32;
+
+        let sync_loops = to_ticks(self.sync_gap_us).div_ceil(2);
+        if !(1..=MAX_SYNC_LOOPS).contains(&sync_loops) {
+            return Err("sync_gap_us does not fit to clock_divider");
+        }
+
+        let glitch_ticks = to_ticks(self.glitch_filter_us);
+        if glitch_ticks > MAX_DELAY + 1 {
+            return Err("glitch_filter_us does not fit to clock_divider");
+        }
+
+        // The sample is taken after the glitch check, see program().
+        let sample_ticks = to_ticks(self.sample_delay_us);
+        let sample_range = if glitch_ticks == 0 {
+            1..=MAX_DELAY + 1
+        } else {
+            glitch_ticks + 1..=glitch_ticks + MAX_DELAY + 2
+        };
+        if !sample_range.contains(&sample_ticks) {
+            return Err("sample_delay_us does not fit to clock_divider and glitch_filter_us");
+        }
+
+        Ok(ProgramTicks {
+            sync_loops: sync_loops as u8,
+            sample_ticks: sample_ticks as u8,
+            glitch_ticks: glitch_ticks as u8,
+        })
+    }
+}
+
+// The instructions of the program, the jump targets are their indexes.
+// They are kept apart from the assembler, so the program can be run on recorded signals in the tests.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Instruction {
+    SetX(u8),
+    JmpPinHigh(usize),
+    JmpXDecNonZero(usize),
+    Jmp(usize),
+    WaitPin { high: bool, delay: u8 },
+    Nop { delay: u8 },
+    InPin,
+    Push,
+    ClearIsr,
+}
+
+const MAX_INSTRUCTIONS: usize = 32;
+
+const STARTUP: usize = 0;
+const SYNC: usize = 1;
+const READ_BITS: usize = 4;
+
+fn instructions(ticks: &ProgramTicks) -> Vec<Instruction, MAX_INSTRUCTIONS> {
+    let mut program = Vec::new();
+    let mut add = |instruction| program.push(instruction).unwrap();
+
+    add(Instruction::SetX(ticks.sync_loops - 1)); // STARTUP
+    add(Instruction::JmpPinHigh(STARTUP)); // SYNC, jumps to the spike check if the glitch filter is enabled
+    add(Instruction::JmpXDecNonZero(SYNC));
+
+    add(Instruction::SetX(24)); // one less than the number of bits to read
+    if ticks.glitch_ticks == 0 {
+        // The sample is taken sample_ticks after the rising edge.
+        add(Instruction::WaitPin { high: true, delay: ticks.sample_ticks - 1 }); // READ_BITS
+    } else {
+        // The pin is checked glitch_ticks after the rising edge, the sample is taken sample_ticks after it.
+        // An aborted frame leaves its bits in the ISR, they are dropped so they do not end up in the next frame.
+        add(Instruction::WaitPin { high: true, delay: ticks.glitch_ticks - 1 }); // READ_BITS
+        add(Instruction::JmpPinHigh(READ_BITS + 4));
+        add(Instruction::ClearIsr);
+        add(Instruction::Jmp(STARTUP));
+        let remaining_ticks = ticks.sample_ticks - ticks.glitch_ticks - 1;
+        if remaining_ticks > 0 {
+            add(Instruction::Nop { delay: remaining_ticks - 1 });
+        }
+    }
+    add(Instruction::InPin);
+    add(Instruction::WaitPin { high: false, delay: 0 });
+    add(Instruction::JmpXDecNonZero(READ_BITS));
+    add(Instruction::Push);
+    add(Instruction::Jmp(STARTUP));
+
+    // A high pulse in the sync gap restarts it only if it is longer than the glitch filter.
+    if ticks.glitch_ticks > 0 {
+        let spike = program.len();
+        program[SYNC] = Instruction::JmpPinHigh(spike);
+        program.push(Instruction::Nop { delay: ticks.glitch_ticks - 1 }).unwrap();
+        program.push(Instruction::JmpPinHigh(STARTUP)).unwrap();
+        program.push(Instruction::JmpXDecNonZero(SYNC)).unwrap();
+        program.push(Instruction::Jmp(STARTUP)).unwrap();
+    }
+    program
+}
+
+#[cfg(not(test))]
+pub fn program(ticks: &ProgramTicks) -> Program<RP2040_MAX_PROGRAM_SIZE> {
+    let mut a = Assembler::<RP2040_MAX_PROGRAM_SIZE>::new();
+    let instructions = instructions(ticks);
+    let mut labels: Vec<Label, MAX_INSTRUCTIONS> = instructions.iter().map(|_| a.label()).collect();
+
+    for (n, instruction) in instructions.iter().enumerate() {
+        a.bind(&mut labels[n]);
+        match *instruction {
+            Instruction::SetX(value) => a.set(SetDestination::X, value),
+            Instruction::JmpPinHigh(target) => a.jmp(JmpCondition::PinHigh, &mut labels[target]),
+            Instruction::JmpXDecNonZero(target) => a.jmp(JmpCondition::XDecNonZero, &mut labels[target]),
+            Instruction::Jmp(target) => a.jmp(JmpCondition::Always, &mut labels[target]),
+            Instruction::WaitPin { high, delay } => a.wait_with_delay(high as u8, WaitSource::PIN, 0, false, delay),
+            Instruction::Nop { delay } => a.nop_with_delay(delay),
+            Instruction::InPin => a.r#in(InSource::PINS, 1),
+            Instruction::Push => a.push(false, false),
+            Instruction::ClearIsr => a.mov(MovDestination::ISR, MovOperation::None, MovSource::NULL),
+        }
+    }
+
+    a.assemble_program()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::persistency::MockPersistencyTrait;
+
+    #[test]
+    fn default_is_the_original_program() {
+        assert_eq!(FixedTimingSettings::DEFAULT.ticks(), Ok(ProgramTicks { sync_loops: 32, sample_ticks: 6, glitch_ticks: 0 }));
+    }
+
+    #[test]
+    fn glitch_filter() {
+        let settings = FixedTimingSettings { glitch_filter_us: 200, ..FixedTimingSettings::DEFAULT };
+
+        assert_eq!(settings.ticks(), Ok(ProgramTicks { sync_loops: 32, sample_ticks: 6, glitch_ticks: 2 }));
+    }
+
+    #[test]
+    fn faster_clock() {
+        // 50kHz, one tick is 20us
+        let settings = FixedTimingSettings {
+            clock_divider: 2500,
+            sync_gap_us: 1200,
+            sample_delay_us: 500,
+            glitch_filter_us: 100,
+        };
+
+        assert_eq!(settings.ticks(), Ok(ProgramTicks { sync_loops: 30, sample_ticks: 25, glitch_ticks: 5 }));
+    }
+
+    #[test]
+    fn out_of_range() {
+        const SETTINGS: &[(FixedTimingSettings, &str)] = &[
+            (FixedTimingSettings { clock_divider: 0, ..FixedTimingSettings::DEFAULT }, "clock_divider must be between 1 and 65535"),
+            (FixedTimingSettings { clock_divider: 65536, ..FixedTimingSettings::DEFAULT }, "clock_divider must be between 1 and 65535"),
+            (FixedTimingSettings { sync_gap_us: 6600, ..FixedTimingSettings::DEFAULT }, "sync_gap_us does not fit to clock_divider"),
+            (FixedTimingSettings { sync_gap_us: 0, ..FixedTimingSettings::DEFAULT }, "sync_gap_us does not fit to clock_divider"),
+            (FixedTimingSettings { glitch_filter_us: 3400, ..FixedTimingSettings::DEFAULT }, "glitch_filter_us does not fit to clock_divider"),
+            (FixedTimingSettings { sample_delay_us: 0, ..FixedTimingSettings::DEFAULT }, "sample_delay_us does not fit to clock_divider and glitch_filter_us"),
+            (FixedTimingSettings { sample_delay_us: 3300, ..FixedTimingSettings::DEFAULT }, "sample_delay_us does not fit to clock_divider and glitch_filter_us"),
+            (FixedTimingSettings { glitch_filter_us: 600, ..FixedTimingSettings::DEFAULT }, "sample_delay_us does not fit to clock_divider and glitch_filter_us"),
+        ];
+
+        for (settings, error) in SETTINGS {
+            assert_eq!(settings.ticks(), Err(*error), "{:?}", settings);
+        }
+    }
+
+    // Runs the program one tick per step on the given pin levels and returns the pushed values.
+    fn run(ticks: &ProgramTicks, pin: &[bool]) -> std::vec::Vec<u32> {
+        let program = instructions(ticks);
+        let (mut pc, mut x, mut isr) = (STARTUP, 0u32, 0u32);
+        let mut pushed = std::vec::Vec::new();
+        let mut tick = 0;
+
+        while tick < pin.len() {
+            let mut next = pc + 1;
+            let mut delay = 0;
+            match program[pc] {
+                Instruction::SetX(value) => x = value as u32,
+                Instruction::JmpPinHigh(target) => if pin[tick] { next = target },
+                Instruction::JmpXDecNonZero(target) => {
+                    if x != 0 {
+                        next = target;
+                    }
+                    x = x.wrapping_sub(1);
+                },
+                Instruction::Jmp(target) => next = target,
+                Instruction::WaitPin { high, delay: wait_delay } => {
+                    if pin[tick] != high {
+                        tick += 1;
+                        continue;
+                    }
+                    delay = wait_delay as usize;
+                },
+                Instruction::Nop { delay: nop_delay } => delay = nop_delay as usize,
+                Instruction::InPin => isr = isr << 1 | pin[tick] as u32,
+                Instruction::Push => pushed.push(core::mem::take(&mut isr)),
+                Instruction::ClearIsr => isr = 0,
+            }
+            pc = next;
+            tick += 1 + delay;
+        }
+        pushed
+    }
+
+    // Bits of rc-switch protocol 1 in ticks of 100us.
+    fn add_bit(pin: &mut std::vec::Vec<bool>, bit: bool) {
+        let high_ticks = if bit { 10 } else { 4 };
+        pin.extend(core::iter::repeat_n(true, high_ticks));
+        pin.extend(core::iter::repeat_n(false, 14 - high_ticks));
+    }
+
+    fn add_frame(pin: &mut std::vec::Vec<bool>, code: u32) {
+        for n in (0..24).rev() {
+            add_bit(pin, code >> n & 1 != 0);
+        }
+        // the sync pulse, it is read as the 25th bit
+        pin.extend(core::iter::repeat_n(true, 4));
+        pin.extend(core::iter::repeat_n(false, 100));
+    }
+
+    #[test]
+    fn frame_after_glitch() {
+        let settings = FixedTimingSettings { glitch_filter_us: 200, ..FixedTimingSettings::DEFAULT };
+        let ticks = settings.ticks().unwrap();
+        const CODE: u32 = 0xBF4F48;
+
+        let mut pin = std::vec![false; 100];
+        add_frame(&mut pin, CODE);
+        for _ in 0..5 {
+            add_bit(&mut pin, true);
+        }
+        // a spike shorter than the glitch filter aborts the frame
+        pin.push(true);
+        pin.extend(core::iter::repeat_n(false, 100));
+        add_frame(&mut pin, CODE);
+
+        assert_eq!(run(&ticks, &pin), [CODE << 1, CODE << 1]);
+    }
+
+    #[test]
+    fn frames_without_glitch_filter() {
+        let mut pin = std::vec![false; 100];
+        add_frame(&mut pin, 0x017E9E);
+        add_frame(&mut pin, 0xBF4F48);
+
+        assert_eq!(run(&FixedTimingSettings::DEFAULT.ticks().unwrap(), &pin), [0x017E9E << 1, 0xBF4F48 << 1]);
+    }
+
+    #[tokio::test]
+    async fn load() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        let stored: [(ValueId, &'static [u8]); 4] = [
+            (ValueId::ClockDivider, b"2500"),
+            (ValueId::SyncGapUs, b"1200"),
+            (ValueId::SampleDelayUs, b""),
+            (ValueId::GlitchFilterUs, b"x"),
+        ];
+        for (value_id, value) in stored {
+            mock_persistency.expect_read()
+                .times(1)
+                .withf(move |id, _| *id == value_id)
+                .returning_st(move |_, answer| {
+                    answer[..value.len()].copy_from_slice(value);
+                    Ok(value.len())
+                });
+        }
+
+        assert_eq!(FixedTimingSettings::load(&mock_persistency).await, FixedTimingSettings {
+            clock_divider: 2500,
+            sync_gap_us: 1200,
+            ..FixedTimingSettings::DEFAULT
+        });
+    }
+}
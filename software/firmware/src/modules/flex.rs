@@ -0,0 +1,611 @@
+//! Decoders defined at runtime with the flex decoder syntax of rtl_433, for devices without a built-in decoder.
+//! A specification like "n=doorbell,m=OOK_PWM,s=400,l=1200,r=12000,bits=25,match={8}a5" is entered on the terminal.
+//! The specifications are stored and the decoders run on the pulses of the receivers in pulse width mode.
+//! Supported options are n, m (OOK_PWM, OOK_PPM, OOK_MC_ZEROBIT or OOK_DMC), s, l, r, g, t, bits, bits>=, bits<=, match and preamble.
+//! The tolerance t is accepted, but the line code decoders use their own tolerances.
+
+use core::fmt::Write;
+use heapless::{String, Vec};
+
+use crate::modules::decoder::Decoder;
+use crate::modules::line_code::{Bits, DifferentialManchesterDecoder, ManchesterDecoder, PpmDecoder, PwmDecoder, MAX_BITS};
+use crate::modules::persistency::{PersistencyTrait, ValueId};
+
+pub const MAX_FLEX_DECODERS: usize = 4;
+pub const MAX_NAME_LENGTH: usize = 16;
+// Enough for all options with common values.
+pub const MAX_SPEC_LENGTH: usize = 112;
+
+// The specifications are stored as text, one per line.
+const MAX_STORED_SIZE: usize = MAX_FLEX_DECODERS * (MAX_SPEC_LENGTH + 1);
+// Devices repeat a frame with short gaps, equal frames within this time are taken as repeats.
+const REPEAT_GAP_MS: u64 = 500;
+// match and preamble are compared as one number.
+const MAX_PATTERN_BITS: usize = 32;
+
+pub type FlexName = String<MAX_NAME_LENGTH>;
+type SpecText = String<MAX_SPEC_LENGTH>;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FlexModulation {
+    Pwm, // short pulse is a one, long pulse is a zero
+    Ppm, // short gap is a zero, long gap is a one
+    Manchester, // half bit of length s, a one is high then low
+    DifferentialManchester, // half bit of length s, the level changes at the start of every bit and in the middle of a one
+}
+
+impl FlexModulation {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "OOK_PWM" => Some(Self::Pwm),
+            "OOK_PPM" => Some(Self::Ppm),
+            "OOK_MC_ZEROBIT" => Some(Self::Manchester),
+            "OOK_DMC" => Some(Self::DifferentialManchester),
+            _ => None,
+        }
+    }
+}
+
+// Up to 32 bits, written as hex digits with an optional bit count in front like "{12}a5f".
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Pattern {
+    value: u32,
+    len: usize,
+}
+
+impl Pattern {
+    fn parse(text: &str) -> Result<Self, &'static str> {
+        const INVALID: &str = "match and preamble must be hex digits, optionally with a bit count like {12}a5f";
+        let (len, hex) = match text.strip_prefix('{') {
+            Some(rest) => {
+                let (len, hex) = rest.split_once('}').ok_or(INVALID)?;
+                (Some(len.parse::<usize>().map_err(|_| INVALID)?), hex)
+            },
+            None => (None, text),
+        };
+        let hex_bits = hex.len() * 4;
+        if hex.is_empty() || hex_bits > MAX_PATTERN_BITS {
+            return Err("match and preamble are limited to 32 bits");
+        }
+        let digits = u32::from_str_radix(hex, 16).map_err(|_| INVALID)?;
+        let len = len.unwrap_or(hex_bits);
+        if len == 0 || len > hex_bits {
+            return Err(INVALID);
+        }
+        Ok(Self { value: digits >> (hex_bits - len), len })
+    }
+
+    // The position of the first bit after the first occurrence of the pattern.
+    fn find(&self, bits: &Bits) -> Option<usize> {
+        let last_start = bits.len().checked_sub(self.len)?;
+        (0..=last_start).find(|&n| bits.bits(n, self.len) == self.value).map(|n| n + self.len)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct FlexSpec {
+    text: SpecText, // as entered, to list and store it
+    name: FlexName,
+    modulation: FlexModulation,
+    short_us: u32,
+    long_us: u32,
+    reset_us: u32,
+    gap_us: Option<u32>, // ends a row earlier than the reset limit, only for OOK_PWM
+    min_bits: usize,
+    max_bits: usize,
+    match_bits: Option<Pattern>,
+    preamble: Option<Pattern>,
+}
+
+impl FlexSpec {
+    pub fn parse(text: &str) -> Result<Self, &'static str> {
+        let mut name = None;
+        let mut modulation = None;
+        let mut short_us = None;
+        let mut long_us = None;
+        let mut reset_us = None;
+        let mut gap_us = None;
+        let mut min_bits = 1;
+        let mut max_bits = MAX_BITS;
+        let mut match_bits = None;
+        let mut preamble = None;
+
+        for option in text.split(',').map(str::trim) {
+            if let Some(value) = option.strip_prefix("bits>=") {
+                min_bits = parse_bit_count(value)?;
+                continue;
+            }
+            if let Some(value) = option.strip_prefix("bits<=") {
+                max_bits = parse_bit_count(value)?;
+                continue;
+            }
+            let (key, value) = option.split_once('=').ok_or("flex options must be given as <key>=<value>")?;
+            match key {
+                "n" | "name" => name = Some(value),
+                "m" | "modulation" => modulation = Some(FlexModulation::from_name(value).ok_or("modulation must be OOK_PWM, OOK_PPM, OOK_MC_ZEROBIT or OOK_DMC")?),
+                "s" | "short" => short_us = Some(parse_us(value)?),
+                "l" | "long" => long_us = Some(parse_us(value)?),
+                "r" | "reset" => reset_us = Some(parse_us(value)?),
+                "g" | "gap" => gap_us = Some(parse_us(value)?),
+                "t" | "tolerance" => {
+                    parse_us(value)?;
+                },
+                "bits" => {
+                    min_bits = parse_bit_count(value)?;
+                    max_bits = min_bits;
+                },
+                "match" => match_bits = Some(Pattern::parse(value)?),
+                "preamble" => preamble = Some(Pattern::parse(value)?),
+                _ => return Err("unsupported flex option, type 'help' for the supported ones"),
+            }
+        }
+
+        let name = name.ok_or("flex decoder needs a name (n=)")?;
+        if name.is_empty() {
+            return Err("name must not be empty");
+        }
+        // The name becomes part of a topic and is listed in JSON.
+        if name.contains(['/', '+', '#', '"', '\\']) {
+            return Err("name must not contain / + # quotes or backslashes");
+        }
+        let modulation = modulation.ok_or("flex decoder needs a modulation (m=)")?;
+        let short_us = short_us.ok_or("flex decoder needs a short width (s=)")?;
+        let reset_us = reset_us.ok_or("flex decoder needs a reset limit (r=)")?;
+        let long_us = match modulation {
+            FlexModulation::Manchester | FlexModulation::DifferentialManchester => long_us.unwrap_or(2 * short_us),
+            FlexModulation::Pwm | FlexModulation::Ppm => long_us.ok_or("flex decoder needs a long width (l=)")?,
+        };
+        if matches!(modulation, FlexModulation::Pwm | FlexModulation::Ppm) && long_us <= short_us {
+            return Err("long width must be longer than short width");
+        }
+        if reset_us <= long_us {
+            return Err("reset limit must be longer than long width");
+        }
+        if min_bits > max_bits {
+            return Err("bits>= must not be more than bits<=");
+        }
+
+        Ok(Self {
+            text: String::try_from(text).map_err(|_| "flex specification is too long")?,
+            name: String::try_from(name).map_err(|_| "name is too long")?,
+            modulation,
+            short_us,
+            long_us,
+            reset_us,
+            gap_us,
+            min_bits,
+            max_bits,
+            match_bits,
+            preamble,
+        })
+    }
+
+    // Checks the row like rtl_433 does: the bit count first, then match, then the preamble is cut off.
+    fn extract(&self, row: Bits) -> Option<Bits> {
+        if !(self.min_bits..=self.max_bits).contains(&row.len()) {
+            return None;
+        }
+        if let Some(match_bits) = &self.match_bits {
+            match_bits.find(&row)?;
+        }
+        let Some(preamble) = &self.preamble else {
+            return Some(row);
+        };
+        let start = preamble.find(&row)?;
+        let mut bits = Bits::new();
+        for n in start..row.len() {
+            bits.push(row.bit(n));
+        }
+        if bits.is_empty() { None } else { Some(bits) }
+    }
+}
+
+fn parse_us(text: &str) -> Result<u32, &'static str> {
+    match text.parse() {
+        Ok(0) | Err(_) => Err("timings must be positive numbers of microseconds"),
+        Ok(us) => Ok(us),
+    }
+}
+
+fn parse_bit_count(text: &str) -> Result<usize, &'static str> {
+    match text.parse() {
+        Ok(count) if (1..=MAX_BITS).contains(&count) => Ok(count),
+        _ => Err("bit counts must be within 1-256"),
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct FlexFrame {
+    pub name: FlexName,
+    pub bits: Bits,
+}
+
+impl FlexFrame {
+    pub fn topic(&self) -> String<48> {
+        let mut topic = String::new();
+        write!(topic, "433MHz_to_MQTT_flex/{}", self.name).unwrap();
+        topic
+    }
+
+    /// The bits as hex like the rows of rtl_433, the last byte is padded with zeros.
+    pub fn payload(&self) -> String<96> {
+        let mut payload = String::new();
+        write!(payload, "{{\"bits\":{},\"data\":\"", self.bits.len()).unwrap();
+        for byte in self.bits.bytes() {
+            write!(payload, "{:02x}", byte).unwrap();
+        }
+        payload.push_str("\"}").unwrap();
+        payload
+    }
+}
+
+// There is no heap to box the Manchester decoder, the few decoders are small enough anyway.
+#[allow(clippy::large_enum_variant)]
+enum LineDecoder {
+    Pwm(PwmDecoder),
+    Ppm(PpmDecoder),
+    Manchester(ManchesterDecoder),
+    DifferentialManchester(DifferentialManchesterDecoder),
+}
+
+pub struct FlexDecoder {
+    spec: FlexSpec,
+    line_decoder: LineDecoder,
+}
+
+impl FlexDecoder {
+    pub fn new(spec: &FlexSpec) -> Self {
+        let line_decoder = match spec.modulation {
+            FlexModulation::Pwm => LineDecoder::Pwm(PwmDecoder::new(spec.short_us, spec.long_us, true, spec.gap_us.unwrap_or(spec.reset_us))),
+            // The pulses of PPM are not specified, they are allowed to be as long as the long gap.
+            FlexModulation::Ppm => LineDecoder::Ppm(PpmDecoder::new(spec.short_us, spec.long_us, spec.long_us)),
+            FlexModulation::Manchester => LineDecoder::Manchester(ManchesterDecoder::new(spec.short_us, spec.reset_us)),
+            FlexModulation::DifferentialManchester => LineDecoder::DifferentialManchester(DifferentialManchesterDecoder::new(spec.short_us, spec.reset_us)),
+        };
+        Self { spec: spec.clone(), line_decoder }
+    }
+}
+
+impl Decoder for FlexDecoder {
+    type Frame = FlexFrame;
+
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<FlexFrame> {
+        let row = match &mut self.line_decoder {
+            LineDecoder::Pwm(decoder) => decoder.run(high_us, low_us),
+            LineDecoder::Ppm(decoder) => decoder.run(high_us, low_us),
+            LineDecoder::Manchester(decoder) => decoder.run(high_us, low_us),
+            LineDecoder::DifferentialManchester(decoder) => decoder.run(high_us, low_us),
+        }?;
+        let bits = self.spec.extract(row)?;
+        Some(FlexFrame { name: self.spec.name.clone(), bits })
+    }
+}
+
+/// All flex decoders of the table, they see every pulse like the decoders in a tuple.
+pub struct FlexDecoders {
+    decoders: Vec<FlexDecoder, MAX_FLEX_DECODERS>,
+}
+
+impl Decoder for FlexDecoders {
+    type Frame = FlexFrame;
+
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<FlexFrame> {
+        let mut frame = None;
+        for decoder in self.decoders.iter_mut() {
+            let decoded = decoder.run(high_us, low_us);
+            frame = frame.or(decoded);
+        }
+        frame
+    }
+}
+
+pub struct FlexTable {
+    specs: Vec<FlexSpec, MAX_FLEX_DECODERS>,
+}
+
+impl FlexTable {
+    pub fn new() -> Self {
+        Self { specs: Vec::new() }
+    }
+
+    pub async fn load<P>(persistency: &P) -> Result<Self, &'static str>
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = persistency.read(ValueId::FlexDecoders, &mut bytes).await?;
+        Self::from_bytes(&bytes[..length])
+    }
+
+    pub async fn save<P>(&self, persistency: &P) -> Result<(), &'static str>
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = self.to_bytes(&mut bytes);
+        persistency.store(&bytes[..length], ValueId::FlexDecoders).await
+    }
+
+    pub fn add(&mut self, text: &str) -> Result<(), &'static str> {
+        let spec = FlexSpec::parse(text)?;
+        if self.specs.iter().any(|known| known.name == spec.name) {
+            return Err("name is already in the flex decoders");
+        }
+        self.specs.push(spec).map_err(|_| "flex decoders are full")
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), &'static str> {
+        match self.specs.iter().position(|spec| spec.name == name) {
+            Some(index) => {
+                self.specs.remove(index);
+                Ok(())
+            },
+            None => Err("name not found in the flex decoders"),
+        }
+    }
+
+    pub fn list(&self, answer: &mut [u8]) -> Result<usize, &'static str> {
+        if self.specs.is_empty() {
+            let text = b"no flex decoders defined";
+            if text.len() > answer.len() {
+                return Err("answer buffer too small");
+            }
+            answer[..text.len()].copy_from_slice(text);
+            return Ok(text.len());
+        }
+
+        let mut length = 0;
+        for (n, spec) in self.specs.iter().enumerate() {
+            let separator: &[u8] = if n > 0 { b"\n" } else { b"" };
+            let line_length = separator.len() + spec.text.len();
+            if length + line_length > answer.len() {
+                return Err("answer buffer too small");
+            }
+            answer[length..length + separator.len()].copy_from_slice(separator);
+            answer[length + separator.len()..length + line_length].copy_from_slice(spec.text.as_bytes());
+            length += line_length;
+        }
+        Ok(length)
+    }
+
+    pub fn decoders(&self) -> FlexDecoders {
+        FlexDecoders { decoders: self.specs.iter().map(FlexDecoder::new).collect() }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        let text = core::str::from_utf8(bytes).map_err(|_| "stored flex decoders are corrupt")?;
+        let mut table = Self::new();
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            table.add(line)?;
+        }
+        Ok(table)
+    }
+
+    fn to_bytes(&self, bytes: &mut [u8; MAX_STORED_SIZE]) -> usize {
+        let mut index = 0;
+        for spec in self.specs.iter() {
+            if index > 0 {
+                bytes[index] = b'\n';
+                index += 1;
+            }
+            bytes[index..index + spec.text.len()].copy_from_slice(spec.text.as_bytes());
+            index += spec.text.len();
+        }
+        index
+    }
+}
+
+pub struct FrameFilter {
+    last: Option<(FlexFrame, u64)>,
+}
+
+impl FrameFilter {
+    pub fn new() -> Self {
+        Self { last: None }
+    }
+
+    pub fn accept(&mut self, frame: &FlexFrame, now_ms: u64) -> bool {
+        let repeated = matches!(&self.last, Some((last, last_ms)) if last == frame && now_ms - last_ms < REPEAT_GAP_MS);
+        self.last = Some((frame.clone(), now_ms));
+        !repeated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::line_code::test_signals;
+    use crate::modules::persistency::MockPersistencyTrait;
+
+    fn decode(spec: &str, pulses: &[(u32, u32)]) -> std::vec::Vec<FlexFrame> {
+        let mut table = FlexTable::new();
+        table.add(spec).unwrap();
+        let mut decoders = table.decoders();
+        pulses.iter().filter_map(|(high, low)| decoders.run(*high, *low)).collect()
+    }
+
+    fn frame(name: &str, bits: &str) -> FlexFrame {
+        FlexFrame { name: String::try_from(name).unwrap(), bits: test_signals::bits(bits) }
+    }
+
+    #[test]
+    fn parse_specification() {
+        let spec = FlexSpec::parse("n=doorbell,m=OOK_PWM,s=400,l=1200,r=12000,g=2000,t=100,bits>=20,bits<=26,match={12}a5f,preamble=c").unwrap();
+        assert_eq!(spec.name, "doorbell");
+        assert_eq!(spec.modulation, FlexModulation::Pwm);
+        assert_eq!((spec.short_us, spec.long_us, spec.reset_us, spec.gap_us), (400, 1200, 12000, Some(2000)));
+        assert_eq!((spec.min_bits, spec.max_bits), (20, 26));
+        assert_eq!(spec.match_bits, Some(Pattern { value: 0xA5F, len: 12 }));
+        assert_eq!(spec.preamble, Some(Pattern { value: 0xC, len: 4 }));
+
+        let spec = FlexSpec::parse("name=remote, modulation=OOK_MC_ZEROBIT, short=500, reset=3000, bits=32, match={3}e").unwrap();
+        assert_eq!((spec.short_us, spec.long_us), (500, 1000));
+        assert_eq!((spec.min_bits, spec.max_bits), (32, 32));
+        assert_eq!(spec.match_bits, Some(Pattern { value: 0b111, len: 3 }));
+    }
+
+    #[test]
+    fn invalid_specifications() {
+        const SPECS: &[(&str, &str)] = &[
+            ("m=OOK_PWM,s=400,l=1200,r=12000", "flex decoder needs a name (n=)"),
+            ("n=a/b,m=OOK_PWM,s=400,l=1200,r=12000", "name must not contain / + # quotes or backslashes"),
+            ("n=a,m=FSK_PCM,s=400,l=1200,r=12000", "modulation must be OOK_PWM, OOK_PPM, OOK_MC_ZEROBIT or OOK_DMC"),
+            ("n=a,s=400,l=1200,r=12000", "flex decoder needs a modulation (m=)"),
+            ("n=a,m=OOK_PPM,s=400,r=12000", "flex decoder needs a long width (l=)"),
+            ("n=a,m=OOK_PPM,s=400,l=1200", "flex decoder needs a reset limit (r=)"),
+            ("n=a,m=OOK_PWM,s=400,l=300,r=12000", "long width must be longer than short width"),
+            ("n=a,m=OOK_PWM,s=400,l=1200,r=1000", "reset limit must be longer than long width"),
+            ("n=a,m=OOK_PWM,s=400us,l=1200,r=12000", "timings must be positive numbers of microseconds"),
+            ("n=a,m=OOK_PWM,s=400,l=1200,r=12000,bits=300", "bit counts must be within 1-256"),
+            ("n=a,m=OOK_PWM,s=400,l=1200,r=12000,bits>=30,bits<=20", "bits>= must not be more than bits<="),
+            ("n=a,m=OOK_PWM,s=400,l=1200,r=12000,match=123456789", "match and preamble are limited to 32 bits"),
+            ("n=a,m=OOK_PWM,s=400,l=1200,r=12000,preamble={9}a5", "match and preamble must be hex digits, optionally with a bit count like {12}a5f"),
+            ("n=a,m=OOK_PWM,s=400,l=1200,r=12000,invert", "flex options must be given as <key>=<value>"),
+            ("n=a,m=OOK_PWM,s=400,l=1200,r=12000,unique=1", "unsupported flex option, type 'help' for the supported ones"),
+        ];
+        for (spec, error) in SPECS {
+            assert_eq!(FlexSpec::parse(spec), Err(*error), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn pwm_with_bit_count_and_match() {
+        const SPEC: &str = "n=doorbell,m=OOK_PWM,s=400,l=1200,r=12000,bits=12,match={4}a";
+        let bits = test_signals::bits("1010 0110 1100");
+        let pulses = test_signals::pwm(&bits, 400, 1200, 1600, 14000);
+        assert_eq!(decode(SPEC, &pulses), [frame("doorbell", "1010 0110 1100")]);
+
+        // no match
+        let bits = test_signals::bits("0110 0110 1100");
+        assert_eq!(decode(SPEC, &test_signals::pwm(&bits, 400, 1200, 1600, 14000)), []);
+        // too short
+        let bits = test_signals::bits("1010 0110");
+        assert_eq!(decode(SPEC, &test_signals::pwm(&bits, 400, 1200, 1600, 14000)), []);
+    }
+
+    #[test]
+    fn ppm_with_preamble() {
+        const SPEC: &str = "n=sensor,m=OOK_PPM,s=1000,l=2000,r=6000,preamble={6}2d";
+        let bits = test_signals::bits("0010 1101 0111 0001 1");
+        let pulses = test_signals::ppm(&bits, 500, 1000, 2000, 4000);
+        assert_eq!(decode(SPEC, &pulses), [frame("sensor", "01 0111 0001 1")]);
+
+        let bits = test_signals::bits("0011 1101 0111 0001 1");
+        assert_eq!(decode(SPEC, &test_signals::ppm(&bits, 500, 1000, 2000, 4000)), []);
+    }
+
+    #[test]
+    fn manchester() {
+        let bits = test_signals::bits("1100 1010 0111 0000");
+        let pulses = test_signals::manchester(&bits, 500, 5000);
+        assert_eq!(decode("n=blinds,m=OOK_MC_ZEROBIT,s=500,r=3000,bits=16", &pulses), [frame("blinds", "1100 1010 0111 0000")]);
+    }
+
+    #[test]
+    fn differential_manchester() {
+        let bits = test_signals::bits("1011 0010 1110 0001");
+        let pulses = test_signals::differential_manchester(&bits, 500, 5000);
+        assert_eq!(decode("n=shutter,m=OOK_DMC,s=500,r=2000,bits=16", &pulses), [frame("shutter", "1011 0010 1110 0001")]);
+    }
+
+    #[test]
+    fn first_decoder_wins() {
+        let mut table = FlexTable::new();
+        table.add("n=first,m=OOK_PWM,s=400,l=1200,r=12000").unwrap();
+        table.add("n=second,m=OOK_PWM,s=400,l=1200,r=12000").unwrap();
+        let mut decoders = table.decoders();
+
+        let bits = test_signals::bits("1010 0110");
+        let frames: std::vec::Vec<FlexFrame> = test_signals::pwm(&bits, 400, 1200, 1600, 14000).iter()
+            .filter_map(|(high, low)| decoders.run(*high, *low))
+            .collect();
+        assert_eq!(frames, [frame("first", "1010 0110")]);
+    }
+
+    #[test]
+    fn topic_and_payload() {
+        let frame = frame("doorbell", "1010 0110 1100");
+        assert_eq!(frame.topic(), "433MHz_to_MQTT_flex/doorbell");
+        assert_eq!(frame.payload(), r#"{"bits":12,"data":"a6c0"}"#);
+    }
+
+    #[test]
+    fn repeated_frames() {
+        let doorbell = frame("doorbell", "1010 0110");
+        let other = frame("doorbell", "1010 0111");
+        let mut filter = FrameFilter::new();
+
+        assert!(filter.accept(&doorbell, 1000));
+        assert!(!filter.accept(&doorbell, 1090));
+        assert!(!filter.accept(&doorbell, 1500));
+        assert!(filter.accept(&other, 1600));
+        assert!(filter.accept(&doorbell, 1700));
+        assert!(filter.accept(&doorbell, 2300));
+    }
+
+    #[test]
+    fn add_remove_list() {
+        let mut table = FlexTable::new();
+        let mut answer = [0u8; 300];
+
+        let length = table.list(&mut answer).unwrap();
+        assert_eq!(&answer[..length], b"no flex decoders defined");
+
+        table.add("n=doorbell,m=OOK_PWM,s=400,l=1200,r=12000").unwrap();
+        table.add("n=blinds,m=OOK_MC_ZEROBIT,s=500,r=3000").unwrap();
+        assert_eq!(table.add("n=doorbell,m=OOK_PPM,s=400,l=1200,r=12000"), Err("name is already in the flex decoders"));
+        table.add("n=c,m=OOK_PWM,s=400,l=1200,r=12000").unwrap();
+        table.add("n=d,m=OOK_PWM,s=400,l=1200,r=12000").unwrap();
+        assert_eq!(table.add("n=e,m=OOK_PWM,s=400,l=1200,r=12000"), Err("flex decoders are full"));
+
+        table.remove("c").unwrap();
+        table.remove("d").unwrap();
+        assert_eq!(table.remove("d"), Err("name not found in the flex decoders"));
+
+        let length = table.list(&mut answer).unwrap();
+        assert_eq!(&answer[..length], b"n=doorbell,m=OOK_PWM,s=400,l=1200,r=12000\nn=blinds,m=OOK_MC_ZEROBIT,s=500,r=3000");
+        assert_eq!(table.list(&mut answer[..50]), Err("answer buffer too small"));
+    }
+
+    #[test]
+    fn longest_specs_are_stored() {
+        // A specification of the maximum length, padded with leading zeros of the tolerance.
+        let spec = |name: char| {
+            let start = format!("n={},m=OOK_PWM,s=400,l=1200,r=12000,t=", name);
+            format!("{}{:0>width$}", start, 1, width = MAX_SPEC_LENGTH - start.len())
+        };
+        let mut table = FlexTable::new();
+        for name in ['a', 'b', 'c', 'd'] {
+            table.add(&spec(name)).unwrap();
+        }
+        assert_eq!(table.add(&spec('e')), Err("flex decoders are full"));
+
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = table.to_bytes(&mut bytes);
+        assert_eq!(FlexTable::from_bytes(&bytes[..length]).unwrap().specs.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn save_and_load() {
+        const STORED: &[u8] = b"n=doorbell,m=OOK_PWM,s=400,l=1200,r=12000\nn=blinds,m=OOK_MC_ZEROBIT,s=500,r=3000";
+
+        let mut table = FlexTable::new();
+        table.add("n=doorbell,m=OOK_PWM,s=400,l=1200,r=12000").unwrap();
+        table.add("n=blinds,m=OOK_MC_ZEROBIT,s=500,r=3000").unwrap();
+
+        let mut mock_persistency = MockPersistencyTrait::new();
+        mock_persistency.expect_store()
+            .times(1)
+            .withf(|value, id| value == STORED && *id == ValueId::FlexDecoders)
+            .returning(|_, _| Ok(()));
+        mock_persistency.expect_read()
+            .times(1)
+            .withf(|id, _| *id == ValueId::FlexDecoders)
+            .returning(|_, answer| {
+                answer[..STORED.len()].copy_from_slice(STORED);
+                Ok(STORED.len())
+            });
+
+        table.save(&mock_persistency).await.unwrap();
+        let loaded = FlexTable::load(&mock_persistency).await.unwrap();
+        assert_eq!(loaded.specs, table.specs);
+    }
+}
@@ -0,0 +1,304 @@
+//! Export and import of codes as Flipper Zero SubGhz .sub files, to share captures with a Flipper.
+//! 24 bit codes of rc-switch protocol 1 are written as Princeton key files, all others as RAW files.
+//! Imported key files must use the Princeton protocol, RAW files are decoded as rc-switch frames.
+
+use core::fmt::Write;
+use heapless::String;
+
+use crate::modules::decoder::Decoder;
+use crate::modules::rc_switch::{self, Frame, RcSwitchDecoder};
+
+// Princeton is the same as rc-switch protocol 1 with 24 bits.
+const PRINCETON_PROTOCOL: u8 = 1;
+const PRINCETON_BIT_COUNT: u8 = 24;
+// The base time unit of rc-switch protocol 1.
+const PRINCETON_TE_US: u32 = 350;
+const PRESET: &str = "FuriHalSubGhzPresetOok650Async";
+// The first frame is only decoded with the sync pulse of the one before, so RAW files contain several.
+const RAW_REPEATS: usize = 3;
+// Ends the last frame of RAW data, which may end with a high level.
+const END_GAP_US: u32 = 10000;
+
+/// Writes the .sub file of a frame, one line of RAW_Data per repetition.
+pub fn export(frame: &Frame, frequency_hz: u32, answer: &mut [u8]) -> Result<usize, &'static str> {
+    let levels = rc_switch::encode(frame)?;
+    let mut writer = Writer { answer, length: 0 };
+
+    let raw = frame.protocol != PRINCETON_PROTOCOL || frame.bit_count != PRINCETON_BIT_COUNT;
+    writer.line(format_args!("Filetype: Flipper SubGhz {} File", if raw { "RAW" } else { "Key" }))?;
+    writer.line(format_args!("Version: 1"))?;
+    writer.line(format_args!("Frequency: {}", frequency_hz))?;
+    writer.line(format_args!("Preset: {}", PRESET))?;
+    if raw {
+        writer.line(format_args!("Protocol: RAW"))?;
+        for _ in 0..RAW_REPEATS {
+            let mut line: String<512> = String::new();
+            line.push_str("RAW_Data:").unwrap();
+            for (level, duration_us) in levels.iter() {
+                write!(line, " {}{}", if *level { "" } else { "-" }, duration_us).map_err(|_| "answer buffer too small")?;
+            }
+            writer.line(format_args!("{}", line))?;
+        }
+    } else {
+        writer.line(format_args!("Protocol: Princeton"))?;
+        writer.line(format_args!("Bit: {}", frame.bit_count))?;
+        let key = (frame.value as u64).to_be_bytes();
+        let mut line: String<32> = String::new();
+        line.push_str("Key:").unwrap();
+        for byte in key {
+            write!(line, " {:02X}", byte).unwrap();
+        }
+        writer.line(format_args!("{}", line))?;
+        writer.line(format_args!("TE: {}", PRINCETON_TE_US))?;
+    }
+    // The last line needs no line break, the terminal adds one.
+    Ok(writer.length - 1)
+}
+
+struct Writer<'a> {
+    answer: &'a mut [u8],
+    length: usize,
+}
+
+impl Writer<'_> {
+    fn line(&mut self, args: core::fmt::Arguments) -> Result<(), &'static str> {
+        let mut line: String<600> = String::new();
+        line.write_fmt(args).map_err(|_| "answer buffer too small")?;
+        line.push('\n').map_err(|_| "answer buffer too small")?;
+        if self.length + line.len() > self.answer.len() {
+            return Err("answer buffer too small");
+        }
+        self.answer[self.length..self.length + line.len()].copy_from_slice(line.as_bytes());
+        self.length += line.len();
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum FileType {
+    Key,
+    Raw,
+}
+
+/// Takes a .sub file line by line, the lines are pasted into the terminal one after the other.
+pub struct SubImport {
+    file_type: Option<FileType>,
+    princeton: bool,
+    bit_count: Option<u8>,
+    key: Option<u64>,
+    decoder: RcSwitchDecoder,
+    high_us: Option<u32>,
+    low_us: u32, // after the high level
+    frame: Option<Frame>,
+}
+
+impl SubImport {
+    pub fn new() -> Self {
+        Self {
+            file_type: None,
+            princeton: false,
+            bit_count: None,
+            key: None,
+            decoder: RcSwitchDecoder::new(),
+            high_us: None,
+            low_us: 0,
+            frame: None,
+        }
+    }
+
+    pub fn add_line(&mut self, line: &str) -> Result<(), &'static str> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+        let (key, value) = line.split_once(':').ok_or("not a line of a .sub file")?;
+        let value = value.trim();
+        match key {
+            "Filetype" => {
+                self.file_type = Some(match value {
+                    "Flipper SubGhz Key File" => FileType::Key,
+                    "Flipper SubGhz RAW File" => FileType::Raw,
+                    _ => return Err("not a Flipper SubGhz key or RAW file"),
+                });
+            },
+            "Protocol" => match value {
+                "Princeton" => self.princeton = true,
+                "RAW" => (),
+                _ => return Err("only the protocols Princeton and RAW are supported"),
+            },
+            "Bit" => {
+                let bit_count = value.parse().map_err(|_| "Bit is not a number")?;
+                if !(1..=32).contains(&bit_count) {
+                    return Err("Bit must be between 1 and 32");
+                }
+                self.bit_count = Some(bit_count);
+            },
+            "Key" => {
+                let hex = value.split(' ').filter(|byte| !byte.is_empty()).try_fold(0u64, |key, byte| {
+                    let byte = u8::from_str_radix(byte, 16).map_err(|_| "Key must be hex bytes")?;
+                    key.checked_mul(0x100).map(|key| key | byte as u64).ok_or("Key has more than 8 bytes")
+                })?;
+                self.key = Some(hex);
+            },
+            "RAW_Data" => {
+                for duration in value.split(' ').filter(|duration| !duration.is_empty()) {
+                    let duration: i32 = duration.parse().map_err(|_| "RAW_Data must be numbers")?;
+                    self.add_duration(duration > 0, duration.unsigned_abs());
+                }
+            },
+            // Frequency, preset and timing don't change the code.
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Returns the frame of the file.
+    pub fn finish(mut self) -> Result<Frame, &'static str> {
+        match self.file_type.ok_or("Filetype is missing")? {
+            FileType::Key => {
+                if !self.princeton {
+                    return Err("only the protocols Princeton and RAW are supported");
+                }
+                let bit_count = self.bit_count.ok_or("Bit is missing")?;
+                let key = self.key.ok_or("Key is missing")?;
+                if key >> bit_count != 0 {
+                    return Err("Key has more bits than Bit");
+                }
+                Ok(Frame { protocol: PRINCETON_PROTOCOL, value: key as u32, bit_count })
+            },
+            FileType::Raw => {
+                self.add_duration(false, END_GAP_US);
+                self.decode_pulse();
+                self.frame.ok_or("no rc-switch frame found in RAW_Data")
+            },
+        }
+    }
+
+    // Flipper splits levels, equal levels in a row are merged.
+    fn add_duration(&mut self, level: bool, duration_us: u32) {
+        match (level, self.high_us) {
+            (true, Some(high_us)) if self.low_us == 0 => self.high_us = Some(high_us.saturating_add(duration_us)),
+            (true, _) => {
+                self.decode_pulse();
+                self.high_us = Some(duration_us);
+            },
+            (false, Some(_)) => self.low_us = self.low_us.saturating_add(duration_us),
+            (false, None) => (), // before the first high level
+        }
+    }
+
+    // The first frame found is kept.
+    fn decode_pulse(&mut self) {
+        if let Some(high_us) = self.high_us.take() {
+            let frame = self.decoder.run(high_us, core::mem::take(&mut self.low_us));
+            self.frame = self.frame.or(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(text: &str) -> Result<Frame, &'static str> {
+        let mut import = SubImport::new();
+        for line in text.lines() {
+            import.add_line(line)?;
+        }
+        import.finish()
+    }
+
+    #[test]
+    fn export_princeton() {
+        let mut answer = [0u8; 2048];
+        let length = export(&Frame { protocol: 1, value: 0x95D5D4, bit_count: 24 }, 433920000, &mut answer).unwrap();
+        assert_eq!(core::str::from_utf8(&answer[..length]).unwrap(), concat!(
+            "Filetype: Flipper SubGhz Key File\n",
+            "Version: 1\n",
+            "Frequency: 433920000\n",
+            "Preset: FuriHalSubGhzPresetOok650Async\n",
+            "Protocol: Princeton\n",
+            "Bit: 24\n",
+            "Key: 00 00 00 00 00 95 D5 D4\n",
+            "TE: 350",
+        ));
+    }
+
+    #[test]
+    fn export_raw() {
+        let mut answer = [0u8; 2048];
+        let length = export(&Frame { protocol: 2, value: 0x5, bit_count: 8 }, 315000000, &mut answer).unwrap();
+        let data = " 650 -1300 650 -1300 650 -1300 650 -1300 650 -1300 1300 -650 650 -1300 1300 -650 650 -6500";
+        let mut expected = std::string::String::from("Filetype: Flipper SubGhz RAW File\nVersion: 1\nFrequency: 315000000\nPreset: FuriHalSubGhzPresetOok650Async\nProtocol: RAW");
+        for _ in 0..RAW_REPEATS {
+            expected += "\nRAW_Data:";
+            expected += data;
+        }
+        assert_eq!(core::str::from_utf8(&answer[..length]).unwrap(), expected);
+
+        assert_eq!(export(&Frame { protocol: 2, value: 0x5, bit_count: 8 }, 315000000, &mut answer[..100]), Err("answer buffer too small"));
+        assert_eq!(export(&Frame { protocol: 13, value: 0x5, bit_count: 8 }, 315000000, &mut answer), Err("protocol must be between 1 and 12"));
+    }
+
+    #[test]
+    fn export_and_import() {
+        let frames = [
+            Frame { protocol: 1, value: 0x95D5D4, bit_count: 24 },
+            Frame { protocol: 1, value: 0x1C5103, bit_count: 28 },
+            Frame { protocol: 2, value: 0xABCDE, bit_count: 20 },
+        ];
+        for frame in frames {
+            let mut answer = [0u8; 2048];
+            let length = export(&frame, 433920000, &mut answer).unwrap();
+            assert_eq!(import(core::str::from_utf8(&answer[..length]).unwrap()), Ok(frame));
+        }
+    }
+
+    #[test]
+    fn import_flipper_files() {
+        const KEY_FILE: &str = "Filetype: Flipper SubGhz Key File\n\
+            Version: 1\n\
+            # saved by a Flipper\n\
+            Frequency: 433920000\n\
+            Preset: FuriHalSubGhzPresetOok650Async\n\
+            Protocol: Princeton\n\
+            Bit: 24\n\
+            Key: 00 00 00 00 00 17 E9 E9\n\
+            TE: 412\n\
+            Guard_time: 30";
+        assert_eq!(import(KEY_FILE), Ok(Frame { protocol: 1, value: 0x17E9E9, bit_count: 24 }));
+
+        // Flipper splits levels, the durations of equal levels are added.
+        let mut raw_file = std::string::String::from("Filetype: Flipper SubGhz RAW File\nVersion: 1\nProtocol: RAW\nRAW_Data: 97 -5000");
+        for _ in 0..3 {
+            raw_file += "\nRAW_Data: -10850 ";
+            for bit in [1, 0, 1, 1, 0, 0, 0, 1] {
+                raw_file += if bit == 1 { "300 750 -350 " } else { "350 -1050 " };
+            }
+            raw_file += "350";
+        }
+        assert_eq!(import(&raw_file), Ok(Frame { protocol: 1, value: 0xB1, bit_count: 8 }));
+    }
+
+    #[test]
+    fn invalid_files() {
+        const FILES: &[(&str, &str)] = &[
+            ("Version: 1", "Filetype is missing"),
+            ("Filetype: Flipper SubGhz Key File\nProtocol: CAME", "only the protocols Princeton and RAW are supported"),
+            ("Filetype: Flipper NFC device", "not a Flipper SubGhz key or RAW file"),
+            ("Filetype: Flipper SubGhz Key File\nProtocol: Princeton\nBit: 24", "Key is missing"),
+            ("Filetype: Flipper SubGhz Key File\nProtocol: Princeton\nBit: 40\nKey: 00 00 00 00 01 17 E9 E9", "Bit must be between 1 and 32"),
+            ("Filetype: Flipper SubGhz Key File\nProtocol: Princeton\nBit: 0\nKey: 00 00 00 00 00 00 00 00", "Bit must be between 1 and 32"),
+            ("Filetype: Flipper SubGhz Key File\nProtocol: Princeton\nBit: 16\nKey: 00 00 00 00 00 17 E9 E9", "Key has more bits than Bit"),
+            ("Filetype: Flipper SubGhz Key File\nKey: 00 00 00 00 00 17 E9 XX", "Key must be hex bytes"),
+            ("Filetype: Flipper SubGhz RAW File\nRAW_Data: 350 -1050 350 -10850 350", "no rc-switch frame found in RAW_Data"),
+            ("Filetype: Flipper SubGhz RAW File\nRAW_Data: 350 -1050 a", "RAW_Data must be numbers"),
+            // Levels in a row are merged, their sum must not overflow.
+            ("Filetype: Flipper SubGhz RAW File\nRAW_Data: 2147483647 2147483647 2147483647 -2147483648 -2147483648 -2147483648 350", "no rc-switch frame found in RAW_Data"),
+            ("Filetype: Flipper SubGhz RAW File\nthis is no sub file", "not a line of a .sub file"),
+        ];
+        for (file, error) in FILES {
+            assert_eq!(import(file), Err(*error), "{}", file);
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Decodes the self-learning protocol of Intertechno, Nexa and HomeEasy switches.
+//! A frame starts with a sync of one pulse and a gap of 10 base times, every bit is sent as two pulses:
+//! a zero as a short and a long gap, a one as a long and a short gap and the dim state as two short gaps.
+//! The frame is ended by a gap of 40 base times.
+
+use core::fmt::Write;
+use heapless::{String, Vec};
+
+use crate::modules::decoder::Decoder;
+
+// Limits of the sync gap, the base time is a tenth of it (about 250us to 350us).
+const MIN_SYNC_GAP_US: u32 = 2000;
+const MAX_SYNC_GAP_US: u32 = 3500;
+
+// Repetitions of a command are sent without a pause, the gap between them is below this time.
+const REPEAT_GAP_MS: u64 = 500;
+
+const HOUSE_BITS: usize = 26;
+const COMMAND_BITS: usize = 32;
+const DIM_COMMAND_BITS: usize = 36;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Action {
+    Off,
+    On,
+    Dim { level: u8 }, // 0 to 15
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct IntertechnoCommand {
+    pub house: u32, // 26 bits, chosen by the remote
+    pub group: bool, // all units of the house
+    pub unit: u8,
+    pub action: Action,
+}
+
+impl IntertechnoCommand {
+    pub fn topic(&self) -> String<48> {
+        let mut topic = String::new();
+        write!(topic, "433MHz_to_MQTT_intertechno/{:07X}", self.house).unwrap();
+        topic
+    }
+
+    pub fn payload(&self) -> String<80> {
+        let mut payload = String::new();
+        write!(payload, "{{\"house\":\"{:07X}\",\"group\":{},\"unit\":{},", self.house, self.group, self.unit).unwrap();
+        match self.action {
+            Action::Off => write!(payload, "\"state\":\"off\"}}").unwrap(),
+            Action::On => write!(payload, "\"state\":\"on\"}}").unwrap(),
+            Action::Dim { level } => write!(payload, "\"state\":\"dim\",\"dim_level\":{}}}", level).unwrap(),
+        }
+        payload
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Symbol {
+    Zero,
+    One,
+    Dim,
+}
+
+pub struct IntertechnoDecoder {
+    pulse_us: Option<u32>, // base time, known after a sync
+    first_gap_long: Option<bool>, // first half of the bit being received
+    symbols: Vec<Symbol, DIM_COMMAND_BITS>,
+}
+
+impl IntertechnoDecoder {
+    pub fn new() -> Self {
+        Self {
+            pulse_us: None,
+            first_gap_long: None,
+            symbols: Vec::new(),
+        }
+    }
+
+    // Returns None if the pulse does not belong to a frame.
+    fn receive(&mut self, high_us: u32, low_us: u32) -> Option<Option<IntertechnoCommand>> {
+        let pulse_us = self.pulse_us?;
+        if !(pulse_us / 2..2 * pulse_us).contains(&high_us) {
+            return None;
+        }
+        if low_us > 20 * pulse_us {
+            return self.first_gap_long.is_none().then(|| self.command());
+        }
+        let long = if (pulse_us / 2..5 * pulse_us / 2).contains(&low_us) {
+            false
+        } else if (3 * pulse_us..7 * pulse_us).contains(&low_us) {
+            true
+        } else {
+            return None;
+        };
+        let Some(first_long) = self.first_gap_long.take() else {
+            self.first_gap_long = Some(long);
+            return Some(None);
+        };
+        let symbol = match (first_long, long) {
+            (false, true) => Symbol::Zero,
+            (true, false) => Symbol::One,
+            (false, false) => Symbol::Dim,
+            (true, true) => return None,
+        };
+        self.symbols.push(symbol).ok()?;
+        Some(None)
+    }
+
+    fn command(&self) -> Option<IntertechnoCommand> {
+        let bits = |range: core::ops::Range<usize>| {
+            self.symbols[range].iter().try_fold(0u32, |value, symbol| match symbol {
+                Symbol::Zero => Some(value << 1),
+                Symbol::One => Some(value << 1 | 1),
+                Symbol::Dim => None,
+            })
+        };
+        let action = match (self.symbols.len(), self.symbols.get(HOUSE_BITS + 1)) {
+            (COMMAND_BITS, Some(Symbol::Zero)) => Action::Off,
+            (COMMAND_BITS, Some(Symbol::One)) => Action::On,
+            (DIM_COMMAND_BITS, Some(Symbol::Dim)) => Action::Dim { level: bits(COMMAND_BITS..DIM_COMMAND_BITS)? as u8 },
+            _ => return None,
+        };
+        Some(IntertechnoCommand {
+            house: bits(0..HOUSE_BITS)?,
+            group: bits(HOUSE_BITS..HOUSE_BITS + 1)? == 1,
+            unit: bits(HOUSE_BITS + 2..COMMAND_BITS)? as u8,
+            action,
+        })
+    }
+}
+
+impl Decoder for IntertechnoDecoder {
+    type Frame = IntertechnoCommand;
+
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<IntertechnoCommand> {
+        if let Some(command) = self.receive(high_us, low_us) {
+            if command.is_some() {
+                self.pulse_us = None;
+            }
+            return command;
+        }
+
+        self.first_gap_long = None;
+        self.symbols.clear();
+        self.pulse_us = (MIN_SYNC_GAP_US..=MAX_SYNC_GAP_US).contains(&low_us)
+            .then_some(low_us / 10)
+            .filter(|pulse_us| (pulse_us / 2..2 * pulse_us).contains(&high_us));
+        None
+    }
+}
+
+/// Passes a command only once, however often the remote repeats it.
+pub struct CommandFilter {
+    last: Option<(IntertechnoCommand, u64)>,
+}
+
+impl CommandFilter {
+    pub fn new() -> Self {
+        Self { last: None }
+    }
+
+    pub fn accept(&mut self, command: &IntertechnoCommand, now_ms: u64) -> bool {
+        let repeated = matches!(self.last, Some((last, last_ms)) if last == *command && now_ms - last_ms < REPEAT_GAP_MS);
+        self.last = Some((*command, now_ms));
+        !repeated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pulses of a frame given as 0, 1 and D (dim) with the base time of the remote.
+    fn frame(symbols: &str, pulse_us: u32) -> std::vec::Vec<(u32, u32)> {
+        let short = (pulse_us, pulse_us);
+        let long = (pulse_us, 5 * pulse_us);
+        let mut pulses = vec![(pulse_us, 10 * pulse_us)];
+        for symbol in symbols.chars().filter(|c| !c.is_whitespace()) {
+            match symbol {
+                '0' => pulses.extend([short, long]),
+                '1' => pulses.extend([long, short]),
+                'D' => pulses.extend([short, short]),
+                _ => panic!("invalid symbol {}", symbol),
+            }
+        }
+        pulses.push((pulse_us, 40 * pulse_us));
+        pulses
+    }
+
+    fn decode(pulses: &[(u32, u32)]) -> std::vec::Vec<IntertechnoCommand> {
+        let mut decoder = IntertechnoDecoder::new();
+        pulses.iter().filter_map(|(high, low)| decoder.run(*high, *low)).collect()
+    }
+
+    // house 0x12D687A (19748986)
+    const HOUSE: &str = "01 0010 1101 0110 1000 0111 1010";
+
+    #[test]
+    fn on() {
+        let pulses = frame(&format!("{} 0 1 0010", HOUSE), 260);
+
+        assert_eq!(decode(&pulses), [IntertechnoCommand { house: 0x12D687A, group: false, unit: 2, action: Action::On }]);
+    }
+
+    #[test]
+    fn off() {
+        let pulses = frame(&format!("{} 0 0 1111", HOUSE), 260);
+
+        assert_eq!(decode(&pulses), [IntertechnoCommand { house: 0x12D687A, group: false, unit: 15, action: Action::Off }]);
+    }
+
+    #[test]
+    fn group() {
+        let pulses = frame(&format!("{} 1 0 0000", HOUSE), 260);
+
+        assert_eq!(decode(&pulses), [IntertechnoCommand { house: 0x12D687A, group: true, unit: 0, action: Action::Off }]);
+    }
+
+    #[test]
+    fn dim() {
+        let pulses = frame(&format!("{} 0 D 0011 1001", HOUSE), 260);
+
+        assert_eq!(decode(&pulses), [IntertechnoCommand { house: 0x12D687A, group: false, unit: 3, action: Action::Dim { level: 9 } }]);
+    }
+
+    #[test]
+    fn repeated_frames_with_other_base_time() {
+        let mut pulses = frame(&format!("{} 0 1 0010", HOUSE), 320);
+        pulses.extend(frame(&format!("{} 0 1 0010", HOUSE), 320));
+
+        assert_eq!(decode(&pulses).len(), 2);
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let frames = [
+            format!("{} 0 1 001", HOUSE), // too short
+            format!("{} 0 1 0010 0000", HOUSE), // dim level without dim state
+            format!("{} 0 D 0010", HOUSE), // dim state without dim level
+            format!("{} D 1 0010", HOUSE), // dim state in place of the group
+        ];
+        for symbols in frames {
+            assert_eq!(decode(&frame(&symbols, 260)), [], "{}", symbols);
+        }
+
+        // a gap of 3 base times is neither short nor long
+        let mut pulses = frame(&format!("{} 0 1 0010", HOUSE), 260);
+        pulses[10].1 = 3 * 260 - 100;
+        assert_eq!(decode(&pulses), []);
+    }
+
+    #[test]
+    fn payload() {
+        let command = IntertechnoCommand { house: 0x12D687A, group: false, unit: 2, action: Action::On };
+        assert_eq!(command.topic(), "433MHz_to_MQTT_intertechno/12D687A");
+        assert_eq!(command.payload(), r#"{"house":"12D687A","group":false,"unit":2,"state":"on"}"#);
+
+        let command = IntertechnoCommand { group: true, action: Action::Off, ..command };
+        assert_eq!(command.payload(), r#"{"house":"12D687A","group":true,"unit":2,"state":"off"}"#);
+
+        let command = IntertechnoCommand { house: 0x3FFFFFF, unit: 15, action: Action::Dim { level: 15 }, ..command };
+        assert_eq!(command.payload(), r#"{"house":"3FFFFFF","group":true,"unit":15,"state":"dim","dim_level":15}"#);
+    }
+
+    #[test]
+    fn filter() {
+        let on = IntertechnoCommand { house: 0x12D687A, group: false, unit: 2, action: Action::On };
+        let off = IntertechnoCommand { action: Action::Off, ..on };
+        let mut filter = CommandFilter::new();
+
+        assert!(filter.accept(&on, 1000));
+        assert!(!filter.accept(&on, 1090));
+        assert!(!filter.accept(&on, 1180));
+        assert!(filter.accept(&off, 1300));
+        assert!(filter.accept(&on, 1400));
+        assert!(filter.accept(&on, 1900));
+    }
+}
@@ -0,0 +1,446 @@
+//! Decoders for the common line codes of 433MHz devices, they turn pulse durations into rows of bits.
+//! A row ends with a long gap, protocol decoders built on top of them interpret the bits.
+
+use heapless::Vec;
+
+use crate::modules::decoder::Decoder;
+
+// Oregon Scientific version 2.1 sends every bit twice, which makes its frames the longest.
+pub const MAX_BITS: usize = 256;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Bits {
+    bytes: Vec<u8, { MAX_BITS / 8 }>,
+    len: usize,
+}
+
+impl Bits {
+    pub fn new() -> Self {
+        Self { bytes: Vec::new(), len: 0 }
+    }
+
+    /// Returns false if there is no space left.
+    pub fn push(&mut self, bit: bool) -> bool {
+        if self.len == MAX_BITS {
+            return false;
+        }
+        if self.len % 8 == 0 {
+            self.bytes.push(0).unwrap();
+        }
+        if bit {
+            self.bytes[self.len / 8] |= 0x80 >> (self.len % 8);
+        }
+        self.len += 1;
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+        self.len = 0;
+    }
+
+    pub fn bit(&self, n: usize) -> bool {
+        self.bytes[n / 8] & (0x80 >> (n % 8)) != 0
+    }
+
+    /// Up to 32 bits starting at bit n, the first bit is the most significant one.
+    pub fn bits(&self, n: usize, count: usize) -> u32 {
+        (n..n + count).fold(0, |value, n| value << 1 | self.bit(n) as u32)
+    }
+
+    /// The bits packed into bytes, the last byte is padded with zeros.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn take(&mut self) -> Self {
+        core::mem::replace(self, Self::new())
+    }
+}
+
+/// Pulse position modulation, the bits are encoded in the length of the gaps between equal pulses.
+/// A row is ended by a gap longer than the one of a one bit.
+pub struct PpmDecoder {
+    zero_gap_us: u32,
+    one_gap_us: u32,
+    max_pulse_us: u32,
+    bits: Bits,
+}
+
+impl PpmDecoder {
+    pub fn new(zero_gap_us: u32, one_gap_us: u32, max_pulse_us: u32) -> Self {
+        Self { zero_gap_us, one_gap_us, max_pulse_us, bits: Bits::new() }
+    }
+}
+
+impl Decoder for PpmDecoder {
+    type Frame = Bits;
+
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<Bits> {
+        let limit_us = (self.zero_gap_us + self.one_gap_us) / 2;
+        let end_us = 2 * self.one_gap_us - self.zero_gap_us;
+
+        if high_us > self.max_pulse_us || low_us < self.zero_gap_us / 2 {
+            self.bits.clear();
+            return None;
+        }
+        if low_us >= end_us {
+            return if self.bits.is_empty() { None } else { Some(self.bits.take()) };
+        }
+        if !self.bits.push(low_us >= limit_us) {
+            self.bits.clear();
+        }
+        None
+    }
+}
+
+/// Pulse width modulation, the bits are encoded in the length of the high pulses.
+/// The gap after the last pulse of a row is longer than the reset limit.
+pub struct PwmDecoder {
+    short_us: u32,
+    long_us: u32,
+    short_is_one: bool,
+    reset_us: u32,
+    bits: Bits,
+}
+
+impl PwmDecoder {
+    pub fn new(short_us: u32, long_us: u32, short_is_one: bool, reset_us: u32) -> Self {
+        Self { short_us, long_us, short_is_one, reset_us, bits: Bits::new() }
+    }
+}
+
+impl Decoder for PwmDecoder {
+    type Frame = Bits;
+
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<Bits> {
+        let limit_us = (self.short_us + self.long_us) / 2;
+        let max_us = self.long_us + (self.long_us - self.short_us);
+
+        if high_us < self.short_us / 2 || high_us > max_us {
+            self.bits.clear();
+            return None;
+        }
+        let short = high_us < limit_us;
+        if !self.bits.push(short == self.short_is_one) {
+            self.bits.clear();
+            return None;
+        }
+        if low_us > self.reset_us {
+            return Some(self.bits.take());
+        }
+        None
+    }
+}
+
+/// Manchester code, a one is sent as high then low and a zero as low then high.
+/// The row starts with the first high pulse after a gap longer than the reset limit.
+pub struct ManchesterDecoder {
+    half_bit_us: u32,
+    reset_us: u32,
+    halves: Vec<bool, { 2 * MAX_BITS + 2 }>,
+}
+
+impl ManchesterDecoder {
+    pub fn new(half_bit_us: u32, reset_us: u32) -> Self {
+        let mut decoder = Self { half_bit_us, reset_us, halves: Vec::new() };
+        decoder.restart();
+        decoder
+    }
+
+    fn push(&mut self, level: bool, count: usize) -> bool {
+        (0..count).all(|_| self.halves.push(level).is_ok())
+    }
+
+    fn restart(&mut self) {
+        self.halves.clear();
+        // A row can start with the low half of a zero, which is merged with the gap before.
+        self.halves.push(false).unwrap();
+    }
+
+    // The first half is not known to be the start of a bit, so both possibilities are tried.
+    fn decode(&self) -> Option<Bits> {
+        let decode_from = |offset: usize| {
+            let mut bits = Bits::new();
+            for pair in self.halves[offset..].chunks_exact(2) {
+                match (pair[0], pair[1]) {
+                    (true, false) => bits.push(true),
+                    (false, true) => bits.push(false),
+                    _ => break,
+                };
+            }
+            bits
+        };
+        let bits = [decode_from(0), decode_from(1)].into_iter().max_by_key(|bits| bits.len())?;
+        if bits.is_empty() { None } else { Some(bits) }
+    }
+}
+
+impl Decoder for ManchesterDecoder {
+    type Frame = Bits;
+
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<Bits> {
+        let Some(high_halves) = half_bits(high_us, self.half_bit_us) else {
+            self.restart();
+            return None;
+        };
+        if !self.push(true, high_halves) {
+            self.restart();
+            return None;
+        }
+
+        if low_us > self.reset_us {
+            // The last half of a one is low and merges with the gap.
+            let _ = self.halves.push(false);
+            let bits = self.decode();
+            self.restart();
+            return bits;
+        }
+
+        let pushed = half_bits(low_us, self.half_bit_us).is_some_and(|low_halves| self.push(false, low_halves));
+        if !pushed {
+            self.restart();
+        }
+        None
+    }
+}
+
+/// Differential Manchester code in the biphase mark variant: the level changes at the start of every bit
+/// and a one has an additional change in the middle. The row starts with the first high pulse after a gap.
+pub struct DifferentialManchesterDecoder {
+    half_bit_us: u32,
+    reset_us: u32,
+    half_pending: bool, // the first half of a one was received
+    bits: Bits,
+}
+
+impl DifferentialManchesterDecoder {
+    pub fn new(half_bit_us: u32, reset_us: u32) -> Self {
+        Self { half_bit_us, reset_us, half_pending: false, bits: Bits::new() }
+    }
+
+    fn add(&mut self, duration_us: u32) -> bool {
+        match (half_bits(duration_us, self.half_bit_us), self.half_pending) {
+            (Some(1), false) => {
+                self.half_pending = true;
+                true
+            },
+            (Some(1), true) => {
+                self.half_pending = false;
+                self.bits.push(true)
+            },
+            (Some(2), false) => self.bits.push(false),
+            _ => false,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.half_pending = false;
+        self.bits.clear();
+    }
+}
+
+impl Decoder for DifferentialManchesterDecoder {
+    type Frame = Bits;
+
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<Bits> {
+        if !self.add(high_us) {
+            self.restart();
+            return None;
+        }
+
+        if low_us > self.reset_us {
+            // The last half of a one ending low merges with the gap.
+            if self.half_pending && !self.bits.push(true) {
+                self.restart();
+                return None;
+            }
+            self.half_pending = false;
+            return if self.bits.is_empty() { None } else { Some(self.bits.take()) };
+        }
+
+        if !self.add(low_us) {
+            self.restart();
+        }
+        None
+    }
+}
+
+// The number of half bits a pulse or gap lasts, code with two levels per bit have either one or two.
+fn half_bits(duration_us: u32, half_bit_us: u32) -> Option<usize> {
+    match duration_us * 2 / half_bit_us {
+        1..=2 => Some(1),
+        3..=4 => Some(2),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub mod test_signals {
+    //! Generates pulse durations for the line codes, to test the protocol decoders.
+
+    use super::Bits;
+    use crate::modules::rc_switch::test_signals::pulses;
+
+    pub fn bits(text: &str) -> Bits {
+        let mut bits = Bits::new();
+        for c in text.chars().filter(|c| !c.is_whitespace()) {
+            assert!(bits.push(c == '1'));
+        }
+        bits
+    }
+
+    pub fn ppm(bits: &Bits, pulse_us: u32, zero_gap_us: u32, one_gap_us: u32, sync_gap_us: u32) -> Vec<(u32, u32)> {
+        let mut durations: Vec<(u32, u32)> = (0..bits.len())
+            .map(|n| (pulse_us, if bits.bit(n) { one_gap_us } else { zero_gap_us }))
+            .collect();
+        durations.push((pulse_us, sync_gap_us));
+        durations
+    }
+
+    pub fn pwm(bits: &Bits, one_us: u32, zero_us: u32, period_us: u32, reset_us: u32) -> Vec<(u32, u32)> {
+        let mut durations: Vec<(u32, u32)> = (0..bits.len())
+            .map(|n| if bits.bit(n) { (one_us, period_us - one_us) } else { (zero_us, period_us - zero_us) })
+            .collect();
+        durations.last_mut().unwrap().1 = reset_us;
+        durations
+    }
+
+    pub fn manchester(bits: &Bits, half_bit_us: u32, reset_us: u32) -> Vec<(u32, u32)> {
+        let mut levels = vec![(false, reset_us)];
+        for n in 0..bits.len() {
+            levels.push((bits.bit(n), half_bit_us));
+            levels.push((!bits.bit(n), half_bit_us));
+        }
+        levels.push((false, reset_us));
+        pulses(&levels)
+    }
+
+    pub fn differential_manchester(bits: &Bits, half_bit_us: u32, reset_us: u32) -> Vec<(u32, u32)> {
+        let mut levels = vec![(false, reset_us)];
+        let mut level = false;
+        for n in 0..bits.len() {
+            level = !level;
+            levels.push((level, half_bit_us));
+            if bits.bit(n) {
+                level = !level;
+            }
+            levels.push((level, half_bit_us));
+        }
+        levels.push((false, reset_us));
+        pulses(&levels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_signals;
+
+    fn decode(pulses: &[(u32, u32)], decoder: &mut impl Decoder<Frame = Bits>) -> std::vec::Vec<Bits> {
+        pulses.iter().filter_map(|(high, low)| decoder.run(*high, *low)).collect()
+    }
+
+    #[test]
+    fn bits() {
+        let bits = test_signals::bits("1010 0001 1");
+
+        assert_eq!(bits.len(), 9);
+        assert_eq!(bits.bytes(), [0xA1, 0x80]);
+        assert_eq!(bits.bits(0, 4), 0xA);
+        assert_eq!(bits.bits(4, 5), 0x03);
+        assert!(bits.bit(8));
+    }
+
+    #[test]
+    fn bits_full() {
+        let mut bits = Bits::new();
+        for _ in 0..MAX_BITS {
+            assert!(bits.push(true));
+        }
+        assert!(!bits.push(true));
+        assert_eq!(bits.len(), MAX_BITS);
+    }
+
+    #[test]
+    fn ppm() {
+        let bits = test_signals::bits("1100 1010 0111");
+        let mut pulses = test_signals::ppm(&bits, 500, 1000, 2000, 4000);
+        pulses.extend(test_signals::ppm(&bits, 500, 1000, 2000, 4000));
+
+        let mut decoder = PpmDecoder::new(1000, 2000, 1000);
+        assert_eq!(decode(&pulses, &mut decoder), [bits.clone(), bits]);
+    }
+
+    #[test]
+    fn ppm_noise() {
+        let bits = test_signals::bits("1100 1010 0111");
+        let mut pulses = vec![(500, 1000), (500, 200)];
+        pulses.extend(test_signals::ppm(&bits, 500, 1000, 2000, 4000));
+
+        let mut decoder = PpmDecoder::new(1000, 2000, 1000);
+        assert_eq!(decode(&pulses, &mut decoder), [bits]);
+    }
+
+    #[test]
+    fn pwm() {
+        let bits = test_signals::bits("0000 1010 1110 0001");
+        let mut pulses = test_signals::pwm(&bits, 550, 1400, 2400, 20_000);
+        pulses.extend(test_signals::pwm(&bits, 550, 1400, 2400, 20_000));
+
+        let mut decoder = PwmDecoder::new(550, 1400, true, 4000);
+        assert_eq!(decode(&pulses, &mut decoder), [bits.clone(), bits]);
+    }
+
+    #[test]
+    fn manchester() {
+        for text in ["1111 0101 0011", "0110 1000 1101", "01", "10"] {
+            let bits = test_signals::bits(text);
+            let pulses = test_signals::manchester(&bits, 488, 5000);
+
+            let mut decoder = ManchesterDecoder::new(488, 2000);
+            assert_eq!(decode(&pulses, &mut decoder), [bits], "{}", text);
+        }
+    }
+
+    #[test]
+    fn manchester_timing_deviation() {
+        let bits = test_signals::bits("1111 1111 0101 1100 0010");
+        let pulses: std::vec::Vec<(u32, u32)> = test_signals::manchester(&bits, 488, 5000)
+            .iter()
+            .map(|(high, low)| (high - 80, if *low < 5000 { low + 80 } else { *low }))
+            .collect();
+
+        let mut decoder = ManchesterDecoder::new(488, 2000);
+        assert_eq!(decode(&pulses, &mut decoder), [bits]);
+    }
+
+    #[test]
+    fn differential_manchester() {
+        for text in ["1111 0101 0011", "0110 1000 1101", "0", "1", "0000 0001"] {
+            let bits = test_signals::bits(text);
+            let pulses = test_signals::differential_manchester(&bits, 500, 5000);
+
+            let mut decoder = DifferentialManchesterDecoder::new(500, 2000);
+            assert_eq!(decode(&pulses, &mut decoder), [bits], "{}", text);
+        }
+    }
+
+    #[test]
+    fn differential_manchester_invalid_timing() {
+        let bits = test_signals::bits("1111 0101 0011");
+        let mut pulses = vec![(500, 1000), (500, 1000), (500, 1500)];
+        pulses.extend(test_signals::differential_manchester(&bits, 500, 5000));
+
+        let mut decoder = DifferentialManchesterDecoder::new(500, 2000);
+        assert_eq!(decode(&pulses, &mut decoder), [bits]);
+    }
+}
@@ -1,7 +1,33 @@
+pub mod button_events;
 pub mod button_task;
+pub mod calibration;
+pub mod cc1101;
+pub mod click_detector;
+pub mod code_learner;
+pub mod code_table;
+pub mod connection;
+pub mod decoder;
+pub mod devices;
+pub mod entities;
+pub mod ev1527;
+pub mod fixed_timing;
+pub mod flex;
+pub mod flipper;
+pub mod intertechno;
+pub mod line_code;
 pub mod mqtt;
 pub mod parser;
 pub mod persistency;
+pub mod pulse_capture;
+pub mod rc_switch;
+pub mod receiver_control;
+pub mod receivers;
 pub mod remote_receiver;
+pub mod rolling_code;
+pub mod signal_quality;
 pub mod terminal;
+pub mod transmitter;
+pub mod tri_state;
+pub mod unknown_codes;
 pub mod usb_communication;
+pub mod weather;
@@ -1,4 +1,7 @@
-//! Sets up and handles the MQTT connection.
+//! Sets up and supervises the MQTT connection.
+//! A supervisor task owns the client. It re-establishes Wi-Fi, DHCP, TCP and the broker connection
+//! with backoff, whenever one of them is lost. Messages to publish are handed over through a queue.
+//! Send commands are received on a subscribed topic and queued for the transmitter.
 
 use cfg_if::cfg_if;
 
@@ -7,32 +10,76 @@ cfg_if! {
         use defmt::{info, error};
         use embassy_executor::{task, Spawner};
         use embassy_rp::gpio;
-        use embassy_time::{Duration, Timer};
-        use embassy_net;
+        use embassy_time::{Duration, Instant, Timer, with_timeout};
+        use embassy_futures::select::{select3, Either3};
+        use embassy_net::{self, Stack};
+        use embassy_net::tcp::TcpSocket;
         use embassy_rp::clocks::RoscRng;
-        use embassy_rp::pio::Pio;
+        use embassy_rp::pio::{Common, Irq, StateMachine};
         use embassy_rp::peripherals::{DMA_CH1, PIO1, PIN_23, PIN_24, PIN_25, PIN_29};
         use rand_core::RngCore; // Don't know why this is needed. Is it because the 'use' is missing in embassy_rp::clocks::RoscRng?
         use static_cell::StaticCell;
         use cyw43_pio::DEFAULT_CLOCK_DIVIDER;
         use cyw43::JoinOptions;
         use core::net::Ipv4Addr;
-        use heapless::String;
-        use rust_mqtt::client::client::MqttClient;
+        use heapless::{String, Vec};
+        use embedded_io_async::{ErrorType, Read, Write};
+        use rust_mqtt::client::raw_client::{Event, RawMqttClient};
+        use rust_mqtt::client::client_config::{ClientConfig, MqttVersion};
+        use rust_mqtt::packet::v5::publish_packet::QualityOfService;
+        use rust_mqtt::packet::v5::reason_codes::ReasonCode;
         use rust_mqtt::utils::rng_generator::CountingRng;
+        use embassy_sync::channel::Channel;
         use embassy_sync::mutex::Mutex;
-        use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+        use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+
+        use crate::modules::persistency::{self, Persistency, PersistencyTrait};
+        use crate::modules::code_table::CodeTable;
+        use crate::modules::connection::{Backoff, ConnectionState, ConnectionStatus};
+        use crate::modules::receiver_control::{ReceiverControl, ReceiverControlTrait};
+        use crate::modules::remote_receiver::ReceiverMode;
+        use crate::modules::transmitter;
+
+        // The payload is a code table name or "<hex code> [<bit count> [<protocol>]]", like the terminal command send.
+        const SEND_TOPIC: &str = "433MHz_to_MQTT_send";
+
+        const LINK_CHECK_INTERVAL: Duration = Duration::from_millis(400);
+        const PING_INTERVAL: Duration = Duration::from_secs(30);
+        const DHCP_TIMEOUT: Duration = Duration::from_secs(30);
+        const SOCKET_TIMEOUT: Duration = Duration::from_secs(100);
+
+        // Labelled payloads with signal quality take up to 256 bytes, the topic comes on top.
+        const PACKET_SIZE: usize = 320;
+        const MAX_TOPIC_LENGTH: usize = 64;
+        const MAX_PAYLOAD_LENGTH: usize = 256;
+        const OUTGOING_QUEUE_SIZE: usize = 8;
+        const COMMAND_QUEUE_SIZE: usize = 2;
+
+        struct Message {
+            topic: String<MAX_TOPIC_LENGTH>,
+            payload: Vec<u8, MAX_PAYLOAD_LENGTH>,
+            retain: bool,
+        }
+
+        type Outgoing = Channel<CriticalSectionRawMutex, Message, OUTGOING_QUEUE_SIZE>;
+        type Commands = Channel<CriticalSectionRawMutex, String<64>, COMMAND_QUEUE_SIZE>;
 
-        use crate::modules::persistency::{self, PersistencyTrait};
+        // The client reads and writes through the mutex, in between serve() waits on the socket for the next packet.
+        type SharedSocket<'a> = Mutex<NoopRawMutex, TcpSocket<'a>>;
 
-        type MqttClientMutexed = Mutex<CriticalSectionRawMutex, MqttClient<'static, embassy_net::tcp::TcpSocket<'static>, 5, CountingRng>>;
+        struct Transport<'s, 'a>(&'s SharedSocket<'a>);
+
+        type Client<'b, 's, 'a> = RawMqttClient<'b, Transport<'s, 'a>, 5, CountingRng>;
 
         pub struct WifiHw {
             pub pin_23: PIN_23,
             pub pin_24: PIN_24,
             pub pin_25: PIN_25,
             pub pin_29: PIN_29,
-            pub pio_1: Pio<'static, PIO1>,
+            // The other state machines of PIO1 are left for the transmitter.
+            pub pio_1_common: Common<'static, PIO1>,
+            pub pio_1_sm0: StateMachine<'static, PIO1, 0>,
+            pub pio_1_irq0: Irq<'static, PIO1, 0>,
             pub dma_ch1: DMA_CH1,
         }
 
@@ -46,6 +93,26 @@ cfg_if! {
             mqtt_broker_username: String<MQTT_BROKER_USERNAME_LENGTH>,
             mqtt_broker_password: String<MQTT_BROKER_PASSWORD_LENGTH>,
         }
+
+        // The client is created anew for every connection, so the buffers are borrowed again each time.
+        struct Buffers {
+            rx: &'static mut [u8; 4096],
+            tx: &'static mut [u8; 4096],
+            recv: &'static mut [u8; PACKET_SIZE],
+            write: &'static mut [u8; PACKET_SIZE],
+        }
+
+        // Everything the supervisor needs to establish the connection again.
+        struct Link {
+            control: cyw43::Control<'static>,
+            stack: Stack<'static>,
+            credentials: Credentials,
+            remote_endpoint: (Ipv4Addr, u16),
+            buffers: Buffers,
+            outgoing: &'static Outgoing,
+            commands: &'static Commands,
+            status: &'static ConnectionStatus,
+        }
     }
 }
 
@@ -53,12 +120,18 @@ use core::str;
 
 pub struct MQTT {
     #[cfg(not(test))]
-    client_mutexed: &'static MqttClientMutexed,
+    outgoing: &'static Outgoing,
+    #[cfg(not(test))]
+    commands: &'static Commands,
+    #[cfg(not(test))]
+    status: &'static ConnectionStatus,
 }
 
 impl MQTT {
+    /// Returns as soon as the connection is being established, None if the credentials are not usable.
+    /// The supervisor keeps the status up to date, for the modules that want to know whether messages get through.
     #[cfg(not(test))]
-    pub async fn new<P>(persistency: &'static P, mut hw: WifiHw, spawner: Spawner) -> Option<Self>
+    pub async fn new<P>(persistency: &'static P, mut hw: WifiHw, status: &'static ConnectionStatus, spawner: Spawner) -> Option<Self>
     where P: PersistencyTrait,
     {
         let fw = include_bytes!("../../../cyw43-firmware/43439A0.bin");
@@ -68,10 +141,10 @@ impl MQTT {
         let cs = gpio::Output::new(hw.pin_25, gpio::Level::High);
 
         let spi = cyw43_pio::PioSpi::new(
-            &mut hw.pio_1.common,
-            hw.pio_1.sm0,
+            &mut hw.pio_1_common,
+            hw.pio_1_sm0,
             DEFAULT_CLOCK_DIVIDER,
-            hw.pio_1.irq0,
+            hw.pio_1_irq0,
             cs,
             hw.pin_24,
             hw.pin_29,
@@ -106,94 +179,46 @@ impl MQTT {
             return None;
         }
 
-        static MQTT_BROKER_USERNAME: StaticCell<String<MQTT_BROKER_USERNAME_LENGTH>> = StaticCell::new();
-        let mqtt_broker_username = MQTT_BROKER_USERNAME.init(credentials.mqtt_broker_username.clone());
-        static MQTT_BROKER_PASSWORD: StaticCell<String<MQTT_BROKER_PASSWORD_LENGTH>> = StaticCell::new();
-        let mqtt_broker_password = MQTT_BROKER_PASSWORD.init(credentials.mqtt_broker_password.clone());
-
         if credentials.wifi_password.len() < 8 {
             error!("WIFI Password is too short: {}", credentials.wifi_password);
             return None;
         }
 
-        loop {
-            match control.join(credentials.wifi_ssid.as_str(), JoinOptions::new(credentials.wifi_password.as_bytes())).await {
-                Ok(_) => {
-                    info!("join successful");
-                    break
-                },
-                Err(err) => info!("join failed with status={}", err.status),
-            }
-        }
-
-        info!("waiting for DHCP...");
-        while !network_stack.is_config_up() {
-            Timer::after_millis(100).await;
-        }
-        info!("DHCP is now up!");
-
-        let (ip0, ip1, ip2, ip3) = Self::parse_ip(&credentials.mqtt_host_ip).unwrap();
+        let (ip0, ip1, ip2, ip3) = Self::parse_ip(&credentials.mqtt_host_ip)?;
         let address = Ipv4Addr::new(ip0, ip1, ip2, ip3);
-        let remote_endpoint = (address, 1883);
 
         //TODO: The following buffer sizes have mostly been taken from examples. There might be better values.
         static RX_BUFFER: StaticCell<[u8; 4096]> = StaticCell::new();
-        let rx_buffer = RX_BUFFER.init([0; 4096]);
         static TX_BUFFER: StaticCell<[u8; 4096]> = StaticCell::new();
-        let tx_buffer = TX_BUFFER.init([0; 4096]);
-        let mut socket = embassy_net::tcp::TcpSocket::new(network_stack, rx_buffer, tx_buffer);
-        socket.set_timeout(Some(embassy_time::Duration::from_secs(100)));
-        static RECV_BUFFER: StaticCell<[u8; 150]> = StaticCell::new(); //was 80
-        let recv_buffer = RECV_BUFFER.init([0; 150]);
-        static WRITE_BUFFER: StaticCell<[u8; 150]> = StaticCell::new(); //was 80
-        let write_buffer = WRITE_BUFFER.init([0; 150]);
-
-        let connection = socket.connect(remote_endpoint).await;
-        if let Err(e) = connection {
-            error!("connect error: {:?}", e);
-        }
-        info!("connected to broker!");
-
-        let mut config = rust_mqtt::client::client_config::ClientConfig::new(
-            rust_mqtt::client::client_config::MqttVersion::MQTTv5,
-            rust_mqtt::utils::rng_generator::CountingRng(20000),
-        );
-        config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1);
-        config.add_client_id("433MHz_to_MQTT");
-        config.add_username(mqtt_broker_username);
-        config.add_password(mqtt_broker_password);
-        config.max_packet_size = 150; //was 100
-
-        let client = MqttClient::<_, 5, _>::new(
-            socket,
-            write_buffer,
-            150,
-            recv_buffer,
-            150,
-            config,
-        );
-        static CLIENT_MUTEXED: StaticCell<MqttClientMutexed> = StaticCell::new();
-        let client_mutexed = CLIENT_MUTEXED.init(Mutex::new(client));
-
-        loop {
-            let mut client = client_mutexed.lock().await;
-            match client.connect_to_broker().await {
-                Ok(()) => {
-                    info!("Connected to broker 555");
-                    break;
-                }
-                Err(mqtt_error) => match mqtt_error {
-                    rust_mqtt::packet::v5::reason_codes::ReasonCode::NetworkError => error!("MQTT Network Error"),
-                    _ => error!("Other MQTT Error: {:?}", mqtt_error),
-                },
-            }
-            Timer::after(Duration::from_millis(2000)).await;
-        }
+        static RECV_BUFFER: StaticCell<[u8; PACKET_SIZE]> = StaticCell::new();
+        static WRITE_BUFFER: StaticCell<[u8; PACKET_SIZE]> = StaticCell::new();
+        let buffers = Buffers {
+            rx: RX_BUFFER.init([0; 4096]),
+            tx: TX_BUFFER.init([0; 4096]),
+            recv: RECV_BUFFER.init([0; PACKET_SIZE]),
+            write: WRITE_BUFFER.init([0; PACKET_SIZE]),
+        };
 
-        spawner.spawn(ping_task(client_mutexed)).unwrap();
+        static OUTGOING: StaticCell<Outgoing> = StaticCell::new();
+        let outgoing = OUTGOING.init(Channel::new());
+        static COMMANDS: StaticCell<Commands> = StaticCell::new();
+        let commands = COMMANDS.init(Channel::new());
+
+        spawner.spawn(supervisor_task(Link {
+            control,
+            stack: network_stack,
+            credentials,
+            remote_endpoint: (address, 1883),
+            buffers,
+            outgoing,
+            commands,
+            status,
+        })).unwrap();
 
         Some(Self {
-            client_mutexed,
+            outgoing,
+            commands,
+            status,
         })
     }
 
@@ -264,14 +289,65 @@ impl MQTT {
         Some((ip[0], ip[1], ip[2], ip[3]))
     }
 
+    /// Queues the codes received on the send topic, the supervisor subscribes to it on every connection.
+    #[cfg(not(test))]
+    pub fn receive_commands(&self, spawner: Spawner, persistency: &'static Persistency, receiver_control: &'static ReceiverControl) {
+        spawner.spawn(command_task(self.commands, persistency, receiver_control)).unwrap();
+    }
+
     #[cfg(not(test))]
     pub async fn send_message(&mut self, payload: &[u8]) {
-        let mut client = self.client_mutexed.lock().await;
-        let result = client.send_message("433MHz_to_MQTT_button", payload, rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1, false).await;
-        match result {
-            Ok(()) => info!("message sent"),
-            Err(mqtt_error) => info!("message NOT sent: {:?}", mqtt_error),
+        self.send_message_to("433MHz_to_MQTT_button", payload).await;
+    }
+
+    #[cfg(not(test))]
+    pub async fn send_message_to(&mut self, topic: &str, payload: &[u8]) {
+        self.publish(topic, payload, false).await;
+    }
+
+    /// The broker keeps the message and hands it to every new subscriber, used for states.
+    #[cfg(not(test))]
+    pub async fn send_retained_message_to(&mut self, topic: &str, payload: &[u8]) {
+        self.publish(topic, payload, true).await;
+    }
+
+    // Messages are not kept while the connection is down, a button press published minutes later would surprise.
+    #[cfg(not(test))]
+    async fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        if !self.status.is_connected() {
+            info!("message NOT sent: {}", self.status.state().as_str());
+            return;
         }
+        let (Ok(topic), Ok(payload)) = (String::try_from(topic), Vec::from_slice(payload)) else {
+            error!("message NOT sent: too long");
+            return;
+        };
+        if self.outgoing.try_send(Message { topic, payload, retain }).is_err() {
+            info!("message NOT sent: queue is full");
+        }
+    }
+}
+
+#[cfg(not(test))]
+impl ErrorType for Transport<'_, '_> {
+    type Error = embassy_net::tcp::Error;
+}
+
+#[cfg(not(test))]
+impl Read for Transport<'_, '_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.0.lock().await.read(buf).await
+    }
+}
+
+#[cfg(not(test))]
+impl Write for Transport<'_, '_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0.lock().await.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.lock().await.flush().await
     }
 }
 
@@ -287,17 +363,193 @@ async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'sta
     runner.run().await
 }
 
+/// Establishes the layers of the connection from the lowest one that is lost and serves the connection until it fails.
+/// Failed attempts are repeated with exponential backoff, which starts over once the broker is connected.
 #[cfg(not(test))]
 #[task]
-async fn ping_task(client: &'static MqttClientMutexed) -> ! {
+async fn supervisor_task(link: Link) -> ! {
+    let Link { mut control, stack, credentials, remote_endpoint, buffers, outgoing, commands, status } = link;
+    let mut backoff = Backoff::new();
+    let mut rng = RoscRng;
+
     loop {
-        Timer::after(Duration::from_secs(30)).await;
+        if !stack.is_link_up() {
+            status.set(ConnectionState::Joining);
+            if let Err(err) = control.join(credentials.wifi_ssid.as_str(), JoinOptions::new(credentials.wifi_password.as_bytes())).await {
+                info!("join failed with status={}", err.status);
+                backoff.wait(rng.next_u32()).await;
+                continue;
+            }
+            info!("join successful");
+        }
 
-        let mut client = client.lock().await;
-        let result = client.send_ping().await;
-        match result {
-            Ok(()) => info!("ping sent"),
-            Err(mqtt_error) => info!("ping NOT sent: {:?}", mqtt_error),
+        status.set(ConnectionState::WaitingForDhcp);
+        let dhcp = async {
+            while !stack.is_config_up() {
+                Timer::after_millis(100).await;
+            }
+        };
+        if with_timeout(DHCP_TIMEOUT, dhcp).await.is_err() {
+            error!("no DHCP lease received");
+            // The access point is joined again, which starts DHCP over.
+            control.leave().await;
+            backoff.wait(rng.next_u32()).await;
+            continue;
+        }
+
+        status.set(ConnectionState::ConnectingTcp);
+        let mut socket = TcpSocket::new(stack, &mut buffers.rx[..], &mut buffers.tx[..]);
+        socket.set_timeout(Some(SOCKET_TIMEOUT));
+        if let Err(e) = socket.connect(remote_endpoint).await {
+            error!("connect error: {:?}", e);
+            backoff.wait(rng.next_u32()).await;
+            continue;
+        }
+
+        status.set(ConnectionState::ConnectingBroker);
+        let socket = SharedSocket::new(socket);
+        let mut client = RawMqttClient::<_, 5, _>::new(
+            Transport(&socket),
+            &mut buffers.write[..],
+            PACKET_SIZE,
+            &mut buffers.recv[..],
+            PACKET_SIZE,
+            client_config(&credentials),
+        );
+        if let Err(mqtt_error) = connect_to_broker(&mut client).await {
+            error!("connecting to broker failed: {:?}", mqtt_error);
+            backoff.wait(rng.next_u32()).await;
+            continue;
+        }
+        // Every connection starts a new session, so the subscription is made again.
+        match subscribe(&mut client).await {
+            Ok(()) => info!("subscribed to {}", SEND_TOPIC),
+            Err(mqtt_error) => error!("subscription to {} failed: {:?}", SEND_TOPIC, mqtt_error),
+        }
+        status.set(ConnectionState::Connected);
+        backoff.reset();
+
+        let reason = serve(&mut client, &socket, stack, outgoing, commands).await;
+        error!("connection lost: {}", reason);
+        backoff.wait(rng.next_u32()).await;
+    }
+}
+
+#[cfg(not(test))]
+fn client_config(credentials: &Credentials) -> ClientConfig<'_, 5, CountingRng> {
+    let mut config = ClientConfig::new(MqttVersion::MQTTv5, CountingRng(20000));
+    config.add_max_subscribe_qos(QualityOfService::QoS1);
+    config.add_client_id("433MHz_to_MQTT");
+    config.add_username(&credentials.mqtt_broker_username);
+    config.add_password(&credentials.mqtt_broker_password);
+    config.max_packet_size = PACKET_SIZE as u32;
+    config
+}
+
+// Only network errors mean the connection is lost, other errors concern a single message.
+#[cfg(not(test))]
+fn connection_lost(mqtt_error: &ReasonCode) -> bool {
+    matches!(mqtt_error, ReasonCode::NetworkError)
+}
+
+// The raw client only sends the packets, the answers are read by the caller.
+// Nothing else is subscribed yet, so the answer is the next packet.
+#[cfg(not(test))]
+async fn connect_to_broker(client: &mut Client<'_, '_, '_>) -> Result<(), ReasonCode> {
+    client.connect_to_broker().await?;
+    match client.poll::<1>().await? {
+        Event::Connack => Ok(()),
+        _ => Err(ReasonCode::ImplementationSpecificError),
+    }
+}
+
+#[cfg(not(test))]
+async fn subscribe(client: &mut Client<'_, '_, '_>) -> Result<(), ReasonCode> {
+    let topics: Vec<&str, 1> = Vec::from_slice(&[SEND_TOPIC]).unwrap();
+    client.subscribe_to_topics(&topics).await?;
+    match client.poll::<1>().await? {
+        Event::Suback(_) => Ok(()),
+        _ => Err(ReasonCode::ImplementationSpecificError),
+    }
+}
+
+// Publishes the queued messages, receives send commands and pings the broker, until a layer of the connection fails.
+// All packets from the broker are read in one place, so a send command that arrives while a publication or a ping
+// waits for its answer is not taken as the wrong answer. Only waiting for a packet is cancelled, never reading one,
+// which would leave the rest of the packet in the stream.
+#[cfg(not(test))]
+async fn serve(client: &mut Client<'_, '_, '_>, socket: &SharedSocket<'_>, stack: Stack<'static>, outgoing: &Outgoing, commands: &Commands) -> &'static str {
+    let mut next_ping = Instant::now() + PING_INTERVAL;
+    let mut ping_answered = true;
+    loop {
+        if !stack.is_link_up() {
+            return "Wi-Fi link is down";
+        }
+        if !stack.is_config_up() {
+            return "DHCP lease is lost";
+        }
+
+        let packet_ready = async { socket.lock().await.wait_read_ready().await };
+        match select3(outgoing.receive(), packet_ready, Timer::after(LINK_CHECK_INTERVAL)).await {
+            // The broker acknowledges the message with a packet of its own.
+            Either3::First(message) => match client.send_message(&message.topic, &message.payload, QualityOfService::QoS1, message.retain).await {
+                Ok(_) => (),
+                Err(mqtt_error) if connection_lost(&mqtt_error) => return "publishing failed",
+                Err(mqtt_error) => info!("message NOT sent: {:?}", mqtt_error),
+            },
+            Either3::Second(()) => match client.poll::<1>().await {
+                Ok(Event::Message(SEND_TOPIC, payload)) => match str::from_utf8(payload).ok().and_then(|text| String::try_from(text).ok()) {
+                    Some(parameters) => {
+                        if commands.try_send(parameters).is_err() {
+                            error!("send command dropped, too many are queued");
+                        }
+                    },
+                    None => error!("send command is not valid"),
+                },
+                Ok(Event::Message(topic, _)) => info!("message on unexpected topic {}", topic),
+                Ok(Event::Puback(_)) => info!("message sent"),
+                Ok(Event::Pingresp) => ping_answered = true,
+                Ok(Event::Disconnect(_)) => return "broker closed the connection",
+                Ok(_) => (),
+                Err(mqtt_error) if connection_lost(&mqtt_error) => return "receiving failed",
+                Err(mqtt_error) => info!("message NOT received: {:?}", mqtt_error),
+            },
+            Either3::Third(()) => {
+                if Instant::now() >= next_ping {
+                    if !ping_answered {
+                        return "broker does not answer pings";
+                    }
+                    next_ping = Instant::now() + PING_INTERVAL;
+                    match client.send_ping().await {
+                        Ok(()) => {
+                            info!("ping sent");
+                            ping_answered = false;
+                        },
+                        Err(mqtt_error) => {
+                            info!("ping NOT sent: {:?}", mqtt_error);
+                            return "ping failed";
+                        },
+                    }
+                }
+            },
+        }
+    }
+}
+
+#[cfg(not(test))]
+#[task]
+async fn command_task(commands: &'static Commands, persistency: &'static Persistency, receiver_control: &'static ReceiverControl) -> ! {
+    loop {
+        let parameters = commands.receive().await;
+
+        let code_table = CodeTable::load(persistency).await.unwrap_or_else(|_| CodeTable::new());
+        let mode = ReceiverMode::load(persistency).await;
+        let queued = match transmitter::parse_send_parameters(parameters.as_bytes(), &code_table, mode) {
+            Ok(frame) => receiver_control.queue_transmission(frame).await,
+            Err(msg) => Err(msg),
+        };
+        if let Err(msg) = queued {
+            error!("send command {} failed: {}", parameters.as_str(), msg);
         }
     }
 }
@@ -1,16 +1,36 @@
 //! Parses received messages, forwards them accordingly and returns the answer.
 
 use crate::modules::persistency::{ValueId, PersistencyTrait};
+use crate::modules::code_table::{CodeTable, MAX_NAME_LENGTH};
+use crate::modules::entities::{EntityKind, EntityTable};
+use crate::modules::receivers::ReceiverTable;
+use crate::modules::receiver_control::ReceiverControlTrait;
+use crate::modules::remote_receiver::ReceiverMode;
+use crate::modules::fixed_timing::FixedTimingSettings;
+use crate::modules::flex::FlexTable;
+use crate::modules::transmitter::{self, TX_PINS};
+use crate::modules::cc1101::{self, Modulation, Radio, RadioSettings};
+use crate::modules::flipper::{self, SubImport};
 
-pub struct Parser<'a, P: PersistencyTrait> {
+use core::fmt::Write;
+use heapless::String;
+
+/// Size of the answer buffer of the terminal, longer answers are cut off.
+pub const MAX_ANSWER_LENGTH: usize = 2048;
+
+pub struct Parser<'a, P: PersistencyTrait, R: ReceiverControlTrait> {
     persistency: &'a P,
+    receiver_control: &'a R,
+    // A .sub file being pasted and the name its code gets, its lines are not taken as commands.
+    sub_import: Option<(String<MAX_NAME_LENGTH>, SubImport)>,
 }
 
-impl <'a, P> Parser<'a, P>
+impl <'a, P, R> Parser<'a, P, R>
 where P: PersistencyTrait,
+      R: ReceiverControlTrait,
 {
-    pub fn new(persistency: &'a P) -> Self {
-        Self { persistency }
+    pub fn new(persistency: &'a P, receiver_control: &'a R) -> Self {
+        Self { persistency, receiver_control, sub_import: None }
     }
 
     async fn parse_store_command(&mut self, parameters: &[u8]) -> Result<(), &'static str> {
@@ -19,37 +39,149 @@ where P: PersistencyTrait,
         const MQTT_HOST_IP: &[u8] = b"mqtt_host_ip ";
         const MQTT_BROKER_USERNAME: &[u8] = b"mqtt_broker_username ";
         const MQTT_BROKER_PASSWORD: &[u8] = b"mqtt_broker_password ";
+        const RECEIVER_MODE: &[u8] = b"receiver_mode ";
+        const RELEASE_GAP_MS: &[u8] = b"release_gap_ms ";
+        const CLICK_WINDOW_MS: &[u8] = b"click_window_ms ";
+        const CONFIRM_FRAMES: &[u8] = b"confirm_frames ";
+        const CONFIRM_GAP_MS: &[u8] = b"confirm_gap_ms ";
+        const LOCKOUT_MS: &[u8] = b"lockout_ms ";
+        const CLOCK_DIVIDER: &[u8] = b"clock_divider ";
+        const SYNC_GAP_US: &[u8] = b"sync_gap_us ";
+        const SAMPLE_DELAY_US: &[u8] = b"sample_delay_us ";
+        const GLITCH_FILTER_US: &[u8] = b"glitch_filter_us ";
+        const TX_PIN: &[u8] = b"tx_pin ";
+        const TX_REPEATS: &[u8] = b"tx_repeats ";
+        const RADIO: &[u8] = b"radio ";
+        const RADIO_FREQUENCY_KHZ: &[u8] = b"radio_frequency_khz ";
+        const RADIO_MODULATION: &[u8] = b"radio_modulation ";
+        const SIGNAL_QUALITY: &[u8] = b"signal_quality ";
 
         if parameters.starts_with(WIFI_SSID) {
             let value = &parameters[WIFI_SSID.len()..];
-            self.persistency.store(value, ValueId::WifiSsid).await;
-            Ok(())
+            self.persistency.store(value, ValueId::WifiSsid).await
         }
         else if parameters.starts_with(WIFI_PASSWORD) {
             let value = &parameters[WIFI_PASSWORD.len()..];
-            self.persistency.store(value, ValueId::WifiPassword).await;
-            Ok(())
+            self.persistency.store(value, ValueId::WifiPassword).await
         }
         else if parameters.starts_with(MQTT_HOST_IP) {
             let value = &parameters[MQTT_HOST_IP.len()..];
-            self.persistency.store(value, ValueId::MqttHostIp).await;
-            Ok(())
+            self.persistency.store(value, ValueId::MqttHostIp).await
         }
         else if parameters.starts_with(MQTT_BROKER_USERNAME) {
             let value = &parameters[MQTT_BROKER_USERNAME.len()..];
-            self.persistency.store(value, ValueId::MqttBrokerUsername).await;
-            Ok(())
+            self.persistency.store(value, ValueId::MqttBrokerUsername).await
         }
         else if parameters.starts_with(MQTT_BROKER_PASSWORD) {
             let value = &parameters[MQTT_BROKER_PASSWORD.len()..];
-            self.persistency.store(value, ValueId::MqttBrokerPassword).await;
-            Ok(())
+            self.persistency.store(value, ValueId::MqttBrokerPassword).await
+        }
+        else if parameters.starts_with(RECEIVER_MODE) {
+            let value = &parameters[RECEIVER_MODE.len()..];
+            if ReceiverMode::from_bytes(value).is_none() {
+                return Err("receiver_mode must be 'fixed' or 'pulse'");
+            }
+            self.persistency.store(value, ValueId::ReceiverMode).await
+        }
+        else if parameters.starts_with(RELEASE_GAP_MS) {
+            let value = &parameters[RELEASE_GAP_MS.len()..];
+            Self::parse_number(value)?;
+            self.persistency.store(value, ValueId::ReleaseGapMs).await
+        }
+        else if parameters.starts_with(CLICK_WINDOW_MS) {
+            let value = &parameters[CLICK_WINDOW_MS.len()..];
+            Self::parse_number(value)?;
+            self.persistency.store(value, ValueId::ClickWindowMs).await
+        }
+        else if parameters.starts_with(CONFIRM_FRAMES) {
+            let value = &parameters[CONFIRM_FRAMES.len()..];
+            if !(1..=255).contains(&Self::parse_number(value)?) {
+                return Err("confirm_frames must be between 1 and 255");
+            }
+            self.persistency.store(value, ValueId::ConfirmFrames).await
+        }
+        else if parameters.starts_with(CONFIRM_GAP_MS) {
+            let value = &parameters[CONFIRM_GAP_MS.len()..];
+            Self::parse_number(value)?;
+            self.persistency.store(value, ValueId::ConfirmGapMs).await
+        }
+        else if parameters.starts_with(LOCKOUT_MS) {
+            let value = &parameters[LOCKOUT_MS.len()..];
+            Self::parse_number(value)?;
+            self.persistency.store(value, ValueId::LockoutMs).await
+        }
+        else if parameters.starts_with(CLOCK_DIVIDER) {
+            self.store_fixed_timing(&parameters[CLOCK_DIVIDER.len()..], ValueId::ClockDivider).await
+        }
+        else if parameters.starts_with(SYNC_GAP_US) {
+            self.store_fixed_timing(&parameters[SYNC_GAP_US.len()..], ValueId::SyncGapUs).await
+        }
+        else if parameters.starts_with(SAMPLE_DELAY_US) {
+            self.store_fixed_timing(&parameters[SAMPLE_DELAY_US.len()..], ValueId::SampleDelayUs).await
+        }
+        else if parameters.starts_with(GLITCH_FILTER_US) {
+            self.store_fixed_timing(&parameters[GLITCH_FILTER_US.len()..], ValueId::GlitchFilterUs).await
+        }
+        else if parameters.starts_with(TX_PIN) {
+            let value = &parameters[TX_PIN.len()..];
+            if !u8::try_from(Self::parse_number(value)?).is_ok_and(|pin| TX_PINS.contains(&pin)) {
+                return Err("tx_pin must be one of 16 to 22, 26 or 27");
+            }
+            self.persistency.store(value, ValueId::TxPin).await
+        }
+        else if parameters.starts_with(TX_REPEATS) {
+            let value = &parameters[TX_REPEATS.len()..];
+            if !(1..=255).contains(&Self::parse_number(value)?) {
+                return Err("tx_repeats must be between 1 and 255");
+            }
+            self.persistency.store(value, ValueId::TxRepeats).await
+        }
+        else if parameters.starts_with(RADIO) {
+            let value = &parameters[RADIO.len()..];
+            if Radio::from_bytes(value).is_none() {
+                return Err("radio must be 'module' or 'cc1101'");
+            }
+            self.persistency.store(value, ValueId::Radio).await
+        }
+        else if parameters.starts_with(RADIO_FREQUENCY_KHZ) {
+            let value = &parameters[RADIO_FREQUENCY_KHZ.len()..];
+            cc1101::frequency_word(Self::parse_number(value)?)?;
+            self.persistency.store(value, ValueId::RadioFrequencyKhz).await
+        }
+        else if parameters.starts_with(RADIO_MODULATION) {
+            let value = &parameters[RADIO_MODULATION.len()..];
+            if Modulation::from_bytes(value).is_none() {
+                return Err("radio_modulation must be 'ook' or 'fsk'");
+            }
+            self.persistency.store(value, ValueId::RadioModulation).await
+        }
+        else if parameters.starts_with(SIGNAL_QUALITY) {
+            let value = &parameters[SIGNAL_QUALITY.len()..];
+            if value != b"on" && value != b"off" {
+                return Err("signal_quality must be 'on' or 'off'");
+            }
+            self.persistency.store(value, ValueId::SignalQuality).await
         }
         else {
             Err("unknown store parameter, type 'read help' for help ('store help' not yet available)")
         }
     }
 
+    // The fixed timing settings depend on each other, so the new value is checked together with the stored ones.
+    async fn store_fixed_timing(&mut self, value: &[u8], value_id: ValueId) -> Result<(), &'static str> {
+        let number = Self::parse_number(value)?;
+        let mut settings = FixedTimingSettings::load(self.persistency).await;
+        match value_id {
+            ValueId::ClockDivider => settings.clock_divider = number,
+            ValueId::SyncGapUs => settings.sync_gap_us = number,
+            ValueId::SampleDelayUs => settings.sample_delay_us = number,
+            ValueId::GlitchFilterUs => settings.glitch_filter_us = number,
+            _ => unreachable!(),
+        }
+        settings.ticks()?;
+        self.persistency.store(value, value_id).await
+    }
+
     async fn parse_read_command(&mut self, parameters: &[u8], answer: &mut [u8]) -> Result<usize, &'static str> {
         if parameters.starts_with(b"wifi_ssid") {
             self.persistency.read(ValueId::WifiSsid, answer).await
@@ -66,6 +198,54 @@ where P: PersistencyTrait,
         else if parameters.starts_with(b"mqtt_broker_password") {
             self.persistency.read(ValueId::MqttBrokerPassword, answer).await
         }
+        else if parameters.starts_with(b"receiver_mode") {
+            self.persistency.read(ValueId::ReceiverMode, answer).await
+        }
+        else if parameters.starts_with(b"release_gap_ms") {
+            self.persistency.read(ValueId::ReleaseGapMs, answer).await
+        }
+        else if parameters.starts_with(b"click_window_ms") {
+            self.persistency.read(ValueId::ClickWindowMs, answer).await
+        }
+        else if parameters.starts_with(b"confirm_frames") {
+            self.persistency.read(ValueId::ConfirmFrames, answer).await
+        }
+        else if parameters.starts_with(b"confirm_gap_ms") {
+            self.persistency.read(ValueId::ConfirmGapMs, answer).await
+        }
+        else if parameters.starts_with(b"lockout_ms") {
+            self.persistency.read(ValueId::LockoutMs, answer).await
+        }
+        else if parameters.starts_with(b"clock_divider") {
+            self.persistency.read(ValueId::ClockDivider, answer).await
+        }
+        else if parameters.starts_with(b"sync_gap_us") {
+            self.persistency.read(ValueId::SyncGapUs, answer).await
+        }
+        else if parameters.starts_with(b"sample_delay_us") {
+            self.persistency.read(ValueId::SampleDelayUs, answer).await
+        }
+        else if parameters.starts_with(b"glitch_filter_us") {
+            self.persistency.read(ValueId::GlitchFilterUs, answer).await
+        }
+        else if parameters.starts_with(b"tx_pin") {
+            self.persistency.read(ValueId::TxPin, answer).await
+        }
+        else if parameters.starts_with(b"tx_repeats") {
+            self.persistency.read(ValueId::TxRepeats, answer).await
+        }
+        else if parameters.starts_with(b"radio_frequency_khz") {
+            self.persistency.read(ValueId::RadioFrequencyKhz, answer).await
+        }
+        else if parameters.starts_with(b"radio_modulation") {
+            self.persistency.read(ValueId::RadioModulation, answer).await
+        }
+        else if parameters.starts_with(b"radio") {
+            self.persistency.read(ValueId::Radio, answer).await
+        }
+        else if parameters.starts_with(b"signal_quality") {
+            self.persistency.read(ValueId::SignalQuality, answer).await
+        }
         else if parameters.starts_with(b"help") {
             Ok(Self::copy_to_beginning(answer, concat!(
                 "read value names:\n",
@@ -73,7 +253,23 @@ where P: PersistencyTrait,
                 "wifi_password\n",
                 "mqtt_host_ip\n",
                 "mqtt_broker_username\n",
-                "mqtt_broker_password"
+                "mqtt_broker_password\n",
+                "receiver_mode (fixed or pulse, applied after restart)\n",
+                "release_gap_ms (silence after which a button counts as released, applied after restart)\n",
+                "click_window_ms (time to wait for a further click of a button, applied after restart)\n",
+                "confirm_frames (equal frames in a row needed for a press, applied after restart)\n",
+                "confirm_gap_ms (maximum time between these frames, 0 is no limit, applied after restart)\n",
+                "lockout_ms (time after a press in which no other press is accepted, applied after restart)\n",
+                "clock_divider (of the fixed timing receiver, 12500 is 10kHz, applied after restart)\n",
+                "sync_gap_us (low time before a frame of the fixed timing receiver, applied after restart)\n",
+                "sample_delay_us (time from the rising edge to the sample of a bit, applied after restart)\n",
+                "glitch_filter_us (shorter high pulses are ignored by the fixed timing receiver, applied after restart)\n",
+                "tx_pin (gpio of the transmitter, 16 to 22, 26 or 27, applied after restart)\n",
+                "tx_repeats (how often a code is sent, applied after restart)\n",
+                "radio (module or cc1101, applied after restart)\n",
+                "radio_frequency_khz (of the cc1101, e.g. 315000, 433920 or 868300, applied after restart)\n",
+                "radio_modulation (of the cc1101, ook or fsk, applied after restart)\n",
+                "signal_quality (on adds repeats, rejected frames, jitter and rssi to the events, applied after restart)"
             ).as_bytes()))
         }
         else {
@@ -81,9 +277,325 @@ where P: PersistencyTrait,
         }
     }
 
+    async fn parse_code_command(&mut self, parameters: &[u8], answer: &mut [u8]) -> Result<usize, &'static str> {
+        const ADD: &[u8] = b"add ";
+        const REMOTE: &[u8] = b"remote ";
+        const REMOVE: &[u8] = b"remove ";
+
+        let mut code_table = CodeTable::load(self.persistency).await?;
+
+        if parameters.starts_with(ADD) {
+            let parameters = &parameters[ADD.len()..];
+            let separator = parameters.iter().position(|&b| b == b' ').ok_or("usage: code add <code> <name>")?;
+            let code = Self::parse_hex(&parameters[..separator])?;
+            let name = core::str::from_utf8(&parameters[separator + 1..]).map_err(|_| "name is not valid utf-8")?;
+            let mode = ReceiverMode::load(self.persistency).await;
+            code_table.add(transmitter::hex_code(code, mode), name)?;
+            code_table.save(self.persistency).await?;
+            self.receiver_control.tables_changed().await;
+            Ok(0)
+        }
+        else if parameters.starts_with(REMOTE) {
+            let parameters = &parameters[REMOTE.len()..];
+            let separator = parameters.iter().position(|&b| b == b' ').ok_or("usage: code remote <remote id> <name>")?;
+            let remote_id = Self::parse_hex(&parameters[..separator])?;
+            let name = core::str::from_utf8(&parameters[separator + 1..]).map_err(|_| "name is not valid utf-8")?;
+            code_table.add_remote(remote_id, name)?;
+            code_table.save(self.persistency).await?;
+            self.receiver_control.tables_changed().await;
+            Ok(0)
+        }
+        else if parameters.starts_with(REMOVE) {
+            let name = core::str::from_utf8(&parameters[REMOVE.len()..]).map_err(|_| "name is not valid utf-8")?;
+            code_table.remove(name)?;
+            code_table.save(self.persistency).await?;
+            self.receiver_control.tables_changed().await;
+            Ok(0)
+        }
+        else if parameters == b"list" {
+            code_table.list(answer)
+        }
+        else {
+            Err("unknown code command, type 'help' for help")
+        }
+    }
+
+    async fn parse_entity_command(&mut self, parameters: &[u8], answer: &mut [u8]) -> Result<usize, &'static str> {
+        const CONTACT: &[u8] = b"contact ";
+        const MOTION: &[u8] = b"motion ";
+        const REMOVE: &[u8] = b"remove ";
+        const CONTACT_USAGE: &str = "usage: entity contact <open code> <closed code> <tamper code or -> <name>";
+        const MOTION_USAGE: &str = "usage: entity motion <code> <clear seconds> <name>";
+
+        let mut entity_table = EntityTable::load(self.persistency).await?;
+
+        if parameters.starts_with(CONTACT) {
+            let (open_code, parameters) = Self::split_word(&parameters[CONTACT.len()..]).ok_or(CONTACT_USAGE)?;
+            let (closed_code, parameters) = Self::split_word(parameters).ok_or(CONTACT_USAGE)?;
+            let (tamper_code, name) = Self::split_word(parameters).ok_or(CONTACT_USAGE)?;
+            let kind = EntityKind::Contact {
+                open_code: Self::parse_hex(open_code)?,
+                closed_code: Self::parse_hex(closed_code)?,
+                tamper_code: if tamper_code == b"-" { None } else { Some(Self::parse_hex(tamper_code)?) },
+            };
+            let name = core::str::from_utf8(name).map_err(|_| "name is not valid utf-8")?;
+            entity_table.add(name, kind)?;
+            entity_table.save(self.persistency).await?;
+            self.receiver_control.tables_changed().await;
+            Ok(0)
+        }
+        else if parameters.starts_with(MOTION) {
+            let (code, parameters) = Self::split_word(&parameters[MOTION.len()..]).ok_or(MOTION_USAGE)?;
+            let (clear_s, name) = Self::split_word(parameters).ok_or(MOTION_USAGE)?;
+            let kind = EntityKind::Motion {
+                code: Self::parse_hex(code)?,
+                clear_s: Self::parse_number(clear_s)?,
+            };
+            let name = core::str::from_utf8(name).map_err(|_| "name is not valid utf-8")?;
+            entity_table.add(name, kind)?;
+            entity_table.save(self.persistency).await?;
+            self.receiver_control.tables_changed().await;
+            Ok(0)
+        }
+        else if parameters.starts_with(REMOVE) {
+            let name = core::str::from_utf8(&parameters[REMOVE.len()..]).map_err(|_| "name is not valid utf-8")?;
+            entity_table.remove(name)?;
+            entity_table.save(self.persistency).await?;
+            self.receiver_control.tables_changed().await;
+            Ok(0)
+        }
+        else if parameters == b"list" {
+            entity_table.list(answer)
+        }
+        else {
+            Err("unknown entity command, type 'help' for help")
+        }
+    }
+
+    async fn parse_receiver_command(&mut self, parameters: &[u8], answer: &mut [u8]) -> Result<usize, &'static str> {
+        const ADD: &[u8] = b"add ";
+        const REMOVE: &[u8] = b"remove ";
+        const ADD_USAGE: &str = "usage: receiver add <pin> <fixed or pulse> <label>";
+
+        let mut receiver_table = ReceiverTable::load(self.persistency).await?;
+
+        if parameters.starts_with(ADD) {
+            let (pin, parameters) = Self::split_word(&parameters[ADD.len()..]).ok_or(ADD_USAGE)?;
+            let (mode, label) = Self::split_word(parameters).ok_or(ADD_USAGE)?;
+            let pin = u8::try_from(Self::parse_number(pin)?).map_err(|_| "pin must be one of 2 to 11 or 28")?;
+            let mode = ReceiverMode::from_bytes(mode).ok_or("mode must be 'fixed' or 'pulse'")?;
+            let label = core::str::from_utf8(label).map_err(|_| "label is not valid utf-8")?;
+            receiver_table.add(pin, mode, label)?;
+            receiver_table.save(self.persistency).await?;
+            Ok(0)
+        }
+        else if parameters.starts_with(REMOVE) {
+            let label = core::str::from_utf8(&parameters[REMOVE.len()..]).map_err(|_| "label is not valid utf-8")?;
+            receiver_table.remove(label)?;
+            receiver_table.save(self.persistency).await?;
+            Ok(0)
+        }
+        else if parameters == b"list" {
+            receiver_table.list(answer)
+        }
+        else {
+            Err("unknown receiver command, type 'help' for help")
+        }
+    }
+
+    async fn parse_flex_command(&mut self, parameters: &[u8], answer: &mut [u8]) -> Result<usize, &'static str> {
+        const ADD: &[u8] = b"add ";
+        const REMOVE: &[u8] = b"remove ";
+
+        let mut flex_table = FlexTable::load(self.persistency).await?;
+
+        if parameters.starts_with(ADD) {
+            let spec = core::str::from_utf8(&parameters[ADD.len()..]).map_err(|_| "flex specification is not valid utf-8")?;
+            flex_table.add(spec)?;
+            flex_table.save(self.persistency).await?;
+            Ok(0)
+        }
+        else if parameters.starts_with(REMOVE) {
+            let name = core::str::from_utf8(&parameters[REMOVE.len()..]).map_err(|_| "name is not valid utf-8")?;
+            flex_table.remove(name)?;
+            flex_table.save(self.persistency).await?;
+            Ok(0)
+        }
+        else if parameters == b"list" {
+            flex_table.list(answer)
+        }
+        else {
+            Err("unknown flex command, type 'help' for help")
+        }
+    }
+
+    async fn parse_sub_command(&mut self, parameters: &[u8], answer: &mut [u8]) -> Result<usize, &'static str> {
+        const EXPORT: &[u8] = b"export ";
+        const IMPORT: &[u8] = b"import ";
+
+        if parameters.starts_with(EXPORT) {
+            let code_table = CodeTable::load(self.persistency).await?;
+            let mode = ReceiverMode::load(self.persistency).await;
+            let frame = transmitter::parse_send_parameters(&parameters[EXPORT.len()..], &code_table, mode)?;
+            let frequency_khz = RadioSettings::load(self.persistency).await.frequency_khz;
+            flipper::export(&frame, frequency_khz * 1000, answer)
+        }
+        else if parameters.starts_with(IMPORT) {
+            let name = core::str::from_utf8(&parameters[IMPORT.len()..]).map_err(|_| "name is not valid utf-8")?;
+            if name.is_empty() {
+                return Err("name must not be empty");
+            }
+            if CodeTable::load(self.persistency).await?.contains_name(name) {
+                return Err("name is already in the table");
+            }
+            let name = String::try_from(name).map_err(|_| "name is too long")?;
+            self.sub_import = Some((name, SubImport::new()));
+            Ok(Self::copy_to_beginning(answer, b"paste the .sub file and end it with an empty line"))
+        }
+        else {
+            Err("unknown sub command, type 'help' for help")
+        }
+    }
+
+    // Takes a line of the .sub file being pasted, the empty line at the end adds its code to the code table.
+    async fn parse_sub_line(&mut self, msg: &[u8], answer: &mut [u8]) -> Result<usize, &'static str> {
+        // An invalid line ends the import.
+        let Some((name, mut sub_import)) = self.sub_import.take() else {
+            return Err("no .sub file is being imported");
+        };
+        if !msg.is_empty() {
+            let line = core::str::from_utf8(msg).map_err(|_| "line is not valid utf-8, import aborted")?;
+            sub_import.add_line(line)?;
+            self.sub_import = Some((name, sub_import));
+            return Ok(0);
+        }
+
+        let frame = sub_import.finish()?;
+        let mode = ReceiverMode::load(self.persistency).await;
+        let code = transmitter::received_code(&frame, mode)?;
+        let mut code_table = CodeTable::load(self.persistency).await?;
+        if code_table.lookup(&code).is_some() {
+            return Err("imported code is already in the table, type 'code list' to see it");
+        }
+        code_table.add(code, &name)?;
+        code_table.save(self.persistency).await?;
+        self.receiver_control.tables_changed().await;
+
+        let mut text: String<96> = String::new();
+        write!(text, "imported code {}, send it with 'send {}'", code, name).unwrap();
+        Ok(Self::copy_to_beginning(answer, text.as_bytes()))
+    }
+
+    // Splits off the text up to the first space.
+    fn split_word(text: &[u8]) -> Option<(&[u8], &[u8])> {
+        let separator = text.iter().position(|&b| b == b' ')?;
+        Some((&text[..separator], &text[separator + 1..]))
+    }
+
+    async fn parse_learn_command(&mut self, name: &[u8], answer: &mut [u8]) -> Result<usize, &'static str> {
+        let name = core::str::from_utf8(name).map_err(|_| "name is not valid utf-8")?;
+
+        let mut code_table = CodeTable::load(self.persistency).await?;
+        if code_table.contains_name(name) {
+            return Err("name is already in the table");
+        }
+
+        let code = self.receiver_control.next_code().await?;
+        if code_table.lookup(&code).is_some() {
+            return Err("received code is already in the table, type 'code list' to see it");
+        }
+        code_table.add(code, name)?;
+        code_table.save(self.persistency).await?;
+        self.receiver_control.tables_changed().await;
+
+        let mut text: String<64> = String::new();
+        write!(text, "learned code {}", code).unwrap();
+        Ok(Self::copy_to_beginning(answer, text.as_bytes()))
+    }
+
+    async fn parse_send_command(&mut self, parameters: &[u8], answer: &mut [u8]) -> Result<usize, &'static str> {
+        let code_table = CodeTable::load(self.persistency).await?;
+        let mode = ReceiverMode::load(self.persistency).await;
+        let frame = transmitter::parse_send_parameters(parameters, &code_table, mode)?;
+        self.receiver_control.queue_transmission(frame).await?;
+
+        let mut text: String<64> = String::new();
+        write!(text, "sending code 0x{:08X}, {} bits, protocol {}", frame.value, frame.bit_count, frame.protocol).unwrap();
+        Ok(Self::copy_to_beginning(answer, text.as_bytes()))
+    }
+
+    async fn parse_capture_command(&mut self, parameters: &[u8], answer: &mut [u8]) -> Result<usize, &'static str> {
+        if parameters == b"start" {
+            self.receiver_control.start_capture().await?;
+            Ok(Self::copy_to_beginning(answer, b"capture started, pulses follow in rtl_433 OOK pulse data format"))
+        }
+        else if parameters == b"stop" {
+            self.receiver_control.stop_capture().await?;
+            Ok(Self::copy_to_beginning(answer, b"capture stopped"))
+        }
+        else {
+            Err("unknown capture command, type 'help' for help")
+        }
+    }
+
+    // The measured timing is stored as fixed timing settings, the pulse mode used for measuring stays until it is changed.
+    async fn parse_calibrate_command(&mut self, parameters: &[u8], answer: &mut [u8]) -> Result<usize, &'static str> {
+        if parameters == b"start" {
+            self.receiver_control.start_calibration().await?;
+            Ok(Self::copy_to_beginning(answer, b"calibration started, press a button of the remote several times"))
+        }
+        else if parameters == b"stop" {
+            let calibration = self.receiver_control.stop_calibration().await?;
+            let glitch_filter_us = FixedTimingSettings::load(self.persistency).await.glitch_filter_us;
+            let settings = calibration.settings(glitch_filter_us)?;
+            for (number, value_id) in [
+                (settings.clock_divider, ValueId::ClockDivider),
+                (settings.sync_gap_us, ValueId::SyncGapUs),
+                (settings.sample_delay_us, ValueId::SampleDelayUs),
+                (settings.glitch_filter_us, ValueId::GlitchFilterUs),
+            ] {
+                let mut value: String<10> = String::new();
+                write!(value, "{}", number).unwrap();
+                self.persistency.store(value.as_bytes(), value_id).await?;
+            }
+
+            let mut text: String<256> = String::new();
+            write!(text, "{}; stored clock_divider {}, sync_gap_us {}, sample_delay_us {}, glitch_filter_us {}, applied after restart with receiver_mode fixed",
+                calibration.text().as_str(), settings.clock_divider, settings.sync_gap_us, settings.sample_delay_us, settings.glitch_filter_us).unwrap();
+            Ok(Self::copy_to_beginning(answer, text.as_bytes()))
+        }
+        else {
+            Err("unknown calibrate command, type 'help' for help")
+        }
+    }
+
+    fn parse_number(text: &[u8]) -> Result<u32, &'static str> {
+        let text = core::str::from_utf8(text).map_err(|_| "value is not a number")?;
+        text.parse().map_err(|_| "value is not a number")
+    }
+
+    fn parse_hex(text: &[u8]) -> Result<u32, &'static str> {
+        let text = text.strip_prefix(b"0x").unwrap_or(text);
+        let text = core::str::from_utf8(text).map_err(|_| "code is not a hex number")?;
+        u32::from_str_radix(text, 16).map_err(|_| "code is not a hex number")
+    }
+
     pub async fn parse_message(&mut self, msg: &[u8], answer: &mut [u8]) -> Result<usize, &'static str> {
         const STORE_COMMAND: &[u8] = b"store ";
         const READ_COMMAND: &[u8] = b"read ";
+        const CODE_COMMAND: &[u8] = b"code ";
+        const LEARN_COMMAND: &[u8] = b"learn ";
+        const ENTITY_COMMAND: &[u8] = b"entity ";
+        const RECEIVER_COMMAND: &[u8] = b"receiver ";
+        const FLEX_COMMAND: &[u8] = b"flex ";
+        const SUB_COMMAND: &[u8] = b"sub ";
+        const CAPTURE_COMMAND: &[u8] = b"capture ";
+        const CALIBRATE_COMMAND: &[u8] = b"calibrate ";
+        const SEND_COMMAND: &[u8] = b"send ";
+        // The lines of a .sub file being imported are no commands.
+        if self.sub_import.is_some() {
+            return self.parse_sub_line(msg, answer).await;
+        }
         if msg == b"enter bootloader" {
             embassy_rp::rom_data::reset_to_usb_boot(0, 0);
             // Note: probably this message won't be seen, because of immediate restart.
@@ -145,18 +657,119 @@ where P: PersistencyTrait,
             let parameters = &msg[READ_COMMAND.len()..];
             self.parse_read_command(parameters, answer).await
         }
-        else if msg.starts_with(b"help") {
-            Ok(Self::copy_to_beginning(answer, concat!(
+        else if msg.starts_with(CODE_COMMAND) {
+            let parameters = &msg[CODE_COMMAND.len()..];
+            self.parse_code_command(parameters, answer).await
+        }
+        else if msg.starts_with(ENTITY_COMMAND) {
+            let parameters = &msg[ENTITY_COMMAND.len()..];
+            self.parse_entity_command(parameters, answer).await
+        }
+        else if msg.starts_with(RECEIVER_COMMAND) {
+            let parameters = &msg[RECEIVER_COMMAND.len()..];
+            self.parse_receiver_command(parameters, answer).await
+        }
+        else if msg.starts_with(FLEX_COMMAND) {
+            let parameters = &msg[FLEX_COMMAND.len()..];
+            self.parse_flex_command(parameters, answer).await
+        }
+        else if msg == b"devices" {
+            self.receiver_control.list_devices(answer).await
+        }
+        else if msg == b"rfstats" {
+            self.receiver_control.signal_stats(answer).await
+        }
+        else if msg == b"connection" {
+            let (state, connections) = self.receiver_control.connection_status().await;
+            let mut text: String<64> = String::new();
+            write!(text, "{}, established {} times since start", state.as_str(), connections).unwrap();
+            Ok(Self::copy_to_beginning(answer, text.as_bytes()))
+        }
+        else if msg.starts_with(LEARN_COMMAND) {
+            let name = &msg[LEARN_COMMAND.len()..];
+            self.parse_learn_command(name, answer).await
+        }
+        else if msg.starts_with(CAPTURE_COMMAND) {
+            let parameters = &msg[CAPTURE_COMMAND.len()..];
+            self.parse_capture_command(parameters, answer).await
+        }
+        else if msg.starts_with(CALIBRATE_COMMAND) {
+            let parameters = &msg[CALIBRATE_COMMAND.len()..];
+            self.parse_calibrate_command(parameters, answer).await
+        }
+        else if msg.starts_with(SEND_COMMAND) {
+            let parameters = &msg[SEND_COMMAND.len()..];
+            self.parse_send_command(parameters, answer).await
+        }
+        else if msg.starts_with(SUB_COMMAND) {
+            let parameters = &msg[SUB_COMMAND.len()..];
+            self.parse_sub_command(parameters, answer).await
+        }
+        else if let Some(topic) = msg.strip_prefix(b"help") {
+            let help = Self::help(topic.strip_prefix(b" ").unwrap_or(topic))?;
+            Ok(Self::copy_to_beginning(answer, help.as_bytes()))
+        } else {
+            Err("not a valid command, type 'help' for help")
+        }
+    }
+
+    // The help is split into pages, as all of it does not fit into one answer.
+    fn help(topic: &[u8]) -> Result<&'static str, &'static str> {
+        match topic {
+            b"" => Ok(concat!(
                 "commands:\n",
                 "enter bootloader           : enters the bootloader to flash via usb\n",
                 "ping                       : results in 'pong'\n",
                 "version                    : provides version information\n",
                 "store <value_name> <value> : stores a value persistently\n",
-                "read <value_name>          : reads a persistent value\n",
-                "help                       : prints this help"
-            ).as_bytes()))
-        } else {
-            Err("not a valid command, type 'help' for help")
+                "read <value_name>          : reads a persistent value, 'read help' lists the value names\n",
+                "code ...                   : manages the code table, see 'help code'\n",
+                "learn <name>               : maps the next received code to a button name\n",
+                "entity ...                 : manages contact and motion sensors, see 'help entity'\n",
+                "receiver ...               : manages labelled receivers, see 'help receiver'\n",
+                "flex ...                   : manages rtl_433 flex decoders, see 'help flex'\n",
+                "devices                    : lists the battery and tamper flags of the devices\n",
+                "rfstats                    : shows the signal quality of the last frames per receiver\n",
+                "connection                 : shows the state of the MQTT connection and how often it was established\n",
+                "capture start              : streams received pulses in rtl_433 OOK format\n",
+                "capture stop               : stops streaming received pulses\n",
+                "calibrate start            : measures the pulses of a remote, receiver_mode pulse only\n",
+                "calibrate stop             : shows the measured base time unit and stores the fixed timing settings for it\n",
+                "send ...                   : sends a code, see 'help send'\n",
+                "sub ...                    : exports and imports Flipper Zero .sub files, see 'help sub'\n",
+                "help [<topic>]             : prints this help or the help of code, entity, receiver, flex, send or sub"
+            )),
+            b"code" => Ok(concat!(
+                "code add <code> <name>     : maps a received hex code to a button name, 24 bits of protocol 1 in pulse mode\n",
+                "code remote <id> <name>    : names all keys of the remote with the hex id\n",
+                "code remove <name>         : removes a button from the code table\n",
+                "code list                  : lists the code table"
+            )),
+            b"entity" => Ok(concat!(
+                "entity contact <open> <closed> <tamper or -> <name> : declares a door or window contact by its hex codes\n",
+                "entity motion <code> <clear seconds> <name> : declares a motion detector that is clear after the given time\n",
+                "entity remove <name>       : removes an entity\n",
+                "entity list                : lists the entities"
+            )),
+            b"receiver" => Ok(concat!(
+                "receiver add <pin> <fixed or pulse> <label> : adds a labelled receiver, up to 4, applied after restart\n",
+                "receiver remove <label>    : removes a receiver, applied after restart\n",
+                "receiver list              : lists the receivers"
+            )),
+            b"flex" => Ok(concat!(
+                "flex add <spec>            : adds an rtl_433 flex decoder like n=bell,m=OOK_PWM,s=400,l=1200,r=12000,bits=25, up to 4, pulse mode only, applied after restart\n",
+                "flex remove <name>         : removes a flex decoder, applied after restart\n",
+                "flex list                  : lists the flex decoders"
+            )),
+            b"send" => Ok(concat!(
+                "send <name>                : sends a code of the code table\n",
+                "send <code> [<bits> [<protocol>]] : sends a hex code, 24 bits of rc-switch protocol 1 by default"
+            )),
+            b"sub" => Ok(concat!(
+                "sub export <name or code>  : prints a code as Flipper Zero .sub file, the code is given like for send\n",
+                "sub import <name>          : maps the code of a pasted Flipper Zero .sub file, ended by an empty line, to a button name"
+            )),
+            _ => Err("unknown help topic, type 'help' for help"),
         }
     }
 
@@ -172,11 +785,17 @@ mod tests {
     use super::*;
     use tokio;
     use crate::modules::persistency::MockPersistencyTrait;
+    use crate::modules::receiver_control::MockReceiverControlTrait;
+    use crate::modules::rc_switch::Frame;
+    use crate::modules::calibration::Calibration;
+    use crate::modules::code_table::Code;
+    use crate::modules::connection::ConnectionState;
 
     #[tokio::test]
     async fn test_ping_pong() {
         let mock_persistency = MockPersistencyTrait::new();
-        let mut parser = Parser::new(&mock_persistency);
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
 
         let mut answer: [u8; 32] = ['2' as u8; 32];
         let length = parser.parse_message(b"ping", &mut answer).await.unwrap();
@@ -191,6 +810,23 @@ mod tests {
             (b"mqtt_host_ip".as_ref(),         b"this.is.no.ip".as_ref(), ValueId::MqttHostIp),
             (b"mqtt_broker_username".as_ref(), b"UOWKDNDLE".as_ref(),     ValueId::MqttBrokerUsername),
             (b"mqtt_broker_password".as_ref(), b"__::)()()".as_ref(),     ValueId::MqttBrokerPassword),
+            (b"receiver_mode".as_ref(),        b"pulse".as_ref(),         ValueId::ReceiverMode),
+            (b"receiver_mode".as_ref(),        b"fixed".as_ref(),         ValueId::ReceiverMode),
+            (b"release_gap_ms".as_ref(),       b"300".as_ref(),           ValueId::ReleaseGapMs),
+            (b"click_window_ms".as_ref(),      b"400".as_ref(),           ValueId::ClickWindowMs),
+            (b"confirm_frames".as_ref(),       b"3".as_ref(),             ValueId::ConfirmFrames),
+            (b"confirm_gap_ms".as_ref(),       b"200".as_ref(),           ValueId::ConfirmGapMs),
+            (b"lockout_ms".as_ref(),           b"500".as_ref(),           ValueId::LockoutMs),
+            (b"clock_divider".as_ref(),        b"15000".as_ref(),         ValueId::ClockDivider),
+            (b"sync_gap_us".as_ref(),          b"5000".as_ref(),          ValueId::SyncGapUs),
+            (b"sample_delay_us".as_ref(),      b"700".as_ref(),           ValueId::SampleDelayUs),
+            (b"glitch_filter_us".as_ref(),     b"100".as_ref(),           ValueId::GlitchFilterUs),
+            (b"tx_pin".as_ref(),               b"26".as_ref(),            ValueId::TxPin),
+            (b"tx_repeats".as_ref(),           b"15".as_ref(),            ValueId::TxRepeats),
+            (b"radio".as_ref(),                b"cc1101".as_ref(),        ValueId::Radio),
+            (b"radio_frequency_khz".as_ref(),  b"868300".as_ref(),        ValueId::RadioFrequencyKhz),
+            (b"radio_modulation".as_ref(),     b"fsk".as_ref(),           ValueId::RadioModulation),
+            (b"signal_quality".as_ref(),       b"on".as_ref(),            ValueId::SignalQuality),
         ];
 
         for (command, value, value_id) in commands {
@@ -198,9 +834,13 @@ mod tests {
             mock_persistency.expect_store()
                 .times(1)
                 .withf(move |v, id| v == value && *id == value_id)
-                .returning(|_, _| ());
+                .returning(|_, _| Ok(()));
+            // The fixed timing settings are checked together with the stored ones, none are stored here.
+            mock_persistency.expect_read()
+                .returning(|_, _| Ok(0));
 
-            let mut parser = Parser::new(&mock_persistency);
+            let mock_receiver_control = MockReceiverControlTrait::new();
+            let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
 
             let mut message = Vec::new();
             message.extend_from_slice(b"store ");
@@ -217,7 +857,8 @@ mod tests {
     #[tokio::test]
     async fn invalid_store_value_name() {
         let mock_persistency = MockPersistencyTrait::new();
-        let mut parser = Parser::new(&mock_persistency);
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
 
         let mut answer = ['\0' as u8; 100];
         match parser.parse_message(b"store dummy", &mut answer).await {
@@ -226,6 +867,130 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn value_that_is_not_stored() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        mock_persistency.expect_store()
+            .times(1)
+            .returning(|_, _| Err("no space left for the value"));
+
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        assert_eq!(parser.parse_message(b"store wifi_ssid my_wifi", &mut answer).await, Err("no space left for the value"));
+    }
+
+    #[tokio::test]
+    async fn invalid_receiver_mode() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        mock_persistency.expect_store().never();
+
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        match parser.parse_message(b"store receiver_mode nonsense", &mut answer).await {
+            Ok(_) => assert!(false),
+            Err(msg) => assert_eq!(msg, "receiver_mode must be 'fixed' or 'pulse'"),
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_number() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        mock_persistency.expect_store().never();
+
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        match parser.parse_message(b"store release_gap_ms 3OO", &mut answer).await {
+            Ok(_) => assert!(false),
+            Err(msg) => assert_eq!(msg, "value is not a number"),
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_confirm_frames() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        mock_persistency.expect_store().never();
+
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        for message in [b"store confirm_frames 0".as_ref(), b"store confirm_frames 256".as_ref()] {
+            let mut answer = ['\0' as u8; 100];
+            match parser.parse_message(message, &mut answer).await {
+                Ok(_) => assert!(false),
+                Err(msg) => assert_eq!(msg, "confirm_frames must be between 1 and 255"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_transmitter_settings() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        mock_persistency.expect_store().never();
+
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        const MESSAGES: &[(&[u8], &str)] = &[
+            (b"store tx_pin 28", "tx_pin must be one of 16 to 22, 26 or 27"),
+            (b"store tx_pin 300", "tx_pin must be one of 16 to 22, 26 or 27"),
+            (b"store tx_repeats 0", "tx_repeats must be between 1 and 255"),
+            (b"store tx_repeats many", "value is not a number"),
+        ];
+        for (message, error) in MESSAGES {
+            let mut answer = ['\0' as u8; 100];
+            assert_eq!(parser.parse_message(message, &mut answer).await, Err(*error));
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_radio_settings() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        mock_persistency.expect_store().never();
+
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        const MESSAGES: &[(&[u8], &str)] = &[
+            (b"store radio cc1100", "radio must be 'module' or 'cc1101'"),
+            (b"store radio_frequency_khz 500000", "radio_frequency_khz must be within 300000-348000, 387000-464000 or 779000-928000"),
+            (b"store radio_frequency_khz 433.92", "value is not a number"),
+            (b"store radio_modulation ask", "radio_modulation must be 'ook' or 'fsk'"),
+            (b"store signal_quality yes", "signal_quality must be 'on' or 'off'"),
+        ];
+        for (message, error) in MESSAGES {
+            let mut answer = ['\0' as u8; 100];
+            assert_eq!(parser.parse_message(message, &mut answer).await, Err(*error));
+        }
+    }
+
+    #[tokio::test]
+    async fn fixed_timing_not_fitting_to_stored_values() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        mock_persistency.expect_store().never();
+        mock_persistency.expect_read()
+            .returning(|id, answer| {
+                let value: &[u8] = if id == ValueId::ClockDivider { b"2500" } else { b"" };
+                answer[..value.len()].copy_from_slice(value);
+                Ok(value.len())
+            });
+
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        // The default sync gap is too long for the faster clock.
+        let mut answer = ['\0' as u8; 100];
+        match parser.parse_message(b"store sync_gap_us 6400", &mut answer).await {
+            Ok(_) => assert!(false),
+            Err(msg) => assert_eq!(msg, "sync_gap_us does not fit to clock_divider"),
+        }
+    }
+
     #[tokio::test]
     async fn test_read_command() {
         const COMMANDS: &[( &[u8], &[u8], ValueId )] = &[
@@ -234,6 +999,22 @@ mod tests {
             (b"mqtt_host_ip",         b"this.is.no.ip", ValueId::MqttHostIp),
             (b"mqtt_broker_username", b"UOWKDNDLE",     ValueId::MqttBrokerUsername),
             (b"mqtt_broker_password", b"__::)()()",     ValueId::MqttBrokerPassword),
+            (b"receiver_mode",        b"pulse",         ValueId::ReceiverMode),
+            (b"release_gap_ms",       b"300",           ValueId::ReleaseGapMs),
+            (b"click_window_ms",      b"400",           ValueId::ClickWindowMs),
+            (b"confirm_frames",       b"3",             ValueId::ConfirmFrames),
+            (b"confirm_gap_ms",       b"200",           ValueId::ConfirmGapMs),
+            (b"lockout_ms",           b"500",           ValueId::LockoutMs),
+            (b"clock_divider",        b"15000",         ValueId::ClockDivider),
+            (b"sync_gap_us",          b"5000",          ValueId::SyncGapUs),
+            (b"sample_delay_us",      b"700",           ValueId::SampleDelayUs),
+            (b"glitch_filter_us",     b"100",           ValueId::GlitchFilterUs),
+            (b"tx_pin",               b"26",            ValueId::TxPin),
+            (b"tx_repeats",           b"15",            ValueId::TxRepeats),
+            (b"radio",                b"cc1101",        ValueId::Radio),
+            (b"radio_frequency_khz",  b"868300",        ValueId::RadioFrequencyKhz),
+            (b"radio_modulation",     b"fsk",           ValueId::RadioModulation),
+            (b"signal_quality",       b"on",            ValueId::SignalQuality),
         ];
 
         let mut mock_persistency = MockPersistencyTrait::new();
@@ -248,7 +1029,8 @@ mod tests {
                 });
         }
 
-        let mut parser = Parser::new(&mock_persistency);
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
 
         for (command, value, _) in COMMANDS {
             let mut message = Vec::new();
@@ -264,7 +1046,8 @@ mod tests {
     #[tokio::test]
     async fn invalid_read_value_name() {
         let mock_persistency = MockPersistencyTrait::new();
-        let mut parser = Parser::new(&mock_persistency);
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
 
         let mut answer = ['\0' as u8; 100];
         match parser.parse_message(b"read adfasdf", &mut answer).await {
@@ -273,6 +1056,735 @@ mod tests {
         }
     }
 
+    fn expect_code_table(mock_persistency: &mut MockPersistencyTrait, stored: &'static [u8]) {
+        mock_persistency.expect_read()
+            .times(1)
+            .withf(|id, _| *id == ValueId::CodeTable)
+            .returning_st(move |_, answer| {
+                answer[..stored.len()].copy_from_slice(stored);
+                Ok(stored.len())
+            });
+    }
+
+    #[tokio::test]
+    async fn test_code_add_command() {
+        // The code is taken as the receiver shows it.
+        const COMMANDS: &[(&[u8], &[u8], &[u8])] = &[
+            (b"code add 0x017E9E88 button 2", b"fixed", b"\x90\x9E\x7E\x01\x00\x19\x08button 1\x88\x9E\x7E\x01\x00\x19\x08button 2"),
+            (b"code add 1C5103 socket", b"pulse", b"\x90\x9E\x7E\x01\x00\x19\x08button 1\x03\x51\x1C\x00\x01\x18\x06socket"),
+        ];
+
+        for (command, mode, stored) in COMMANDS {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            expect_send_settings(&mut mock_persistency, b"\x90\x9E\x7E\x01\x00\x19\x08button 1", mode);
+            mock_persistency.expect_store()
+                .times(1)
+                .withf(move |v, id| v == *stored && *id == ValueId::CodeTable)
+                .returning(|_, _| Ok(()));
+
+            let mut mock_receiver_control = MockReceiverControlTrait::new();
+
+            mock_receiver_control.expect_tables_changed()
+
+                .times(1)
+
+                .returning(|| ());
+            let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+            let mut answer = ['\0' as u8; 100];
+            let length = parser.parse_message(command, &mut answer).await.unwrap();
+            assert_eq!(&answer[..length], b"");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_code_add_command_invalid() {
+        const COMMANDS: &[(&[u8], &str)] = &[
+            (b"code add 0x017E9E88", "usage: code add <code> <name>"),
+            (b"code add 0x017E9EXX button 2", "code is not a hex number"),
+            (b"code add 0x017E9E90 button 2", "code is already in the table"),
+            (b"code add 0x017E9E88 button 1", "name is already in the table"),
+        ];
+
+        for (command, error) in COMMANDS {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            expect_send_settings(&mut mock_persistency, b"\x90\x9E\x7E\x01\x00\x19\x08button 1", b"fixed");
+            mock_persistency.expect_store().never();
+
+            let mock_receiver_control = MockReceiverControlTrait::new();
+            let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+            let mut answer = ['\0' as u8; 100];
+            match parser.parse_message(command, &mut answer).await {
+                Ok(_) => assert!(false),
+                Err(msg) => assert_eq!(msg, *error),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_code_remote_command() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_code_table(&mut mock_persistency, b"\x90\x9E\x7E\x01\x00\x19\x08button 1");
+        mock_persistency.expect_store()
+            .times(1)
+            .withf(|v, id| v == b"\x90\x9E\x7E\x01\x00\x19\x08button 1\xF4\xF4\x0B\x00\x00\x00\x86garage" && *id == ValueId::CodeTable)
+            .returning(|_, _| Ok(()));
+
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+
+        mock_receiver_control.expect_tables_changed()
+
+            .times(1)
+
+            .returning(|| ());
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        let length = parser.parse_message(b"code remote 0xBF4F4 garage", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"");
+    }
+
+    #[tokio::test]
+    async fn test_code_remove_command() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_code_table(&mut mock_persistency, b"\x90\x9E\x7E\x01\x00\x19\x08button 1\x88\x9E\x7E\x01\x00\x19\x08button 2");
+        mock_persistency.expect_store()
+            .times(1)
+            .withf(|v, id| v == b"\x88\x9E\x7E\x01\x00\x19\x08button 2" && *id == ValueId::CodeTable)
+            .returning(|_, _| Ok(()));
+
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+
+        mock_receiver_control.expect_tables_changed()
+
+            .times(1)
+
+            .returning(|| ());
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        let length = parser.parse_message(b"code remove button 1", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"");
+    }
+
+    #[tokio::test]
+    async fn test_code_list_command() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_code_table(&mut mock_persistency, b"\x90\x9E\x7E\x01\x00\x19\x08button 1\x88\x9E\x7E\x01\x00\x19\x08button 2");
+        mock_persistency.expect_store().never();
+
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        let length = parser.parse_message(b"code list", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"0x017E9E90 fixed timing: button 1\n0x017E9E88 fixed timing: button 2");
+    }
+
+    fn expect_entity_table(mock_persistency: &mut MockPersistencyTrait, stored: &'static [u8]) {
+        mock_persistency.expect_read()
+            .times(1)
+            .withf(|id, _| *id == ValueId::Entities)
+            .returning_st(move |_, answer| {
+                answer[..stored.len()].copy_from_slice(stored);
+                Ok(stored.len())
+            });
+    }
+
+    const DOOR_ENTITY: &[u8] = b"\x00\xC1\xB2\xA1\x00\xC2\xB2\xA1\x00\x00\x00\x00\x00\x04door";
+    const HALL_ENTITY: &[u8] = b"\x01\xF6\xE5\xD4\x00\x1E\x00\x00\x00\x00\x00\x00\x00\x04hall";
+
+    #[tokio::test]
+    async fn test_entity_add_commands() {
+        let commands: [(&[u8], &[u8], &[u8]); 2] = [
+            (b"entity contact 0xA1B2C1 0xA1B2C2 - door", b"", DOOR_ENTITY),
+            (b"entity motion D4E5F6 30 hall", DOOR_ENTITY, HALL_ENTITY),
+        ];
+
+        for (command, stored, added) in commands {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            expect_entity_table(&mut mock_persistency, stored);
+            let expected = [stored, added].concat();
+            mock_persistency.expect_store()
+                .times(1)
+                .withf(move |v, id| v == expected && *id == ValueId::Entities)
+                .returning(|_, _| Ok(()));
+
+            let mut mock_receiver_control = MockReceiverControlTrait::new();
+
+            mock_receiver_control.expect_tables_changed()
+
+                .times(1)
+
+                .returning(|| ());
+            let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+            let mut answer = ['\0' as u8; 100];
+            let length = parser.parse_message(command, &mut answer).await.unwrap();
+            assert_eq!(&answer[..length], b"");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_entity_command_invalid() {
+        const COMMANDS: &[(&[u8], &str)] = &[
+            (b"entity contact 0xA1B2C1 0xA1B2C2", "usage: entity contact <open code> <closed code> <tamper code or -> <name>"),
+            (b"entity contact 0xA1B2C1 0xA1B2XX - window", "code is not a hex number"),
+            (b"entity contact 0x11 0x12 - door", "name is already in the entity table"),
+            (b"entity motion 0xA1B2C2 30 hall", "code is already used by another entity"),
+            (b"entity motion 0x11 soon hall", "value is not a number"),
+            (b"entity motion 0x11", "usage: entity motion <code> <clear seconds> <name>"),
+            (b"entity remove hall", "name not found in the entity table"),
+            (b"entity rename door", "unknown entity command, type 'help' for help"),
+        ];
+
+        for (command, error) in COMMANDS {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            expect_entity_table(&mut mock_persistency, DOOR_ENTITY);
+            mock_persistency.expect_store().never();
+
+            let mock_receiver_control = MockReceiverControlTrait::new();
+            let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+            let mut answer = ['\0' as u8; 100];
+            assert_eq!(parser.parse_message(command, &mut answer).await, Err(*error));
+        }
+    }
+
+    fn expect_receiver_table(mock_persistency: &mut MockPersistencyTrait, stored: &'static [u8]) {
+        mock_persistency.expect_read()
+            .times(1)
+            .withf(|id, _| *id == ValueId::Receivers)
+            .returning_st(move |_, answer| {
+                answer[..stored.len()].copy_from_slice(stored);
+                Ok(stored.len())
+            });
+    }
+
+    const GARAGE_RECEIVER: &[u8] = b"\x03\x01\x06garage";
+
+    #[tokio::test]
+    async fn test_receiver_commands() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_receiver_table(&mut mock_persistency, b"");
+        mock_persistency.expect_store()
+            .times(1)
+            .withf(|v, id| v == GARAGE_RECEIVER && *id == ValueId::Receivers)
+            .returning(|_, _| Ok(()));
+        expect_receiver_table(&mut mock_persistency, GARAGE_RECEIVER);
+        expect_receiver_table(&mut mock_persistency, GARAGE_RECEIVER);
+        mock_persistency.expect_store()
+            .times(1)
+            .withf(|v, id| v.is_empty() && *id == ValueId::Receivers)
+            .returning(|_, _| Ok(()));
+
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        assert_eq!(parser.parse_message(b"receiver add 3 pulse garage", &mut answer).await, Ok(0));
+        let length = parser.parse_message(b"receiver list", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"garage: pin 3, pulse");
+        assert_eq!(parser.parse_message(b"receiver remove garage", &mut answer).await, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_receiver_command_invalid() {
+        const COMMANDS: &[(&[u8], &str)] = &[
+            (b"receiver add 3 pulse", "usage: receiver add <pin> <fixed or pulse> <label>"),
+            (b"receiver add 300 pulse attic", "pin must be one of 2 to 11 or 28"),
+            (b"receiver add 13 pulse attic", "pin must be one of 2 to 11 or 28"),
+            (b"receiver add 4 fsk attic", "mode must be 'fixed' or 'pulse'"),
+            (b"receiver add 4 pulse garage", "label is already in the receiver table"),
+            (b"receiver remove attic", "label not found in the receiver table"),
+            (b"receiver rename garage", "unknown receiver command, type 'help' for help"),
+        ];
+
+        for (command, error) in COMMANDS {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            expect_receiver_table(&mut mock_persistency, GARAGE_RECEIVER);
+            mock_persistency.expect_store().never();
+
+            let mock_receiver_control = MockReceiverControlTrait::new();
+            let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+            let mut answer = ['\0' as u8; 100];
+            assert_eq!(parser.parse_message(command, &mut answer).await, Err(*error));
+        }
+    }
+
+    fn expect_flex_table(mock_persistency: &mut MockPersistencyTrait, stored: &'static [u8]) {
+        mock_persistency.expect_read()
+            .times(1)
+            .withf(|id, _| *id == ValueId::FlexDecoders)
+            .returning_st(move |_, answer| {
+                answer[..stored.len()].copy_from_slice(stored);
+                Ok(stored.len())
+            });
+    }
+
+    const DOORBELL_DECODER: &[u8] = b"n=doorbell,m=OOK_PWM,s=400,l=1200,r=12000,bits=25";
+
+    #[tokio::test]
+    async fn test_flex_commands() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_flex_table(&mut mock_persistency, b"");
+        mock_persistency.expect_store()
+            .times(1)
+            .withf(|v, id| v == DOORBELL_DECODER && *id == ValueId::FlexDecoders)
+            .returning(|_, _| Ok(()));
+        expect_flex_table(&mut mock_persistency, DOORBELL_DECODER);
+        expect_flex_table(&mut mock_persistency, DOORBELL_DECODER);
+        mock_persistency.expect_store()
+            .times(1)
+            .withf(|v, id| v.is_empty() && *id == ValueId::FlexDecoders)
+            .returning(|_, _| Ok(()));
+
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        assert_eq!(parser.parse_message(b"flex add n=doorbell,m=OOK_PWM,s=400,l=1200,r=12000,bits=25", &mut answer).await, Ok(0));
+        let length = parser.parse_message(b"flex list", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], DOORBELL_DECODER);
+        assert_eq!(parser.parse_message(b"flex remove doorbell", &mut answer).await, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_flex_command_invalid() {
+        const COMMANDS: &[(&[u8], &str)] = &[
+            (b"flex add n=doorbell,m=OOK_PPM,s=400,l=1200,r=12000", "name is already in the flex decoders"),
+            (b"flex add n=siren,m=OOK_PWM,s=400,r=12000", "flex decoder needs a long width (l=)"),
+            (b"flex remove siren", "name not found in the flex decoders"),
+            (b"flex show doorbell", "unknown flex command, type 'help' for help"),
+        ];
+
+        for (command, error) in COMMANDS {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            expect_flex_table(&mut mock_persistency, DOORBELL_DECODER);
+            mock_persistency.expect_store().never();
+
+            let mock_receiver_control = MockReceiverControlTrait::new();
+            let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+            let mut answer = ['\0' as u8; 100];
+            assert_eq!(parser.parse_message(command, &mut answer).await, Err(*error));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_entity_remove_and_list_commands() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_entity_table(&mut mock_persistency, b"\x00\xC1\xB2\xA1\x00\xC2\xB2\xA1\x00\x00\x00\x00\x00\x04door\x01\xF6\xE5\xD4\x00\x1E\x00\x00\x00\x00\x00\x00\x00\x04hall");
+        mock_persistency.expect_store()
+            .times(1)
+            .withf(|v, id| v == HALL_ENTITY && *id == ValueId::Entities)
+            .returning(|_, _| Ok(()));
+        expect_entity_table(&mut mock_persistency, HALL_ENTITY);
+
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+
+        mock_receiver_control.expect_tables_changed()
+
+            .times(1)
+
+            .returning(|| ());
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        let length = parser.parse_message(b"entity remove door", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"");
+
+        let length = parser.parse_message(b"entity list", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"hall: motion 0x00D4E5F6, clear after 30 s");
+    }
+
+    #[tokio::test]
+    async fn test_learn_command() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_code_table(&mut mock_persistency, b"\x90\x9E\x7E\x01\x00\x19\x08button 1");
+        mock_persistency.expect_store()
+            .times(1)
+            .withf(|v, id| v == b"\x90\x9E\x7E\x01\x00\x19\x08button 1\x88\x9E\x7E\x01\x00\x19\x08button 2" && *id == ValueId::CodeTable)
+            .returning(|_, _| Ok(()));
+
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+
+        mock_receiver_control.expect_tables_changed()
+
+            .times(1)
+
+            .returning(|| ());
+        mock_receiver_control.expect_next_code()
+            .times(1)
+            .returning(|| Ok(Code::fixed_timing(0x017E9E88)));
+
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        let length = parser.parse_message(b"learn button 2", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"learned code 0x017E9E88 fixed timing");
+    }
+
+    #[tokio::test]
+    async fn test_learn_command_timeout() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_code_table(&mut mock_persistency, b"");
+        mock_persistency.expect_store().never();
+
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+        mock_receiver_control.expect_next_code()
+            .times(1)
+            .returning(|| Err("no code received within 10 seconds"));
+
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        match parser.parse_message(b"learn button 2", &mut answer).await {
+            Ok(_) => assert!(false),
+            Err(msg) => assert_eq!(msg, "no code received within 10 seconds"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_learn_command_code_already_bound() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_code_table(&mut mock_persistency, b"\x90\x9E\x7E\x01\x00\x19\x08button 1");
+        mock_persistency.expect_store().never();
+
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+        mock_receiver_control.expect_next_code()
+            .times(1)
+            .returning(|| Ok(Code::fixed_timing(0x017E9E90)));
+
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        match parser.parse_message(b"learn button 2", &mut answer).await {
+            Ok(_) => assert!(false),
+            Err(msg) => assert_eq!(msg, "received code is already in the table, type 'code list' to see it"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_learn_command_name_already_bound() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_code_table(&mut mock_persistency, b"\x90\x9E\x7E\x01\x00\x19\x08button 1");
+        mock_persistency.expect_store().never();
+
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+        mock_receiver_control.expect_next_code().never();
+
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        match parser.parse_message(b"learn button 1", &mut answer).await {
+            Ok(_) => assert!(false),
+            Err(msg) => assert_eq!(msg, "name is already in the table"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capture_command() {
+        let mock_persistency = MockPersistencyTrait::new();
+
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+        mock_receiver_control.expect_start_capture()
+            .times(1)
+            .returning(|| Ok(()));
+        mock_receiver_control.expect_stop_capture()
+            .times(1)
+            .returning(|| Ok(()));
+
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        let length = parser.parse_message(b"capture start", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"capture started, pulses follow in rtl_433 OOK pulse data format");
+
+        let length = parser.parse_message(b"capture stop", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"capture stopped");
+    }
+
+    #[tokio::test]
+    async fn test_calibrate_command() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        mock_persistency.expect_read()
+            .returning(|id, answer| {
+                let value: &[u8] = if id == ValueId::GlitchFilterUs { b"300" } else { b"" };
+                answer[..value.len()].copy_from_slice(value);
+                Ok(value.len())
+            });
+        for (value, value_id) in [
+            (b"16667".as_ref(), ValueId::ClockDivider),
+            (b"8000".as_ref(),  ValueId::SyncGapUs),
+            (b"820".as_ref(),   ValueId::SampleDelayUs),
+            (b"210".as_ref(),   ValueId::GlitchFilterUs),
+        ] {
+            mock_persistency.expect_store()
+                .withf(move |v, id| v == value && *id == value_id)
+                .times(1)
+                .returning(|_, _| Ok(()));
+        }
+
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+        mock_receiver_control.expect_start_calibration()
+            .times(1)
+            .returning(|| Ok(()));
+        mock_receiver_control.expect_stop_calibration()
+            .times(1)
+            .returning(|| Ok(Calibration { frames: 4, base_us: 400, short_us: 420, long_us: 1220, sync_gap_us: 12000 }));
+
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 256];
+        let length = parser.parse_message(b"calibrate start", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"calibration started, press a button of the remote several times");
+
+        let length = parser.parse_message(b"calibrate stop", &mut answer).await.unwrap();
+        assert_eq!(core::str::from_utf8(&answer[..length]).unwrap(), concat!(
+            "4 frames measured: base time unit 400 us, short pulse 420 us, long pulse 1220 us, sync gap 12000 us; ",
+            "stored clock_divider 16667, sync_gap_us 8000, sample_delay_us 820, glitch_filter_us 210, applied after restart with receiver_mode fixed"));
+    }
+
+    #[tokio::test]
+    async fn test_calibrate_command_failing() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        mock_persistency.expect_store().never();
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+        mock_receiver_control.expect_stop_calibration()
+            .times(1)
+            .returning(|| Err("no frames measured, press a button of the remote while calibrating"));
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        assert_eq!(parser.parse_message(b"calibrate stop", &mut answer).await,
+            Err("no frames measured, press a button of the remote while calibrating"));
+        assert_eq!(parser.parse_message(b"calibrate now", &mut answer).await,
+            Err("unknown calibrate command, type 'help' for help"));
+    }
+
+    #[tokio::test]
+    async fn test_devices_command() {
+        let mock_persistency = MockPersistencyTrait::new();
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+        mock_receiver_control.expect_list_devices()
+            .times(1)
+            .returning(|answer| {
+                let text = b"Nexus-TH/5A: battery low, seen 64 s ago";
+                answer[..text.len()].copy_from_slice(text);
+                Ok(text.len())
+            });
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        let length = parser.parse_message(b"devices", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"Nexus-TH/5A: battery low, seen 64 s ago");
+    }
+
+    #[tokio::test]
+    async fn test_rfstats_command() {
+        let mock_persistency = MockPersistencyTrait::new();
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+        mock_receiver_control.expect_signal_stats()
+            .times(1)
+            .returning(|answer| {
+                let text = b"receiver: last 3 frames, repeats avg 4.0, rejected 1";
+                answer[..text.len()].copy_from_slice(text);
+                Ok(text.len())
+            });
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        let length = parser.parse_message(b"rfstats", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"receiver: last 3 frames, repeats avg 4.0, rejected 1");
+    }
+
+    #[tokio::test]
+    async fn test_connection_command() {
+        let mock_persistency = MockPersistencyTrait::new();
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+        mock_receiver_control.expect_connection_status()
+            .times(1)
+            .returning(|| (ConnectionState::WaitingForDhcp, 2));
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        let length = parser.parse_message(b"connection", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"waiting for DHCP, established 2 times since start");
+    }
+
+    #[tokio::test]
+    async fn test_help_fits_the_answer() {
+        let mock_persistency = MockPersistencyTrait::new();
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let commands = [
+            b"help".as_ref(), b"help code", b"help entity", b"help receiver", b"help flex", b"help send", b"help sub", b"read help",
+        ];
+        for command in commands {
+            // A larger buffer shows whether the help would be cut off by the one of the terminal.
+            let mut answer = [0u8; 2 * MAX_ANSWER_LENGTH];
+            let length = parser.parse_message(command, &mut answer).await.unwrap();
+            assert!(length < MAX_ANSWER_LENGTH, "{} takes {} bytes", core::str::from_utf8(command).unwrap(), length);
+        }
+
+        let mut answer = [0u8; 64];
+        assert_eq!(parser.parse_message(b"help pong", &mut answer).await, Err("unknown help topic, type 'help' for help"));
+    }
+
+    fn expect_send_settings(mock_persistency: &mut MockPersistencyTrait, code_table: &'static [u8], mode: &'static [u8]) {
+        mock_persistency.expect_read()
+            .returning_st(move |id, answer| {
+                let stored = if id == ValueId::CodeTable { code_table } else { mode };
+                answer[..stored.len()].copy_from_slice(stored);
+                Ok(stored.len())
+            });
+    }
+
+    #[tokio::test]
+    async fn test_sub_export_command() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_send_settings(&mut mock_persistency, b"\x90\x9E\x7E\x01\x00\x19\x08button 1", b"fixed");
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 300];
+        let length = parser.parse_message(b"sub export button 1", &mut answer).await.unwrap();
+        let file = core::str::from_utf8(&answer[..length]).unwrap();
+        assert!(file.starts_with("Filetype: Flipper SubGhz Key File\n"), "{}", file);
+        assert!(file.contains("\nKey: 00 00 00 00 00 BF 4F 48\n"), "{}", file);
+
+        assert_eq!(parser.parse_message(b"sub export button 2", &mut answer).await, Err("code is neither a name in the code table nor a hex number"));
+        assert_eq!(parser.parse_message(b"sub show button 1", &mut answer).await, Err("unknown sub command, type 'help' for help"));
+    }
+
+    const SUB_FILE: &[&[u8]] = &[
+        b"Filetype: Flipper SubGhz Key File",
+        b"Version: 1",
+        b"Frequency: 433920000",
+        b"Preset: FuriHalSubGhzPresetOok650Async",
+        b"Protocol: Princeton",
+        b"Bit: 24",
+        b"Key: 00 00 00 00 00 17 E9 E9",
+        b"TE: 412",
+    ];
+
+    #[tokio::test]
+    async fn test_sub_import_command() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_send_settings(&mut mock_persistency, b"\x90\x9E\x7E\x01\x00\x19\x08button 1", b"pulse");
+        mock_persistency.expect_store()
+            .times(1)
+            .withf(|v, id| v == b"\x90\x9E\x7E\x01\x00\x19\x08button 1\xE9\xE9\x17\x00\x01\x18\x04door" && *id == ValueId::CodeTable)
+            .returning(|_, _| Ok(()));
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+        mock_receiver_control.expect_tables_changed()
+            .times(1)
+            .returning(|| ());
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        let length = parser.parse_message(b"sub import door", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"paste the .sub file and end it with an empty line");
+        for line in SUB_FILE {
+            assert_eq!(parser.parse_message(line, &mut answer).await, Ok(0));
+        }
+        let length = parser.parse_message(b"", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"imported code 0x0017E9E9 protocol 1 24 bits, send it with 'send door'");
+
+        // The import is over, commands are taken again.
+        let length = parser.parse_message(b"ping", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"pong");
+    }
+
+    #[tokio::test]
+    async fn test_sub_import_invalid() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_send_settings(&mut mock_persistency, b"\x90\x9E\x7E\x01\x00\x19\x08button 1", b"fixed");
+        mock_persistency.expect_store().never();
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        assert_eq!(parser.parse_message(b"sub import button 1", &mut answer).await, Err("name is already in the table"));
+
+        // An invalid line ends the import.
+        parser.parse_message(b"sub import door", &mut answer).await.unwrap();
+        assert_eq!(parser.parse_message(b"Protocol: CAME", &mut answer).await, Err("only the protocols Princeton and RAW are supported"));
+        let length = parser.parse_message(b"ping", &mut answer).await.unwrap();
+        assert_eq!(&answer[..length], b"pong");
+
+        // The fixed timing receiver can't read the code.
+        parser.parse_message(b"sub import door", &mut answer).await.unwrap();
+        for line in SUB_FILE.iter().map(|line| if line.starts_with(b"Bit") { b"Bit: 28".as_ref() } else { line }) {
+            assert_eq!(parser.parse_message(line, &mut answer).await, Ok(0));
+        }
+        assert_eq!(parser.parse_message(b"", &mut answer).await, Err("the fixed timing receiver only reads 24 bit codes of protocol 1"));
+    }
+
+    #[tokio::test]
+    async fn test_send_command() {
+        // command, receiver mode, then the frame sent and the answer
+        type SendCommand = (&'static [u8], &'static [u8], Frame, &'static [u8]);
+        const COMMANDS: &[SendCommand] = &[
+            (b"send 0x1C5103", b"pulse", Frame { protocol: 1, value: 0x1C5103, bit_count: 24 }, b"sending code 0x001C5103, 24 bits, protocol 1"),
+            (b"send ABCDE 20 6", b"pulse", Frame { protocol: 6, value: 0xABCDE, bit_count: 20 }, b"sending code 0x000ABCDE, 20 bits, protocol 6"),
+            (b"send button 1", b"fixed", Frame { protocol: 1, value: 0xBF4F48, bit_count: 24 }, b"sending code 0x00BF4F48, 24 bits, protocol 1"),
+            (b"send button 1", b"pulse", Frame { protocol: 1, value: 0xBF4F48, bit_count: 24 }, b"sending code 0x00BF4F48, 24 bits, protocol 1"),
+        ];
+
+        for (command, mode, frame, expected) in COMMANDS {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            expect_send_settings(&mut mock_persistency, b"\x90\x9E\x7E\x01\x00\x19\x08button 1", mode);
+            let mut mock_receiver_control = MockReceiverControlTrait::new();
+            mock_receiver_control.expect_queue_transmission()
+                .times(1)
+                .withf(move |queued| queued == frame)
+                .returning(|_| Ok(()));
+            let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+            let mut answer = ['\0' as u8; 100];
+            let length = parser.parse_message(command, &mut answer).await.unwrap();
+            assert_eq!(&answer[..length], *expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_command_invalid() {
+        let mut mock_persistency = MockPersistencyTrait::new();
+        expect_send_settings(&mut mock_persistency, b"", b"pulse");
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+        mock_receiver_control.expect_queue_transmission()
+            .times(1)
+            .returning(|_| Err("send queue is full, try again later"));
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        assert_eq!(parser.parse_message(b"send button 1", &mut answer).await, Err("code is neither a name in the code table nor a hex number"));
+        assert_eq!(parser.parse_message(b"send 0x1C5103", &mut answer).await, Err("send queue is full, try again later"));
+    }
+
+    #[tokio::test]
+    async fn test_capture_command_not_available() {
+        let mock_persistency = MockPersistencyTrait::new();
+
+        let mut mock_receiver_control = MockReceiverControlTrait::new();
+        mock_receiver_control.expect_start_capture()
+            .times(1)
+            .returning(|| Err("capture needs a running receiver with receiver_mode pulse"));
+
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
+
+        let mut answer = ['\0' as u8; 100];
+        match parser.parse_message(b"capture start", &mut answer).await {
+            Ok(_) => assert!(false),
+            Err(msg) => assert_eq!(msg, "capture needs a running receiver with receiver_mode pulse"),
+        }
+    }
+
     #[tokio::test]
     async fn test_nothing_to_parse() {
         let mut mock_persistency = MockPersistencyTrait::new();
@@ -280,7 +1792,8 @@ mod tests {
         mock_persistency.expect_read().never();
         mock_persistency.expect_store().never();
 
-        let mut parser = Parser::new(&mock_persistency);
+        let mock_receiver_control = MockReceiverControlTrait::new();
+        let mut parser = Parser::new(&mock_persistency, &mock_receiver_control);
 
         let mut answer = ['\0' as u8; 300];
         match parser.parse_message(b"no command", &mut answer).await {
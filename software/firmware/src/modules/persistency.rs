@@ -13,12 +13,25 @@ use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 const FLASH_SIZE: usize = 2*1024*1024; // 2MB is valid for Raspberry Pi Pico.
 const DATA_SIZE: usize = flash::ERASE_SIZE; // must be a multiple of ERASE_SIZE.
 const DATA_ADDRESS_OFFSET: usize = FLASH_SIZE - flash::ERASE_SIZE; // put data at the end of flash memory.
-const FILE_DESCRIPTOR_SIZE: usize = 5;
+const FILE_DESCRIPTOR_SIZE: usize = 25;
+// The data starts with the version of its layout, followed by the file descriptor
+// with the length of each value in two bytes, little endian.
+const HEADER_SIZE: usize = 1 + 2 * FILE_DESCRIPTOR_SIZE;
+// Versions count down from here, so they are taken neither for erased flash
+// nor for the length of the Wi-Fi SSID (at most 32 bytes) that the first layout starts with.
+const LAYOUT_VERSION: u8 = 0xFD;
+// The previous layout stored the lengths in one byte, which limited each value to 254 bytes.
+const BYTE_LENGTHS_LAYOUT_VERSION: u8 = 0xFE;
+// The first layout had no version and a file descriptor of the five credentials only.
+const FIRST_LAYOUT_DESCRIPTOR_SIZE: usize = 5;
+const ERASED: u8 = 0xFF;
+const ERASED_LENGTH: u16 = 0xFFFF;
+const MAX_VALUE_LENGTH: usize = DATA_SIZE - HEADER_SIZE;
 
 
 #[cfg_attr(test, mockall::automock)]
 pub trait PersistencyTrait{
-    async fn store<'a>(&'a self, value: &'a [u8], field: ValueId);
+    async fn store<'a>(&'a self, value: &'a [u8], field: ValueId) -> Result<(), &'static str>;
     async fn read<'a>(&'a self, field: ValueId, answer: &'a mut [u8]) -> Result<usize, &'static str>;
 }
 
@@ -34,6 +47,14 @@ impl Persistency {
         let persistency = PersistencyUnprotected::new(flash, dma);
         Self { persistency_mutexed: PersistencyMutexed::new(persistency) }
     }
+
+    /// Converts data stored by an older firmware to the current layout.
+    /// Returns true if there was such data, so the caller can add what older firmwares had built in.
+    #[cfg(not(test))]
+    pub async fn upgrade(&self) -> bool {
+        let mut persistency = self.persistency_mutexed.lock().await;
+        persistency.upgrade()
+    }
 }
 
 impl PersistencyTrait for Persistency {
@@ -42,9 +63,9 @@ impl PersistencyTrait for Persistency {
         persistency.read(value_id, answer)
     }
 
-    async fn store(&self, value_data: &[u8], value_id: ValueId) {
+    async fn store(&self, value_data: &[u8], value_id: ValueId) -> Result<(), &'static str> {
         let mut persistency = self.persistency_mutexed.lock().await;
-        persistency.store(value_data, value_id);
+        persistency.store(value_data, value_id)
     }
 }
 
@@ -76,29 +97,38 @@ impl PersistencyUnprotected{
         }
     }
 
-    fn store(&mut self, value_data: &[u8], value_id: ValueId) {
+    fn store(&mut self, value_data: &[u8], value_id: ValueId) -> Result<(), &'static str> {
         self.read_all();
 
-        self.filesystem.update_values(&value_id, value_data);
+        self.filesystem.update_values(&value_id, value_data)?;
+        self.write_all();
+        Ok(())
+    }
 
-        self.flash.blocking_erase(DATA_ADDRESS_OFFSET as u32, (DATA_ADDRESS_OFFSET + DATA_SIZE) as u32).expect("Failed to erase flash memory.");
-        self.flash.blocking_write(DATA_ADDRESS_OFFSET as u32, &self.filesystem.data).expect("Failed to write flash memory.");
+    fn upgrade(&mut self) -> bool {
+        let converted = self.read_all();
+        if converted {
+            self.write_all();
+        }
+        converted
     }
 
-    fn read_all(&mut self) {
+    // Returns true if the data was stored in an older layout, it is converted in memory only.
+    fn read_all(&mut self) -> bool {
         self.flash.blocking_read(DATA_ADDRESS_OFFSET as u32, &mut self.filesystem.data).expect("failed to read flash memory");
 
-        for n in 0..self.filesystem.values.len() {
-            self.filesystem.values[n].length = self.filesystem.data[n];
-        }
+        self.filesystem.load()
+    }
 
-        self.filesystem.update_values_indexes();
+    fn write_all(&mut self) {
+        self.flash.blocking_erase(DATA_ADDRESS_OFFSET as u32, (DATA_ADDRESS_OFFSET + DATA_SIZE) as u32).expect("Failed to erase flash memory.");
+        self.flash.blocking_write(DATA_ADDRESS_OFFSET as u32, &self.filesystem.data).expect("Failed to write flash memory.");
     }
 }
 
 struct Value {
     id: ValueId,
-    length: u8,
+    length: u16,
     index: usize,
 }
 
@@ -112,13 +142,33 @@ impl Value {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum ValueId {
     WifiSsid,
     WifiPassword,
     MqttHostIp,
     MqttBrokerUsername,
     MqttBrokerPassword,
+    CodeTable,
+    ReceiverMode,
+    ReleaseGapMs,
+    ClickWindowMs,
+    ConfirmFrames,
+    ConfirmGapMs,
+    LockoutMs,
+    ClockDivider,
+    SyncGapUs,
+    SampleDelayUs,
+    GlitchFilterUs,
+    Entities,
+    TxPin,
+    TxRepeats,
+    Radio,
+    RadioFrequencyKhz,
+    RadioModulation,
+    Receivers,
+    SignalQuality,
+    FlexDecoders,
 }
 
 struct Filesystem {
@@ -135,15 +185,117 @@ impl Filesystem {
                 Value::new(ValueId::MqttHostIp),
                 Value::new(ValueId::MqttBrokerUsername),
                 Value::new(ValueId::MqttBrokerPassword),
+                Value::new(ValueId::CodeTable),
+                Value::new(ValueId::ReceiverMode),
+                Value::new(ValueId::ReleaseGapMs),
+                Value::new(ValueId::ClickWindowMs),
+                Value::new(ValueId::ConfirmFrames),
+                Value::new(ValueId::ConfirmGapMs),
+                Value::new(ValueId::LockoutMs),
+                Value::new(ValueId::ClockDivider),
+                Value::new(ValueId::SyncGapUs),
+                Value::new(ValueId::SampleDelayUs),
+                Value::new(ValueId::GlitchFilterUs),
+                Value::new(ValueId::Entities),
+                Value::new(ValueId::TxPin),
+                Value::new(ValueId::TxRepeats),
+                Value::new(ValueId::Radio),
+                Value::new(ValueId::RadioFrequencyKhz),
+                Value::new(ValueId::RadioModulation),
+                Value::new(ValueId::Receivers),
+                Value::new(ValueId::SignalQuality),
+                Value::new(ValueId::FlexDecoders),
             ],
-            data: [0; DATA_SIZE],
+            data: core::array::from_fn(|n| if n == 0 { LAYOUT_VERSION } else { 0 }),
+        }
+    }
+
+    // Returns true if the data was in an older layout and has been converted.
+    fn load(&mut self) -> bool {
+        if self.data[0] == LAYOUT_VERSION {
+            self.load_descriptor();
+            return false;
         }
+        if self.data[0] == BYTE_LENGTHS_LAYOUT_VERSION {
+            // Erased lengths were taken as empty values, no bytes were left for them.
+            self.convert_byte_lengths(1..(1 + FILE_DESCRIPTOR_SIZE), 0);
+            return true;
+        }
+        if self.data[..FIRST_LAYOUT_DESCRIPTOR_SIZE].iter().all(|length| *length == ERASED) {
+            // Nothing was ever stored.
+            self.data[0] = LAYOUT_VERSION;
+            self.data[1..HEADER_SIZE].fill(0);
+            self.load_descriptor();
+            return false;
+        }
+        // The values that were never stored still have the length 0xFF of the erased flash, and so many bytes were left for them.
+        self.convert_byte_lengths(0..FIRST_LAYOUT_DESCRIPTOR_SIZE, ERASED as usize);
+        true
+    }
+
+    fn stored_length(&self, n: usize) -> u16 {
+        u16::from_le_bytes([self.data[1 + 2 * n], self.data[2 + 2 * n]])
+    }
+
+    fn set_stored_length(&mut self, n: usize, length: u16) {
+        self.data[(1 + 2 * n)..(3 + 2 * n)].copy_from_slice(&length.to_le_bytes());
+    }
+
+    // Lengths that are erased or reach beyond the data are taken as empty values,
+    // so a damaged descriptor can't make reading or storing go beyond the data.
+    fn load_descriptor(&mut self) {
+        let mut index = HEADER_SIZE;
+        for n in 0..self.values.len() {
+            let mut length = self.stored_length(n);
+            if length == ERASED_LENGTH || index + length as usize > DATA_SIZE {
+                length = 0;
+                self.set_stored_length(n, 0);
+            }
+            self.values[n].length = length;
+            self.values[n].index = index;
+            index += length as usize;
+        }
+    }
+
+    // Converts a layout with the lengths in one byte: the values are moved behind the larger header,
+    // the ones with an erased length are dropped, as well as the ones that do not fit anymore.
+    fn convert_byte_lengths(&mut self, descriptor: core::ops::Range<usize>, erased_size: usize) {
+        let mut lengths = [0u8; FILE_DESCRIPTOR_SIZE];
+        let lengths = &mut lengths[..descriptor.len()];
+        lengths.copy_from_slice(&self.data[descriptor.clone()]);
+
+        let mut source = descriptor.end;
+        let mut end = descriptor.end;
+        for length in lengths.iter_mut() {
+            let value_length = *length as usize;
+            if *length == ERASED {
+                source += erased_size;
+                *length = 0;
+                continue;
+            }
+            if source + value_length > DATA_SIZE || HEADER_SIZE + (end - descriptor.end) + value_length > DATA_SIZE {
+                source += value_length;
+                *length = 0;
+                continue;
+            }
+            self.data.copy_within(source..(source + value_length), end);
+            source += value_length;
+            end += value_length;
+        }
+
+        self.data.copy_within(descriptor.end..end, HEADER_SIZE);
+        self.data[0] = LAYOUT_VERSION;
+        self.data[1..HEADER_SIZE].fill(0);
+        for (n, length) in lengths.iter().enumerate() {
+            self.set_stored_length(n, *length as u16);
+        }
+        self.load_descriptor();
     }
 
     fn update_values_indexes(&mut self) {
         for n in 0..self.values.len() {
             if n == 0 {
-                self.values[n].index = FILE_DESCRIPTOR_SIZE;
+                self.values[n].index = HEADER_SIZE;
             } else {
                 self.values[n].index = self.values[n-1].index + self.values[n-1].length as usize;
             }
@@ -159,10 +311,18 @@ impl Filesystem {
         panic!("value not found");
     }
 
-    fn update_values(&mut self, value_id: &ValueId, value_data: &[u8]) {
+    fn update_values(&mut self, value_id: &ValueId, value_data: &[u8]) -> Result<(), &'static str> {
         let new_length = value_data.len();
         let (length, index) = self.get_length_and_index(value_id);
 
+        if new_length > MAX_VALUE_LENGTH {
+            return Err("value is too long");
+        }
+        let last = &self.values[self.values.len() - 1];
+        if last.index + last.length as usize - length + new_length > DATA_SIZE {
+            return Err("no space left for the value");
+        }
+
         // shift old values so the new ones fit
         if new_length > length {
             let offset = new_length - length;
@@ -179,13 +339,13 @@ impl Filesystem {
         // update value data
         for n in 0..self.values.len() {
             if self.values[n].id == *value_id {
-                self.values[n].length = new_length as u8;
-                self.data[n] = new_length as u8;
+                self.values[n].length = new_length as u16;
+                self.set_stored_length(n, new_length as u16);
                 self.update_values_indexes();
 
                 let (_, index) = self.get_length_and_index(value_id);
                 self.data[index..index+value_data.len()].copy_from_slice(value_data);
-                return;
+                return Ok(());
             }
         }
         panic!("value not found");
@@ -194,7 +354,7 @@ impl Filesystem {
 
 #[cfg(test)]
 mod tests {
-    use super::FILE_DESCRIPTOR_SIZE;
+    use super::HEADER_SIZE;
     use super::ValueId;
 
     #[test]
@@ -211,14 +371,74 @@ mod tests {
         f.values[3].index = 0;
         f.values[4].length = 9;
         f.values[4].index = 0;
+        f.values[5].length = 14;
+        f.values[5].index = 0;
+        f.values[6].length = 5;
+        f.values[6].index = 0;
+        f.values[7].length = 3;
+        f.values[7].index = 0;
+        f.values[8].length = 3;
+        f.values[8].index = 0;
+        f.values[9].length = 1;
+        f.values[9].index = 0;
+        f.values[10].length = 3;
+        f.values[10].index = 0;
+        f.values[11].length = 3;
+        f.values[11].index = 0;
+        f.values[12].length = 5;
+        f.values[12].index = 0;
+        f.values[13].length = 4;
+        f.values[13].index = 0;
+        f.values[14].length = 3;
+        f.values[14].index = 0;
+        f.values[15].length = 3;
+        f.values[15].index = 0;
+        f.values[16].length = 20;
+        f.values[16].index = 0;
+        f.values[17].length = 2;
+        f.values[17].index = 0;
+        f.values[18].length = 2;
+        f.values[18].index = 0;
+        f.values[19].length = 6;
+        f.values[19].index = 0;
+        f.values[20].length = 6;
+        f.values[20].index = 0;
+        f.values[21].length = 3;
+        f.values[21].index = 0;
+        f.values[22].length = 7;
+        f.values[22].index = 0;
+        f.values[23].length = 3;
+        f.values[23].index = 0;
+        f.values[24].length = 40;
+        f.values[24].index = 0;
 
         f.update_values_indexes();
 
-        assert_eq!(f.values[0].index, FILE_DESCRIPTOR_SIZE);
-        assert_eq!(f.values[1].index, FILE_DESCRIPTOR_SIZE+5);
-        assert_eq!(f.values[2].index, FILE_DESCRIPTOR_SIZE+5+25);
-        assert_eq!(f.values[3].index, FILE_DESCRIPTOR_SIZE+5+25+42);
-        assert_eq!(f.values[4].index, FILE_DESCRIPTOR_SIZE+5+25+42+68);
+        assert_eq!(f.values[0].index, HEADER_SIZE);
+        assert_eq!(f.values[1].index, HEADER_SIZE+5);
+        assert_eq!(f.values[2].index, HEADER_SIZE+5+25);
+        assert_eq!(f.values[3].index, HEADER_SIZE+5+25+42);
+        assert_eq!(f.values[4].index, HEADER_SIZE+5+25+42+68);
+        assert_eq!(f.values[5].index, HEADER_SIZE+5+25+42+68+9);
+        assert_eq!(f.values[6].index, HEADER_SIZE+5+25+42+68+9+14);
+        assert_eq!(f.values[7].index, HEADER_SIZE+5+25+42+68+9+14+5);
+        assert_eq!(f.values[8].index, HEADER_SIZE+5+25+42+68+9+14+5+3);
+        assert_eq!(f.values[9].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3);
+        assert_eq!(f.values[10].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1);
+        assert_eq!(f.values[11].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3);
+        assert_eq!(f.values[12].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3+3);
+        assert_eq!(f.values[13].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3+3+5);
+        assert_eq!(f.values[14].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3+3+5+4);
+        assert_eq!(f.values[15].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3+3+5+4+3);
+        assert_eq!(f.values[16].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3+3+5+4+3+3);
+        assert_eq!(f.values[17].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3+3+5+4+3+3+20);
+        assert_eq!(f.values[18].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3+3+5+4+3+3+20+2);
+        assert_eq!(f.values[19].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3+3+5+4+3+3+20+2+2);
+        assert_eq!(f.values[20].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3+3+5+4+3+3+20+2+2+6);
+        assert_eq!(f.values[21].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3+3+5+4+3+3+20+2+2+6+6);
+        assert_eq!(f.values[22].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3+3+5+4+3+3+20+2+2+6+6+3);
+        assert_eq!(f.values[23].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3+3+5+4+3+3+20+2+2+6+6+3+7);
+        assert_eq!(f.values[24].index, HEADER_SIZE+5+25+42+68+9+14+5+3+3+1+3+3+5+4+3+3+20+2+2+6+6+3+7+3);
     }
 
     #[test]
@@ -235,6 +455,46 @@ mod tests {
         f.values[3].index  = 8;
         f.values[4].length = 9;
         f.values[4].index = 10;
+        f.values[5].length = 11;
+        f.values[5].index = 12;
+        f.values[6].length = 13;
+        f.values[6].index = 14;
+        f.values[7].length = 15;
+        f.values[7].index = 16;
+        f.values[8].length = 17;
+        f.values[8].index = 18;
+        f.values[9].length = 19;
+        f.values[9].index = 20;
+        f.values[10].length = 21;
+        f.values[10].index = 22;
+        f.values[11].length = 23;
+        f.values[11].index = 24;
+        f.values[12].length = 25;
+        f.values[12].index = 26;
+        f.values[13].length = 27;
+        f.values[13].index = 28;
+        f.values[14].length = 29;
+        f.values[14].index = 30;
+        f.values[15].length = 31;
+        f.values[15].index = 32;
+        f.values[16].length = 33;
+        f.values[16].index = 34;
+        f.values[17].length = 35;
+        f.values[17].index = 36;
+        f.values[18].length = 37;
+        f.values[18].index = 38;
+        f.values[19].length = 39;
+        f.values[19].index = 40;
+        f.values[20].length = 41;
+        f.values[20].index = 42;
+        f.values[21].length = 43;
+        f.values[21].index = 44;
+        f.values[22].length = 45;
+        f.values[22].index = 46;
+        f.values[23].length = 47;
+        f.values[23].index = 48;
+        f.values[24].length = 49;
+        f.values[24].index = 50;
 
         let (l, i) = f.get_length_and_index(&ValueId::WifiSsid);
         assert_eq!(l, 1);
@@ -254,37 +514,157 @@ mod tests {
         let (l, i) = f.get_length_and_index(&ValueId::MqttBrokerPassword);
         assert_eq!(l, 9);
         assert_eq!(i, 10);
+        let (l, i) = f.get_length_and_index(&ValueId::CodeTable);
+        assert_eq!(l, 11);
+        assert_eq!(i, 12);
+        let (l, i) = f.get_length_and_index(&ValueId::ReceiverMode);
+        assert_eq!(l, 13);
+        assert_eq!(i, 14);
+        let (l, i) = f.get_length_and_index(&ValueId::ReleaseGapMs);
+        assert_eq!(l, 15);
+        assert_eq!(i, 16);
+        let (l, i) = f.get_length_and_index(&ValueId::ClickWindowMs);
+        assert_eq!(l, 17);
+        assert_eq!(i, 18);
+        let (l, i) = f.get_length_and_index(&ValueId::ConfirmFrames);
+        assert_eq!(l, 19);
+        assert_eq!(i, 20);
+        let (l, i) = f.get_length_and_index(&ValueId::ConfirmGapMs);
+        assert_eq!(l, 21);
+        assert_eq!(i, 22);
+        let (l, i) = f.get_length_and_index(&ValueId::LockoutMs);
+        assert_eq!(l, 23);
+        assert_eq!(i, 24);
+        let (l, i) = f.get_length_and_index(&ValueId::ClockDivider);
+        assert_eq!(l, 25);
+        assert_eq!(i, 26);
+        let (l, i) = f.get_length_and_index(&ValueId::SyncGapUs);
+        assert_eq!(l, 27);
+        assert_eq!(i, 28);
+        let (l, i) = f.get_length_and_index(&ValueId::SampleDelayUs);
+        assert_eq!(l, 29);
+        assert_eq!(i, 30);
+        let (l, i) = f.get_length_and_index(&ValueId::GlitchFilterUs);
+        assert_eq!(l, 31);
+        assert_eq!(i, 32);
+        let (l, i) = f.get_length_and_index(&ValueId::Entities);
+        assert_eq!(l, 33);
+        assert_eq!(i, 34);
+        let (l, i) = f.get_length_and_index(&ValueId::TxPin);
+        assert_eq!(l, 35);
+        assert_eq!(i, 36);
+        let (l, i) = f.get_length_and_index(&ValueId::TxRepeats);
+        assert_eq!(l, 37);
+        assert_eq!(i, 38);
+        let (l, i) = f.get_length_and_index(&ValueId::Radio);
+        assert_eq!(l, 39);
+        assert_eq!(i, 40);
+        let (l, i) = f.get_length_and_index(&ValueId::RadioFrequencyKhz);
+        assert_eq!(l, 41);
+        assert_eq!(i, 42);
+        let (l, i) = f.get_length_and_index(&ValueId::RadioModulation);
+        assert_eq!(l, 43);
+        assert_eq!(i, 44);
+        let (l, i) = f.get_length_and_index(&ValueId::Receivers);
+        assert_eq!(l, 45);
+        assert_eq!(i, 46);
+        let (l, i) = f.get_length_and_index(&ValueId::SignalQuality);
+        assert_eq!(l, 47);
+        assert_eq!(i, 48);
+        let (l, i) = f.get_length_and_index(&ValueId::FlexDecoders);
+        assert_eq!(l, 49);
+        assert_eq!(i, 50);
     }
 
     #[test]
     fn test_update_values() {
         let mut f = super::Filesystem::new();
 
-        assert_eq!(f.values.len(), 5);
+        assert_eq!(f.values.len(), 25);
 
-        let value_data: [&[u8]; 5] = [
+        let value_data: [&[u8]; 25] = [
             b"my_wifi_ssid",
             b"my_wifi_password",
             b"my_mqtt_host_ip",
             b"my_mqtt_broker_username",
             b"mqtt_broker_password",
+            b"\x90\x9E\x7E\x01\x08button 1",
+            b"pulse",
+            b"250",
+            b"400",
+            b"3",
+            b"200",
+            b"500",
+            b"12500",
+            b"6400",
+            b"600",
+            b"100",
+            b"\x00entity data",
+            b"27",
+            b"10",
+            b"cc1101",
+            b"868300",
+            b"fsk",
+            b"garage",
+            b"off",
+            b"n=doorbell,m=OOK_PWM,s=400,l=1200,r=12000",
         ];
 
-        f.update_values(&ValueId::WifiSsid, value_data[0]);
-        f.update_values(&ValueId::WifiPassword, value_data[1]);
-        f.update_values(&ValueId::MqttHostIp, value_data[2]);
-        f.update_values(&ValueId::MqttBrokerUsername, value_data[3]);
-        f.update_values(&ValueId::MqttBrokerPassword, value_data[4]);
-
-        assert_eq!(f.values[0].index, FILE_DESCRIPTOR_SIZE);
-        assert_eq!(f.values[1].index, FILE_DESCRIPTOR_SIZE + value_data[0].len());
-        assert_eq!(f.values[2].index, FILE_DESCRIPTOR_SIZE + value_data[0].len() + value_data[1].len());
-        assert_eq!(f.values[3].index, FILE_DESCRIPTOR_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len());
-        assert_eq!(f.values[4].index, FILE_DESCRIPTOR_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len());
+        f.update_values(&ValueId::WifiSsid, value_data[0]).unwrap();
+        f.update_values(&ValueId::WifiPassword, value_data[1]).unwrap();
+        f.update_values(&ValueId::MqttHostIp, value_data[2]).unwrap();
+        f.update_values(&ValueId::MqttBrokerUsername, value_data[3]).unwrap();
+        f.update_values(&ValueId::MqttBrokerPassword, value_data[4]).unwrap();
+        f.update_values(&ValueId::CodeTable, value_data[5]).unwrap();
+        f.update_values(&ValueId::ReceiverMode, value_data[6]).unwrap();
+        f.update_values(&ValueId::ReleaseGapMs, value_data[7]).unwrap();
+        f.update_values(&ValueId::ClickWindowMs, value_data[8]).unwrap();
+        f.update_values(&ValueId::ConfirmFrames, value_data[9]).unwrap();
+        f.update_values(&ValueId::ConfirmGapMs, value_data[10]).unwrap();
+        f.update_values(&ValueId::LockoutMs, value_data[11]).unwrap();
+        f.update_values(&ValueId::ClockDivider, value_data[12]).unwrap();
+        f.update_values(&ValueId::SyncGapUs, value_data[13]).unwrap();
+        f.update_values(&ValueId::SampleDelayUs, value_data[14]).unwrap();
+        f.update_values(&ValueId::GlitchFilterUs, value_data[15]).unwrap();
+        f.update_values(&ValueId::Entities, value_data[16]).unwrap();
+        f.update_values(&ValueId::TxPin, value_data[17]).unwrap();
+        f.update_values(&ValueId::TxRepeats, value_data[18]).unwrap();
+        f.update_values(&ValueId::Radio, value_data[19]).unwrap();
+        f.update_values(&ValueId::RadioFrequencyKhz, value_data[20]).unwrap();
+        f.update_values(&ValueId::RadioModulation, value_data[21]).unwrap();
+        f.update_values(&ValueId::Receivers, value_data[22]).unwrap();
+        f.update_values(&ValueId::SignalQuality, value_data[23]).unwrap();
+        f.update_values(&ValueId::FlexDecoders, value_data[24]).unwrap();
+
+        assert_eq!(f.values[0].index, HEADER_SIZE);
+        assert_eq!(f.values[1].index, HEADER_SIZE + value_data[0].len());
+        assert_eq!(f.values[2].index, HEADER_SIZE + value_data[0].len() + value_data[1].len());
+        assert_eq!(f.values[3].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len());
+        assert_eq!(f.values[4].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len());
+        assert_eq!(f.values[5].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len());
+        assert_eq!(f.values[6].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len());
+        assert_eq!(f.values[7].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len());
+        assert_eq!(f.values[8].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len());
+        assert_eq!(f.values[9].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len());
+        assert_eq!(f.values[10].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len());
+        assert_eq!(f.values[11].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len());
+        assert_eq!(f.values[12].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len() + value_data[11].len());
+        assert_eq!(f.values[13].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len() + value_data[11].len() + value_data[12].len());
+        assert_eq!(f.values[14].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len() + value_data[11].len() + value_data[12].len() + value_data[13].len());
+        assert_eq!(f.values[15].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len() + value_data[11].len() + value_data[12].len() + value_data[13].len() + value_data[14].len());
+        assert_eq!(f.values[16].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len() + value_data[11].len() + value_data[12].len() + value_data[13].len() + value_data[14].len() + value_data[15].len());
+        assert_eq!(f.values[17].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len() + value_data[11].len() + value_data[12].len() + value_data[13].len() + value_data[14].len() + value_data[15].len() + value_data[16].len());
+        assert_eq!(f.values[18].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len() + value_data[11].len() + value_data[12].len() + value_data[13].len() + value_data[14].len() + value_data[15].len() + value_data[16].len() + value_data[17].len());
+        assert_eq!(f.values[19].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len() + value_data[11].len() + value_data[12].len() + value_data[13].len() + value_data[14].len() + value_data[15].len() + value_data[16].len() + value_data[17].len() + value_data[18].len());
+        assert_eq!(f.values[20].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len() + value_data[11].len() + value_data[12].len() + value_data[13].len() + value_data[14].len() + value_data[15].len() + value_data[16].len() + value_data[17].len() + value_data[18].len() + value_data[19].len());
+        assert_eq!(f.values[21].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len() + value_data[11].len() + value_data[12].len() + value_data[13].len() + value_data[14].len() + value_data[15].len() + value_data[16].len() + value_data[17].len() + value_data[18].len() + value_data[19].len() + value_data[20].len());
+        assert_eq!(f.values[22].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len() + value_data[11].len() + value_data[12].len() + value_data[13].len() + value_data[14].len() + value_data[15].len() + value_data[16].len() + value_data[17].len() + value_data[18].len() + value_data[19].len() + value_data[20].len() + value_data[21].len());
+        assert_eq!(f.values[23].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len() + value_data[11].len() + value_data[12].len() + value_data[13].len() + value_data[14].len() + value_data[15].len() + value_data[16].len() + value_data[17].len() + value_data[18].len() + value_data[19].len() + value_data[20].len() + value_data[21].len() + value_data[22].len());
+        assert_eq!(f.values[24].index, HEADER_SIZE + value_data[0].len() + value_data[1].len() + value_data[2].len() + value_data[3].len() + value_data[4].len() + value_data[5].len() + value_data[6].len() + value_data[7].len() + value_data[8].len() + value_data[9].len() + value_data[10].len() + value_data[11].len() + value_data[12].len() + value_data[13].len() + value_data[14].len() + value_data[15].len() + value_data[16].len() + value_data[17].len() + value_data[18].len() + value_data[19].len() + value_data[20].len() + value_data[21].len() + value_data[22].len() + value_data[23].len());
 
         for n in 0..f.values.len() {
-            assert_eq!(f.values[n].length, value_data[n].len() as u8);
-            assert_eq!(f.data[n], value_data[n].len() as u8);
+            assert_eq!(f.values[n].length, value_data[n].len() as u16);
+            assert_eq!(f.stored_length(n), value_data[n].len() as u16);
 
             let index = f.values[0].index;
             let length = f.values[0].length as usize;
@@ -292,4 +672,129 @@ mod tests {
         }
     }
 
+    // The data of the first layout: the lengths of the five credentials and the values right after them.
+    fn first_layout(values: [&[u8]; 5]) -> super::Filesystem {
+        let mut f = super::Filesystem::new();
+        f.data.fill(super::ERASED);
+        let mut index = 5;
+        for (n, value) in values.iter().enumerate() {
+            if value.is_empty() {
+                // never stored, the erased bytes are left for it
+                index += 255;
+                continue;
+            }
+            f.data[n] = value.len() as u8;
+            f.data[index..index + value.len()].copy_from_slice(value);
+            index += value.len();
+        }
+        f
+    }
+
+    fn value(f: &super::Filesystem, value_id: ValueId) -> &[u8] {
+        let (length, index) = f.get_length_and_index(&value_id);
+        &f.data[index..index + length]
+    }
+
+    #[test]
+    fn convert_first_layout() {
+        let mut f = first_layout([b"my_wifi_ssid", b"my_wifi_password", b"192.168.1.2", b"", b"secret"]);
+
+        assert!(f.load());
+        assert_eq!(f.data[0], super::LAYOUT_VERSION);
+        assert_eq!(value(&f, ValueId::WifiSsid), b"my_wifi_ssid");
+        assert_eq!(value(&f, ValueId::WifiPassword), b"my_wifi_password");
+        assert_eq!(value(&f, ValueId::MqttHostIp), b"192.168.1.2");
+        assert_eq!(value(&f, ValueId::MqttBrokerUsername), b"");
+        assert_eq!(value(&f, ValueId::MqttBrokerPassword), b"secret");
+        assert_eq!(value(&f, ValueId::CodeTable), b"");
+        assert_eq!(value(&f, ValueId::FlexDecoders), b"");
+
+        // once converted, it is taken as it is
+        assert!(!f.load());
+        assert_eq!(value(&f, ValueId::MqttBrokerPassword), b"secret");
+    }
+
+    #[test]
+    fn convert_byte_lengths_layout() {
+        let mut f = super::Filesystem::new();
+        f.data[0] = super::BYTE_LENGTHS_LAYOUT_VERSION;
+        f.data[1..26].fill(0);
+        f.data[1] = 12; // Wi-Fi SSID
+        f.data[1 + 5] = 10; // code table
+        f.data[1 + 24] = super::ERASED; // flex decoders, written by a firmware with fewer values
+        f.data[26..38].copy_from_slice(b"my_wifi_ssid");
+        f.data[38..48].copy_from_slice(b"\x90\x9E\x7E\x01\x00\x19\x03abc");
+
+        assert!(f.load());
+        assert_eq!(f.data[0], super::LAYOUT_VERSION);
+        assert_eq!(value(&f, ValueId::WifiSsid), b"my_wifi_ssid");
+        assert_eq!(value(&f, ValueId::CodeTable), b"\x90\x9E\x7E\x01\x00\x19\x03abc");
+        assert_eq!(value(&f, ValueId::Entities), b"");
+        assert_eq!(value(&f, ValueId::FlexDecoders), b"");
+
+        assert!(!f.load());
+        assert_eq!(value(&f, ValueId::CodeTable), b"\x90\x9E\x7E\x01\x00\x19\x03abc");
+    }
+
+    #[test]
+    fn erased_flash() {
+        let mut f = super::Filesystem::new();
+        f.data.fill(super::ERASED);
+
+        assert!(!f.load());
+        assert_eq!(value(&f, ValueId::WifiSsid), b"");
+        assert_eq!(value(&f, ValueId::FlexDecoders), b"");
+
+        f.update_values(&ValueId::Entities, b"entity data").unwrap();
+        assert!(!f.load());
+        assert_eq!(value(&f, ValueId::Entities), b"entity data");
+    }
+
+    #[test]
+    fn erased_lengths_in_the_current_layout() {
+        // e.g. the descriptor grew while the data was written by a firmware with fewer values
+        let mut f = super::Filesystem::new();
+        f.update_values(&ValueId::WifiSsid, b"my_wifi_ssid").unwrap();
+        f.data[1 + 2 * 16..HEADER_SIZE].fill(super::ERASED);
+
+        f.load();
+        assert_eq!(value(&f, ValueId::WifiSsid), b"my_wifi_ssid");
+        assert_eq!(value(&f, ValueId::Entities), b"");
+        assert_eq!(value(&f, ValueId::FlexDecoders), b"");
+
+        f.update_values(&ValueId::Receivers, b"garage").unwrap();
+        assert_eq!(value(&f, ValueId::Receivers), b"garage");
+        assert_eq!(value(&f, ValueId::WifiSsid), b"my_wifi_ssid");
+    }
+
+    #[test]
+    fn lengths_beyond_the_data() {
+        let mut f = super::Filesystem::new();
+        for n in 0..f.values.len() {
+            f.set_stored_length(n, super::MAX_VALUE_LENGTH as u16);
+        }
+
+        f.load();
+        let (length, index) = f.get_length_and_index(&ValueId::FlexDecoders);
+        assert_eq!(length, 0);
+        assert!(index <= super::DATA_SIZE);
+        assert_eq!(value(&f, ValueId::WifiSsid).len(), super::MAX_VALUE_LENGTH);
+    }
+
+    #[test]
+    fn values_that_do_not_fit() {
+        let mut f = super::Filesystem::new();
+        let long_value = [b'x'; super::MAX_VALUE_LENGTH + 1];
+
+        assert_eq!(f.update_values(&ValueId::Entities, &long_value), Err("value is too long"));
+        assert_eq!(value(&f, ValueId::Entities), b"");
+
+        // a single value can take all of the data, the next one then does not fit anymore
+        f.update_values(&ValueId::CodeTable, &long_value[10..]).unwrap();
+        assert_eq!(f.update_values(&ValueId::Entities, &long_value[..10]), Err("no space left for the value"));
+        assert_eq!(value(&f, ValueId::Entities), b"");
+        f.update_values(&ValueId::Entities, &long_value[..9]).unwrap();
+        assert_eq!(value(&f, ValueId::CodeTable), &long_value[10..]);
+        assert_eq!(value(&f, ValueId::Entities), &long_value[..9]);
+    }
 }
@@ -0,0 +1,166 @@
+//! Streams raw pulses of the receiver to the terminal.
+//! The output is in the OOK pulse data format of rtl_433 (.ook files), so it can be analyzed offline,
+//! e.g. with 'rtl_433 -r capture.ook -A'.
+
+use cfg_if::cfg_if;
+use core::fmt::Write;
+use heapless::{String, Vec};
+
+cfg_if! {
+    if #[cfg(not(test))] {
+        use embassy_executor::task;
+
+        use crate::modules::receiver_control::ReceiverControl;
+        use crate::modules::usb_communication::UsbSender;
+    }
+}
+
+// A gap longer than this ends a packet.
+const RESET_LIMIT_US: u32 = 20_000;
+const MAX_PACKET_PULSES: usize = 128;
+
+pub const FILE_HEADER: &str = ";pulse data\n;version 1\n;timescale 1us\n";
+pub const PACKET_END: &str = ";end\n";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Pulse {
+    pub pulse_us: u32, // high time
+    pub gap_us: u32, // following low time
+}
+
+pub struct Packet {
+    pulses: Vec<Pulse, MAX_PACKET_PULSES>,
+}
+
+impl Packet {
+    pub fn new() -> Self {
+        Self { pulses: Vec::new() }
+    }
+
+    /// Returns true if the packet is complete and should be written out.
+    pub fn push(&mut self, pulse: Pulse) -> bool {
+        // There is always space, as a full packet is complete and must be cleared before the next push.
+        self.pulses.push(pulse).unwrap();
+        pulse.gap_us > RESET_LIMIT_US || self.pulses.is_full()
+    }
+
+    pub fn clear(&mut self) {
+        self.pulses.clear();
+    }
+
+    pub fn pulses(&self) -> &[Pulse] {
+        &self.pulses
+    }
+
+    /// The frequency is left out if it is not known, as with the plain receiver module.
+    pub fn header(&self, frequency_hz: Option<u32>, dropped_pulses: u32) -> String<64> {
+        let mut header = String::new();
+        writeln!(header, ";ook {} pulses", self.pulses.len()).unwrap();
+        if let Some(frequency_hz) = frequency_hz {
+            writeln!(header, ";freq1 {}", frequency_hz).unwrap();
+        }
+        if dropped_pulses > 0 {
+            // Not part of the format, rtl_433 ignores unknown comments.
+            writeln!(header, ";dropped {} pulses", dropped_pulses).unwrap();
+        }
+        header
+    }
+}
+
+pub fn pulse_line(pulse: &Pulse) -> String<24> {
+    let mut line = String::new();
+    writeln!(line, "{} {}", pulse.pulse_us, pulse.gap_us).unwrap();
+    line
+}
+
+#[cfg(not(test))]
+#[task]
+pub async fn run(receiver_control: &'static ReceiverControl, usb_sender: &'static UsbSender, frequency_hz: Option<u32>) -> ! {
+    let mut packet = Packet::new();
+
+    // Errors are ignored, the data is lost if no terminal is connected.
+    loop {
+        let pulse = receiver_control.captured_pulse().await;
+
+        if receiver_control.take_capture_started() {
+            packet.clear();
+            let _ = usb_sender.send(FILE_HEADER.as_bytes()).await;
+        }
+
+        if packet.push(pulse) {
+            let header = packet.header(frequency_hz, receiver_control.take_dropped_pulses());
+            let _ = usb_sender.send(header.as_bytes()).await;
+            for pulse in packet.pulses() {
+                let _ = usb_sender.send(pulse_line(pulse).as_bytes()).await;
+            }
+            let _ = usb_sender.send(PACKET_END.as_bytes()).await;
+            packet.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_ends_with_reset_gap() {
+        let mut packet = Packet::new();
+
+        assert!(!packet.push(Pulse { pulse_us: 350, gap_us: 10_850 }));
+        assert!(!packet.push(Pulse { pulse_us: 1050, gap_us: 350 }));
+        assert!(packet.push(Pulse { pulse_us: 350, gap_us: 100_000 }));
+        assert_eq!(packet.pulses().len(), 3);
+
+        packet.clear();
+        assert_eq!(packet.pulses().len(), 0);
+    }
+
+    #[test]
+    fn packet_ends_when_full() {
+        let mut packet = Packet::new();
+
+        for _ in 0..MAX_PACKET_PULSES - 1 {
+            assert!(!packet.push(Pulse { pulse_us: 350, gap_us: 1050 }));
+        }
+        assert!(packet.push(Pulse { pulse_us: 350, gap_us: 1050 }));
+        assert_eq!(packet.pulses().len(), MAX_PACKET_PULSES);
+    }
+
+    #[test]
+    fn format() {
+        let mut packet = Packet::new();
+        let _ = packet.push(Pulse { pulse_us: 352, gap_us: 10_846 });
+        let _ = packet.push(Pulse { pulse_us: 1048, gap_us: 355 });
+        let _ = packet.push(Pulse { pulse_us: 349, gap_us: 4_294_967_295 });
+
+        let mut text = std::string::String::new();
+        text.push_str(FILE_HEADER);
+        text.push_str(&packet.header(Some(433_920_000), 0));
+        for pulse in packet.pulses() {
+            text.push_str(&pulse_line(pulse));
+        }
+        text.push_str(PACKET_END);
+
+        assert_eq!(text, concat!(
+            ";pulse data\n",
+            ";version 1\n",
+            ";timescale 1us\n",
+            ";ook 3 pulses\n",
+            ";freq1 433920000\n",
+            "352 10846\n",
+            "1048 355\n",
+            "349 4294967295\n",
+            ";end\n",
+        ));
+    }
+
+    #[test]
+    fn header_with_dropped_pulses() {
+        let mut packet = Packet::new();
+        let _ = packet.push(Pulse { pulse_us: 352, gap_us: 10_846 });
+
+        assert_eq!(packet.header(Some(868_300_000), 17).as_str(), ";ook 1 pulses\n;freq1 868300000\n;dropped 17 pulses\n");
+        assert_eq!(packet.header(None, 17).as_str(), ";ook 1 pulses\n;dropped 17 pulses\n");
+    }
+}
@@ -0,0 +1,423 @@
+//! Decodes pulse durations into frames of the rc-switch protocol family.
+//! The protocol timings are taken from the rc-switch library (https://github.com/sui77/rc-switch).
+//! The base time unit of a frame is derived from its sync gap, so only the ratios of the timings are needed.
+//! Frames are encoded for sending with the base time units of rc-switch.
+
+use heapless::Vec;
+
+use crate::modules::decoder::Decoder;
+
+// Low periods longer than this separate two frames (same value as rc-switch uses).
+const SEPARATION_LIMIT_US: u32 = 4300;
+
+// Allowed deviation of a pulse from its nominal length in percent of the base time unit.
+const TOLERANCE_PERCENT: u32 = 60;
+
+const MIN_BIT_COUNT: usize = 8;
+const MAX_BIT_COUNT: usize = 32;
+
+// The buffer holds the sync pulse of the previous frame, the data bits and the sync pulse of the next frame.
+const MAX_DURATIONS: usize = 2 * MAX_BIT_COUNT + 4;
+
+struct HighLow {
+    high: u32,
+    low: u32,
+}
+
+struct Protocol {
+    sync: HighLow,
+    zero: HighLow,
+    one: HighLow,
+    inverted: bool,
+}
+
+const fn protocol(sync: (u32, u32), zero: (u32, u32), one: (u32, u32), inverted: bool) -> Protocol {
+    Protocol {
+        sync: HighLow { high: sync.0, low: sync.1 },
+        zero: HighLow { high: zero.0, low: zero.1 },
+        one: HighLow { high: one.0, low: one.1 },
+        inverted,
+    }
+}
+
+const PROTOCOLS: [Protocol; 12] = [
+    protocol((1, 31), (1, 3), (3, 1), false),
+    protocol((1, 10), (1, 2), (2, 1), false),
+    protocol((30, 71), (4, 11), (9, 6), false),
+    protocol((1, 6), (1, 3), (3, 1), false),
+    protocol((6, 14), (1, 2), (2, 1), false),
+    protocol((23, 1), (1, 2), (2, 1), true), // HT6P20B
+    protocol((2, 62), (1, 6), (6, 1), false), // HS2303-PT
+    protocol((3, 130), (7, 16), (3, 16), false), // Conrad RS-200 RX
+    protocol((130, 7), (16, 7), (16, 3), true), // Conrad RS-200 TX
+    protocol((18, 1), (3, 1), (1, 3), true), // 1ByOne Doorbell
+    protocol((36, 1), (1, 2), (2, 1), true), // HT12E
+    protocol((36, 1), (1, 2), (2, 1), true), // SM5212
+];
+
+// Base time units of the protocols in microseconds, only needed for sending.
+const PULSE_LENGTHS_US: [u32; 12] = [350, 650, 100, 380, 500, 450, 150, 200, 200, 365, 270, 320];
+
+// The data bits and the sync pulse, each of them a high and a low level.
+const MAX_LEVELS: usize = 2 * (MAX_BIT_COUNT + 1);
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Frame {
+    pub protocol: u8, // numbered from 1 like in rc-switch
+    pub value: u32,
+    pub bit_count: u8,
+}
+
+/// Returns the (level, duration) pairs of one frame like rc-switch sends it, the data bits followed by the sync pulse.
+/// Inverted protocols start with a low level.
+pub fn encode(frame: &Frame) -> Result<Vec<(bool, u32), MAX_LEVELS>, &'static str> {
+    let index = (frame.protocol as usize).checked_sub(1).filter(|index| *index < PROTOCOLS.len()).ok_or("protocol must be between 1 and 12")?;
+    if !(MIN_BIT_COUNT..=MAX_BIT_COUNT).contains(&(frame.bit_count as usize)) {
+        return Err("bit count must be between 8 and 32");
+    }
+    if frame.value.checked_shr(frame.bit_count as u32).is_some_and(|rest| rest != 0) {
+        return Err("code has more bits than the bit count");
+    }
+    let protocol = &PROTOCOLS[index];
+    let pulse_length_us = PULSE_LENGTHS_US[index];
+
+    let mut levels = Vec::new();
+    let mut push = |high_low: &HighLow| {
+        levels.push((!protocol.inverted, high_low.high * pulse_length_us)).unwrap();
+        levels.push((protocol.inverted, high_low.low * pulse_length_us)).unwrap();
+    };
+    for n in (0..frame.bit_count).rev() {
+        push(if frame.value & (1 << n) != 0 { &protocol.one } else { &protocol.zero });
+    }
+    push(&protocol.sync);
+    Ok(levels)
+}
+
+enum Decoding {
+    Frame { value: u32, bit_count: u8, jitter_us: u32 },
+    Corrupt, // looks like a frame of the protocol, but a bit can't be read
+    NoFrame,
+}
+
+pub struct RcSwitchDecoder {
+    // Alternating high and low durations in microseconds, starting with a high one.
+    durations: Vec<u32, MAX_DURATIONS>,
+    synchronized: bool,
+    jitter_us: u32,
+    corrupt_frames: u16,
+}
+
+impl RcSwitchDecoder {
+    pub fn new() -> Self {
+        Self {
+            durations: Vec::new(),
+            synchronized: false,
+            jitter_us: 0,
+            corrupt_frames: 0,
+        }
+    }
+
+    /// The average deviation of the data pulses of the last frame from their nominal length.
+    pub fn jitter_us(&self) -> u32 {
+        self.jitter_us
+    }
+
+    /// The number of corrupt frames since the last call.
+    pub fn take_corrupt_frames(&mut self) -> u16 {
+        core::mem::take(&mut self.corrupt_frames)
+    }
+
+    fn decode(durations: &[u32], protocol: &Protocol) -> Decoding {
+        let gap = durations[1];
+
+        // Frame layout in durations (h = high, l = low, the last pair is the sync of the next frame):
+        // not inverted: [h0, gap] [h1, l1] ... [hn-1, ln-1] [hn, ln]  -> bits are (h, l) pairs
+        // inverted:     [h0, gap, h1] [l1, h2] ... [ln-1, hn] [ln]     -> bits are (l, h) pairs
+        let (pulse_length, data) = if protocol.inverted {
+            (gap / protocol.sync.high, &durations[3..durations.len() - 1])
+        } else {
+            (gap / protocol.sync.low, &durations[2..durations.len() - 2])
+        };
+
+        let bit_count = data.len() / 2;
+        if !(MIN_BIT_COUNT..=MAX_BIT_COUNT).contains(&bit_count) {
+            return Decoding::NoFrame;
+        }
+
+        let tolerance = pulse_length * TOLERANCE_PERCENT / 100;
+        let matches = |duration: u32, multiple: u32| duration.abs_diff(pulse_length * multiple) < tolerance;
+
+        // Repetitions of a frame are separated by equal gaps. This drops the last frame of a transmission,
+        // as its last bit can't be told apart from the silence afterwards.
+        if !matches(durations[durations.len() - 1], gap / pulse_length) {
+            return Decoding::NoFrame;
+        }
+        if protocol.inverted && !matches(durations[2], protocol.sync.low) {
+            return Decoding::NoFrame;
+        }
+
+        let mut value = 0u32;
+        let mut deviation_us = 0;
+        for bit in data.chunks(2) {
+            value <<= 1;
+            let nominal = if matches(bit[0], protocol.zero.high) && matches(bit[1], protocol.zero.low) {
+                &protocol.zero
+            } else if matches(bit[0], protocol.one.high) && matches(bit[1], protocol.one.low) {
+                value |= 1;
+                &protocol.one
+            } else {
+                return Decoding::Corrupt;
+            };
+            deviation_us += bit[0].abs_diff(pulse_length * nominal.high) + bit[1].abs_diff(pulse_length * nominal.low);
+        }
+        Decoding::Frame { value, bit_count: bit_count as u8, jitter_us: deviation_us / data.len() as u32 }
+    }
+}
+
+impl Decoder for RcSwitchDecoder {
+    type Frame = Frame;
+
+    /// Returns a frame as soon as the separation gap after it has been received.
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<Frame> {
+        if self.durations.is_full() {
+            // Too long to be a frame, wait for the next separation gap.
+            self.durations.clear();
+            self.synchronized = false;
+        }
+        self.durations.push(high_us).unwrap();
+        self.durations.push(low_us).unwrap();
+
+        if low_us <= SEPARATION_LIMIT_US {
+            return None;
+        }
+
+        let mut corrupt = false;
+        let frame = if self.synchronized {
+            PROTOCOLS.iter()
+                .enumerate()
+                .find_map(|(n, protocol)| match Self::decode(&self.durations, protocol) {
+                    Decoding::Frame { value, bit_count, jitter_us } => {
+                        self.jitter_us = jitter_us;
+                        Some(Frame { protocol: n as u8 + 1, value, bit_count })
+                    },
+                    Decoding::Corrupt => {
+                        corrupt = true;
+                        None
+                    },
+                    Decoding::NoFrame => None,
+                })
+        } else {
+            None
+        };
+        if frame.is_none() && corrupt {
+            self.corrupt_frames = self.corrupt_frames.saturating_add(1);
+        }
+
+        // The pulse ending with the gap is the sync pulse of the next frame.
+        self.durations.clear();
+        self.durations.push(high_us).unwrap();
+        self.durations.push(low_us).unwrap();
+        self.synchronized = true;
+
+        frame
+    }
+}
+
+#[cfg(test)]
+pub mod test_signals {
+    //! Generates pulse durations like they are received from rc-switch transmitters.
+    //! It is public within the crate, so other decoders can be tested with the same signals.
+
+    use super::{PROTOCOLS, PULSE_LENGTHS_US};
+
+    /// Returns (high, low) pairs in microseconds for the given number of repetitions of a frame.
+    pub fn rc_switch(protocol: u8, value: u32, bit_count: u8, repetitions: usize) -> Vec<(u32, u32)> {
+        let t = PULSE_LENGTHS_US[protocol as usize - 1];
+        let protocol = &PROTOCOLS[protocol as usize - 1];
+
+        // (level, duration) as sent by the transmitter
+        let mut levels = Vec::new();
+        for _ in 0..repetitions {
+            levels.push((!protocol.inverted, protocol.sync.high * t));
+            levels.push((protocol.inverted, protocol.sync.low * t));
+            for n in (0..bit_count).rev() {
+                let bit = if value & (1 << n) != 0 { &protocol.one } else { &protocol.zero };
+                levels.push((!protocol.inverted, bit.high * t));
+                levels.push((protocol.inverted, bit.low * t));
+            }
+        }
+        // The transmitter is silent after the last repetition.
+        levels.push((false, 100_000));
+
+        pulses(&levels)
+    }
+
+    /// Merges (level, duration) pairs into (high, low) pairs like they come from the receiver.
+    pub fn pulses(levels: &[(bool, u32)]) -> Vec<(u32, u32)> {
+        let mut pulses = Vec::new();
+        let mut high = None;
+        for (level, duration) in levels {
+            match (level, high) {
+                (true, None) => high = Some(*duration),
+                (true, Some(h)) => high = Some(h + duration),
+                (false, Some(h)) => {
+                    pulses.push((h, *duration));
+                    high = None;
+                },
+                (false, None) => {
+                    // merge with the previous low period, if there is one
+                    if let Some(last) = pulses.last_mut() {
+                        last.1 += duration;
+                    }
+                },
+            }
+        }
+        pulses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_signals;
+
+    fn decode_all(decoder: &mut RcSwitchDecoder, pulses: &[(u32, u32)]) -> std::vec::Vec<Frame> {
+        pulses.iter().filter_map(|(high, low)| decoder.run(*high, *low)).collect()
+    }
+
+    #[test]
+    fn all_protocols() {
+        // The sync gap of protocol 4 is shorter than the separation limit, rc-switch can't receive it either.
+        // Protocol 9 is the inverted twin of protocol 8 and is received as such, also like in rc-switch.
+        for protocol in (1..=PROTOCOLS.len() as u8).filter(|protocol| *protocol != 4 && *protocol != 9) {
+            let mut decoder = RcSwitchDecoder::new();
+            let pulses = test_signals::rc_switch(protocol, 0x7E9E90, 24, 4);
+
+            let frames = decode_all(&mut decoder, &pulses);
+
+            // The first frame is used to synchronize and the last one is not followed by a sync.
+            let expected = Frame { protocol, value: 0x7E9E90, bit_count: 24 };
+            assert!(frames.len() >= 2, "protocol {}: {:?}", protocol, frames);
+            for frame in frames {
+                // Protocols with the same timings decode to the first of them.
+                assert_eq!(frame.value, expected.value, "protocol {}", protocol);
+                assert_eq!(frame.bit_count, expected.bit_count, "protocol {}", protocol);
+                assert!(frame.protocol <= protocol, "protocol {}", protocol);
+            }
+        }
+    }
+
+    #[test]
+    fn protocol_1() {
+        let mut decoder = RcSwitchDecoder::new();
+        let pulses = test_signals::rc_switch(1, 0x017E9E, 24, 3);
+
+        let frames = decode_all(&mut decoder, &pulses);
+
+        assert_eq!(frames, [Frame { protocol: 1, value: 0x017E9E, bit_count: 24 }; 2]);
+        assert_eq!(decoder.jitter_us(), 0);
+        // The last frame is not followed by a sync, which does not make it corrupt.
+        assert_eq!(decoder.take_corrupt_frames(), 0);
+    }
+
+    #[test]
+    fn inverted_protocol() {
+        let mut decoder = RcSwitchDecoder::new();
+        // The first sync gap of an inverted protocol is not preceded by a high pulse, so it is missed.
+        let pulses = test_signals::rc_switch(6, 0xABCDE, 20, 4);
+
+        let frames = decode_all(&mut decoder, &pulses);
+
+        assert_eq!(frames, [Frame { protocol: 6, value: 0xABCDE, bit_count: 20 }; 2]);
+    }
+
+    #[test]
+    fn timing_deviation() {
+        let mut decoder = RcSwitchDecoder::new();
+
+        // A transmitter that is 20% slow, with 50us of jitter on the pulses.
+        let pulses: std::vec::Vec<(u32, u32)> = test_signals::rc_switch(1, 0x5555, 16, 3)
+            .iter()
+            .enumerate()
+            .map(|(n, (high, low))| {
+                let jitter = if n % 2 == 0 { 50 } else { 0 };
+                (high * 12 / 10 + jitter, low * 12 / 10 - jitter)
+            })
+            .collect();
+
+        let frames = decode_all(&mut decoder, &pulses);
+
+        assert_eq!(frames, [Frame { protocol: 1, value: 0x5555, bit_count: 16 }; 2]);
+        // The base time unit is taken from the gap, so only the jitter counts. It is on every other pulse pair.
+        assert_eq!(decoder.jitter_us(), 25);
+    }
+
+    #[test]
+    fn too_few_bits() {
+        let mut decoder = RcSwitchDecoder::new();
+        let pulses = test_signals::rc_switch(1, 0x5, 4, 3);
+
+        assert_eq!(decode_all(&mut decoder, &pulses), []);
+    }
+
+    #[test]
+    fn encoded_frames_are_decoded() {
+        // Protocols 4 and 9 can't be received, see all_protocols().
+        for protocol in (1..=PROTOCOLS.len() as u8).filter(|protocol| *protocol != 4 && *protocol != 9) {
+            let frame = Frame { protocol, value: 0x7E9E90, bit_count: 24 };
+            let levels = encode(&frame).unwrap();
+            let mut repeated: std::vec::Vec<(bool, u32)> = levels.iter().cycle().take(4 * levels.len()).copied().collect();
+            repeated.push((false, 100_000));
+
+            let frames = decode_all(&mut RcSwitchDecoder::new(), &test_signals::pulses(&repeated));
+
+            assert!(frames.len() >= 2, "protocol {}: {:?}", protocol, frames);
+            assert!(frames.iter().all(|decoded| decoded.value == frame.value && decoded.bit_count == 24), "protocol {}", protocol);
+        }
+    }
+
+    #[test]
+    fn encode_protocol_1() {
+        let levels = encode(&Frame { protocol: 1, value: 0b1000_0001, bit_count: 8 }).unwrap();
+
+        assert_eq!(levels.len(), 18);
+        assert_eq!(levels[..4], [(true, 1050), (false, 350), (true, 350), (false, 1050)]);
+        assert_eq!(levels[16..], [(true, 350), (false, 10850)]);
+    }
+
+    #[test]
+    fn encode_invalid_frames() {
+        assert_eq!(encode(&Frame { protocol: 0, value: 1, bit_count: 24 }), Err("protocol must be between 1 and 12"));
+        assert_eq!(encode(&Frame { protocol: 13, value: 1, bit_count: 24 }), Err("protocol must be between 1 and 12"));
+        assert_eq!(encode(&Frame { protocol: 1, value: 1, bit_count: 4 }), Err("bit count must be between 8 and 32"));
+        assert_eq!(encode(&Frame { protocol: 1, value: 0x1000000, bit_count: 24 }), Err("code has more bits than the bit count"));
+        assert!(encode(&Frame { protocol: 1, value: u32::MAX, bit_count: 32 }).is_ok());
+    }
+
+    #[test]
+    fn noise() {
+        let mut decoder = RcSwitchDecoder::new();
+
+        let mut pulses = vec![(120, 80), (35, 5000), (900, 40)];
+        for n in 0..200u32 {
+            pulses.push((50 + n * 37 % 700, 30 + n * 91 % 5000));
+        }
+
+        assert_eq!(decode_all(&mut decoder, &pulses), []);
+    }
+
+    #[test]
+    fn corrupt_bit() {
+        let mut decoder = RcSwitchDecoder::new();
+        let mut pulses = test_signals::rc_switch(1, 0x017E9E, 24, 3);
+
+        // destroy a bit of the second repetition
+        pulses[30].0 = 700;
+
+        let frames = decode_all(&mut decoder, &pulses);
+
+        assert_eq!(frames, [Frame { protocol: 1, value: 0x017E9E, bit_count: 24 }]);
+        assert_eq!(decoder.take_corrupt_frames(), 1);
+        assert_eq!(decoder.take_corrupt_frames(), 0);
+    }
+}
@@ -0,0 +1,230 @@
+//! Connects the terminal with the remote receiver.
+//! Codes confirmed by the receiver are handed over, so they can be learned,
+//! and raw pulses are forwarded while a capture is running or measured while a calibration is running.
+//! Unknown codes are forwarded to be shown on the terminal.
+//! The battery and tamper flags of the devices are kept here, so the terminal can list them.
+//! The same goes for the signal quality of the received frames and the state of the MQTT connection.
+//! The receivers keep the code and entity tables in memory, the terminal tells them here when it changed one.
+//! Codes to send are queued here for the button task, which owns the receiver and the transmitter.
+
+use cfg_if::cfg_if;
+
+use crate::modules::rc_switch::Frame;
+use crate::modules::calibration::Calibration;
+use crate::modules::code_table::Code;
+use crate::modules::connection::ConnectionState;
+
+cfg_if! {
+    if #[cfg(not(test))] {
+        use embassy_sync::channel::Channel;
+        use core::cell::RefCell;
+        use embassy_sync::mutex::Mutex;
+        use embassy_sync::blocking_mutex;
+        use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+        use embassy_time::Instant;
+        use heapless::Vec;
+        use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+        use crate::modules::code_learner::CodeLearner;
+        use crate::modules::connection::ConnectionStatus;
+        use crate::modules::pulse_capture::Pulse;
+        use crate::modules::calibration::Calibrator;
+        use crate::modules::unknown_codes::UnknownCode;
+        use crate::modules::devices::{DeviceFlags, DeviceList, DiagnosticMessage};
+        use crate::modules::signal_quality::{FrameQuality, SignalStats};
+
+        const CAPTURE_QUEUE_SIZE: usize = 64;
+        const UNKNOWN_CODE_QUEUE_SIZE: usize = 8;
+        const TRANSMISSION_QUEUE_SIZE: usize = 4;
+    }
+}
+
+#[cfg_attr(test, mockall::automock)]
+pub trait ReceiverControlTrait {
+    async fn next_code(&self) -> Result<Code, &'static str>;
+    async fn start_capture(&self) -> Result<(), &'static str>;
+    async fn stop_capture(&self) -> Result<(), &'static str>;
+    async fn start_calibration(&self) -> Result<(), &'static str>;
+    async fn stop_calibration(&self) -> Result<Calibration, &'static str>;
+    async fn list_devices(&self, answer: &mut [u8]) -> Result<usize, &'static str>;
+    async fn queue_transmission(&self, frame: Frame) -> Result<(), &'static str>;
+    async fn signal_stats(&self, answer: &mut [u8]) -> Result<usize, &'static str>;
+    async fn connection_status(&self) -> (ConnectionState, u32);
+    async fn tables_changed(&self);
+}
+
+#[cfg(not(test))]
+pub struct ReceiverControl {
+    code_learner: CodeLearner,
+    pulses_available: AtomicBool,
+    capture_running: AtomicBool,
+    capture_started: AtomicBool,
+    captured_pulses: Channel<CriticalSectionRawMutex, Pulse, CAPTURE_QUEUE_SIZE>,
+    dropped_pulses: AtomicU32,
+    // The pulses are offered without waiting, so the calibrator is locked only for a moment.
+    calibrator: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<Option<Calibrator>>>,
+    unknown_codes: Channel<CriticalSectionRawMutex, UnknownCode, UNKNOWN_CODE_QUEUE_SIZE>,
+    devices: Mutex<CriticalSectionRawMutex, DeviceList>,
+    signal_stats: Mutex<CriticalSectionRawMutex, SignalStats>,
+    transmissions: Channel<CriticalSectionRawMutex, Frame, TRANSMISSION_QUEUE_SIZE>,
+    connection: ConnectionStatus,
+    table_generation: AtomicU32,
+}
+
+#[cfg(not(test))]
+impl ReceiverControl {
+    pub fn new() -> Self {
+        Self {
+            code_learner: CodeLearner::new(),
+            pulses_available: AtomicBool::new(false),
+            capture_running: AtomicBool::new(false),
+            capture_started: AtomicBool::new(false),
+            captured_pulses: Channel::new(),
+            dropped_pulses: AtomicU32::new(0),
+            calibrator: blocking_mutex::Mutex::new(RefCell::new(None)),
+            unknown_codes: Channel::new(),
+            devices: Mutex::new(DeviceList::new()),
+            signal_stats: Mutex::new(SignalStats::new()),
+            transmissions: Channel::new(),
+            connection: ConnectionStatus::new(),
+            table_generation: AtomicU32::new(0),
+        }
+    }
+
+    /// Handed to the MQTT supervisor, which keeps it up to date.
+    pub fn connection(&self) -> &ConnectionStatus {
+        &self.connection
+    }
+
+    /// Changes whenever the code table or the entity table was stored, so a copy can be reloaded.
+    pub fn table_generation(&self) -> u32 {
+        self.table_generation.load(Ordering::Relaxed)
+    }
+
+    pub fn offer_code(&self, code: Code) {
+        self.code_learner.offer(code);
+    }
+
+    /// Called by the receiver, if it measures raw pulses.
+    pub fn set_pulses_available(&self) {
+        self.pulses_available.store(true, Ordering::Relaxed);
+    }
+
+    /// Never blocks. If the capture output can't keep up, pulses are dropped.
+    pub fn offer_pulse(&self, pulse: Pulse) {
+        self.calibrator.lock(|calibrator| {
+            if let Some(calibrator) = calibrator.borrow_mut().as_mut() {
+                calibrator.add_pulse(pulse.pulse_us, pulse.gap_us);
+            }
+        });
+        if !self.capture_running.load(Ordering::Relaxed) {
+            return;
+        }
+        if self.captured_pulses.try_send(pulse).is_err() {
+            self.dropped_pulses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub async fn captured_pulse(&self) -> Pulse {
+        self.captured_pulses.receive().await
+    }
+
+    /// Returns true once after each start of a capture.
+    pub fn take_capture_started(&self) -> bool {
+        self.capture_started.swap(false, Ordering::Relaxed)
+    }
+
+    pub fn take_dropped_pulses(&self) -> u32 {
+        self.dropped_pulses.swap(0, Ordering::Relaxed)
+    }
+
+    /// Never blocks, the code is dropped if the terminal can't keep up.
+    pub fn offer_unknown_code(&self, unknown_code: UnknownCode) {
+        let _ = self.unknown_codes.try_send(unknown_code);
+    }
+
+    pub async fn unknown_code(&self) -> UnknownCode {
+        self.unknown_codes.receive().await
+    }
+
+    pub async fn transmission(&self) -> Frame {
+        self.transmissions.receive().await
+    }
+
+    /// Returns the diagnostic messages to publish for the changed flags.
+    pub async fn update_device(&self, name: &str, flags: DeviceFlags, now_ms: u64) -> Vec<DiagnosticMessage, 2> {
+        self.devices.lock().await.update(name, flags, now_ms)
+    }
+
+    pub async fn record_quality(&self, label: &str, quality: FrameQuality) {
+        self.signal_stats.lock().await.record(label, quality);
+    }
+}
+
+#[cfg(not(test))]
+impl ReceiverControlTrait for ReceiverControl {
+    async fn next_code(&self) -> Result<Code, &'static str> {
+        self.code_learner.next_code().await
+    }
+
+    async fn start_capture(&self) -> Result<(), &'static str> {
+        if !self.pulses_available.load(Ordering::Relaxed) {
+            return Err("capture needs a running receiver with receiver_mode pulse");
+        }
+        if self.capture_running.load(Ordering::Relaxed) {
+            return Err("capture is already running");
+        }
+        // Pulses left over from an earlier capture are dropped.
+        while self.captured_pulses.try_receive().is_ok() {}
+        self.dropped_pulses.store(0, Ordering::Relaxed);
+        self.capture_started.store(true, Ordering::Relaxed);
+        self.capture_running.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn stop_capture(&self) -> Result<(), &'static str> {
+        if !self.capture_running.swap(false, Ordering::Relaxed) {
+            return Err("capture is not running");
+        }
+        Ok(())
+    }
+
+    async fn start_calibration(&self) -> Result<(), &'static str> {
+        if !self.pulses_available.load(Ordering::Relaxed) {
+            return Err("calibration needs a running receiver with receiver_mode pulse");
+        }
+        self.calibrator.lock(|calibrator| {
+            let mut calibrator = calibrator.borrow_mut();
+            if calibrator.is_some() {
+                return Err("calibration is already running");
+            }
+            *calibrator = Some(Calibrator::new());
+            Ok(())
+        })
+    }
+
+    async fn stop_calibration(&self) -> Result<Calibration, &'static str> {
+        let calibrator = self.calibrator.lock(|calibrator| calibrator.borrow_mut().take());
+        calibrator.ok_or("calibration is not running")?.result()
+    }
+
+    async fn list_devices(&self, answer: &mut [u8]) -> Result<usize, &'static str> {
+        self.devices.lock().await.list(Instant::now().as_millis(), answer)
+    }
+
+    async fn queue_transmission(&self, frame: Frame) -> Result<(), &'static str> {
+        self.transmissions.try_send(frame).map_err(|_| "send queue is full, try again later")
+    }
+
+    async fn signal_stats(&self, answer: &mut [u8]) -> Result<usize, &'static str> {
+        self.signal_stats.lock().await.list(answer)
+    }
+
+    async fn connection_status(&self) -> (ConnectionState, u32) {
+        (self.connection.state(), self.connection.connections())
+    }
+
+    async fn tables_changed(&self) {
+        self.table_generation.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,466 @@
+//! The receivers of the gateway, up to four, each on its own state machine of PIO0.
+//! A receiver has a pin, a mode and a label, e.g. "433 living room" or "315 garage".
+//! The label is carried in the payload of every event published for a code the receiver received.
+//! Without configured receivers there is one unlabelled receiver on GP28 in the stored receiver mode.
+
+use cfg_if::cfg_if;
+use core::fmt::Write;
+use heapless::{String, Vec};
+
+use crate::modules::persistency::{PersistencyTrait, ValueId};
+use crate::modules::remote_receiver::ReceiverMode;
+
+cfg_if! {
+    if #[cfg(not(test))] {
+        use defmt::error;
+        use embassy_futures::select::{select4, Either4};
+        use embassy_rp::gpio::Pull;
+        use embassy_rp::pio::{Common, Pin, StateMachine};
+        use embassy_rp::peripherals::{PIO0, PIN_2, PIN_3, PIN_4, PIN_5, PIN_6, PIN_7, PIN_8, PIN_9, PIN_10, PIN_11, PIN_28};
+
+        use crate::modules::fixed_timing::FixedTimingSettings;
+        use crate::modules::flex::FlexTable;
+        use crate::modules::receiver_control::ReceiverControl;
+        use crate::modules::remote_receiver::{ConfirmationPolicy, Received, ReceiverPrograms, RemoteReceiver};
+        use crate::modules::signal_quality::FrameQuality;
+    }
+}
+
+pub const MAX_RECEIVERS: usize = 4;
+pub const MAX_LABEL_LENGTH: usize = 16;
+
+/// The pins a receiver can be connected to, the others are taken by Wi-Fi, the CC1101 and the transmitter.
+pub const RECEIVER_PINS: [u8; 11] = [2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 28];
+const DEFAULT_PIN: u8 = 28;
+
+// Each receiver is stored as: pin (1 byte), mode (1 byte), label length (1 byte), label.
+const ENTRY_HEADER_SIZE: usize = 3;
+const MAX_STORED_SIZE: usize = MAX_RECEIVERS * (ENTRY_HEADER_SIZE + MAX_LABEL_LENGTH);
+const MODE_FIXED: u8 = 0;
+const MODE_PULSE: u8 = 1;
+
+pub type ReceiverLabel = String<MAX_LABEL_LENGTH>;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ReceiverConfig {
+    pub pin: u8,
+    pub mode: ReceiverMode,
+    pub label: ReceiverLabel, // empty for the default receiver
+}
+
+pub struct ReceiverTable {
+    receivers: Vec<ReceiverConfig, MAX_RECEIVERS>,
+}
+
+impl ReceiverTable {
+    pub fn new() -> Self {
+        Self { receivers: Vec::new() }
+    }
+
+    pub async fn load<P>(persistency: &P) -> Result<Self, &'static str>
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = persistency.read(ValueId::Receivers, &mut bytes).await?;
+        Self::from_bytes(&bytes[..length])
+    }
+
+    pub async fn save<P>(&self, persistency: &P) -> Result<(), &'static str>
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = self.to_bytes(&mut bytes);
+        persistency.store(&bytes[..length], ValueId::Receivers).await
+    }
+
+    pub fn add(&mut self, pin: u8, mode: ReceiverMode, label: &str) -> Result<(), &'static str> {
+        if !RECEIVER_PINS.contains(&pin) {
+            return Err("pin must be one of 2 to 11 or 28");
+        }
+        if label.is_empty() {
+            return Err("label must not be empty");
+        }
+        // The label is put into JSON payloads as it is.
+        if label.contains(['"', '\\']) {
+            return Err("label must not contain quotes or backslashes");
+        }
+        if self.receivers.iter().any(|receiver| receiver.label == label) {
+            return Err("label is already in the receiver table");
+        }
+        if self.receivers.iter().any(|receiver| receiver.pin == pin && receiver.mode == mode) {
+            return Err("there is already a receiver with this pin and mode");
+        }
+        let receiver = ReceiverConfig {
+            pin,
+            mode,
+            label: String::try_from(label).map_err(|_| "label is too long")?,
+        };
+        self.receivers.push(receiver).map_err(|_| "receiver table is full")
+    }
+
+    pub fn remove(&mut self, label: &str) -> Result<(), &'static str> {
+        match self.receivers.iter().position(|receiver| receiver.label == label) {
+            Some(index) => {
+                self.receivers.remove(index);
+                Ok(())
+            },
+            None => Err("label not found in the receiver table"),
+        }
+    }
+
+    pub fn list(&self, answer: &mut [u8]) -> Result<usize, &'static str> {
+        if self.receivers.is_empty() {
+            let text = b"receiver table is empty, the receiver on pin 28 uses receiver_mode";
+            if text.len() > answer.len() {
+                return Err("answer buffer too small");
+            }
+            answer[..text.len()].copy_from_slice(text);
+            return Ok(text.len());
+        }
+
+        let mut length = 0;
+        for (n, receiver) in self.receivers.iter().enumerate() {
+            let mut line: String<{ 32 + MAX_LABEL_LENGTH }> = String::new();
+            if n > 0 {
+                line.push('\n').unwrap();
+            }
+            write!(line, "{}: pin {}, {}", receiver.label, receiver.pin, mode_name(receiver.mode)).unwrap();
+
+            if length + line.len() > answer.len() {
+                return Err("answer buffer too small");
+            }
+            answer[length..length + line.len()].copy_from_slice(line.as_bytes());
+            length += line.len();
+        }
+        Ok(length)
+    }
+
+    /// The receivers to start, the default receiver if none are configured.
+    pub fn receivers(&self, default_mode: ReceiverMode) -> Vec<ReceiverConfig, MAX_RECEIVERS> {
+        if self.receivers.is_empty() {
+            let mut receivers = Vec::new();
+            receivers.push(ReceiverConfig { pin: DEFAULT_PIN, mode: default_mode, label: String::new() }).unwrap();
+            return receivers;
+        }
+        self.receivers.clone()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        let mut table = Self::new();
+        let mut index = 0;
+
+        while index < bytes.len() {
+            if index + ENTRY_HEADER_SIZE > bytes.len() {
+                return Err("stored receiver table is corrupt");
+            }
+            let pin = bytes[index];
+            let mode = match bytes[index + 1] {
+                MODE_FIXED => ReceiverMode::FixedTiming,
+                MODE_PULSE => ReceiverMode::PulseWidth,
+                _ => return Err("stored receiver table is corrupt"),
+            };
+            let label_length = bytes[index + 2] as usize;
+            index += ENTRY_HEADER_SIZE;
+
+            if index + label_length > bytes.len() {
+                return Err("stored receiver table is corrupt");
+            }
+            let label = core::str::from_utf8(&bytes[index..index + label_length]).map_err(|_| "stored receiver table is corrupt")?;
+            index += label_length;
+
+            table.add(pin, mode, label)?;
+        }
+        Ok(table)
+    }
+
+    fn to_bytes(&self, bytes: &mut [u8; MAX_STORED_SIZE]) -> usize {
+        let mut index = 0;
+        for receiver in self.receivers.iter() {
+            bytes[index] = receiver.pin;
+            bytes[index + 1] = match receiver.mode {
+                ReceiverMode::FixedTiming => MODE_FIXED,
+                ReceiverMode::PulseWidth => MODE_PULSE,
+            };
+            bytes[index + 2] = receiver.label.len() as u8;
+            index += ENTRY_HEADER_SIZE;
+
+            bytes[index..index + receiver.label.len()].copy_from_slice(receiver.label.as_bytes());
+            index += receiver.label.len();
+        }
+        index
+    }
+}
+
+fn mode_name(mode: ReceiverMode) -> &'static str {
+    match mode {
+        ReceiverMode::FixedTiming => "fixed",
+        ReceiverMode::PulseWidth => "pulse",
+    }
+}
+
+/// Adds the label of the receiver to a payload, JSON payloads get a "receiver" member.
+/// Payloads of the default receiver are not changed.
+pub fn labelled(payload: &str, label: &str) -> String<256> {
+    let mut labelled = String::new();
+    if label.is_empty() {
+        labelled.push_str(payload).unwrap();
+    } else if let Some(members) = payload.strip_suffix('}').filter(|_| payload.starts_with('{')) {
+        write!(labelled, "{},\"receiver\":\"{}\"}}", members, label).unwrap();
+    } else {
+        write!(labelled, "{}, receiver {}", payload, label).unwrap();
+    }
+    labelled
+}
+
+/// The pins of RECEIVER_PINS, handed over by main. Each is given to PIO0 at most once.
+#[cfg(not(test))]
+pub struct ReceiverPins {
+    pub pin_2: Option<PIN_2>,
+    pub pin_3: Option<PIN_3>,
+    pub pin_4: Option<PIN_4>,
+    pub pin_5: Option<PIN_5>,
+    pub pin_6: Option<PIN_6>,
+    pub pin_7: Option<PIN_7>,
+    pub pin_8: Option<PIN_8>,
+    pub pin_9: Option<PIN_9>,
+    pub pin_10: Option<PIN_10>,
+    pub pin_11: Option<PIN_11>,
+    pub pin_28: Option<PIN_28>,
+}
+
+#[cfg(not(test))]
+impl ReceiverPins {
+    // The pin types differ, so every allowed pin needs its own arm. None if the pin is not free.
+    fn make_pio_pin(&mut self, pio: &mut Common<'static, PIO0>, pin: u8) -> Option<Pin<'static, PIO0>> {
+        let mut pin = match pin {
+            2 => pio.make_pio_pin(self.pin_2.take()?),
+            3 => pio.make_pio_pin(self.pin_3.take()?),
+            4 => pio.make_pio_pin(self.pin_4.take()?),
+            5 => pio.make_pio_pin(self.pin_5.take()?),
+            6 => pio.make_pio_pin(self.pin_6.take()?),
+            7 => pio.make_pio_pin(self.pin_7.take()?),
+            8 => pio.make_pio_pin(self.pin_8.take()?),
+            9 => pio.make_pio_pin(self.pin_9.take()?),
+            10 => pio.make_pio_pin(self.pin_10.take()?),
+            11 => pio.make_pio_pin(self.pin_11.take()?),
+            28 => pio.make_pio_pin(self.pin_28.take()?),
+            _ => return None,
+        };
+        pin.set_pull(Pull::None);
+        Some(pin)
+    }
+}
+
+#[cfg(not(test))]
+type Receiver<const SM: usize, P> = RemoteReceiver<'static, PIO0, SM, P>;
+
+/// The running receivers, the n-th configured one is on state machine n.
+#[cfg(not(test))]
+pub struct Receivers<P: PersistencyTrait + 'static> {
+    receiver_0: Option<Receiver<0, P>>,
+    receiver_1: Option<Receiver<1, P>>,
+    receiver_2: Option<Receiver<2, P>>,
+    receiver_3: Option<Receiver<3, P>>,
+    labels: Vec<ReceiverLabel, MAX_RECEIVERS>,
+}
+
+#[cfg(not(test))]
+impl<P: PersistencyTrait + 'static> Receivers<P> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        pio: &mut Common<'static, PIO0>,
+        state_machines: (StateMachine<'static, PIO0, 0>, StateMachine<'static, PIO0, 1>, StateMachine<'static, PIO0, 2>, StateMachine<'static, PIO0, 3>),
+        mut pins: ReceiverPins,
+        configs: &[ReceiverConfig],
+        fixed_timing: FixedTimingSettings,
+        policy: ConfirmationPolicy,
+        flex_table: &FlexTable,
+        persistency: &'static P,
+        receiver_control: &'static ReceiverControl,
+    ) -> Self {
+        // The programs are loaded once and shared, two of them fit into the instruction memory of PIO0.
+        let mut programs = ReceiverPrograms::new(fixed_timing);
+        // The pulse capture shows the pulses of the first receiver in pulse width mode only.
+        let capture = configs.iter().position(|config| config.mode == ReceiverMode::PulseWidth);
+        let (sm0, sm1, sm2, sm3) = state_machines;
+
+        // Receivers sharing a pin only read it, so the pin is given to PIO0 once and all of them use it.
+        let mut pio_pins: Vec<(u8, Pin<'static, PIO0>), MAX_RECEIVERS> = Vec::new();
+        for config in configs {
+            if pio_pins.iter().any(|(pin, _)| *pin == config.pin) {
+                continue;
+            }
+            match pins.make_pio_pin(pio, config.pin) {
+                // There are no more pins than configs.
+                Some(pin) => { let _ = pio_pins.push((config.pin, pin)); },
+                None => error!("pin {} is not free, the receiver {} is not started", config.pin, config.label.as_str()),
+            }
+        }
+
+        let receiver = |n: usize| configs.get(n).and_then(|config: &ReceiverConfig| {
+            let (_, pin) = pio_pins.iter().find(|(pin, _)| *pin == config.pin)?;
+            Some((pin, config.mode, capture == Some(n)))
+        });
+        let receiver_0 = receiver(0).map(|(pin, mode, capture)| RemoteReceiver::new(pio, &mut programs, sm0, pin, mode, policy, flex_table.decoders(), capture, persistency, receiver_control));
+        let receiver_1 = receiver(1).map(|(pin, mode, capture)| RemoteReceiver::new(pio, &mut programs, sm1, pin, mode, policy, flex_table.decoders(), capture, persistency, receiver_control));
+        let receiver_2 = receiver(2).map(|(pin, mode, capture)| RemoteReceiver::new(pio, &mut programs, sm2, pin, mode, policy, flex_table.decoders(), capture, persistency, receiver_control));
+        let receiver_3 = receiver(3).map(|(pin, mode, capture)| RemoteReceiver::new(pio, &mut programs, sm3, pin, mode, policy, flex_table.decoders(), capture, persistency, receiver_control));
+
+        Self {
+            receiver_0,
+            receiver_1,
+            receiver_2,
+            receiver_3,
+            labels: configs.iter().map(|config| config.label.clone()).collect(),
+        }
+    }
+
+    /// Returns the label of the receiver, what it received and how well. Can be cancelled like RemoteReceiver::read.
+    pub async fn read(&mut self) -> (ReceiverLabel, Received, FrameQuality) {
+        let (n, (received, quality)) = match select4(
+            read(&mut self.receiver_0),
+            read(&mut self.receiver_1),
+            read(&mut self.receiver_2),
+            read(&mut self.receiver_3),
+        ).await {
+            Either4::First(received) => (0, received),
+            Either4::Second(received) => (1, received),
+            Either4::Third(received) => (2, received),
+            Either4::Fourth(received) => (3, received),
+        };
+        (self.labels[n].clone(), received, quality)
+    }
+
+    pub fn discard_received(&mut self) {
+        if let Some(receiver) = &mut self.receiver_0 {
+            receiver.discard_received();
+        }
+        if let Some(receiver) = &mut self.receiver_1 {
+            receiver.discard_received();
+        }
+        if let Some(receiver) = &mut self.receiver_2 {
+            receiver.discard_received();
+        }
+        if let Some(receiver) = &mut self.receiver_3 {
+            receiver.discard_received();
+        }
+    }
+}
+
+#[cfg(not(test))]
+async fn read<const SM: usize, P: PersistencyTrait>(receiver: &mut Option<Receiver<SM, P>>) -> (Received, FrameQuality) {
+    match receiver {
+        Some(receiver) => receiver.read().await,
+        None => core::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::transmitter::TX_PINS;
+
+    fn table() -> ReceiverTable {
+        let mut table = ReceiverTable::new();
+        table.add(28, ReceiverMode::FixedTiming, "433 living room").unwrap();
+        table.add(3, ReceiverMode::PulseWidth, "315 garage").unwrap();
+        table
+    }
+
+    #[test]
+    fn add_rejects_invalid_receivers() {
+        let mut table = table();
+        const RECEIVERS: &[(u8, ReceiverMode, &str, &str)] = &[
+            (12, ReceiverMode::PulseWidth, "cc1101", "pin must be one of 2 to 11 or 28"),
+            (27, ReceiverMode::PulseWidth, "tx pin", "pin must be one of 2 to 11 or 28"),
+            (4, ReceiverMode::PulseWidth, "", "label must not be empty"),
+            (4, ReceiverMode::PulseWidth, "\"attic\"", "label must not contain quotes or backslashes"),
+            (4, ReceiverMode::PulseWidth, "315 garage", "label is already in the receiver table"),
+            (28, ReceiverMode::FixedTiming, "again", "there is already a receiver with this pin and mode"),
+            (4, ReceiverMode::PulseWidth, "868 in the basement", "label is too long"),
+        ];
+
+        for (pin, mode, label, error) in RECEIVERS {
+            assert_eq!(table.add(*pin, *mode, label), Err(*error), "{}", label);
+        }
+        assert_eq!(table.receivers.len(), 2);
+    }
+
+    #[test]
+    fn receiver_pins_are_not_used_otherwise() {
+        // The SPI of the CC1101 and the Wi-Fi chip.
+        const TAKEN_PINS: [u8; 8] = [12, 13, 14, 15, 23, 24, 25, 29];
+
+        for pin in RECEIVER_PINS {
+            assert!(!TX_PINS.contains(&pin) && !TAKEN_PINS.contains(&pin), "pin {}", pin);
+        }
+    }
+
+    #[test]
+    fn up_to_four_receivers() {
+        let mut table = table();
+
+        // A pin can be shared by receivers of different modes.
+        table.add(28, ReceiverMode::PulseWidth, "433 pulses").unwrap();
+        table.add(4, ReceiverMode::PulseWidth, "868 attic").unwrap();
+        assert_eq!(table.add(5, ReceiverMode::PulseWidth, "fifth"), Err("receiver table is full"));
+    }
+
+    #[test]
+    fn remove() {
+        let mut table = table();
+
+        table.remove("315 garage").unwrap();
+        assert_eq!(table.receivers.len(), 1);
+        assert_eq!(table.remove("315 garage"), Err("label not found in the receiver table"));
+    }
+
+    #[test]
+    fn list() {
+        let mut answer = [0u8; 200];
+
+        let length = ReceiverTable::new().list(&mut answer).unwrap();
+        assert_eq!(&answer[..length], b"receiver table is empty, the receiver on pin 28 uses receiver_mode");
+
+        let length = table().list(&mut answer).unwrap();
+        assert_eq!(core::str::from_utf8(&answer[..length]).unwrap(), "433 living room: pin 28, fixed\n315 garage: pin 3, pulse");
+
+        assert_eq!(table().list(&mut answer[..20]), Err("answer buffer too small"));
+    }
+
+    #[test]
+    fn default_receiver() {
+        let receivers = ReceiverTable::new().receivers(ReceiverMode::PulseWidth);
+        assert_eq!(receivers[..], [ReceiverConfig { pin: 28, mode: ReceiverMode::PulseWidth, label: String::new() }]);
+
+        assert_eq!(table().receivers(ReceiverMode::PulseWidth)[..], table().receivers[..]);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let table = table();
+
+        let mut bytes = [0u8; MAX_STORED_SIZE];
+        let length = table.to_bytes(&mut bytes);
+        assert_eq!(length, 2 * ENTRY_HEADER_SIZE + "433 living room".len() + "315 garage".len());
+        assert_eq!(bytes[..ENTRY_HEADER_SIZE], [28, MODE_FIXED, 15]);
+
+        let loaded = ReceiverTable::from_bytes(&bytes[..length]).unwrap();
+        assert_eq!(loaded.receivers, table.receivers);
+    }
+
+    #[test]
+    fn from_corrupt_bytes() {
+        assert!(ReceiverTable::from_bytes(&[28, MODE_FIXED]).is_err());
+        assert!(ReceiverTable::from_bytes(&[28, 7, 1, b'x']).is_err());
+        assert!(ReceiverTable::from_bytes(&[28, MODE_PULSE, 5, b'x']).is_err());
+        assert!(ReceiverTable::from_bytes(&[1, MODE_PULSE, 1, b'x']).is_err());
+    }
+
+    #[test]
+    fn labelled_payloads() {
+        assert_eq!(labelled("button 1 pressed", ""), "button 1 pressed");
+        assert_eq!(labelled("button 1 pressed", "315 garage"), "button 1 pressed, receiver 315 garage");
+        assert_eq!(labelled(r#"{"house":"12D687A","state":"on"}"#, "315 garage"), r#"{"house":"12D687A","state":"on","receiver":"315 garage"}"#);
+    }
+}
@@ -3,168 +3,737 @@
 use cfg_if::cfg_if;
 use {defmt_rtt as _, panic_probe as _};
 
+use crate::modules::code_table::{Code, CodeTable};
+use crate::modules::persistency::{PersistencyTrait, ValueId};
+
 cfg_if! {
     if #[cfg(not(test))] {
-        use embassy_rp::{gpio, pio};
-        use embassy_rp::pio::PioPin;
+        use defmt::{debug, error};
+        use embassy_rp::pio;
         use embassy_rp::pio::program::pio_asm;
+        use embassy_time::Instant;
         use fixed::traits::ToFixed;
+        use heapless::String;
+
+        use crate::modules::code_table::MAX_NAME_LENGTH;
+        use crate::modules::ev1527::RemoteFrame;
+        use crate::modules::fixed_timing::{self, FixedTimingSettings};
+        use crate::modules::flex::{FlexDecoders, FlexFrame, FrameFilter};
+        use crate::modules::decoder::{Converted, Decoder};
+        use crate::modules::intertechno::{CommandFilter, IntertechnoCommand, IntertechnoDecoder};
+        use crate::modules::rc_switch::{Frame, RcSwitchDecoder};
+        use crate::modules::rolling_code::{ReplayFilter, RollingCodeDecoder, RollingFrame};
+        use crate::modules::signal_quality::FrameQuality;
+        use crate::modules::tri_state::TriStateCode;
+        use crate::modules::weather::{ReadingFilter, SensorReading, WeatherDecoder};
+        use crate::modules::receiver_control::ReceiverControl;
+        use crate::modules::pulse_capture::Pulse;
+
+        // The decoders of pulse width mode, they all run on the same pulse stream.
+        // The specific protocols come first, so their frames are not taken as rc-switch codes.
+        // Further protocols are added by adding their decoders to the tuple.
+        type PulseDecoders = (
+            Converted<WeatherDecoder, Value>,
+            Converted<IntertechnoDecoder, Value>,
+            Converted<FlexDecoders, Value>,
+            Converted<RollingCodeDecoder, Value>,
+            Converted<RcSwitchDecoder, Value>,
+        );
+
+        fn pulse_decoders(flex_decoders: FlexDecoders) -> PulseDecoders {
+            (
+                Converted::new(WeatherDecoder::new()),
+                Converted::new(IntertechnoDecoder::new()),
+                Converted::new(flex_decoders),
+                Converted::new(RollingCodeDecoder::new()),
+                Converted::new(RcSwitchDecoder::new()),
+            )
+        }
+    }
+}
+
+// The fixed timing program reads 24 bits of rc-switch protocol 1 and the following sync pulse.
+#[cfg(not(test))]
+const FIXED_TIMING_PROTOCOL: u8 = 1;
+#[cfg(not(test))]
+const FIXED_TIMING_BIT_COUNT: u8 = 25;
+
+#[cfg(not(test))]
+pub struct ReceivedButton {
+    pub code: Code,
+    pub name: Option<String<MAX_NAME_LENGTH>>, // None if the code is not in the code table
+    pub remote: Option<RemoteFrame>,
+    pub tri_state: Option<TriStateCode>,
+}
+
+#[cfg(not(test))]
+pub enum Received {
+    Button(ReceivedButton),
+    Sensor(SensorReading),
+    Intertechno(IntertechnoCommand),
+    Flex(FlexFrame),
+}
+
+#[cfg(not(test))]
+enum Value {
+    Code(Frame, Option<RemoteFrame>, Option<TriStateCode>),
+    Sensor(SensorReading),
+    Intertechno(IntertechnoCommand),
+    Flex(FlexFrame),
+    Rolling(RollingFrame),
+}
+
+#[cfg(not(test))]
+impl Value {
+    fn log(&self) {
+        match self {
+            Value::Code(frame, _, _) => debug!("frame: protocol {}, {} bits, value 0x{:08X}", frame.protocol, frame.bit_count, frame.value),
+            Value::Sensor(reading) => debug!("{} sensor 0x{:02X}: {}", reading.model, reading.id, reading.payload().as_str()),
+            Value::Intertechno(command) => debug!("intertechno: {}", command.payload().as_str()),
+            Value::Flex(frame) => debug!("flex decoder {}: {}", frame.name.as_str(), frame.payload().as_str()),
+            Value::Rolling(frame) => debug!("{} 0x{:07X}, button 0x{:X}, rolling code 0x{:08X}", frame.protocol.as_str(), frame.serial, frame.button, frame.rolling_code),
+        }
+    }
+}
+
+#[cfg(not(test))]
+impl From<Frame> for Value {
+    fn from(frame: Frame) -> Self {
+        Value::Code(frame, RemoteFrame::split(&frame), TriStateCode::decode(&frame))
+    }
+}
+
+#[cfg(not(test))]
+impl From<SensorReading> for Value {
+    fn from(reading: SensorReading) -> Self {
+        Value::Sensor(reading)
+    }
+}
+
+#[cfg(not(test))]
+impl From<IntertechnoCommand> for Value {
+    fn from(command: IntertechnoCommand) -> Self {
+        Value::Intertechno(command)
+    }
+}
+
+#[cfg(not(test))]
+impl From<FlexFrame> for Value {
+    fn from(frame: FlexFrame) -> Self {
+        Value::Flex(frame)
+    }
+}
+
+#[cfg(not(test))]
+impl From<RollingFrame> for Value {
+    fn from(frame: RollingFrame) -> Self {
+        Value::Rolling(frame)
     }
 }
 
 #[cfg(not(test))]
-pub struct RemoteReceiver<'d, PIO: pio::Instance, const SM: usize> {
+pub struct RemoteReceiver<'d, PIO: pio::Instance, const SM: usize, P: PersistencyTrait> {
     pio_sm: pio::StateMachine<'d, PIO, SM>,
+    mode: ReceiverMode,
+    decoders: PulseDecoders,
+    reading_filter: ReadingFilter,
+    command_filter: CommandFilter,
+    frame_filter: FrameFilter,
+    replay_filter: ReplayFilter,
+    pending_high_us: Option<u32>,
+    capture_pulses: bool,
     button_parser: ButtonParser,
+    persistency: &'d P,
+    receiver_control: &'d ReceiverControl,
+    code_table: CodeTable,
+    // The table generation the code table was loaded at, none before it was loaded.
+    code_table_generation: Option<u32>,
 }
 
+/// The programs of the receivers, each is loaded once when the first receiver of its mode needs it.
 #[cfg(not(test))]
-impl<'d, PIO: pio::Instance, const SM: usize> RemoteReceiver<'d, PIO, SM> {
-    pub fn new(pio: &mut pio::Common<'d, PIO>, mut pio_sm: pio::StateMachine<'d, PIO, SM>, receiver_pin: impl PioPin) -> Self {
-        let mut pin = pio.make_pio_pin(receiver_pin);
-        pin.set_pull(gpio::Pull::None);
-        pio_sm.set_pin_dirs(pio::Direction::In, &[&pin]);
-
-        let prg = pio_asm!(
-            "startup:"
-                "set x 31", // 31 is maximum and sufficient
-            "assert_initial_low_pulse:",
-                "jmp pin startup"
-                "jmp x-- assert_initial_low_pulse",
-
-            "set x 24", // one less than the number of bits to read
-            "read_bits:",
-                "wait 1 pin 0 [5]",
-                "in pins, 1",
-                "wait 0 pin 0",
-                "jmp x-- read_bits",
-
-            "push",
-        );
+pub struct ReceiverPrograms<'d, PIO: pio::Instance> {
+    fixed_timing: FixedTimingSettings,
+    fixed_timing_program: Option<pio::LoadedProgram<'d, PIO>>,
+    pulse_width_program: Option<pio::LoadedProgram<'d, PIO>>,
+}
+
+#[cfg(not(test))]
+impl<'d, PIO: pio::Instance> ReceiverPrograms<'d, PIO> {
+    pub fn new(fixed_timing: FixedTimingSettings) -> Self {
+        let fixed_timing = match fixed_timing.ticks() {
+            Ok(_) => fixed_timing,
+            Err(msg) => {
+                error!("{}, the default fixed timing is used", msg);
+                FixedTimingSettings::DEFAULT
+            },
+        };
+        Self { fixed_timing, fixed_timing_program: None, pulse_width_program: None }
+    }
+
+    fn configure(&mut self, pio: &mut pio::Common<'d, PIO>, mode: ReceiverMode, cfg: &mut pio::Config<'d, PIO>) {
+        match mode {
+            ReceiverMode::FixedTiming => {
+                let settings = self.fixed_timing;
+                let program = self.fixed_timing_program.get_or_insert_with(|| {
+                    let prg = fixed_timing::program(&settings.ticks().unwrap());
+                    pio.load_program(&prg)
+                });
+                cfg.shift_in.direction = pio::ShiftDirection::Left;
+                cfg.clock_divider = settings.clock_divider.to_fixed(); // 125MHz / 12500 = 10kHz by default
+                cfg.use_program(program, &[]);
+            },
+            ReceiverMode::PulseWidth => {
+                let program = self.pulse_width_program.get_or_insert_with(|| {
+                    // Pushes the duration of each high pulse and of the following low pulse.
+                    // x counts down from 0xFFFFFFFF, so ~x is the number of loop iterations.
+                    let prg = pio_asm!(
+                        ".wrap_target",
+                            "wait 1 pin 0",
+                            "mov x, ~null",
+                        "high:",
+                            "jmp pin high_continue",
+                            "jmp high_end",
+                        "high_continue:",
+                            "jmp x-- high",
+                        "high_end:",
+                            "mov isr, ~x",
+                            "push block",
+
+                            "mov x, ~null",
+                        "low:",
+                            "jmp pin low_end",
+                            "jmp x-- low",
+                        "low_end:",
+                            "mov isr, ~x",
+                            "push block",
+                        ".wrap",
+                    );
+                    pio.load_program(&prg.program)
+                });
+                cfg.clock_divider = 62.5.to_fixed(); // 125MHz / 62.5 = 2MHz, one loop iteration (2 cycles) is 1us
+                cfg.use_program(program, &[]);
+            },
+        }
+    }
+}
+
+#[cfg(not(test))]
+impl<'d, PIO: pio::Instance, const SM: usize, P: PersistencyTrait> RemoteReceiver<'d, PIO, SM, P> {
+    /// Only one receiver should capture pulses, the pulse capture can't tell receivers apart.
+    /// The pin is set up by the caller, receivers of different modes may share it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(pio: &mut pio::Common<'d, PIO>, programs: &mut ReceiverPrograms<'d, PIO>, mut pio_sm: pio::StateMachine<'d, PIO, SM>, pin: &pio::Pin<'d, PIO>, mode: ReceiverMode, policy: ConfirmationPolicy, flex_decoders: FlexDecoders, capture_pulses: bool, persistency: &'d P, receiver_control: &'d ReceiverControl) -> Self {
+        pio_sm.set_pin_dirs(pio::Direction::In, &[pin]);
 
         let mut cfg = pio::Config::default();
-        cfg.set_in_pins(&[&pin]);
-        cfg.set_jmp_pin(&pin);
+        cfg.set_in_pins(&[pin]);
+        cfg.set_jmp_pin(pin);
         cfg.fifo_join = pio::FifoJoin::RxOnly;
-        cfg.shift_in.direction = pio::ShiftDirection::Left;
-        cfg.clock_divider = 12500.to_fixed(); // 125MHz / 12500 = 10kHz
-        cfg.use_program(&pio.load_program(&prg.program), &[]);
+        programs.configure(pio, mode, &mut cfg);
+        if capture_pulses {
+            receiver_control.set_pulses_available();
+        }
+
         pio_sm.set_config(&cfg);
         pio_sm.set_enable(true);
 
         Self {
             pio_sm,
-            button_parser: ButtonParser::new(),
+            mode,
+            decoders: pulse_decoders(flex_decoders),
+            reading_filter: ReadingFilter::new(),
+            command_filter: CommandFilter::new(),
+            frame_filter: FrameFilter::new(),
+            replay_filter: ReplayFilter::new(),
+            pending_high_us: None,
+            capture_pulses,
+            button_parser: ButtonParser::new(policy),
+            persistency,
+            receiver_control,
+            code_table: CodeTable::new(),
+            code_table_generation: None,
         }
     }
 
-    pub async fn read(&mut self) -> &str {
+    /// Can be cancelled. At worst the frame being processed is lost, which the remote repeats anyway.
+    pub async fn read(&mut self) -> (Received, FrameQuality) {
         loop {
-            let value = self.pio_sm.rx().wait_pull().await;
-            if let Some(button) = self.button_parser.run(value) {
-                return button;
+            let (code, remote, tri_state, jitter_us) = match self.read_value().await {
+                Value::Code(frame, remote, tri_state) => match self.mode {
+                    ReceiverMode::FixedTiming => (Code::fixed_timing(frame.value), remote, tri_state, None),
+                    ReceiverMode::PulseWidth => (Code::pulse_width(&frame), remote, tri_state, Some(self.rc_switch_decoder().jitter_us())),
+                },
+                // Only the fixed part goes on, so the button is confirmed and looked up like a fixed code.
+                Value::Rolling(frame) => {
+                    if !self.replay_filter.accept(&frame, Instant::now().as_millis()) {
+                        error!("{} frame of 0x{:07X} rejected, its counter did not advance", frame.protocol.as_str(), frame.serial);
+                        continue;
+                    }
+                    (frame.code(), None, None, None)
+                },
+                Value::Sensor(reading) => {
+                    if self.reading_filter.accept(&reading, Instant::now().as_millis()) {
+                        return (Received::Sensor(reading), self.quality(1, None));
+                    }
+                    continue;
+                },
+                Value::Intertechno(command) => {
+                    if self.command_filter.accept(&command, Instant::now().as_millis()) {
+                        return (Received::Intertechno(command), self.quality(1, None));
+                    }
+                    continue;
+                },
+                Value::Flex(frame) => {
+                    if self.frame_filter.accept(&frame, Instant::now().as_millis()) {
+                        return (Received::Flex(frame), self.quality(1, None));
+                    }
+                    continue;
+                },
+            };
+
+            // Changes made on the terminal take effect with the next value.
+            let generation = self.receiver_control.table_generation();
+            if self.code_table_generation != Some(generation) {
+                match CodeTable::load(self.persistency).await {
+                    Ok(code_table) => self.code_table = code_table,
+                    Err(msg) => error!("Error loading code table: {}", msg),
+                }
+                self.code_table_generation = Some(generation);
+            }
+
+            if let Some(button) = self.button_parser.run(code, Instant::now().as_millis(), &self.code_table) {
+                if let Some(remote) = remote {
+                    debug!("remote 0x{:05X}, key 0x{:X}, protocol {}, {} bits", remote.remote_id, remote.key, remote.protocol, remote.bit_count);
+                }
+                if let Some(tri_state) = &tri_state {
+                    debug!("tri-state code {}", tri_state.as_str());
+                }
+                // Codes that are not in the table themselves may belong to a named remote.
+                let name = match button {
+                    Button::Known(name) => Some(String::try_from(name).unwrap()),
+                    Button::Unknown => remote.and_then(|remote| self.code_table.lookup_key(remote.remote_id, remote.key)),
+                };
+                let quality = self.quality(self.button_parser.repeats(), jitter_us);
+                return (Received::Button(ReceivedButton {
+                    code,
+                    name,
+                    remote,
+                    tri_state,
+                }), quality);
             }
         }
     }
+
+    // The rc-switch decoder is the last one of the pulse decoders.
+    fn rc_switch_decoder(&mut self) -> &mut RcSwitchDecoder {
+        self.decoders.4.decoder()
+    }
+
+    // The RSSI is added by the caller, only it knows the radio.
+    fn quality(&mut self, repeats: u8, jitter_us: Option<u32>) -> FrameQuality {
+        let rejected = self.button_parser.take_rejected()
+            .saturating_add(self.rc_switch_decoder().take_corrupt_frames())
+            .saturating_add(self.replay_filter.take_rejected());
+        FrameQuality { jitter_us, repeats, rejected, rssi_dbm: None }
+    }
+
+    /// Drops what was received while the gateway was sending, so its own codes are not taken as received.
+    pub fn discard_received(&mut self) {
+        match self.mode {
+            ReceiverMode::FixedTiming => while self.pio_sm.rx().try_pull().is_some() {},
+            ReceiverMode::PulseWidth => {
+                // High and low durations alternate, the next one must still be taken as the right one.
+                let mut high_pending = self.pending_high_us.is_some();
+                while self.pio_sm.rx().try_pull().is_some() {
+                    high_pending = !high_pending;
+                }
+                // A pulse of length 0 is no part of a frame, so the decoders start over.
+                self.pending_high_us = high_pending.then_some(0);
+            },
+        }
+    }
+
+    // Must be cancel safe, so no pulse gets lost when reading is aborted.
+    async fn read_value(&mut self) -> Value {
+        match self.mode {
+            ReceiverMode::FixedTiming => {
+                let value = self.pio_sm.rx().wait_pull().await;
+                let frame = Frame { protocol: FIXED_TIMING_PROTOCOL, value, bit_count: FIXED_TIMING_BIT_COUNT };
+                Value::Code(frame, RemoteFrame::from_fixed_timing(value), TriStateCode::from_fixed_timing(value))
+            },
+            ReceiverMode::PulseWidth => loop {
+                let duration_us = self.pio_sm.rx().wait_pull().await;
+                let Some(high_us) = self.pending_high_us.take() else {
+                    self.pending_high_us = Some(duration_us);
+                    continue;
+                };
+                let low_us = duration_us;
+                if self.capture_pulses {
+                    self.receiver_control.offer_pulse(Pulse { pulse_us: high_us, gap_us: low_us });
+                }
+                if let Some(value) = self.decoders.run(high_us, low_us) {
+                    value.log();
+                    return value;
+                }
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ReceiverMode {
+    FixedTiming, // fixed start gap, 25 bits sampled at 10kHz
+    PulseWidth, // pulse durations decoded as rc-switch protocols, Intertechno, KeeLoq, Somfy RTS, weather sensors and flex decoders
+}
+
+impl ReceiverMode {
+    pub async fn load<P>(persistency: &P) -> Self
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; 16];
+        match persistency.read(ValueId::ReceiverMode, &mut bytes).await {
+            Ok(length) => Self::from_bytes(&bytes[..length]).unwrap_or(Self::FixedTiming),
+            Err(_) => Self::FixedTiming,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            b"fixed" => Some(Self::FixedTiming),
+            b"pulse" => Some(Self::PulseWidth),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Button<'a> {
+    Known(&'a str),
+    Unknown,
+}
+
+/// Decides when repeated frames count as a button press.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ConfirmationPolicy {
+    pub frames: u8, // number of equal frames in a row that are needed
+    pub max_gap_ms: u64, // maximum time between two of these frames, 0 means no limit
+    pub lockout_ms: u64, // no other press is confirmed for this time after the last frame of a press
+}
+
+impl ConfirmationPolicy {
+    pub const DEFAULT: Self = Self { frames: 2, max_gap_ms: 0, lockout_ms: 0 };
+
+    pub async fn load<P>(persistency: &P) -> Self
+    where P: PersistencyTrait,
+    {
+        Self {
+            frames: Self::load_number(persistency, ValueId::ConfirmFrames).await
+                .and_then(|frames| u8::try_from(frames).ok())
+                .filter(|frames| *frames > 0)
+                .unwrap_or(Self::DEFAULT.frames),
+            max_gap_ms: Self::load_number(persistency, ValueId::ConfirmGapMs).await.unwrap_or(Self::DEFAULT.max_gap_ms),
+            lockout_ms: Self::load_number(persistency, ValueId::LockoutMs).await.unwrap_or(Self::DEFAULT.lockout_ms),
+        }
+    }
+
+    async fn load_number<P>(persistency: &P, value_id: ValueId) -> Option<u64>
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; 10];
+        let length = persistency.read(value_id, &mut bytes).await.ok()?;
+        core::str::from_utf8(&bytes[..length]).ok()?.parse().ok()
+    }
 }
 
 struct ButtonParser {
-    last_value: Option<u32>,
+    policy: ConfirmationPolicy,
+    last_value: Option<Code>,
+    last_ms: u64,
     value_cnt: u8,
+    confirmed: bool,
+    lockout_end_ms: u64,
+    rejected: u16, // frames of runs that were never confirmed
 }
 
 impl ButtonParser {
-    pub fn new() -> Self {
+    pub fn new(policy: ConfirmationPolicy) -> Self {
         Self {
+            policy,
             last_value: None,
+            last_ms: 0,
             value_cnt: 0,
+            confirmed: false,
+            lockout_end_ms: 0,
+            rejected: 0,
         }
     }
 
-    pub fn run(&mut self, value: u32) -> Option<&'static str> {
+    /// The number of equal frames in a row up to the last one.
+    pub fn repeats(&self) -> u8 {
+        self.value_cnt
+    }
+
+    /// The number of rejected frames since the last call.
+    pub fn take_rejected(&mut self) -> u16 {
+        core::mem::take(&mut self.rejected)
+    }
+
+    pub fn run<'a>(&mut self, value: Code, now_ms: u64, code_table: &'a CodeTable) -> Option<Button<'a>> {
+        let in_time = self.policy.max_gap_ms == 0 || now_ms - self.last_ms <= self.policy.max_gap_ms;
         match self.last_value {
-            Some(last) if value == last => {
-                self.value_cnt += 1;
+            Some(last) if value == last && in_time => {
+                self.value_cnt = self.value_cnt.saturating_add(1);
             }
             _ => {
+                if self.confirmed {
+                    self.lockout_end_ms = self.last_ms + self.policy.lockout_ms;
+                } else {
+                    self.rejected = self.rejected.saturating_add(self.value_cnt as u16);
+                }
                 self.value_cnt = 1;
                 self.last_value = Some(value);
+                self.confirmed = false;
             }
         }
+        self.last_ms = now_ms;
 
-        if self.value_cnt >= 2 {
-            match value {
-                0x017E9E90u32 => return Some("button 1"),
-                0x017E9E88u32 => return Some("button 2"),
-                0x017E9E98u32 => return Some("button 3"),
-                0x017E9E84u32 => return Some("button 4"),
-                0x017E9E94u32 => return Some("button 5"),
-                0x017E9E8Cu32 => return Some("button 6"),
-                0x017E9E9Cu32 => return Some("button 7"),
-                0x017E9E82u32 => return Some("button 8"),
-                0x017E9E92u32 => return Some("button 9"),
-                0x017E9E8Au32 => return Some("button 10"),
-                _ => return Some("undefined button"),
-            }
+        if self.value_cnt < self.policy.frames {
+            return None;
+        }
+        // Once confirmed, the repetitions of a press are passed on, so holding a button works.
+        if !self.confirmed && now_ms < self.lockout_end_ms {
+            return None;
         }
-        None
+        self.confirmed = true;
+        Some(code_table.lookup(&value).map_or(Button::Unknown, Button::Known))
     }
 }
 
 #[cfg(test)]
 mod button_parser_tests {
-    use super::ButtonParser;
-
-    const VALUES: &[(u32, &str)] = &[
-        (0x017E9E90u32, "button 1"),
-        (0x017E9E88u32, "button 2"),
-        (0x017E9E98u32, "button 3"),
-        (0x017E9E84u32, "button 4"),
-        (0x017E9E94u32, "button 5"),
-        (0x017E9E8Cu32, "button 6"),
-        (0x017E9E9Cu32, "button 7"),
-        (0x017E9E82u32, "button 8"),
-        (0x017E9E92u32, "button 9"),
-        (0x017E9E8Au32, "button 10"),
-        (42u32, "undefined button"),
+    use super::{Button, ButtonParser, ConfirmationPolicy};
+    use crate::modules::persistency::{MockPersistencyTrait, ValueId};
+    use crate::modules::code_table::{Code, CodeTable};
+
+    const VALUES: &[(u32, Button)] = &[
+        (0x017E9E90u32, Button::Known("button 1")),
+        (0x017E9E88u32, Button::Known("button 2")),
+        (0x017E9E98u32, Button::Known("button 3")),
+        (0x017E9E84u32, Button::Known("button 4")),
+        (0x017E9E94u32, Button::Known("button 5")),
+        (0x017E9E8Cu32, Button::Known("button 6")),
+        (0x017E9E9Cu32, Button::Known("button 7")),
+        (0x017E9E82u32, Button::Known("button 8")),
+        (0x017E9E92u32, Button::Known("button 9")),
+        (0x017E9E8Au32, Button::Known("button 10")),
+        (42u32, Button::Unknown),
     ];
 
+    fn code_table() -> CodeTable {
+        let mut code_table = CodeTable::new();
+        for (value, button) in VALUES {
+            if let Button::Known(name) = button {
+                code_table.add(Code::fixed_timing(*value), name).unwrap();
+            }
+        }
+        code_table
+    }
+
     #[test]
     fn the_same_button() {
-        let mut button_parser = ButtonParser::new();
+        let mut button_parser = ButtonParser::new(ConfirmationPolicy::DEFAULT);
+        let code_table = code_table();
 
         for (value, button) in VALUES {
             // first time is expected None
-            let result_button = button_parser.run(*value);
-            assert_eq!(result_button, None, "expected button: {}", *button);
+            let result_button = button_parser.run(Code::fixed_timing(*value), 0, &code_table);
+            assert_eq!(result_button, None, "expected button: {:?}", *button);
 
             // second time is expected the correct button
-            let result_button = button_parser.run(*value);
-            assert_eq!(result_button.unwrap(), *button, "expected button: {}", *button);
+            let result_button = button_parser.run(Code::fixed_timing(*value), 0, &code_table);
+            assert_eq!(result_button.unwrap(), *button, "expected button: {:?}", *button);
 
             // third time is also expected the correct button
-            let result_button = button_parser.run(*value);
-            assert_eq!(result_button.unwrap(), *button, "expected button: {}", *button);
+            let result_button = button_parser.run(Code::fixed_timing(*value), 0, &code_table);
+            assert_eq!(result_button.unwrap(), *button, "expected button: {:?}", *button);
         }
     }
 
     #[test]
     fn changing_button() {
-        let mut button_parser = ButtonParser::new();
+        let mut button_parser = ButtonParser::new(ConfirmationPolicy::DEFAULT);
+        let code_table = code_table();
 
         // twice the same button results in the button
-        let (value, button) = VALUES[0];
-        let _ = button_parser.run(value);
-        let result_button = button_parser.run(value);
-        assert_eq!(result_button.unwrap(), button, "expected button: {}", button);
+        let (value, ref button) = VALUES[0];
+        let _ = button_parser.run(Code::fixed_timing(value), 0, &code_table);
+        let result_button = button_parser.run(Code::fixed_timing(value), 0, &code_table);
+        assert_eq!(result_button.unwrap(), *button, "expected button: {:?}", button);
 
         // changing the button results first in None
-        let (value, button) = VALUES[1];
-        let result_button = button_parser.run(value);
-        assert_eq!(result_button, None, "expected button: {}", button);
+        let (value, ref button) = VALUES[1];
+        let result_button = button_parser.run(Code::fixed_timing(value), 0, &code_table);
+        assert_eq!(result_button, None, "expected button: {:?}", button);
 
         // then again in the right button
-        let result_button = button_parser.run(value);
-        assert_eq!(result_button.unwrap(), button, "expected button: {}", button);
+        let result_button = button_parser.run(Code::fixed_timing(value), 0, &code_table);
+        assert_eq!(result_button.unwrap(), *button, "expected button: {:?}", button);
+    }
+
+    #[test]
+    fn edited_code_table() {
+        let mut button_parser = ButtonParser::new(ConfirmationPolicy::DEFAULT);
+        let mut code_table = CodeTable::new();
+
+        let _ = button_parser.run(Code::fixed_timing(0x42), 0, &code_table);
+        assert_eq!(button_parser.run(Code::fixed_timing(0x42), 0, &code_table).unwrap(), Button::Unknown);
+
+        code_table.add(Code::fixed_timing(0x42), "doorbell").unwrap();
+        assert_eq!(button_parser.run(Code::fixed_timing(0x42), 0, &code_table).unwrap(), Button::Known("doorbell"));
+
+        code_table.remove("doorbell").unwrap();
+        assert_eq!(button_parser.run(Code::fixed_timing(0x42), 0, &code_table).unwrap(), Button::Unknown);
+    }
+
+    // Runs (value, time) frames and returns the times at which a button was confirmed.
+    fn confirmed_at(policy: ConfirmationPolicy, frames: &[(u32, u64)]) -> std::vec::Vec<u64> {
+        let mut button_parser = ButtonParser::new(policy);
+        let code_table = code_table();
+        frames.iter()
+            .filter(|(value, now_ms)| button_parser.run(Code::fixed_timing(*value), *now_ms, &code_table).is_some())
+            .map(|(_, now_ms)| *now_ms)
+            .collect()
+    }
+
+    #[test]
+    fn first_frame_is_enough() {
+        let policy = ConfirmationPolicy { frames: 1, ..ConfirmationPolicy::DEFAULT };
+
+        assert_eq!(confirmed_at(policy, &[(0x017E9E90, 1000), (0x017E9E90, 1040), (0x017E9E88, 1080)]), [1000, 1040, 1080]);
+    }
+
+    #[test]
+    fn higher_threshold() {
+        let policy = ConfirmationPolicy { frames: 3, ..ConfirmationPolicy::DEFAULT };
+
+        // noise between the frames restarts counting
+        let frames = [
+            (0x017E9E90, 1000), (0x017E9E90, 1040), (0x00000042, 1060),
+            (0x017E9E90, 1080), (0x017E9E90, 1120), (0x017E9E90, 1160), (0x017E9E90, 1200),
+        ];
+        assert_eq!(confirmed_at(policy, &frames), [1160, 1200]);
+    }
+
+    #[test]
+    fn frames_too_far_apart() {
+        let policy = ConfirmationPolicy { max_gap_ms: 100, ..ConfirmationPolicy::DEFAULT };
+
+        let frames = [(0x017E9E90, 1000), (0x017E9E90, 1200), (0x017E9E90, 1300), (0x017E9E90, 1401), (0x017E9E90, 1500)];
+        assert_eq!(confirmed_at(policy, &frames), [1300, 1500]);
+
+        // without a limit, any gap is accepted
+        assert_eq!(confirmed_at(ConfirmationPolicy::DEFAULT, &frames), [1200, 1300, 1401, 1500]);
+    }
+
+    #[test]
+    fn lockout_after_press() {
+        let policy = ConfirmationPolicy { lockout_ms: 500, ..ConfirmationPolicy::DEFAULT };
+
+        let frames = [
+            // press of button 1, the repetitions are passed on
+            (0x017E9E90, 1000), (0x017E9E90, 1040), (0x017E9E90, 1080),
+            // button 2 within the lockout window is ignored
+            (0x017E9E88, 1200), (0x017E9E88, 1240), (0x017E9E88, 1540),
+            // until the window is over
+            (0x017E9E88, 1620),
+            // button 1 again, after the lockout window of button 2
+            (0x017E9E90, 2200), (0x017E9E90, 2240),
+        ];
+        assert_eq!(confirmed_at(policy, &frames), [1040, 1080, 1620, 2240]);
+    }
+
+    #[test]
+    fn repeats_and_rejected_frames() {
+        let mut button_parser = ButtonParser::new(ConfirmationPolicy::DEFAULT);
+        let code_table = code_table();
+
+        // a corrupted frame between two frames of a press
+        for (value, now_ms) in [(0x017E9E90, 1000), (0x017E9E00, 1040), (0x017E9E90, 1080)] {
+            assert_eq!(button_parser.run(Code::fixed_timing(value), now_ms, &code_table), None);
+        }
+        assert!(button_parser.run(Code::fixed_timing(0x017E9E90), 1120, &code_table).is_some());
+        assert_eq!(button_parser.repeats(), 2);
+        assert_eq!(button_parser.take_rejected(), 2);
+
+        // the frames of a confirmed press are not rejected
+        assert!(button_parser.run(Code::fixed_timing(0x017E9E90), 1160, &code_table).is_some());
+        assert_eq!(button_parser.repeats(), 3);
+        let _ = button_parser.run(Code::fixed_timing(0x017E9E88), 1200, &code_table);
+        assert_eq!(button_parser.take_rejected(), 0);
+    }
+
+    #[tokio::test]
+    async fn load_policy() {
+        // frames, gap and lockout as stored, then the policy they load to
+        type StoredPolicy = (&'static [u8], &'static [u8], &'static [u8], ConfirmationPolicy);
+        const STORED: &[StoredPolicy] = &[
+            (b"3", b"200", b"500", ConfirmationPolicy { frames: 3, max_gap_ms: 200, lockout_ms: 500 }),
+            (b"", b"", b"", ConfirmationPolicy::DEFAULT),
+            (b"0", b"x", b"-5", ConfirmationPolicy::DEFAULT),
+            (b"256", b"200", b"", ConfirmationPolicy { max_gap_ms: 200, ..ConfirmationPolicy::DEFAULT }),
+        ];
+
+        for (frames, max_gap_ms, lockout_ms, policy) in STORED {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            for (value_id, stored) in [(ValueId::ConfirmFrames, frames), (ValueId::ConfirmGapMs, max_gap_ms), (ValueId::LockoutMs, lockout_ms)] {
+                mock_persistency.expect_read()
+                    .times(1)
+                    .withf(move |id, _| *id == value_id)
+                    .returning_st(move |_, answer| {
+                        answer[..stored.len()].copy_from_slice(stored);
+                        Ok(stored.len())
+                    });
+            }
+
+            assert_eq!(ConfirmationPolicy::load(&mock_persistency).await, *policy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod receiver_mode_tests {
+    use super::ReceiverMode;
+    use crate::modules::persistency::{MockPersistencyTrait, ValueId};
+
+    #[test]
+    fn from_bytes() {
+        assert_eq!(ReceiverMode::from_bytes(b"fixed"), Some(ReceiverMode::FixedTiming));
+        assert_eq!(ReceiverMode::from_bytes(b"pulse"), Some(ReceiverMode::PulseWidth));
+        assert_eq!(ReceiverMode::from_bytes(b"pulses"), None);
+        assert_eq!(ReceiverMode::from_bytes(b""), None);
+    }
+
+    #[tokio::test]
+    async fn load() {
+        const STORED: &[(&[u8], ReceiverMode)] = &[
+            (b"pulse", ReceiverMode::PulseWidth),
+            (b"fixed", ReceiverMode::FixedTiming),
+            (b"", ReceiverMode::FixedTiming),
+            (b"nonsense", ReceiverMode::FixedTiming),
+        ];
+
+        for (stored, mode) in STORED {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            mock_persistency.expect_read()
+                .times(1)
+                .withf(|id, _| *id == ValueId::ReceiverMode)
+                .returning_st(move |_, answer| {
+                    answer[..stored.len()].copy_from_slice(stored);
+                    Ok(stored.len())
+                });
+
+            assert_eq!(ReceiverMode::load(&mock_persistency).await, *mode);
+        }
     }
 }
@@ -0,0 +1,368 @@
+//! Identifies the transmitters of KeeLoq and Somfy RTS remotes, which send a new code on every press.
+//! Only the fixed part of a frame, the serial number and the button, is taken as code, so these remotes
+//! can be learned like any other. Nothing is decrypted: the counter of Somfy frames is merely obfuscated,
+//! the one of KeeLoq frames is encrypted and only its hopping code is compared.
+
+use heapless::Vec;
+
+use crate::modules::code_table::Code;
+use crate::modules::decoder::Decoder;
+use crate::modules::line_code::{Bits, ManchesterDecoder, PwmDecoder};
+
+// KeeLoq encoders like the HCS301 send a bit in three base times of about 400us,
+// a one as a short and a zero as a long pulse. The frame is ended by a guard time of 39 base times.
+const KEELOQ_SHORT_US: u32 = 400;
+const KEELOQ_LONG_US: u32 = 800;
+const KEELOQ_RESET_US: u32 = 2000; // between the longest gap of a bit and the header of 10 base times
+const KEELOQ_BITS: usize = 66; // 32 bits hopping code, 28 bits serial, 4 bits button, battery low and repeat
+
+// Somfy RTS sends 7 bytes in Manchester code of 1280us per bit, frames are separated by about 30ms.
+const SOMFY_HALF_BIT_US: u32 = 640;
+const SOMFY_RESET_US: u32 = 5000;
+const SOMFY_BITS: usize = 56;
+
+// Repetitions of a press are sent with the same counter and without a pause, the gap between them is below this time.
+const REPEAT_GAP_MS: u64 = 500;
+const MAX_TRANSMITTERS: usize = 8;
+// KeeLoq hopping codes can't be ordered without the key, so the last ones of a transmitter are kept.
+const HISTORY_SIZE: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RollingProtocol {
+    KeeLoq,
+    Somfy,
+}
+
+impl RollingProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::KeeLoq => "KeeLoq",
+            Self::Somfy => "Somfy RTS",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RollingFrame {
+    pub protocol: RollingProtocol,
+    pub serial: u32, // 28 bits for KeeLoq, the 24 bit address for Somfy
+    pub button: u8, // 4 bits
+    pub rolling_code: u32, // the encrypted hopping code for KeeLoq, the 16 bit counter for Somfy
+}
+
+impl RollingFrame {
+    /// The part that is the same for every press of the button, it takes the place of the code of fixed code remotes.
+    pub fn code(&self) -> Code {
+        let (protocol, bit_count) = match self.protocol {
+            RollingProtocol::KeeLoq => (Code::KEELOQ_PROTOCOL, 32),
+            RollingProtocol::Somfy => (Code::SOMFY_PROTOCOL, 28),
+        };
+        Code { value: self.serial << 4 | self.button as u32, protocol, bit_count }
+    }
+}
+
+/// Runs the decoders of all supported rolling code protocols on the pulse stream.
+pub struct RollingCodeDecoder {
+    decoders: (KeeLoqDecoder, SomfyDecoder),
+}
+
+impl RollingCodeDecoder {
+    pub fn new() -> Self {
+        Self { decoders: (KeeLoqDecoder::new(), SomfyDecoder::new()) }
+    }
+}
+
+impl Decoder for RollingCodeDecoder {
+    type Frame = RollingFrame;
+
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<RollingFrame> {
+        self.decoders.run(high_us, low_us)
+    }
+}
+
+pub struct KeeLoqDecoder {
+    pwm: PwmDecoder,
+}
+
+impl KeeLoqDecoder {
+    pub fn new() -> Self {
+        Self { pwm: PwmDecoder::new(KEELOQ_SHORT_US, KEELOQ_LONG_US, true, KEELOQ_RESET_US) }
+    }
+}
+
+impl Decoder for KeeLoqDecoder {
+    type Frame = RollingFrame;
+
+    // The preamble ends in a row of its own at the header gap, it is dropped for its length.
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<RollingFrame> {
+        keeloq(&self.pwm.run(high_us, low_us)?)
+    }
+}
+
+pub struct SomfyDecoder {
+    manchester: ManchesterDecoder,
+}
+
+impl SomfyDecoder {
+    pub fn new() -> Self {
+        Self { manchester: ManchesterDecoder::new(SOMFY_HALF_BIT_US, SOMFY_RESET_US) }
+    }
+}
+
+impl Decoder for SomfyDecoder {
+    type Frame = RollingFrame;
+
+    // The wake up and sync pulses are too long for the line code, so the row starts after them.
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<RollingFrame> {
+        somfy(&self.manchester.run(high_us, low_us)?)
+    }
+}
+
+// The fields are sent least significant bit first.
+fn keeloq(row: &Bits) -> Option<RollingFrame> {
+    if row.len() != KEELOQ_BITS {
+        return None;
+    }
+    let field = |n: usize, count: usize| (0..count).fold(0u32, |value, i| value | (row.bit(n + i) as u32) << i);
+    Some(RollingFrame {
+        protocol: RollingProtocol::KeeLoq,
+        serial: field(32, 28),
+        button: field(60, 4) as u8,
+        rolling_code: field(0, 32),
+    })
+}
+
+// A one is sent as low then high, the inverse of the line code decoder. Each byte is obfuscated by
+// an XOR with the byte sent before it, and the nibbles of the frame including the checksum XOR to zero.
+fn somfy(row: &Bits) -> Option<RollingFrame> {
+    if row.len() != SOMFY_BITS {
+        return None;
+    }
+    let mut bytes = [0u8; SOMFY_BITS / 8];
+    for (n, byte) in bytes.iter_mut().enumerate() {
+        *byte = !row.bits(8 * n, 8) as u8;
+    }
+    for n in (1..bytes.len()).rev() {
+        bytes[n] ^= bytes[n - 1];
+    }
+    if bytes.iter().fold(0, |sum, byte| sum ^ byte ^ byte >> 4) & 0x0F != 0 {
+        return None;
+    }
+    Some(RollingFrame {
+        protocol: RollingProtocol::Somfy,
+        serial: u32::from_be_bytes([0, bytes[4], bytes[5], bytes[6]]),
+        button: bytes[1] >> 4,
+        rolling_code: u16::from_be_bytes([bytes[2], bytes[3]]) as u32,
+    })
+}
+
+struct Transmitter {
+    protocol: RollingProtocol,
+    serial: u32,
+    last_ms: u64,
+    rolling_codes: Vec<u32, HISTORY_SIZE>, // the last one is the latest
+}
+
+impl Transmitter {
+    fn latest(&self) -> u32 {
+        *self.rolling_codes.last().unwrap()
+    }
+
+    // A Somfy counter must count up, half of its range is taken as ahead, as the remote may have been used out of range.
+    fn advanced(&self, rolling_code: u32) -> bool {
+        match self.protocol {
+            RollingProtocol::KeeLoq => !self.rolling_codes.contains(&rolling_code),
+            RollingProtocol::Somfy => (1..0x8000).contains(&(rolling_code as u16).wrapping_sub(self.latest() as u16)),
+        }
+    }
+}
+
+/// Passes the repetitions of a press and new presses, but rejects frames whose counter does not advance,
+/// as they are most likely replayed. The counters are not persisted, so the first frame after a restart is taken as it is.
+pub struct ReplayFilter {
+    transmitters: Vec<Transmitter, MAX_TRANSMITTERS>,
+    rejected: u16,
+}
+
+impl ReplayFilter {
+    pub fn new() -> Self {
+        Self { transmitters: Vec::new(), rejected: 0 }
+    }
+
+    /// The number of rejected frames since the last call.
+    pub fn take_rejected(&mut self) -> u16 {
+        core::mem::take(&mut self.rejected)
+    }
+
+    pub fn accept(&mut self, frame: &RollingFrame, now_ms: u64) -> bool {
+        let Some(transmitter) = self.transmitters.iter_mut()
+            .find(|transmitter| transmitter.protocol == frame.protocol && transmitter.serial == frame.serial) else {
+            if self.transmitters.is_full() {
+                let oldest = (0..self.transmitters.len()).min_by_key(|n| self.transmitters[*n].last_ms).unwrap();
+                self.transmitters.swap_remove(oldest);
+            }
+            let mut rolling_codes = Vec::new();
+            rolling_codes.push(frame.rolling_code).unwrap();
+            let _ = self.transmitters.push(Transmitter { protocol: frame.protocol, serial: frame.serial, last_ms: now_ms, rolling_codes });
+            return true;
+        };
+
+        let repeated = transmitter.latest() == frame.rolling_code && now_ms - transmitter.last_ms < REPEAT_GAP_MS;
+        if !repeated {
+            if !transmitter.advanced(frame.rolling_code) {
+                self.rejected = self.rejected.saturating_add(1);
+                return false;
+            }
+            if transmitter.rolling_codes.is_full() {
+                transmitter.rolling_codes.remove(0);
+            }
+            transmitter.rolling_codes.push(frame.rolling_code).unwrap();
+        }
+        transmitter.last_ms = now_ms;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::line_code::test_signals;
+    use crate::modules::rc_switch::test_signals::pulses;
+
+    fn decode(pulses: &[(u32, u32)]) -> std::vec::Vec<RollingFrame> {
+        let mut decoder = RollingCodeDecoder::new();
+        pulses.iter().filter_map(|(high, low)| decoder.run(*high, *low)).collect()
+    }
+
+    // A KeeLoq frame with preamble and header, like sent by a HCS301 with a base time of 400us.
+    fn keeloq_pulses(hopping_code: u32, serial: u32, button: u8) -> std::vec::Vec<(u32, u32)> {
+        let mut bits = Bits::new();
+        let mut push = |value: u32, count: usize| (0..count).for_each(|n| assert!(bits.push(value & 1 << n != 0)));
+        push(hopping_code, 32);
+        push(serial, 28);
+        push(button as u32, 4);
+        push(0, 2);
+        let mut pulses = vec![(400, 400); 11];
+        pulses.push((400, 4000));
+        pulses.extend(test_signals::pwm(&bits, 400, 800, 1200, 15_600));
+        pulses
+    }
+
+    // The bytes of a Somfy RTS frame with the checksum added.
+    fn somfy_bytes(button: u8, counter: u16, address: u32) -> [u8; 7] {
+        let [_, address_high, address_middle, address_low] = address.to_be_bytes();
+        let [counter_high, counter_low] = counter.to_be_bytes();
+        let mut bytes = [0xA7, button << 4, counter_high, counter_low, address_high, address_middle, address_low];
+        bytes[1] |= bytes.iter().fold(0, |sum, byte| sum ^ byte ^ byte >> 4) & 0x0F;
+        bytes
+    }
+
+    // The obfuscated bytes with wake up and sync pulses.
+    fn somfy_pulses(mut bytes: [u8; 7]) -> std::vec::Vec<(u32, u32)> {
+        for n in 1..bytes.len() {
+            bytes[n] ^= bytes[n - 1];
+        }
+        let mut levels = vec![(true, 9415), (false, 89_565)];
+        for _ in 0..2 {
+            levels.extend([(true, 2560), (false, 2560)]);
+        }
+        levels.extend([(true, 4550), (false, 640)]);
+        for n in 0..SOMFY_BITS {
+            let bit = bytes[n / 8] & 0x80 >> (n % 8) != 0;
+            levels.extend([(!bit, 640), (bit, 640)]);
+        }
+        levels.push((false, 30_415));
+        pulses(&levels)
+    }
+
+    #[test]
+    fn keeloq() {
+        let pulses = keeloq_pulses(0x8E3A_52C1, 0x0A1B2C3, 0x2);
+
+        let frame = RollingFrame { protocol: RollingProtocol::KeeLoq, serial: 0x0A1B2C3, button: 0x2, rolling_code: 0x8E3A_52C1 };
+        assert_eq!(decode(&pulses), [frame]);
+        assert_eq!(frame.code(), Code { value: 0xA1B2C32, protocol: Code::KEELOQ_PROTOCOL, bit_count: 32 });
+    }
+
+    #[test]
+    fn keeloq_needs_all_bits() {
+        let mut pulses = keeloq_pulses(0x8E3A_52C1, 0x0A1B2C3, 0x2);
+        pulses.remove(20);
+
+        assert_eq!(decode(&pulses), []);
+    }
+
+    #[test]
+    fn somfy() {
+        // The first bit is a zero or a one, which both merge differently with the sync.
+        for (button, counter, address) in [(0x2, 0x00A5, 0x3F1C02), (0x4, 0x1234, 0xFEDCBA), (0x1, 0xFFFF, 0x000001)] {
+            let frame = RollingFrame { protocol: RollingProtocol::Somfy, serial: address, button, rolling_code: counter as u32 };
+            assert_eq!(decode(&somfy_pulses(somfy_bytes(button, counter, address))), [frame]);
+        }
+        let frame = decode(&somfy_pulses(somfy_bytes(0x2, 0x00A5, 0x3F1C02)))[0];
+        assert_eq!(frame.code(), Code { value: 0x3F1C022, protocol: Code::SOMFY_PROTOCOL, bit_count: 28 });
+        assert_eq!(frame.protocol.as_str(), "Somfy RTS");
+    }
+
+    #[test]
+    fn somfy_wrong_checksum() {
+        let mut bytes = somfy_bytes(0x2, 0x00A5, 0x3F1C02);
+        bytes[3] ^= 0x01;
+
+        assert_eq!(decode(&somfy_pulses(bytes)), []);
+    }
+
+    fn frame(protocol: RollingProtocol, serial: u32, rolling_code: u32) -> RollingFrame {
+        RollingFrame { protocol, serial, button: 0x1, rolling_code }
+    }
+
+    #[test]
+    fn repeats_and_replays() {
+        let mut filter = ReplayFilter::new();
+        for protocol in [RollingProtocol::KeeLoq, RollingProtocol::Somfy] {
+            // a press of three frames
+            assert!(filter.accept(&frame(protocol, 0x123, 100), 1000));
+            assert!(filter.accept(&frame(protocol, 0x123, 100), 1100));
+            assert!(filter.accept(&frame(protocol, 0x123, 100), 1200));
+            // the next press
+            assert!(filter.accept(&frame(protocol, 0x123, 101), 5000));
+            // the frames of the first press are replayed
+            assert!(!filter.accept(&frame(protocol, 0x123, 100), 9000));
+            assert!(!filter.accept(&frame(protocol, 0x123, 100), 9100));
+            // the frame of the last press is replayed later
+            assert!(!filter.accept(&frame(protocol, 0x123, 101), 9200));
+            assert_eq!(filter.take_rejected(), 3);
+            assert_eq!(filter.take_rejected(), 0);
+        }
+    }
+
+    #[test]
+    fn counters() {
+        let mut filter = ReplayFilter::new();
+        // Somfy counters must count up, also across the overflow.
+        assert!(filter.accept(&frame(RollingProtocol::Somfy, 0x123, 0xFFF0), 1000));
+        assert!(filter.accept(&frame(RollingProtocol::Somfy, 0x123, 0x0005), 2000));
+        assert!(!filter.accept(&frame(RollingProtocol::Somfy, 0x123, 0xFFF8), 3000));
+        assert!(filter.accept(&frame(RollingProtocol::Somfy, 0x123, 0x0105), 4000));
+
+        // Any new KeeLoq hopping code is taken.
+        assert!(filter.accept(&frame(RollingProtocol::KeeLoq, 0x123, 0x8E3A_52C1), 1000));
+        assert!(filter.accept(&frame(RollingProtocol::KeeLoq, 0x123, 0x1F00_0C2D), 2000));
+        assert!(!filter.accept(&frame(RollingProtocol::KeeLoq, 0x123, 0x8E3A_52C1), 3000));
+    }
+
+    #[test]
+    fn transmitters_are_kept_apart() {
+        let mut filter = ReplayFilter::new();
+        assert!(filter.accept(&frame(RollingProtocol::Somfy, 0x123, 100), 1000));
+        assert!(filter.accept(&frame(RollingProtocol::Somfy, 0x456, 50), 2000));
+        assert!(filter.accept(&frame(RollingProtocol::KeeLoq, 0x123, 100), 3000));
+
+        // the oldest transmitter is forgotten
+        for serial in 0..MAX_TRANSMITTERS as u32 - 2 {
+            assert!(filter.accept(&frame(RollingProtocol::KeeLoq, 0x1000 + serial, 100), 4000 + serial as u64));
+        }
+        assert!(!filter.accept(&frame(RollingProtocol::Somfy, 0x456, 40), 9000));
+        assert!(filter.accept(&frame(RollingProtocol::Somfy, 0x123, 90), 9100));
+    }
+}
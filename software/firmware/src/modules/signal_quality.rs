@@ -0,0 +1,201 @@
+//! Quality of the received frames, to tell range problems from noise.
+//! Every frame gets the jitter of its pulses, the equal frames seen in a row, the frames rejected since the
+//! previous one and the RSSI if the CC1101 is used. Rolling aggregates per receiver are shown by `rfstats`.
+
+use core::fmt::Write;
+use heapless::{Deque, String, Vec};
+
+use crate::modules::persistency::{PersistencyTrait, ValueId};
+use crate::modules::receivers::{ReceiverLabel, MAX_RECEIVERS};
+
+// The aggregates are taken over this many frames of a receiver.
+const WINDOW_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct FrameQuality {
+    pub jitter_us: Option<u32>, // average deviation of the pulses from their nominal length, only in pulse width mode
+    pub repeats: u8, // equal frames in a row up to this one
+    pub rejected: u16, // corrupt and single frames since the previous frame that was passed on
+    pub rssi_dbm: Option<i16>, // only known with the CC1101
+}
+
+impl FrameQuality {
+    /// Whether the quality is added to published events, off by default to keep the payloads unchanged.
+    pub async fn load_enabled<P>(persistency: &P) -> bool
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; 3];
+        matches!(persistency.read(ValueId::SignalQuality, &mut bytes).await, Ok(2) if &bytes[..2] == b"on")
+    }
+
+    /// Adds the quality to a payload, JSON payloads get members for it.
+    pub fn add_to(&self, payload: &str) -> String<224> {
+        let mut extended = String::new();
+        match payload.strip_suffix('}').filter(|_| payload.starts_with('{')) {
+            Some(members) => {
+                write!(extended, "{},\"repeats\":{},\"rejected\":{}", members, self.repeats, self.rejected).unwrap();
+                if let Some(jitter_us) = self.jitter_us {
+                    write!(extended, ",\"jitter_us\":{}", jitter_us).unwrap();
+                }
+                if let Some(rssi_dbm) = self.rssi_dbm {
+                    write!(extended, ",\"rssi_dbm\":{}", rssi_dbm).unwrap();
+                }
+                extended.push('}').unwrap();
+            },
+            None => {
+                write!(extended, "{}, repeats {}, rejected {}", payload, self.repeats, self.rejected).unwrap();
+                if let Some(jitter_us) = self.jitter_us {
+                    write!(extended, ", jitter {} us", jitter_us).unwrap();
+                }
+                if let Some(rssi_dbm) = self.rssi_dbm {
+                    write!(extended, ", rssi {} dBm", rssi_dbm).unwrap();
+                }
+            },
+        }
+        extended
+    }
+}
+
+/// The qualities of the last frames of each receiver.
+pub struct SignalStats {
+    receivers: Vec<(ReceiverLabel, Deque<FrameQuality, WINDOW_SIZE>), MAX_RECEIVERS>,
+}
+
+impl SignalStats {
+    pub const fn new() -> Self {
+        Self { receivers: Vec::new() }
+    }
+
+    pub fn record(&mut self, label: &str, quality: FrameQuality) {
+        let n = match self.receivers.iter().position(|(known, _)| known == label) {
+            Some(n) => n,
+            None => {
+                let Ok(label) = ReceiverLabel::try_from(label) else {
+                    return;
+                };
+                if self.receivers.push((label, Deque::new())).is_err() {
+                    return;
+                }
+                self.receivers.len() - 1
+            },
+        };
+        let frames = &mut self.receivers[n].1;
+        if frames.is_full() {
+            frames.pop_front();
+        }
+        frames.push_back(quality).unwrap();
+    }
+
+    pub fn list(&self, answer: &mut [u8]) -> Result<usize, &'static str> {
+        if self.receivers.is_empty() {
+            let text = b"no frames received yet";
+            if text.len() > answer.len() {
+                return Err("answer buffer too small");
+            }
+            answer[..text.len()].copy_from_slice(text);
+            return Ok(text.len());
+        }
+
+        let mut length = 0;
+        for (n, (label, frames)) in self.receivers.iter().enumerate() {
+            let mut line: String<192> = String::new();
+            if n > 0 {
+                line.push('\n').unwrap();
+            }
+            Self::write_line(&mut line, if label.is_empty() { "receiver" } else { label }, frames);
+
+            if length + line.len() > answer.len() {
+                return Err("answer buffer too small");
+            }
+            answer[length..length + line.len()].copy_from_slice(line.as_bytes());
+            length += line.len();
+        }
+        Ok(length)
+    }
+
+    fn write_line(line: &mut String<192>, label: &str, frames: &Deque<FrameQuality, WINDOW_SIZE>) {
+        let count = frames.len() as i32;
+        let repeats: i32 = frames.iter().map(|quality| quality.repeats as i32).sum();
+        let rejected: u32 = frames.iter().map(|quality| quality.rejected as u32).sum();
+        write!(line, "{}: last {} frames, repeats avg {}.{}, rejected {}", label, count, repeats / count, repeats * 10 / count % 10, rejected).unwrap();
+
+        let jitters = || frames.iter().filter_map(|quality| quality.jitter_us);
+        if let Some(max) = jitters().max() {
+            write!(line, ", jitter avg {} us max {} us", jitters().sum::<u32>() / jitters().count() as u32, max).unwrap();
+        }
+        let rssis = || frames.iter().filter_map(|quality| quality.rssi_dbm.map(i32::from));
+        if let (Some(min), Some(max)) = (rssis().min(), rssis().max()) {
+            write!(line, ", rssi avg {} dBm min {} dBm max {} dBm", rssis().sum::<i32>() / rssis().count() as i32, min, max).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::persistency::MockPersistencyTrait;
+
+    const QUALITY: FrameQuality = FrameQuality { jitter_us: Some(42), repeats: 3, rejected: 1, rssi_dbm: Some(-67) };
+
+    #[test]
+    fn added_to_payloads() {
+        assert_eq!(QUALITY.add_to("button 1 pressed"), "button 1 pressed, repeats 3, rejected 1, jitter 42 us, rssi -67 dBm");
+        assert_eq!(QUALITY.add_to(r#"{"house":"12D687A","state":"on"}"#), r#"{"house":"12D687A","state":"on","repeats":3,"rejected":1,"jitter_us":42,"rssi_dbm":-67}"#);
+
+        let quality = FrameQuality { repeats: 2, ..FrameQuality::default() };
+        assert_eq!(quality.add_to("button 1 pressed"), "button 1 pressed, repeats 2, rejected 0");
+        assert_eq!(quality.add_to("{\"id\":90}"), "{\"id\":90,\"repeats\":2,\"rejected\":0}");
+    }
+
+    #[test]
+    fn aggregates_per_receiver() {
+        let mut stats = SignalStats::new();
+        let mut answer = [0u8; 300];
+
+        let length = stats.list(&mut answer).unwrap();
+        assert_eq!(&answer[..length], b"no frames received yet");
+
+        stats.record("433 living room", QUALITY);
+        stats.record("433 living room", FrameQuality { jitter_us: Some(100), repeats: 4, rejected: 0, rssi_dbm: Some(-80) });
+        stats.record("", FrameQuality { jitter_us: None, repeats: 2, rejected: 5, rssi_dbm: None });
+        let length = stats.list(&mut answer).unwrap();
+        assert_eq!(core::str::from_utf8(&answer[..length]).unwrap(), concat!(
+            "433 living room: last 2 frames, repeats avg 3.5, rejected 1, jitter avg 71 us max 100 us, rssi avg -73 dBm min -80 dBm max -67 dBm\n",
+            "receiver: last 1 frames, repeats avg 2.0, rejected 5"
+        ));
+
+        assert_eq!(stats.list(&mut answer[..50]), Err("answer buffer too small"));
+    }
+
+    #[test]
+    fn rolling_window() {
+        let mut stats = SignalStats::new();
+        for rejected in 0..WINDOW_SIZE as u16 + 10 {
+            stats.record("garage", FrameQuality { rejected, ..QUALITY });
+        }
+
+        let mut answer = [0u8; 200];
+        let length = stats.list(&mut answer).unwrap();
+        // the rejected frames of the last 32 frames are 10 + 11 + ... + 41
+        assert!(core::str::from_utf8(&answer[..length]).unwrap().starts_with("garage: last 32 frames, repeats avg 3.0, rejected 816,"));
+    }
+
+    #[tokio::test]
+    async fn load_enabled() {
+        const STORED: &[(&[u8], bool)] = &[(b"on", true), (b"off", false), (b"", false), (b"yes", false)];
+
+        for (stored, enabled) in STORED {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            mock_persistency.expect_read()
+                .times(1)
+                .withf(|id, _| *id == ValueId::SignalQuality)
+                .returning_st(move |_, answer| {
+                    let length = stored.len().min(answer.len());
+                    answer[..length].copy_from_slice(&stored[..length]);
+                    Ok(length)
+                });
+
+            assert_eq!(FrameQuality::load_enabled(&mock_persistency).await, *enabled);
+        }
+    }
+}
@@ -6,10 +6,11 @@ cfg_if! {
     if #[cfg(not(test))] {
         use core::panic;
         use embassy_executor::task;
-        use crate::modules::parser::Parser;
+        use crate::modules::parser::{self, Parser};
 
         // Note: This dependency should be removed. But as embassy::task does not support generics it cant be replaced with trait.
         use crate::modules::persistency::Persistency;
+        use crate::modules::receiver_control::ReceiverControl;
 
         use crate::modules::usb_communication::{self, UsbReceiver, UsbSender};
         use embassy_usb::driver::EndpointError;
@@ -18,9 +19,10 @@ cfg_if! {
 
 #[cfg(not(test))]
 #[task]
-pub async fn run(mut usb_receiver: UsbReceiver, usb_sender: &'static UsbSender, mut parser: Parser<'static, Persistency>) -> ! {
+pub async fn run(mut usb_receiver: UsbReceiver, usb_sender: &'static UsbSender, mut parser: Parser<'static, Persistency, ReceiverControl>) -> ! {
     let mut bytes = [0u8; usb_communication::MAX_PACKET_SIZE as usize];
-    let mut receive_buffer = [0u8; 128];
+    // Long enough for the RAW_Data lines of Flipper .sub files, which hold up to 512 durations.
+    let mut receive_buffer = [0u8; 4096];
     let mut receive_buffer_index = 0usize;
     let mut ignore_message = false;
 
@@ -50,7 +52,7 @@ pub async fn run(mut usb_receiver: UsbReceiver, usb_sender: &'static UsbSender,
                     ignore_message = false;
                 }
                 else {
-                    let mut answer = [0u8; 300];
+                    let mut answer = [0u8; parser::MAX_ANSWER_LENGTH];
                     match parser.parse_message(&receive_buffer[..receive_buffer_index], &mut answer).await {
                         Ok(length) => {
                             usb_sender.send(&answer[..length]).await.unwrap();
@@ -0,0 +1,278 @@
+//! Sends codes with a cheap 433 MHz transmitter module.
+//! A code is encoded as rc-switch frame, repeated and played by a PIO program that holds the pin at a level for a given time.
+//! Send requests of the terminal and of MQTT are queued, the button task sends them between receptions.
+
+use cfg_if::cfg_if;
+
+use crate::modules::code_table::{Code, CodeTable};
+use crate::modules::persistency::{PersistencyTrait, ValueId};
+use crate::modules::rc_switch::{self, Frame};
+use crate::modules::remote_receiver::ReceiverMode;
+
+cfg_if! {
+    if #[cfg(not(test))] {
+        use embassy_rp::{gpio, pio};
+        use embassy_rp::pio::PioPin;
+        use embassy_rp::pio::program::pio_asm;
+        use fixed::traits::ToFixed;
+        use embassy_time::Timer;
+    }
+}
+
+/// The pins the transmitter can be connected to, next to the pin of the receiver.
+pub const TX_PINS: [u8; 9] = [16, 17, 18, 19, 20, 21, 22, 26, 27];
+
+// Codes given without bit count and protocol are sent like the ones of most cheap remotes.
+// The fixed timing receiver reads these as well, followed by the sync pulse.
+const DEFAULT_BIT_COUNT: u8 = 24;
+const DEFAULT_PROTOCOL: u8 = 1;
+
+// Cycles of the PIO program between two level changes in addition to the delay loop, one cycle is 1us.
+const PROGRAM_OVERHEAD_US: u32 = 4;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TransmitterSettings {
+    pub pin: u8,
+    pub repeats: u8, // how often a frame is sent
+}
+
+impl TransmitterSettings {
+    // rc-switch sends a frame 10 times by default.
+    pub const DEFAULT: Self = Self { pin: 27, repeats: 10 };
+
+    pub async fn load<P>(persistency: &P) -> Self
+    where P: PersistencyTrait,
+    {
+        Self {
+            pin: Self::load_number(persistency, ValueId::TxPin).await
+                .filter(|pin| TX_PINS.contains(pin))
+                .unwrap_or(Self::DEFAULT.pin),
+            repeats: Self::load_number(persistency, ValueId::TxRepeats).await
+                .filter(|repeats| *repeats > 0)
+                .unwrap_or(Self::DEFAULT.repeats),
+        }
+    }
+
+    async fn load_number<P>(persistency: &P, value_id: ValueId) -> Option<u8>
+    where P: PersistencyTrait,
+    {
+        let mut bytes = [0u8; 10];
+        let length = persistency.read(value_id, &mut bytes).await.ok()?;
+        core::str::from_utf8(&bytes[..length]).ok()?.parse().ok()
+    }
+}
+
+/// Takes the name of a code in the code table or `<hex code> [<bit count> [<protocol>]]`.
+/// Named codes are sent with the protocol and bit count they were received with.
+/// Codes without bit count are taken as the receiver shows them, in fixed timing mode that includes the sync bit.
+pub fn parse_send_parameters(parameters: &[u8], code_table: &CodeTable, mode: ReceiverMode) -> Result<Frame, &'static str> {
+    let name = core::str::from_utf8(parameters).map_err(|_| "parameters are not valid utf-8")?;
+    let mut words = name.split(' ');
+    let code = match code_table.code_of(name) {
+        Some(code) => code,
+        None => {
+            let code = words.next().unwrap_or("");
+            let code = u32::from_str_radix(code.strip_prefix("0x").unwrap_or(code), 16)
+                .map_err(|_| "code is neither a name in the code table nor a hex number")?;
+            let mut number = |error| words.next().map(|word| word.parse::<u8>().map_err(|_| error)).transpose();
+            let bit_count = number("bit count is not a number")?;
+            let protocol = number("protocol is not a number")?;
+            if words.next().is_some() {
+                return Err("too many parameters");
+            }
+            match bit_count {
+                None => hex_code(code, mode),
+                Some(bit_count) => Code { value: code, protocol: protocol.unwrap_or(DEFAULT_PROTOCOL), bit_count },
+            }
+        },
+    };
+
+    let frame = sent_frame(&code)?;
+    rc_switch::encode(&frame)?;
+    Ok(frame)
+}
+
+/// A code given without bit count, as the receiver shows it in the given mode.
+pub fn hex_code(value: u32, mode: ReceiverMode) -> Code {
+    match mode {
+        ReceiverMode::FixedTiming => Code::fixed_timing(value),
+        ReceiverMode::PulseWidth => Code { value, protocol: DEFAULT_PROTOCOL, bit_count: DEFAULT_BIT_COUNT },
+    }
+}
+
+// The sync pulse read by the fixed timing receiver is not part of the frame that is sent.
+fn sent_frame(code: &Code) -> Result<Frame, &'static str> {
+    match code.protocol {
+        Code::FIXED_TIMING_PROTOCOL => {
+            if code.value & 1 != 0 || code.value >> Code::FIXED_TIMING_BIT_COUNT != 0 {
+                return Err("code was not read by the fixed timing receiver");
+            }
+            Ok(Frame { protocol: DEFAULT_PROTOCOL, value: code.value >> 1, bit_count: DEFAULT_BIT_COUNT })
+        },
+        // Only the fixed part is known, the receiver would reject a replayed frame anyway.
+        Code::KEELOQ_PROTOCOL | Code::SOMFY_PROTOCOL => Err("rolling codes can't be sent"),
+        protocol => Ok(Frame { protocol, value: code.value, bit_count: code.bit_count }),
+    }
+}
+
+/// The code of a frame as the receiver shows it, the reverse of `parse_send_parameters`.
+pub fn received_code(frame: &Frame, mode: ReceiverMode) -> Result<Code, &'static str> {
+    match mode {
+        ReceiverMode::PulseWidth => Ok(Code::pulse_width(frame)),
+        ReceiverMode::FixedTiming if frame.protocol == DEFAULT_PROTOCOL && frame.bit_count == DEFAULT_BIT_COUNT => Ok(Code::fixed_timing(frame.value << 1)),
+        ReceiverMode::FixedTiming => Err("the fixed timing receiver only reads 24 bit codes of protocol 1"),
+    }
+}
+
+// The lowest bit is the level, the others are the loop count of the delay.
+fn pio_word(level: bool, duration_us: u32) -> u32 {
+    duration_us.saturating_sub(PROGRAM_OVERHEAD_US) << 1 | level as u32
+}
+
+#[cfg(not(test))]
+pub struct Transmitter<'d, PIO: pio::Instance, const SM: usize> {
+    pio_sm: pio::StateMachine<'d, PIO, SM>,
+    repeats: u8,
+}
+
+#[cfg(not(test))]
+impl<'d, PIO: pio::Instance, const SM: usize> Transmitter<'d, PIO, SM> {
+    pub fn new(pio: &mut pio::Common<'d, PIO>, mut pio_sm: pio::StateMachine<'d, PIO, SM>, tx_pin: impl PioPin, repeats: u8) -> Self {
+        let pin = pio.make_pio_pin(tx_pin);
+        pio_sm.set_pins(gpio::Level::Low, &[&pin]);
+        pio_sm.set_pin_dirs(pio::Direction::Out, &[&pin]);
+
+        // Sets the level given by the lowest bit and holds it for the loop count given by the other bits.
+        let prg = pio_asm!(
+            ".wrap_target",
+                "pull block",
+                "out pins, 1",
+                "out x, 31",
+            "delay:",
+                "jmp x-- delay",
+            ".wrap",
+        );
+        let mut cfg = pio::Config::default();
+        cfg.set_out_pins(&[&pin]);
+        cfg.shift_out.direction = pio::ShiftDirection::Right;
+        cfg.fifo_join = pio::FifoJoin::TxOnly;
+        cfg.clock_divider = 125.to_fixed(); // 125MHz / 125 = 1MHz, one cycle is 1us
+        cfg.use_program(&pio.load_program(&prg.program), &[]);
+
+        pio_sm.set_config(&cfg);
+        pio_sm.set_enable(true);
+
+        Self { pio_sm, repeats }
+    }
+
+    /// Returns when the last frame has been sent.
+    pub async fn send(&mut self, frame: &Frame) -> Result<(), &'static str> {
+        let levels = rc_switch::encode(frame)?;
+        for _ in 0..self.repeats {
+            for (level, duration_us) in &levels {
+                self.pio_sm.tx().wait_push(pio_word(*level, *duration_us)).await;
+            }
+        }
+        // The pin is left low. Once this is taken from the FIFO, the frames have been sent.
+        self.pio_sm.tx().wait_push(pio_word(false, 0)).await;
+        while !self.pio_sm.tx().empty() {
+            Timer::after_micros(500).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::persistency::MockPersistencyTrait;
+
+    fn code_table() -> CodeTable {
+        let mut code_table = CodeTable::new();
+        code_table.add(Code::fixed_timing(0x017E9E90), "button 1").unwrap();
+        code_table.add(Code { value: 0x1C5103, protocol: 1, bit_count: 24 }, "socket").unwrap();
+        code_table.add(Code { value: 0xABCDE, protocol: 6, bit_count: 20 }, "gate").unwrap();
+        code_table.add(Code { value: 0x3F1C022, protocol: Code::SOMFY_PROTOCOL, bit_count: 28 }, "blinds").unwrap();
+        code_table
+    }
+
+    #[test]
+    fn send_hex_code() {
+        let parse = |parameters: &[u8]| parse_send_parameters(parameters, &code_table(), ReceiverMode::PulseWidth);
+
+        assert_eq!(parse(b"0xBF4F48"), Ok(Frame { protocol: 1, value: 0xBF4F48, bit_count: 24 }));
+        assert_eq!(parse(b"5555 16"), Ok(Frame { protocol: 1, value: 0x5555, bit_count: 16 }));
+        assert_eq!(parse(b"ABCDE 20 6"), Ok(Frame { protocol: 6, value: 0xABCDE, bit_count: 20 }));
+    }
+
+    #[test]
+    fn send_named_code() {
+        // Named codes are sent as they were received, whatever mode the receiver is in now.
+        for mode in [ReceiverMode::PulseWidth, ReceiverMode::FixedTiming] {
+            assert_eq!(parse_send_parameters(b"socket", &code_table(), mode), Ok(Frame { protocol: 1, value: 0x1C5103, bit_count: 24 }));
+            assert_eq!(parse_send_parameters(b"gate", &code_table(), mode), Ok(Frame { protocol: 6, value: 0xABCDE, bit_count: 20 }));
+            // The sync bit read by the fixed timing receiver is not sent.
+            assert_eq!(parse_send_parameters(b"button 1", &code_table(), mode), Ok(Frame { protocol: 1, value: 0xBF4F48, bit_count: 24 }));
+        }
+    }
+
+    #[test]
+    fn codes_of_received_frames() {
+        let frame = Frame { protocol: 1, value: 0xBF4F48, bit_count: 24 };
+        assert_eq!(received_code(&frame, ReceiverMode::FixedTiming), Ok(Code::fixed_timing(0x017E9E90)));
+        assert_eq!(received_code(&frame, ReceiverMode::PulseWidth), Ok(Code { value: 0xBF4F48, protocol: 1, bit_count: 24 }));
+
+        let frame = Frame { protocol: 6, value: 0xABCDE, bit_count: 20 };
+        assert_eq!(received_code(&frame, ReceiverMode::FixedTiming), Err("the fixed timing receiver only reads 24 bit codes of protocol 1"));
+        assert_eq!(received_code(&frame, ReceiverMode::PulseWidth), Ok(Code { value: 0xABCDE, protocol: 6, bit_count: 20 }));
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        const PARAMETERS: &[(&[u8], &str)] = &[
+            (b"button 2", "code is neither a name in the code table nor a hex number"),
+            (b"", "code is neither a name in the code table nor a hex number"),
+            (b"0x5555 sixteen", "bit count is not a number"),
+            (b"0x5555 16 x", "protocol is not a number"),
+            (b"0x5555 16 1 2", "too many parameters"),
+            (b"0x5555 16 13", "protocol must be between 1 and 12"),
+            (b"0x5555 4", "bit count must be between 8 and 32"),
+            (b"blinds", "rolling codes can't be sent"),
+        ];
+        for (parameters, error) in PARAMETERS {
+            assert_eq!(parse_send_parameters(parameters, &code_table(), ReceiverMode::PulseWidth), Err(*error));
+        }
+
+        // The lowest bit of the fixed timing receiver is the sync pulse, which is always read as 0.
+        assert_eq!(parse_send_parameters(b"017E9E91", &code_table(), ReceiverMode::FixedTiming), Err("code was not read by the fixed timing receiver"));
+    }
+
+    #[test]
+    fn pio_words() {
+        assert_eq!(pio_word(true, 350), 346 << 1 | 1);
+        assert_eq!(pio_word(false, 10850), 10846 << 1);
+        assert_eq!(pio_word(false, 0), 0);
+    }
+
+    #[tokio::test]
+    async fn load_settings() {
+        const STORED: &[(&[u8], &[u8], TransmitterSettings)] = &[
+            (b"16", b"5", TransmitterSettings { pin: 16, repeats: 5 }),
+            (b"", b"", TransmitterSettings::DEFAULT),
+            (b"28", b"0", TransmitterSettings::DEFAULT),
+            (b"x", b"300", TransmitterSettings::DEFAULT),
+        ];
+
+        for (pin, repeats, settings) in STORED {
+            let mut mock_persistency = MockPersistencyTrait::new();
+            mock_persistency.expect_read()
+                .returning_st(move |id, answer| {
+                    let stored = if id == ValueId::TxPin { pin } else { repeats };
+                    answer[..stored.len()].copy_from_slice(stored);
+                    Ok(stored.len())
+                });
+
+            assert_eq!(TransmitterSettings::load(&mock_persistency).await, *settings);
+        }
+    }
+}
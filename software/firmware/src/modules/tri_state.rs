@@ -0,0 +1,111 @@
+//! Reads frames of PT2262 style encoders as tri-state codes.
+//! Each of the 12 positions is a pin of the encoder, tied to ground (0), to the supply (1) or left open (F).
+//! It is sent as two bits: 00 for 0, 11 for 1 and 01 for F. The combination 10 is not used.
+//! Most devices use the first 8 positions as the address set by DIP switches and the last 4 as data.
+
+use core::fmt::Write;
+use heapless::String;
+
+use crate::modules::rc_switch::Frame;
+
+const POSITIONS: usize = 12;
+const ADDRESS_POSITIONS: usize = 8;
+const BIT_COUNT: u8 = 2 * POSITIONS as u8;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TriStateCode {
+    positions: [u8; POSITIONS], // b'0', b'1' or b'F'
+}
+
+impl TriStateCode {
+    /// Returns None if the frame is not a valid tri-state code.
+    pub fn decode(frame: &Frame) -> Option<Self> {
+        if frame.bit_count != BIT_COUNT {
+            return None;
+        }
+        let mut positions = [0; POSITIONS];
+        for (n, position) in positions.iter_mut().enumerate() {
+            *position = match frame.value >> (2 * (POSITIONS - 1 - n)) & 0b11 {
+                0b00 => b'0',
+                0b11 => b'1',
+                0b01 => b'F',
+                _ => return None,
+            };
+        }
+        Some(Self { positions })
+    }
+
+    /// The fixed timing receiver samples the 24 data bits followed by the sync pulse, which is always read as 0.
+    pub fn from_fixed_timing(value: u32) -> Option<Self> {
+        if value & 1 != 0 || value >> (BIT_COUNT + 1) != 0 {
+            return None;
+        }
+        Self::decode(&Frame { protocol: 1, value: value >> 1, bit_count: BIT_COUNT })
+    }
+
+    /// All positions, e.g. 0F10FF0F0001.
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.positions).unwrap()
+    }
+
+    /// The positions usually set by DIP switches.
+    pub fn address(&self) -> &str {
+        &self.as_str()[..ADDRESS_POSITIONS]
+    }
+
+    pub fn data(&self) -> &str {
+        &self.as_str()[ADDRESS_POSITIONS..]
+    }
+
+    pub fn topic(&self) -> String<48> {
+        let mut topic = String::new();
+        write!(topic, "433MHz_to_MQTT_tristate/{}", self.address()).unwrap();
+        topic
+    }
+
+    pub fn payload(&self) -> String<64> {
+        let mut payload = String::new();
+        write!(payload, "{{\"code\":\"{}\",\"address\":\"{}\",\"data\":\"{}\"}}", self.as_str(), self.address(), self.data()).unwrap();
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A socket with the DIP switches 0F10FF0F, switched on with data 0001:
+    // 00 01 11 00 01 01 00 01 00 00 00 11
+    const VALUE: u32 = 0b00_01_11_00_01_01_00_01_00_00_00_11;
+
+    #[test]
+    fn decode() {
+        let code = TriStateCode::decode(&Frame { protocol: 1, value: VALUE, bit_count: 24 }).unwrap();
+
+        assert_eq!(code.as_str(), "0F10FF0F0001");
+        assert_eq!(code.address(), "0F10FF0F");
+        assert_eq!(code.data(), "0001");
+    }
+
+    #[test]
+    fn invalid_codes() {
+        // 10 in the last position
+        assert_eq!(TriStateCode::decode(&Frame { protocol: 1, value: VALUE ^ 0b01, bit_count: 24 }), None);
+        assert_eq!(TriStateCode::decode(&Frame { protocol: 1, value: VALUE, bit_count: 25 }), None);
+    }
+
+    #[test]
+    fn from_fixed_timing() {
+        assert_eq!(TriStateCode::from_fixed_timing(VALUE << 1).unwrap().as_str(), "0F10FF0F0001");
+        assert_eq!(TriStateCode::from_fixed_timing(VALUE << 1 | 1), None);
+        assert_eq!(TriStateCode::from_fixed_timing(1 << 25 | VALUE << 1), None);
+    }
+
+    #[test]
+    fn message() {
+        let code = TriStateCode::decode(&Frame { protocol: 1, value: VALUE, bit_count: 24 }).unwrap();
+
+        assert_eq!(code.topic(), "433MHz_to_MQTT_tristate/0F10FF0F");
+        assert_eq!(code.payload(), r#"{"code":"0F10FF0F0001","address":"0F10FF0F","data":"0001"}"#);
+    }
+}
@@ -0,0 +1,99 @@
+//! Reports codes that are not in the code table, so new devices can be integrated without probing.
+//! They are published on their own MQTT topic and written to the terminal.
+
+use cfg_if::cfg_if;
+use core::fmt::Write;
+use heapless::String;
+
+use crate::modules::code_table::Code;
+use crate::modules::tri_state::TriStateCode;
+
+cfg_if! {
+    if #[cfg(not(test))] {
+        use embassy_executor::task;
+
+        use crate::modules::receiver_control::ReceiverControl;
+        use crate::modules::usb_communication::UsbSender;
+
+        pub const TOPIC: &str = "433MHz_to_MQTT_unknown";
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct UnknownCode {
+    pub code: Code, // with protocol and bit count, to tell it from the same value of another protocol
+    pub timestamp_ms: u64, // since start of the gateway
+    pub tri_state: Option<TriStateCode>, // to compare with the DIP switches of PT2262 devices
+    pub rssi_dbm: Option<i16>, // only known with the CC1101
+}
+
+impl UnknownCode {
+    pub fn payload(&self) -> String<112> {
+        let mut payload = String::new();
+        write!(payload, "code {}, {} ms", self.code, self.timestamp_ms).unwrap();
+        if let Some(tri_state) = &self.tri_state {
+            write!(payload, ", tri-state {}", tri_state.as_str()).unwrap();
+        }
+        if let Some(rssi_dbm) = self.rssi_dbm {
+            write!(payload, ", rssi {} dBm", rssi_dbm).unwrap();
+        }
+        payload
+    }
+
+    pub fn terminal_line(&self) -> String<128> {
+        let mut line = String::new();
+        writeln!(line, "unknown {}", self.payload()).unwrap();
+        line
+    }
+}
+
+/// Writes the unknown codes to the terminal, if one is attached. Otherwise they are dropped.
+#[cfg(not(test))]
+#[task]
+pub async fn run(receiver_control: &'static ReceiverControl, usb_sender: &'static UsbSender) -> ! {
+    loop {
+        let unknown_code = receiver_control.unknown_code().await;
+        if usb_sender.terminal_attached().await {
+            let _ = usb_sender.send(unknown_code.terminal_line().as_bytes()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::rc_switch::Frame;
+
+    #[test]
+    fn payload() {
+        let unknown_code = UnknownCode { code: Code::fixed_timing(0x017E9E90), timestamp_ms: 123_456, tri_state: None, rssi_dbm: None };
+
+        assert_eq!(unknown_code.payload(), "code 0x017E9E90 fixed timing, 123456 ms");
+        assert_eq!(unknown_code.terminal_line(), "unknown code 0x017E9E90 fixed timing, 123456 ms\n");
+    }
+
+    #[test]
+    fn payload_with_tri_state() {
+        let frame = Frame { protocol: 1, value: 0x1C5103, bit_count: 24 };
+        let unknown_code = UnknownCode { code: Code::pulse_width(&frame), timestamp_ms: 5000, tri_state: TriStateCode::decode(&frame), rssi_dbm: None };
+
+        assert_eq!(unknown_code.payload(), "code 0x001C5103 protocol 1 24 bits, 5000 ms, tri-state 0F10FF0F0001");
+    }
+
+    #[test]
+    fn payload_with_rssi() {
+        let code = Code { value: 0xBF4F48, protocol: 2, bit_count: 24 };
+        let unknown_code = UnknownCode { code, timestamp_ms: 5000, tri_state: None, rssi_dbm: Some(-67) };
+
+        assert_eq!(unknown_code.payload(), "code 0x00BF4F48 protocol 2 24 bits, 5000 ms, rssi -67 dBm");
+    }
+
+    #[test]
+    fn longest_payload_fits() {
+        let tri_state = TriStateCode::decode(&Frame { protocol: 1, value: 0xFFFFFF, bit_count: 24 });
+        let code = Code { value: u32::MAX, protocol: u8::MAX, bit_count: u8::MAX };
+        let unknown_code = UnknownCode { code, timestamp_ms: u64::MAX, tri_state, rssi_dbm: Some(-138) };
+
+        assert_eq!(unknown_code.terminal_line(), "unknown code 0xFFFFFFFF protocol 255 255 bits, 18446744073709551615 ms, tri-state 111111111111, rssi -138 dBm\n");
+    }
+}
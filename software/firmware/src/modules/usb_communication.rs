@@ -132,6 +132,11 @@ impl UsbSender {
         }
     }
 
+    /// True if a terminal program has opened the port (DTR is set).
+    pub async fn terminal_attached(&self) -> bool {
+        self.usb_sender.lock().await.dtr()
+    }
+
     pub async fn send(&self, data: &[u8]) -> Result<(), EndpointError>{
         let mut usb_sender = self.usb_sender.lock().await;
         for chunk in data.chunks(usb_sender.max_packet_size() as usize) {
@@ -0,0 +1,639 @@
+//! Decodes the frames of common weather sensors and checks their checksums.
+//! The readings are published as JSON on a topic per sensor.
+
+use core::fmt::{self, Write};
+use heapless::{String, Vec};
+
+use crate::modules::decoder::Decoder;
+use crate::modules::devices::{DeviceFlags, DeviceName};
+use crate::modules::line_code::{Bits, ManchesterDecoder, PpmDecoder, PwmDecoder};
+
+// Sensors send every frame several times, equal readings of a sensor within this time are dropped.
+const REPEAT_WINDOW_MS: u64 = 3000;
+const MAX_SENSORS: usize = 8;
+
+// Oregon Scientific sends at least this many ones before the sync nibble.
+const OREGON_MIN_PREAMBLE_BITS: usize = 8;
+const OREGON_SYNC: u32 = 0b0101; // the nibble 0xA, least significant bit first
+const OREGON_MAX_NIBBLES: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SensorReading {
+    pub model: &'static str,
+    pub id: u8, // most sensors choose a new one on every battery change
+    pub channel: Option<u8>,
+    pub temperature_dc: Option<i16>, // tenths of °C
+    pub humidity: Option<u8>, // percent
+    pub battery_low: Option<bool>,
+}
+
+struct Tenths(i16);
+
+impl fmt::Display for Tenths {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        write!(f, "{}{}.{}", sign, self.0.unsigned_abs() / 10, self.0.unsigned_abs() % 10)
+    }
+}
+
+impl SensorReading {
+    pub fn topic(&self) -> String<48> {
+        let mut topic = String::new();
+        write!(topic, "433MHz_to_MQTT_sensor/{}/{:02X}", self.model, self.id).unwrap();
+        topic
+    }
+
+    /// The name of the sensor in the device list, like the last part of the topic.
+    pub fn device_name(&self) -> DeviceName {
+        let mut name = String::new();
+        write!(name, "{}/{:02X}", self.model, self.id).unwrap();
+        name
+    }
+
+    pub fn flags(&self) -> DeviceFlags {
+        DeviceFlags { battery_low: self.battery_low, tampered: None }
+    }
+
+    /// Only the values the sensor sends are contained.
+    pub fn payload(&self) -> String<96> {
+        let mut payload = String::new();
+        let mut separator = "{";
+        let mut field = |payload: &mut String<96>, name: &str, value: &dyn fmt::Display| {
+            write!(payload, "{}\"{}\":{}", separator, name, value).unwrap();
+            separator = ",";
+        };
+        if let Some(temperature_dc) = self.temperature_dc {
+            field(&mut payload, "temperature", &Tenths(temperature_dc));
+        }
+        if let Some(humidity) = self.humidity {
+            field(&mut payload, "humidity", &humidity);
+        }
+        if let Some(channel) = self.channel {
+            field(&mut payload, "channel", &channel);
+        }
+        if let Some(battery_low) = self.battery_low {
+            field(&mut payload, "battery_low", &battery_low);
+        }
+        payload.push('}').unwrap();
+        payload
+    }
+
+    // Sensors sending temperature and humidity in separate frames count as two sensors.
+    fn same_sensor(&self, other: &Self) -> bool {
+        self.model == other.model
+            && self.id == other.id
+            && self.channel == other.channel
+            && self.temperature_dc.is_some() == other.temperature_dc.is_some()
+            && self.humidity.is_some() == other.humidity.is_some()
+    }
+}
+
+/// Runs the decoders of all supported sensors on the pulse stream.
+pub struct WeatherDecoder {
+    decoders: (NexusDecoder, AcuriteDecoder, LaCrosseDecoder, OregonDecoder),
+}
+
+impl WeatherDecoder {
+    pub fn new() -> Self {
+        Self {
+            decoders: (NexusDecoder::new(), AcuriteDecoder::new(), LaCrosseDecoder::new(), OregonDecoder::new()),
+        }
+    }
+}
+
+impl Decoder for WeatherDecoder {
+    type Frame = SensorReading;
+
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<SensorReading> {
+        self.decoders.run(high_us, low_us)
+    }
+}
+
+pub struct NexusDecoder {
+    ppm: PpmDecoder,
+    last_row: Option<Bits>,
+}
+
+impl NexusDecoder {
+    pub fn new() -> Self {
+        Self { ppm: PpmDecoder::new(1000, 2000, 1000), last_row: None }
+    }
+}
+
+impl Decoder for NexusDecoder {
+    type Frame = SensorReading;
+
+    // Nexus frames have no checksum, so a reading is only accepted if the same row was received twice in a row.
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<SensorReading> {
+        let row = self.ppm.run(high_us, low_us)?;
+        let confirmed = self.last_row.as_ref() == Some(&row);
+        let reading = if confirmed { nexus(&row) } else { None };
+        self.last_row = Some(row);
+        reading
+    }
+}
+
+pub struct AcuriteDecoder {
+    ppm: PpmDecoder,
+}
+
+impl AcuriteDecoder {
+    pub fn new() -> Self {
+        Self { ppm: PpmDecoder::new(1000, 2000, 1000) }
+    }
+}
+
+impl Decoder for AcuriteDecoder {
+    type Frame = SensorReading;
+
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<SensorReading> {
+        acurite(&self.ppm.run(high_us, low_us)?)
+    }
+}
+
+pub struct LaCrosseDecoder {
+    pwm: PwmDecoder,
+}
+
+impl LaCrosseDecoder {
+    pub fn new() -> Self {
+        Self { pwm: PwmDecoder::new(550, 1400, true, 4000) }
+    }
+}
+
+impl Decoder for LaCrosseDecoder {
+    type Frame = SensorReading;
+
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<SensorReading> {
+        lacrosse(&self.pwm.run(high_us, low_us)?)
+    }
+}
+
+pub struct OregonDecoder {
+    manchester: ManchesterDecoder,
+}
+
+impl OregonDecoder {
+    pub fn new() -> Self {
+        Self { manchester: ManchesterDecoder::new(488, 2000) } // 1024 bits per second
+    }
+}
+
+impl Decoder for OregonDecoder {
+    type Frame = SensorReading;
+
+    fn run(&mut self, high_us: u32, low_us: u32) -> Option<SensorReading> {
+        oregon(&self.manchester.run(high_us, low_us)?)
+    }
+}
+
+/// Drops readings that are repeated within a short time.
+pub struct ReadingFilter {
+    last: Vec<(SensorReading, u64), MAX_SENSORS>,
+}
+
+impl ReadingFilter {
+    pub fn new() -> Self {
+        Self { last: Vec::new() }
+    }
+
+    pub fn accept(&mut self, reading: &SensorReading, now_ms: u64) -> bool {
+        if let Some((last, last_ms)) = self.last.iter_mut().find(|(last, _)| last.same_sensor(reading)) {
+            if last == reading && now_ms - *last_ms < REPEAT_WINDOW_MS {
+                return false;
+            }
+            *last = *reading;
+            *last_ms = now_ms;
+            return true;
+        }
+        if self.last.is_full() {
+            let oldest = (0..self.last.len()).min_by_key(|n| self.last[*n].1).unwrap();
+            self.last.swap_remove(oldest);
+        }
+        self.last.push((*reading, now_ms)).unwrap();
+        true
+    }
+}
+
+fn signed_12_bits(value: u32) -> i16 {
+    ((value << 4) as u16 as i16) >> 4
+}
+
+/// Nexus-TH, pulse position modulated, without checksum.
+/// id 8 | battery ok 1 | 0 1 | channel 2 | temperature 12, signed 0.1°C | 1111 | humidity 8
+fn nexus(row: &Bits) -> Option<SensorReading> {
+    if row.len() != 36 || row.bits(24, 4) != 0xF {
+        return None;
+    }
+    let humidity = row.bits(28, 8) as u8;
+    if humidity > 100 {
+        return None;
+    }
+    Some(SensorReading {
+        model: "Nexus-TH",
+        id: row.bits(0, 8) as u8,
+        channel: Some(row.bits(10, 2) as u8 + 1),
+        temperature_dc: Some(signed_12_bits(row.bits(12, 12))),
+        humidity: Some(humidity),
+        battery_low: Some(!row.bit(8)),
+    })
+}
+
+/// Acurite 609TXC, pulse position modulated.
+/// id 8 | battery low 1 | 3 bits | temperature 12, signed 0.1°C | humidity 8 | sum of the first four bytes 8
+fn acurite(row: &Bits) -> Option<SensorReading> {
+    let bytes = row.bytes();
+    if row.len() != 40 || bytes[..4].iter().all(|byte| *byte == 0) {
+        return None;
+    }
+    let sum = bytes[..4].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    if sum != bytes[4] || bytes[3] > 100 {
+        return None;
+    }
+    Some(SensorReading {
+        model: "Acurite-609TXC",
+        id: bytes[0],
+        channel: None,
+        temperature_dc: Some(signed_12_bits(row.bits(12, 12))),
+        humidity: Some(bytes[3]),
+        battery_low: Some(row.bit(8)),
+    })
+}
+
+/// LaCrosse TX3/TX4/TX7U, pulse width modulated with a short pulse for a one.
+/// Temperature and humidity are sent in separate frames, the type nibble tells which one it is.
+/// 0 | A | type | address 7, parity 1 | value 3 BCD digits | first two digits repeated | sum of the first ten nibbles
+fn lacrosse(row: &Bits) -> Option<SensorReading> {
+    if row.len() != 44 {
+        return None;
+    }
+    let nibble = |n: usize| row.bits(4 * n, 4);
+    if nibble(0) != 0x0 || nibble(1) != 0xA {
+        return None;
+    }
+    if (0..10).map(nibble).sum::<u32>() & 0xF != nibble(10) {
+        return None;
+    }
+    let (tens, ones, tenths) = (nibble(5), nibble(6), nibble(7));
+    if tens > 9 || ones > 9 || tenths > 9 || nibble(8) != tens || nibble(9) != ones {
+        return None;
+    }
+    let (temperature_dc, humidity) = match nibble(2) {
+        0x0 => (Some((tens * 100 + ones * 10 + tenths) as i16 - 500), None), // offset of 50°C
+        0xE => (None, Some((tens * 10 + ones) as u8)),
+        _ => return None,
+    };
+    Some(SensorReading {
+        model: "LaCrosse-TX",
+        id: row.bits(12, 7) as u8,
+        channel: None,
+        temperature_dc,
+        humidity,
+        battery_low: None,
+    })
+}
+
+struct OregonSensor {
+    id: u16,
+    model: &'static str,
+    humidity: bool,
+    one_hot_channel: bool, // version 2.1 sensors send channel 3 as 4
+}
+
+const OREGON_SENSORS: &[OregonSensor] = &[
+    OregonSensor { id: 0x1D20, model: "Oregon-THGR122N", humidity: true, one_hot_channel: true },
+    OregonSensor { id: 0xEC40, model: "Oregon-THN132N", humidity: false, one_hot_channel: true },
+    OregonSensor { id: 0xF824, model: "Oregon-THGR810", humidity: true, one_hot_channel: false },
+];
+
+/// Oregon Scientific version 2.1 and 3, Manchester coded. Version 2.1 sends every bit twice, first inverted.
+/// After the preamble and the sync nibble the nibbles are sent with the least significant bit first:
+/// sensor id 4 | channel 1 | rolling code 2 | flags 1 | temperature 3 BCD digits, tenths first | sign 1 |
+/// humidity 2 BCD digits, ones first | unknown 1 | sum of the nibbles before 2, low nibble first
+/// Sensors without humidity end with the checksum after the sign.
+fn oregon(row: &Bits) -> Option<SensorReading> {
+    let nibbles = oregon_nibbles(row).or_else(|| oregon_nibbles(&undouble(row)))?;
+    if nibbles.len() < 4 {
+        return None;
+    }
+    let id = nibbles[..4].iter().fold(0u16, |id, nibble| id << 4 | *nibble as u16);
+    let sensor = OREGON_SENSORS.iter().find(|sensor| sensor.id == id)?;
+
+    let checksum_at = if sensor.humidity { 15 } else { 12 };
+    if nibbles.len() < checksum_at + 2 {
+        return None;
+    }
+    let sum = nibbles[..checksum_at].iter().map(|nibble| *nibble as u32).sum::<u32>() & 0xFF;
+    if sum != (nibbles[checksum_at] | nibbles[checksum_at + 1] << 4) as u32 {
+        return None;
+    }
+
+    let digits = [nibbles[10], nibbles[9], nibbles[8]];
+    if digits.iter().any(|digit| *digit > 9) {
+        return None;
+    }
+    let temperature_dc = digits.iter().fold(0i16, |value, digit| value * 10 + *digit as i16);
+    let humidity = if sensor.humidity {
+        if nibbles[12] > 9 || nibbles[13] > 9 {
+            return None;
+        }
+        Some(nibbles[13] * 10 + nibbles[12])
+    } else {
+        None
+    };
+
+    Some(SensorReading {
+        model: sensor.model,
+        id: nibbles[5] << 4 | nibbles[6],
+        channel: Some(if sensor.one_hot_channel && nibbles[4] == 4 { 3 } else { nibbles[4] }),
+        temperature_dc: Some(if nibbles[11] != 0 { -temperature_dc } else { temperature_dc }),
+        humidity,
+        battery_low: Some(nibbles[7] & 0x4 != 0),
+    })
+}
+
+// Finds the sync nibble after the preamble and returns the nibbles following it.
+fn oregon_nibbles(row: &Bits) -> Option<Vec<u8, OREGON_MAX_NIBBLES>> {
+    let start = (OREGON_MIN_PREAMBLE_BITS..row.len().saturating_sub(3)).find(|n| {
+        row.bits(n - OREGON_MIN_PREAMBLE_BITS, OREGON_MIN_PREAMBLE_BITS) == (1 << OREGON_MIN_PREAMBLE_BITS) - 1
+            && row.bits(*n, 4) == OREGON_SYNC
+    })? + 4;
+
+    let mut nibbles = Vec::new();
+    for n in (start..row.len() - 3).step_by(4) {
+        let nibble = (0..4).fold(0, |nibble, bit| nibble | (row.bit(n + bit) as u8) << bit);
+        if nibbles.push(nibble).is_err() {
+            break;
+        }
+    }
+    Some(nibbles)
+}
+
+// Every bit is sent twice, first inverted. Stops at the first pair of equal bits.
+fn undouble(row: &Bits) -> Bits {
+    let undouble_from = |offset: usize| {
+        let mut bits = Bits::new();
+        for n in (offset..row.len().saturating_sub(1)).step_by(2) {
+            if row.bit(n) == row.bit(n + 1) {
+                break;
+            }
+            bits.push(row.bit(n + 1));
+        }
+        bits
+    };
+    let (even, odd) = (undouble_from(0), undouble_from(1));
+    if even.len() >= odd.len() { even } else { odd }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::line_code::test_signals;
+
+    fn decode(pulses: &[(u32, u32)]) -> std::vec::Vec<SensorReading> {
+        let mut decoder = WeatherDecoder::new();
+        pulses.iter().filter_map(|(high, low)| decoder.run(*high, *low)).collect()
+    }
+
+    fn repeated(pulses: std::vec::Vec<(u32, u32)>, count: usize) -> std::vec::Vec<(u32, u32)> {
+        pulses.iter().cycle().take(pulses.len() * count).copied().collect()
+    }
+
+    // Nibbles in the order they are sent, each one least significant bit first.
+    fn oregon_bits(preamble_bits: usize, nibbles: &[u8], doubled: bool) -> Bits {
+        let mut plain = std::vec::Vec::new();
+        plain.extend(std::iter::repeat_n(true, preamble_bits));
+        for nibble in [0xA].iter().chain(nibbles) {
+            plain.extend((0..4).map(|bit| nibble >> bit & 1 == 1));
+        }
+        let mut bits = Bits::new();
+        for bit in plain {
+            if doubled {
+                bits.push(!bit);
+            }
+            bits.push(bit);
+        }
+        bits
+    }
+
+    fn with_checksum(nibbles: &[u8]) -> std::vec::Vec<u8> {
+        let sum = nibbles.iter().map(|nibble| *nibble as u32).sum::<u32>() as u8;
+        let mut nibbles = nibbles.to_vec();
+        nibbles.extend([sum & 0xF, sum >> 4]);
+        nibbles
+    }
+
+    #[test]
+    fn nexus() {
+        // id 0x5A, battery ok, channel 2, 21.7°C, 0xF, 45%
+        let row = test_signals::bits("0101 1010  1 0 01  0000 1101 1001  1111  0010 1101");
+        let pulses = repeated(test_signals::ppm(&row, 500, 1000, 2000, 4000), 3);
+
+        let readings = decode(&pulses);
+        assert_eq!(readings.len(), 2); // the first row is not confirmed yet
+        assert_eq!(readings[0], SensorReading {
+            model: "Nexus-TH",
+            id: 0x5A,
+            channel: Some(2),
+            temperature_dc: Some(217),
+            humidity: Some(45),
+            battery_low: Some(false),
+        });
+    }
+
+    #[test]
+    fn nexus_negative_temperature() {
+        // -5.3°C, battery low, channel 1
+        let row = test_signals::bits("0101 1010  0 0 00  1111 1100 1011  1111  0101 0000");
+        let pulses = repeated(test_signals::ppm(&row, 500, 1000, 2000, 4000), 2);
+
+        let readings = decode(&pulses);
+        assert_eq!(readings.len(), 1);
+        assert_eq!((readings[0].temperature_dc, readings[0].channel, readings[0].battery_low), (Some(-53), Some(1), Some(true)));
+    }
+
+    #[test]
+    fn nexus_needs_equal_rows() {
+        let row_1 = test_signals::bits("0101 1010  1 0 01  0000 1101 1001  1111  0010 1101");
+        let row_2 = test_signals::bits("0101 1010  1 0 01  0000 1101 1000  1111  0010 1101");
+        let mut pulses = test_signals::ppm(&row_1, 500, 1000, 2000, 4000);
+        pulses.extend(test_signals::ppm(&row_2, 500, 1000, 2000, 4000));
+
+        assert_eq!(decode(&pulses), []);
+    }
+
+    #[test]
+    fn acurite() {
+        // id 0x3C, battery ok, 23.4°C, 56%, checksum 0x3C + 0x00 + 0xEA + 0x38 = 0x5E
+        let row = test_signals::bits("0011 1100  0000 0000 1110 1010  0011 1000  0101 1110");
+        let readings = decode(&test_signals::ppm(&row, 500, 1000, 2000, 9000));
+
+        assert_eq!(readings, [SensorReading {
+            model: "Acurite-609TXC",
+            id: 0x3C,
+            channel: None,
+            temperature_dc: Some(234),
+            humidity: Some(56),
+            battery_low: Some(false),
+        }]);
+    }
+
+    #[test]
+    fn acurite_wrong_checksum() {
+        let row = test_signals::bits("0011 1100  0000 0000 1110 1010  0011 1000  0101 1111");
+
+        assert_eq!(decode(&test_signals::ppm(&row, 500, 1000, 2000, 9000)), []);
+    }
+
+    #[test]
+    fn lacrosse() {
+        // temperature frame of address 0x2B, 71.8 - 50 = 21.8°C
+        let temperature = test_signals::bits("0000 1010  0000  0101 0110  0111 0001 1000  0111 0001  1101");
+        // humidity frame, 62%
+        let humidity = test_signals::bits("0000 1010  1110  0101 0110  0110 0010 0000  0110 0010  0011");
+        let mut pulses = test_signals::pwm(&temperature, 550, 1400, 2400, 20_000);
+        pulses.extend(test_signals::pwm(&humidity, 550, 1400, 2400, 20_000));
+
+        let readings = decode(&pulses);
+        assert_eq!(readings, [
+            SensorReading { model: "LaCrosse-TX", id: 0x2B, channel: None, temperature_dc: Some(218), humidity: None, battery_low: None },
+            SensorReading { model: "LaCrosse-TX", id: 0x2B, channel: None, temperature_dc: None, humidity: Some(62), battery_low: None },
+        ]);
+    }
+
+    #[test]
+    fn lacrosse_wrong_checksum() {
+        let row = test_signals::bits("0000 1010  0000  0101 0110  0111 0001 1000  0111 0001  1110");
+
+        assert_eq!(decode(&test_signals::pwm(&row, 550, 1400, 2400, 20_000)), []);
+    }
+
+    #[test]
+    fn oregon_v2_1() {
+        // THGR122N on channel 3, rolling code 0x8B, battery ok, -12.7°C, 48%
+        let nibbles = with_checksum(&[0x1, 0xD, 0x2, 0x0, 0x4, 0x8, 0xB, 0x0, 0x7, 0x2, 0x1, 0x8, 0x8, 0x4, 0x0]);
+        let row = oregon_bits(16, &nibbles, true);
+        let readings = decode(&test_signals::manchester(&row, 488, 10_000));
+
+        assert_eq!(readings, [SensorReading {
+            model: "Oregon-THGR122N",
+            id: 0x8B,
+            channel: Some(3),
+            temperature_dc: Some(-127),
+            humidity: Some(48),
+            battery_low: Some(false),
+        }]);
+    }
+
+    #[test]
+    fn oregon_v2_1_without_humidity() {
+        // THN132N on channel 1, rolling code 0x35, battery low, 4.5°C
+        let nibbles = with_checksum(&[0xE, 0xC, 0x4, 0x0, 0x1, 0x3, 0x5, 0x4, 0x5, 0x4, 0x0, 0x0]);
+        let row = oregon_bits(16, &nibbles, true);
+        let readings = decode(&test_signals::manchester(&row, 488, 10_000));
+
+        assert_eq!(readings, [SensorReading {
+            model: "Oregon-THN132N",
+            id: 0x35,
+            channel: Some(1),
+            temperature_dc: Some(45),
+            humidity: None,
+            battery_low: Some(true),
+        }]);
+    }
+
+    #[test]
+    fn oregon_v3() {
+        // THGR810 on channel 4, rolling code 0xC2, battery ok, 19.6°C, 61%
+        let nibbles = with_checksum(&[0xF, 0x8, 0x2, 0x4, 0x4, 0xC, 0x2, 0x0, 0x6, 0x9, 0x1, 0x0, 0x1, 0x6, 0x0]);
+        let row = oregon_bits(24, &nibbles, false);
+        let readings = decode(&test_signals::manchester(&row, 488, 10_000));
+
+        assert_eq!(readings, [SensorReading {
+            model: "Oregon-THGR810",
+            id: 0xC2,
+            channel: Some(4),
+            temperature_dc: Some(196),
+            humidity: Some(61),
+            battery_low: Some(false),
+        }]);
+    }
+
+    #[test]
+    fn oregon_wrong_checksum() {
+        let mut nibbles = with_checksum(&[0xF, 0x8, 0x2, 0x4, 0x4, 0xC, 0x2, 0x0, 0x6, 0x9, 0x1, 0x0, 0x1, 0x6, 0x0]);
+        nibbles[9] = 0x8;
+        let row = oregon_bits(24, &nibbles, false);
+
+        assert_eq!(decode(&test_signals::manchester(&row, 488, 10_000)), []);
+    }
+
+    #[test]
+    fn payload() {
+        let reading = SensorReading {
+            model: "Nexus-TH",
+            id: 0x5A,
+            channel: Some(2),
+            temperature_dc: Some(-5),
+            humidity: Some(45),
+            battery_low: Some(false),
+        };
+        assert_eq!(reading.topic(), "433MHz_to_MQTT_sensor/Nexus-TH/5A");
+        assert_eq!(reading.payload(), r#"{"temperature":-0.5,"humidity":45,"channel":2,"battery_low":false}"#);
+        assert_eq!(reading.device_name(), "Nexus-TH/5A");
+        assert_eq!(reading.flags(), DeviceFlags { battery_low: Some(false), tampered: None });
+
+        let reading = SensorReading { temperature_dc: None, channel: None, battery_low: None, ..reading };
+        assert_eq!(reading.payload(), r#"{"humidity":45}"#);
+    }
+
+    #[test]
+    fn longest_payload_fits() {
+        let reading = SensorReading {
+            model: "Oregon-THGR122N",
+            id: 0xFF,
+            channel: Some(255),
+            temperature_dc: Some(i16::MIN),
+            humidity: Some(255),
+            battery_low: Some(false),
+        };
+        assert_eq!(reading.topic(), "433MHz_to_MQTT_sensor/Oregon-THGR122N/FF");
+        assert_eq!(reading.payload(), r#"{"temperature":-3276.8,"humidity":255,"channel":255,"battery_low":false}"#);
+        assert_eq!(reading.device_name(), "Oregon-THGR122N/FF");
+    }
+
+    #[test]
+    fn filter() {
+        let reading = SensorReading {
+            model: "Nexus-TH",
+            id: 0x5A,
+            channel: Some(2),
+            temperature_dc: Some(217),
+            humidity: Some(45),
+            battery_low: Some(false),
+        };
+        let other_sensor = SensorReading { channel: Some(3), ..reading };
+        let changed = SensorReading { temperature_dc: Some(218), ..reading };
+        let mut filter = ReadingFilter::new();
+
+        assert!(filter.accept(&reading, 1000));
+        assert!(!filter.accept(&reading, 1100));
+        assert!(filter.accept(&other_sensor, 1200));
+        assert!(!filter.accept(&reading, 3999));
+        assert!(filter.accept(&reading, 4000));
+        assert!(filter.accept(&changed, 4100));
+        assert!(!filter.accept(&changed, 4200));
+    }
+
+    #[test]
+    fn filter_forgets_oldest_sensor() {
+        let reading = |id| SensorReading { model: "Acurite-609TXC", id, channel: None, temperature_dc: Some(234), humidity: Some(56), battery_low: Some(false) };
+        let mut filter = ReadingFilter::new();
+
+        for id in 0..=MAX_SENSORS as u8 {
+            assert!(filter.accept(&reading(id), 1000 + id as u64));
+        }
+        assert!(filter.accept(&reading(0), 1100));
+        assert!(!filter.accept(&reading(MAX_SENSORS as u8), 1100));
+    }
+}